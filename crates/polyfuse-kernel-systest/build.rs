@@ -13,10 +13,57 @@ fn generate_abi_tests() {
     cfg.field_name(|_s, field| field.replace("typ", "type"));
     cfg.skip_field(|s, field| s == "fuse_dirent" && field == "name");
 
-    cfg.skip_struct(|s| s == "UnknownOpcode" || s == "InvalidFileLock");
+    cfg.skip_struct(|s| {
+        matches!(
+            s,
+            "UnknownOpcode" | "InvalidFileLock"
+            // The following items are newer than the bundled libfuse headers.
+            | "fuse_syncfs_in"
+            | "fuse_secctx"
+            | "fuse_secctx_header"
+            | "fuse_sx_time"
+            | "fuse_statx"
+            | "fuse_statx_in"
+            | "fuse_statx_out"
+            | "fuse_ext_header"
+            | "fuse_supp_groups"
+            | "fuse_backing_map"
+        )
+    });
 
-    // FUSE_FSYNC_FDATASYNC is defined since libfuse 3.7.0.
-    cfg.skip_const(|name| name == "FUSE_FSYNC_FDATASYNC");
+    cfg.skip_const(|name| {
+        matches!(
+            name,
+            // FUSE_FSYNC_FDATASYNC is defined since libfuse 3.7.0.
+            "FUSE_FSYNC_FDATASYNC"
+            // The following items are newer than the bundled libfuse headers.
+            | "FOPEN_NOFLUSH"
+            | "FOPEN_PARALLEL_DIRECT_WRITES"
+            | "FOPEN_PASSTHROUGH"
+            | "FUSE_SYNCFS"
+            | "FUSE_TMPFILE"
+            | "FUSE_STATX"
+            | "FUSE_INIT_EXT"
+            | "FUSE_SECURITY_CTX"
+            | "FUSE_SUBMOUNTS"
+            | "FUSE_ATTR_SUBMOUNT"
+            | "FUSE_ATTR_DAX"
+            | "FUSE_PASSTHROUGH"
+            | "FUSE_HAS_RESEND"
+            | "FUSE_CREATE_SUPP_GROUP"
+            | "FUSE_MAX_NR_SECCTX"
+            | "FUSE_EXT_GROUPS"
+            | "FUSE_UNIQUE_RESEND"
+            | "FUSE_NOTIFY_RESEND"
+            | "FILESYSTEM_MAX_STACK_DEPTH"
+            // Android-specific.
+            | "FUSE_CANONICAL_PATH"
+            // Defined with the function-like macros `_IOR` and `_IOW`.
+            | "FUSE_DEV_IOC_CLONE"
+            | "FUSE_DEV_IOC_BACKING_OPEN"
+            | "FUSE_DEV_IOC_BACKING_CLOSE"
+        )
+    });
 
     cfg.generate("../polyfuse-kernel/src/lib.rs", "kernel.rs");
 }
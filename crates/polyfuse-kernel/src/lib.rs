@@ -19,6 +19,9 @@ pub const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
 /// The minimum length of read buffer.
 pub const FUSE_MIN_READ_BUFFER: u32 = 8192;
 
+/// The inode number of the root directory.
+pub const FUSE_ROOT_ID: u64 = 1;
+
 /// Maximum of in_iovecs + out_iovecs
 pub const FUSE_IOCTL_MAX_IOV: u32 = 256;
 
@@ -41,6 +44,9 @@ pub const FOPEN_KEEP_CACHE: u32 = 1 << 1;
 pub const FOPEN_NONSEEKABLE: u32 = 1 << 2;
 pub const FOPEN_CACHE_DIR: u32 = 1 << 3;
 pub const FOPEN_STREAM: u32 = 1 << 4;
+pub const FOPEN_NOFLUSH: u32 = 1 << 5;
+pub const FOPEN_PARALLEL_DIRECT_WRITES: u32 = 1 << 6;
+pub const FOPEN_PASSTHROUGH: u32 = 1 << 7;
 
 // INIT request/reply flags.
 pub const FUSE_ASYNC_READ: u32 = 1;
@@ -69,6 +75,27 @@ pub const FUSE_MAX_PAGES: u32 = 1 << 22;
 pub const FUSE_CACHE_SYMLINKS: u32 = 1 << 23;
 pub const FUSE_NO_OPENDIR_SUPPORT: u32 = 1 << 24;
 pub const FUSE_EXPLICIT_INVAL_DATA: u32 = 1 << 25;
+pub const FUSE_SUBMOUNTS: u32 = 1 << 27;
+pub const FUSE_INIT_EXT: u32 = 1 << 30;
+
+// INIT request/reply flags in `flags2` (since ABI 7.36), which follows `flags`
+// in `fuse_init_in` and occupies the first element of `unused` in `fuse_init_out`.
+pub const FUSE_SECURITY_CTX: u64 = 1 << 32;
+pub const FUSE_PASSTHROUGH: u64 = 1 << 37;
+pub const FUSE_CREATE_SUPP_GROUP: u64 = 1 << 38;
+pub const FUSE_HAS_RESEND: u64 = 1 << 39;
+
+// The bit of `unique` marking the requests resent by `FUSE_NOTIFY_RESEND`
+// (since ABI 7.40).
+pub const FUSE_UNIQUE_RESEND: u64 = 1 << 63;
+
+// The maximum stack depth of the passthrough backing files (since ABI 7.40),
+// stored in the second element of `unused` in `fuse_init_out`.
+pub const FILESYSTEM_MAX_STACK_DEPTH: u32 = 2;
+
+// Attribute flags in `fuse_attr.padding`, which is named `flags` since ABI 7.32.
+pub const FUSE_ATTR_SUBMOUNT: u32 = 1 << 0;
+pub const FUSE_ATTR_DAX: u32 = 1 << 1;
 
 // CUSE INIT request/reply flags.
 pub const CUSE_UNRESTRICTED_IOCTL: u32 = 1 << 0;
@@ -115,6 +142,71 @@ pub const FUSE_COMPAT_INIT_OUT_SIZE: usize = 8;
 pub const FUSE_COMPAT_22_INIT_OUT_SIZE: usize = 24;
 pub const CUSE_INIT_INFO_MAX: u32 = 4096;
 
+// Device ioctls.
+pub const FUSE_DEV_IOC_MAGIC: u32 = 229;
+pub const FUSE_DEV_IOC_CLONE: u32 = ioc(
+    IOC_READ,
+    FUSE_DEV_IOC_MAGIC,
+    0,
+    std::mem::size_of::<u32>() as u32,
+);
+pub const FUSE_DEV_IOC_BACKING_OPEN: u32 = ioc(
+    IOC_WRITE,
+    FUSE_DEV_IOC_MAGIC,
+    1,
+    std::mem::size_of::<fuse_backing_map>() as u32,
+);
+pub const FUSE_DEV_IOC_BACKING_CLOSE: u32 = ioc(
+    IOC_WRITE,
+    FUSE_DEV_IOC_MAGIC,
+    2,
+    std::mem::size_of::<u32>() as u32,
+);
+
+// The direction bits of the ioctl numbers, whose layout depends on the
+// architecture.
+#[cfg(any(
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "sparc",
+    target_arch = "sparc64"
+))]
+const IOC_READ: u32 = 2 << 29;
+#[cfg(any(
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "sparc",
+    target_arch = "sparc64"
+))]
+const IOC_WRITE: u32 = 4 << 29;
+#[cfg(not(any(
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "sparc",
+    target_arch = "sparc64"
+)))]
+const IOC_READ: u32 = 2 << 30;
+#[cfg(not(any(
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "sparc",
+    target_arch = "sparc64"
+)))]
+const IOC_WRITE: u32 = 1 << 30;
+
+/// Encode the number of an ioctl as `_IOR` and `_IOW` do.
+const fn ioc(dir: u32, typ: u32, nr: u32, size: u32) -> u32 {
+    dir | size << 16 | typ << 8 | nr
+}
+
 #[derive(Clone, Copy, Default, FromBytes, AsBytes)]
 #[repr(C)]
 pub struct fuse_attr {
@@ -259,6 +351,14 @@ define_opcode! {
     FUSE_RENAME2 = 45,
     FUSE_LSEEK = 46,
     FUSE_COPY_FILE_RANGE = 47,
+    // FUSE_SETUPMAPPING = 48,
+    // FUSE_REMOVEMAPPING = 49,
+    FUSE_SYNCFS = 50,
+    FUSE_TMPFILE = 51,
+    FUSE_STATX = 52,
+
+    // Android-specific.
+    FUSE_CANONICAL_PATH = 2016,
 
     CUSE_INIT = 4096,
 }
@@ -676,6 +776,110 @@ pub struct fuse_copy_file_range_in {
     pub flags: u64,
 }
 
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_syncfs_in {
+    pub padding: u64,
+}
+
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_sx_time {
+    pub tv_sec: i64,
+    pub tv_nsec: u32,
+    pub __reserved: i32,
+}
+
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_statx {
+    pub mask: u32,
+    pub blksize: u32,
+    pub attributes: u64,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u16,
+    pub __spare0: [u16; 1],
+    pub ino: u64,
+    pub size: u64,
+    pub blocks: u64,
+    pub attributes_mask: u64,
+    pub atime: fuse_sx_time,
+    pub btime: fuse_sx_time,
+    pub ctime: fuse_sx_time,
+    pub mtime: fuse_sx_time,
+    pub rdev_major: u32,
+    pub rdev_minor: u32,
+    pub dev_major: u32,
+    pub dev_minor: u32,
+    pub __spare2: [u64; 14],
+}
+
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_statx_in {
+    pub getattr_flags: u32,
+    pub reserved: u32,
+    pub fh: u64,
+    pub sx_flags: u32,
+    pub sx_mask: u32,
+}
+
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_statx_out {
+    pub attr_valid: u64,
+    pub attr_valid_nsec: u32,
+    pub flags: u32,
+    pub spare: [u64; 2],
+    pub stat: fuse_statx,
+}
+
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_secctx {
+    pub size: u32,
+    pub padding: u32,
+}
+
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_secctx_header {
+    pub size: u32,
+    pub nr_ctx: u32,
+}
+
+// Types of the request extensions (since ABI 7.38). The types up to
+// `FUSE_MAX_NR_SECCTX` are reserved for `fuse_secctx_header`.
+pub const FUSE_MAX_NR_SECCTX: u32 = 31;
+pub const FUSE_EXT_GROUPS: u32 = 32;
+
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_ext_header {
+    pub size: u32,
+    pub typ: u32,
+}
+
+/// The header of the `FUSE_EXT_GROUPS` extension, followed by `nr_groups`
+/// group IDs.
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_supp_groups {
+    pub nr_groups: u32,
+}
+
+/// The argument of `FUSE_DEV_IOC_BACKING_OPEN`, registering a backing file
+/// for the passthrough (since ABI 7.40).
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_backing_map {
+    pub fd: i32,
+    pub flags: u32,
+    pub padding: u64,
+}
+
 macro_rules! define_notify_code {
     ($(
         $(#[$m:meta])*
@@ -704,6 +908,7 @@ define_notify_code! {
     FUSE_NOTIFY_STORE = 4,
     FUSE_NOTIFY_RETRIEVE = 5,
     FUSE_NOTIFY_DELETE = 6,
+    FUSE_NOTIFY_RESEND = 7,
 }
 
 #[derive(Clone, Copy, Default, FromBytes, AsBytes)]
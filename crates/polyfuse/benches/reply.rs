@@ -0,0 +1,204 @@
+//! Benchmarks of the reply path, over a socket pair standing in for the
+//! FUSE device.
+//!
+//! Run with `cargo bench -p polyfuse --bench reply`.
+
+use polyfuse::{KernelConfig, Session};
+use polyfuse_kernel::*;
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    io, mem,
+    os::unix::{net::UnixDatagram, prelude::*},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+use zerocopy::AsBytes as _;
+
+/// The allocator counting the allocations, to measure those of the reply path.
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const ITERATIONS: u32 = 2_000;
+
+/// The size of the payload of the `read` replies, which fits in the socket
+/// buffer along with the header.
+const READ_SIZE: usize = 64 * 1024;
+
+/// The kernel side of a session.
+struct Kernel {
+    socket: UnixDatagram,
+    session: Session,
+    buf: Vec<u8>,
+}
+
+impl Kernel {
+    fn new() -> Self {
+        // SOCK_SEQPACKET preserves the message boundaries like the FUSE device.
+        let mut fds = [0; 2];
+        let res = unsafe {
+            libc::socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            )
+        };
+        assert_eq!(res, 0, "socketpair: {}", io::Error::last_os_error());
+        let socket = unsafe { UnixDatagram::from_raw_fd(fds[0]) };
+
+        let init_in = fuse_init_in {
+            major: 7,
+            minor: FUSE_KERNEL_MINOR_VERSION,
+            max_readahead: 40,
+            flags: 0,
+        };
+        send_request(&socket, fuse_opcode::FUSE_INIT, 1, init_in.as_bytes());
+        let session = unsafe { Session::from_raw_fd(fds[1], KernelConfig::default()) }.unwrap();
+
+        let mut kernel = Self {
+            socket,
+            session,
+            buf: vec![0u8; mem::size_of::<fuse_out_header>() + READ_SIZE],
+        };
+        kernel.recv_reply();
+        kernel
+    }
+
+    fn send_read(&self, unique: u64) {
+        let read_in = fuse_read_in {
+            size: READ_SIZE as u32,
+            ..Default::default()
+        };
+        send_request(
+            &self.socket,
+            fuse_opcode::FUSE_READ,
+            unique,
+            read_in.as_bytes(),
+        );
+    }
+
+    fn recv_reply(&mut self) -> usize {
+        self.socket.recv(&mut self.buf[..]).unwrap()
+    }
+}
+
+fn send_request(socket: &UnixDatagram, opcode: fuse_opcode, unique: u64, arg: &[u8]) {
+    let header = fuse_in_header {
+        len: (mem::size_of::<fuse_in_header>() + arg.len()) as u32,
+        opcode: opcode as u32,
+        unique,
+        nodeid: 1,
+        ..Default::default()
+    };
+    socket.send(&[header.as_bytes(), arg].concat()).unwrap();
+}
+
+fn report(name: &str, elapsed: Duration, bytes: usize) {
+    let per_iter = elapsed / ITERATIONS;
+    let throughput = bytes as f64 * f64::from(ITERATIONS) / elapsed.as_secs_f64() / 1e6;
+    println!(
+        "{:<24} {:>10?}/iter {:>10.1} MB/s",
+        name, per_iter, throughput
+    );
+}
+
+/// Reply by `Request::reply`, which writes the header and the payload as
+/// separate I/O vectors.
+fn read_vectored(kernel: &mut Kernel, payload: &[u8]) {
+    let start = Instant::now();
+    for i in 0..ITERATIONS {
+        kernel.send_read(u64::from(i) + 2);
+        let req = kernel.session.next_request().unwrap().unwrap();
+        req.reply(payload).unwrap();
+        assert_eq!(kernel.recv_reply(), kernel.buf.len());
+    }
+    report("read (vectored)", start.elapsed(), payload.len());
+}
+
+/// Reply by copying the header and the payload into a single buffer, as the
+/// baseline of `read_vectored`.
+fn read_concatenated(kernel: &mut Kernel, payload: &[u8]) {
+    let start = Instant::now();
+    for i in 0..ITERATIONS {
+        kernel.send_read(u64::from(i) + 2);
+        let req = kernel.session.next_request().unwrap().unwrap();
+        let header = fuse_out_header {
+            len: (mem::size_of::<fuse_out_header>() + payload.len()) as u32,
+            error: 0,
+            unique: req.unique(),
+        };
+        let mut msg = Vec::with_capacity(header.len as usize);
+        msg.extend_from_slice(header.as_bytes());
+        msg.extend_from_slice(payload);
+        let res = unsafe {
+            libc::write(
+                kernel.session.as_raw_fd(),
+                msg.as_ptr() as *const libc::c_void,
+                msg.len(),
+            )
+        };
+        assert_eq!(res as usize, msg.len());
+        drop(req);
+        assert_eq!(kernel.recv_reply(), kernel.buf.len());
+    }
+    report("read (concatenated)", start.elapsed(), payload.len());
+}
+
+/// Count the allocations made by `Request::reply` per request, for a reply
+/// of `chunks` chunks such as the entries of `readdir`.
+fn reply_allocations(kernel: &mut Kernel, chunks: usize) {
+    let data = vec![0x5au8; READ_SIZE / chunks];
+    let chunks: Vec<&[u8]> = (0..chunks).map(|_| &data[..]).collect();
+
+    let mut allocations = 0;
+    let start = Instant::now();
+    for i in 0..ITERATIONS {
+        kernel.send_read(u64::from(i) + 2);
+        let req = kernel.session.next_request().unwrap().unwrap();
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        req.reply(&chunks[..]).unwrap();
+        allocations += ALLOCATIONS.load(Ordering::Relaxed) - before;
+        kernel.recv_reply();
+    }
+    report(
+        &format!("reply ({} chunks)", chunks.len()),
+        start.elapsed(),
+        data.len() * chunks.len(),
+    );
+    println!(
+        "{:<24} {:>10.3} allocations/request",
+        "",
+        allocations as f64 / f64::from(ITERATIONS)
+    );
+}
+
+fn main() {
+    let payload = vec![0xa5u8; READ_SIZE];
+    let mut kernel = Kernel::new();
+    read_concatenated(&mut kernel, &payload);
+    read_vectored(&mut kernel, &payload);
+
+    for &chunks in &[1, 4, 64, 512] {
+        reply_allocations(&mut kernel, chunks);
+    }
+}
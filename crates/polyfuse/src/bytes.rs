@@ -9,6 +9,10 @@ use std::os::unix::prelude::*;
 /// This difference is due to the requirement of FUSE kernel driver that all data in
 /// a reply message must be passed in a single `write(2)` syscall.
 ///
+/// Each chunk is passed to `writev(2)` as a separate `iovec` following the
+/// header of the reply, so the payload is never copied into an intermediate
+/// buffer.
+///
 /// [`bytes`]: https://docs.rs/bytes/0.6/bytes
 /// [`Buf`]: https://docs.rs/bytes/0.6/bytes/trait.Buf.html
 pub trait Bytes {
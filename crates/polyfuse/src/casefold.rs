@@ -0,0 +1,260 @@
+//! Case-insensitive lookup of the directory entries.
+//!
+//! The kernel caches the directory entries by the exact names passed to
+//! `lookup`, so on a filesystem emulating a case-insensitive namespace (such
+//! as the one of Windows or macOS) each spelling of a name gets a dentry of
+//! its own. The reply to `lookup` cannot rename the dentry, and the kernel
+//! keeps the spelling of the query, e.g. in `/proc/self/cwd`.
+//!
+//! This has two consequences for the entry cache:
+//!
+//! * A negative entry cached for `README` is not invalidated when `readme`
+//!   is created, since the kernel regards them as different names.
+//! * A positive entry cached for `README` still refers to the inode after
+//!   `readme` is unlinked or renamed.
+//!
+//! `CaseFold` resolves the names regardless of the case, and remembers the
+//! spellings looked up so that the stale entries can be invalidated by
+//! `Notifier::inval_entry` when the directory changes. The spellings are
+//! remembered only as long as the kernel may cache them, i.e. the entry
+//! timeout of the replies. Alternatively, the filesystem may reply every
+//! lookup with a zero entry timeout, at the cost of a `lookup` request for
+//! each path resolution.
+
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fmt,
+    os::unix::prelude::*,
+    time::{Duration, Instant},
+};
+
+/// Fold the case of a file name.
+///
+/// A name in UTF-8 is folded by the Unicode lowercase mapping, and any other
+/// name only by the ASCII one.
+pub fn fold(name: &OsStr) -> OsString {
+    match name.to_str() {
+        Some(name) => name.to_lowercase().into(),
+        None => OsString::from_vec(name.as_bytes().to_ascii_lowercase()),
+    }
+}
+
+/// The table of the directory entries with case-insensitive names.
+///
+/// Each entry is keyed by the inode number of the parent directory and the
+/// folded name, and keeps the name in the case it was created with (the
+/// canonical name), which should be used e.g. in the `readdir` replies.
+pub struct CaseFold {
+    entries: HashMap<(u64, OsString), Slot>,
+    ttl: Duration,
+    next_prune: Instant,
+}
+
+#[derive(Default)]
+struct Slot {
+    entry: Option<(OsString, u64)>,
+    // The spellings along with the time the kernel may cache them until.
+    spellings: Vec<(OsString, Instant)>,
+}
+
+impl fmt::Debug for CaseFold {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CaseFold")
+            .field("entries", &self.entries.len())
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+impl CaseFold {
+    /// Create an empty table.
+    ///
+    /// `ttl` is the longest entry timeout replied to `lookup`, including the
+    /// negative entries. Each spelling is remembered for that long after the
+    /// last lookup, since the kernel then drops the cached entry by itself.
+    /// With a zero `ttl` nothing is remembered, and only the canonical names
+    /// are kept.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            entries: HashMap::new(),
+            ttl,
+            next_prune: Instant::now() + ttl,
+        }
+    }
+
+    /// Resolve the name queried by a `lookup` request.
+    ///
+    /// Return the inode number and the canonical name of the entry, or `None`
+    /// if no entry matches the name in any case. In both cases the spelling
+    /// is remembered as possibly cached by the kernel for `ttl`, so that
+    /// `insert` and `remove` can report it for invalidation meanwhile.
+    pub fn lookup(&mut self, parent: u64, name: &OsStr) -> Option<(u64, &OsStr)> {
+        let key = (parent, fold(name));
+        // With a zero `ttl` there is nothing to remember nor to prune.
+        if self.ttl > Duration::from_secs(0) {
+            let now = Instant::now();
+            self.prune(now);
+
+            let expiry = now + self.ttl;
+            let slot = self.entries.entry(key.clone()).or_default();
+            match slot
+                .spellings
+                .iter_mut()
+                .find(|(spelling, _)| spelling == name)
+            {
+                Some((_, cached_until)) => *cached_until = expiry,
+                None => slot.spellings.push((name.to_owned(), expiry)),
+            }
+        }
+        match self.entries.get(&key)?.entry {
+            Some((ref canonical, ino)) => Some((ino, canonical.as_os_str())),
+            None => None,
+        }
+    }
+
+    /// Register an entry created with the specified name.
+    ///
+    /// Return the spellings of the name previously looked up without
+    /// success. The kernel may have cached them as negative entries, which
+    /// hide the new entry until invalidated.
+    pub fn insert(&mut self, parent: u64, name: &OsStr, ino: u64) -> Vec<OsString> {
+        let now = Instant::now();
+        let slot = self.entries.entry((parent, fold(name))).or_default();
+        slot.entry = Some((name.to_owned(), ino));
+        slot.spellings
+            .drain(..)
+            .filter(|(spelling, cached_until)| spelling != name && *cached_until > now)
+            .map(|(spelling, _)| spelling)
+            .collect()
+    }
+
+    /// Unregister the entry matching the name in any case, such as when it is
+    /// unlinked or renamed.
+    ///
+    /// Return the inode number of the removed entry, along with the
+    /// spellings the kernel may have cached as positive entries pointing to
+    /// the inode. The spelling used in the request is dropped by the kernel
+    /// itself, but invalidating it again does no harm.
+    pub fn remove(&mut self, parent: u64, name: &OsStr) -> Option<(u64, Vec<OsString>)> {
+        let key = (parent, fold(name));
+        match self.entries.get(&key) {
+            Some(Slot {
+                entry: Some(..), ..
+            }) => (),
+            _ => return None,
+        }
+        let now = Instant::now();
+        let slot = self.entries.remove(&key)?;
+        let (_, ino) = slot.entry?;
+        let spellings = slot
+            .spellings
+            .into_iter()
+            .filter(|&(_, cached_until)| cached_until > now)
+            .map(|(spelling, _)| spelling)
+            .collect();
+        Some((ino, spellings))
+    }
+
+    /// Forget the spellings no longer cached by the kernel, along with the
+    /// slots left without the entry, at most once per `ttl`.
+    fn prune(&mut self, now: Instant) {
+        if now < self.next_prune {
+            return;
+        }
+        self.entries.retain(|_, slot| {
+            slot.spellings
+                .retain(|&(_, cached_until)| cached_until > now);
+            slot.entry.is_some() || !slot.spellings.is_empty()
+        });
+        self.next_prune = now + self.ttl;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn fold_names() {
+        assert_eq!(fold(OsStr::new("ReadMe.TXT")), "readme.txt");
+        assert_eq!(fold(OsStr::new("ÉTÉ")), "été");
+        assert_eq!(
+            fold(OsStr::from_bytes(b"ABC\xff")),
+            OsStr::from_bytes(b"abc\xff")
+        );
+    }
+
+    #[test]
+    fn mixed_case_lookup() {
+        let mut table = CaseFold::new(TTL);
+        assert!(table.insert(1, OsStr::new("ReadMe.txt"), 2).is_empty());
+
+        assert_eq!(
+            table.lookup(1, OsStr::new("README.TXT")),
+            Some((2, OsStr::new("ReadMe.txt")))
+        );
+        assert_eq!(
+            table.lookup(1, OsStr::new("readme.txt")),
+            Some((2, OsStr::new("ReadMe.txt")))
+        );
+        assert_eq!(table.lookup(3, OsStr::new("readme.txt")), None);
+
+        // Both spellings may be cached as positive entries.
+        let (ino, stale) = table.remove(1, OsStr::new("README.txt")).unwrap();
+        assert_eq!(ino, 2);
+        assert_eq!(stale, vec!["README.TXT", "readme.txt"]);
+        assert_eq!(table.lookup(1, OsStr::new("readme.txt")), None);
+    }
+
+    #[test]
+    fn negative_entries() {
+        let mut table = CaseFold::new(TTL);
+        assert_eq!(table.lookup(1, OsStr::new("NOTES")), None);
+        assert_eq!(table.lookup(1, OsStr::new("notes")), None);
+        assert_eq!(table.lookup(1, OsStr::new("NOTES")), None);
+
+        // The negative entries other than the created name must be invalidated.
+        let stale = table.insert(1, OsStr::new("notes"), 5);
+        assert_eq!(stale, vec!["NOTES"]);
+        assert_eq!(
+            table.lookup(1, OsStr::new("Notes")),
+            Some((5, OsStr::new("notes")))
+        );
+
+        // Nothing is reported for the names never created.
+        assert!(table.remove(1, OsStr::new("missing")).is_none());
+    }
+
+    #[test]
+    fn expired_spellings() {
+        let mut table = CaseFold::new(TTL);
+        table.insert(1, OsStr::new("notes"), 5);
+        assert!(table.lookup(1, OsStr::new("Notes")).is_some());
+        for i in 0..100 {
+            assert_eq!(table.lookup(1, OsStr::new(&format!("missing{}", i))), None);
+        }
+        assert_eq!(table.entries.len(), 101);
+
+        // The misses are dropped once the kernel no longer caches them.
+        table.prune(Instant::now() + TTL + Duration::from_secs(1));
+        assert_eq!(table.entries.len(), 1);
+        assert_eq!(table.entries[&(1, "notes".into())].spellings.len(), 0);
+
+        // Nothing is remembered nor pruned if the entries are not cached.
+        let mut table = CaseFold::new(Duration::from_secs(0));
+        let next_prune = table.next_prune;
+        for i in 0..100 {
+            assert_eq!(table.lookup(1, OsStr::new(&format!("missing{}", i))), None);
+        }
+        assert!(table.entries.is_empty());
+        assert_eq!(table.next_prune, next_prune);
+        assert!(table.insert(1, OsStr::new("missing0"), 5).is_empty());
+        assert_eq!(
+            table.lookup(1, OsStr::new("MISSING0")),
+            Some((5, OsStr::new("missing0")))
+        );
+    }
+}
@@ -3,12 +3,13 @@ use std::{
     cmp,
     convert::TryInto,
     ffi::{OsStr, OsString},
-    io,
+    fs, io,
     mem::{self, MaybeUninit},
     os::unix::{net::UnixStream, prelude::*},
     path::{Path, PathBuf},
     process::{Command, ExitStatus},
     ptr,
+    time::Duration,
 };
 
 const FUSERMOUNT_PROG: &str = "/usr/bin/fusermount";
@@ -30,8 +31,9 @@ macro_rules! syscall {
 pub struct Connection {
     fd: RawFd,
     child: Option<Fusermount>,
-    mountpoint: PathBuf,
+    mountpoint: Option<PathBuf>,
     mountopts: MountOptions,
+    stream: bool,
 }
 
 impl Drop for Connection {
@@ -43,15 +45,38 @@ impl Drop for Connection {
 impl Connection {
     /// Establish a connection with the FUSE kernel driver.
     pub(crate) fn open(mountpoint: PathBuf, mountopts: MountOptions) -> io::Result<Self> {
+        check_recursive_mount(&mountpoint, &mountopts.backing_paths)?;
+        check_rootmode(&mountpoint, mountopts.rootmode)?;
         let (fd, child) = mount(&mountpoint, &mountopts)?;
         Ok(Self {
             fd,
             child,
-            mountpoint,
+            mountpoint: Some(mountpoint),
             mountopts,
+            stream: false,
         })
     }
 
+    /// Create a connection from an opened file descriptor.
+    ///
+    /// The connection owns the descriptor, but does not unmount anything on drop.
+    pub(crate) fn from_fd(fd: RawFd) -> Self {
+        Self {
+            fd,
+            child: None,
+            mountpoint: None,
+            mountopts: MountOptions::default(),
+            stream: is_stream_socket(fd),
+        }
+    }
+
+    /// Return whether the transport is a stream socket, which does not
+    /// preserve the boundaries of the request messages.
+    #[inline]
+    pub(crate) fn is_stream(&self) -> bool {
+        self.stream
+    }
+
     fn read(&self, dst: &mut [u8]) -> io::Result<usize> {
         let len = syscall! {
             read(
@@ -96,6 +121,107 @@ impl Connection {
         Ok(res as usize)
     }
 
+    /// Open a new FUSE device bound to the same connection by `FUSE_DEV_IOC_CLONE`.
+    pub(crate) fn clone_device(&self) -> io::Result<Self> {
+        let fd = syscall! {
+            open(
+                b"/dev/fuse\0".as_ptr() as *const libc::c_char,
+                libc::O_RDWR | libc::O_CLOEXEC,
+            )
+        };
+        let conn = Self::from_fd(fd);
+        let mut master_fd = self.fd as u32;
+        syscall! { ioctl(fd, polyfuse_kernel::FUSE_DEV_IOC_CLONE as _, &mut master_fd) };
+        Ok(conn)
+    }
+
+    /// Register a backing file for the passthrough by `FUSE_DEV_IOC_BACKING_OPEN`.
+    pub(crate) fn backing_open(&self, fd: RawFd) -> io::Result<u32> {
+        let map = polyfuse_kernel::fuse_backing_map {
+            fd,
+            flags: 0,
+            padding: 0,
+        };
+        let backing_id = syscall! {
+            ioctl(self.fd, polyfuse_kernel::FUSE_DEV_IOC_BACKING_OPEN as _, &map)
+        };
+        Ok(backing_id as u32)
+    }
+
+    /// Unregister a backing file by `FUSE_DEV_IOC_BACKING_CLOSE`.
+    pub(crate) fn backing_close(&self, backing_id: u32) -> io::Result<()> {
+        syscall! {
+            ioctl(self.fd, polyfuse_kernel::FUSE_DEV_IOC_BACKING_CLOSE as _, &backing_id)
+        };
+        Ok(())
+    }
+
+    /// Enable or disable the non-blocking mode of the FUSE device.
+    pub(crate) fn set_nonblocking(&self, enabled: bool) -> io::Result<()> {
+        let flags = syscall! { fcntl(self.fd, libc::F_GETFL) };
+        let flags = if enabled {
+            flags | libc::O_NONBLOCK
+        } else {
+            flags & !libc::O_NONBLOCK
+        };
+        syscall! { fcntl(self.fd, libc::F_SETFL, flags) };
+        Ok(())
+    }
+
+    /// Wait until a message can be read from the FUSE device.
+    ///
+    /// Return `false` if the timeout has elapsed before that. The closed
+    /// connection is reported as readable, so that the following read
+    /// observes the end of the session.
+    pub(crate) fn poll_readable(&self, timeout: Duration) -> io::Result<bool> {
+        let timeout = timeout_millis(Some(timeout));
+        let mut pollfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let n = syscall! { poll(&mut pollfd, 1, timeout) };
+        Ok(n > 0)
+    }
+
+    /// Wait until a message can be written to the FUSE device.
+    ///
+    /// Return `false` if the timeout has elapsed before that.
+    pub(crate) fn poll_writable(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        let timeout = timeout_millis(timeout);
+        let mut pollfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLOUT,
+            revents: 0,
+        };
+        let n = syscall! { poll(&mut pollfd, 1, timeout) };
+        Ok(n > 0 && pollfd.revents & libc::POLLOUT != 0)
+    }
+
+    /// Move a message from the pipe into the FUSE device.
+    ///
+    /// The message must fit in a single `splice(2)`, since the kernel
+    /// does not accept a reply split across multiple writes.
+    pub(crate) fn splice_from(&self, pipe: &Pipe, len: usize, flags: u32) -> io::Result<()> {
+        let res = syscall! {
+            splice(
+                pipe.reader,
+                ptr::null_mut(),
+                self.fd,
+                ptr::null_mut(),
+                len,
+                flags,
+            )
+        };
+        if res as usize != len {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "the reply message is partially spliced",
+            ));
+        }
+        Ok(())
+    }
+
     fn unmount(&mut self) {
         unsafe {
             libc::close(self.fd);
@@ -105,7 +231,9 @@ impl Connection {
             let _ = child.wait();
         }
 
-        unmount(&self.mountpoint);
+        if let Some(ref mountpoint) = self.mountpoint {
+            unmount(mountpoint);
+        }
     }
 }
 
@@ -173,27 +301,329 @@ impl io::Write for &Connection {
     }
 }
 
+// ==== pipe ====
+
+/// A pipe used as the intermediate buffer of `splice(2)`.
+#[derive(Debug)]
+pub(crate) struct Pipe {
+    reader: RawFd,
+    writer: RawFd,
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.reader);
+            libc::close(self.writer);
+        }
+    }
+}
+
+impl Pipe {
+    /// Create a pipe whose capacity is at least `capacity` bytes.
+    pub(crate) fn with_capacity(capacity: usize) -> io::Result<Self> {
+        let mut fds = [0 as c_int; 2];
+        syscall! { pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) };
+        let pipe = Self {
+            reader: fds[0],
+            writer: fds[1],
+        };
+
+        let current = syscall! { fcntl(pipe.writer, libc::F_GETPIPE_SZ) };
+        if (current as usize) < capacity {
+            let size = capacity.try_into().unwrap_or(c_int::MAX);
+            syscall! { fcntl(pipe.writer, libc::F_SETPIPE_SZ, size) };
+        }
+
+        Ok(pipe)
+    }
+
+    /// Move up to `len` bytes from the file at `offset` into the pipe.
+    ///
+    /// Return the number of transferred bytes, which is less than
+    /// `len` only if the end of file has been reached.
+    pub(crate) fn splice_from(&self, fd: RawFd, mut offset: i64, len: usize) -> io::Result<usize> {
+        let mut transferred = 0;
+        while transferred < len {
+            let res = syscall! {
+                splice(
+                    fd,
+                    &mut offset,
+                    self.writer,
+                    ptr::null_mut(),
+                    len - transferred,
+                    libc::SPLICE_F_NONBLOCK,
+                )
+            };
+            if res == 0 {
+                break;
+            }
+            transferred += res as usize;
+        }
+        Ok(transferred)
+    }
+
+    /// Map the pages of `src` into the pipe without copying them.
+    ///
+    /// The pipe refers to the memory until the data is read or spliced out,
+    /// so `src` must not be modified or unmapped until then.
+    pub(crate) fn vmsplice_all(&self, mut src: &[u8]) -> io::Result<()> {
+        while !src.is_empty() {
+            let iov = libc::iovec {
+                iov_base: src.as_ptr() as *mut c_void,
+                iov_len: src.len(),
+            };
+            let res = syscall! { vmsplice(self.writer, &iov, 1, 0) };
+            src = &src[res as usize..];
+        }
+        Ok(())
+    }
+
+    pub(crate) fn write_all(&self, mut src: &[u8]) -> io::Result<()> {
+        while !src.is_empty() {
+            let res = syscall! {
+                write(
+                    self.writer, //
+                    src.as_ptr() as *const c_void,
+                    src.len(),
+                )
+            };
+            src = &src[res as usize..];
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read_exact(&self, mut dst: &mut [u8]) -> io::Result<()> {
+        while !dst.is_empty() {
+            let res = syscall! {
+                read(
+                    self.reader, //
+                    dst.as_mut_ptr() as *mut c_void,
+                    dst.len(),
+                )
+            };
+            if res == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            dst = &mut dst[res as usize..];
+        }
+        Ok(())
+    }
+}
+
+/// Return whether the descriptor refers to a `SOCK_STREAM` socket.
+///
+/// The FUSE device is not a socket, so the query fails with `ENOTSOCK`.
+fn is_stream_socket(fd: RawFd) -> bool {
+    let mut typ: c_int = 0;
+    let mut len = mem::size_of::<c_int>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut typ as *mut c_int as *mut c_void,
+            &mut len,
+        )
+    };
+    res == 0 && typ == libc::SOCK_STREAM
+}
+
+/// Convert the timeout into milliseconds for `poll(2)` and `epoll_wait(2)`,
+/// where `-1` means no timeout.
+///
+/// Round up so that a sub-millisecond timeout does not become a busy loop
+/// and the timeout does not elapse early.
+pub(crate) fn timeout_millis(timeout: Option<Duration>) -> c_int {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return -1,
+    };
+    let mut millis = timeout.as_millis();
+    if Duration::from_millis(millis as u64) < timeout {
+        millis += 1;
+    }
+    millis.try_into().unwrap_or(c_int::MAX)
+}
+
+// ==== epoll ====
+
+/// An `epoll(7)` instance.
+#[derive(Debug)]
+pub(crate) struct Epoll {
+    fd: RawFd,
+}
+
+impl Drop for Epoll {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+impl Epoll {
+    pub(crate) fn new() -> io::Result<Self> {
+        let fd = syscall! { epoll_create1(libc::EPOLL_CLOEXEC) };
+        Ok(Self { fd })
+    }
+
+    /// Watch the descriptor for readability, tagged with `token`.
+    pub(crate) fn add(&self, fd: RawFd, token: u64) -> io::Result<()> {
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: token,
+        };
+        syscall! { epoll_ctl(self.fd, libc::EPOLL_CTL_ADD, fd, &mut event) };
+        Ok(())
+    }
+
+    pub(crate) fn delete(&self, fd: RawFd) -> io::Result<()> {
+        syscall! { epoll_ctl(self.fd, libc::EPOLL_CTL_DEL, fd, ptr::null_mut()) };
+        Ok(())
+    }
+
+    /// Wait for the events and return the tokens of the ready descriptors.
+    pub(crate) fn wait(&self, timeout: Option<Duration>) -> io::Result<Vec<u64>> {
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; 16];
+        let n = syscall! {
+            epoll_wait(
+                self.fd,
+                events.as_mut_ptr(),
+                events.len() as c_int,
+                timeout_millis(timeout),
+            )
+        };
+        Ok(events[..n as usize].iter().map(|event| event.u64).collect())
+    }
+}
+
 // ==== mount ====
 
 #[derive(Debug)]
 pub(crate) struct MountOptions {
     pub(crate) options: Vec<String>,
+    pub(crate) fsname: Option<String>,
+    pub(crate) subtype: Option<String>,
     pub(crate) auto_unmount: bool,
     pub(crate) fusermount_path: Option<PathBuf>,
     pub(crate) fuse_comm_fd: Option<OsString>,
+    pub(crate) backing_paths: Vec<PathBuf>,
+    pub(crate) rootmode: Option<u32>,
 }
 
 impl Default for MountOptions {
     fn default() -> Self {
         Self {
             options: vec![],
+            fsname: None,
+            subtype: None,
             auto_unmount: true,
             fusermount_path: None,
             fuse_comm_fd: None,
+            backing_paths: vec![],
+            rootmode: None,
+        }
+    }
+}
+
+impl MountOptions {
+    /// Build the comma-separated option string passed to `fusermount`.
+    ///
+    /// `rootmode` is not included, since `fusermount` refuses it and derives
+    /// the mode from the mountpoint instead (see `check_rootmode`).
+    pub(crate) fn to_option_string(&self) -> String {
+        let mut opts = String::new();
+        let mut push = |opt: &str| {
+            if !opts.is_empty() {
+                opts.push(',');
+            }
+            opts.push_str(opt);
+        };
+
+        for opt in &self.options {
+            push(opt);
+        }
+        if let Some(ref fsname) = self.fsname {
+            push(&format!("fsname={}", escape_option_value(fsname)));
+        }
+        if let Some(ref subtype) = self.subtype {
+            push(&format!("subtype={}", escape_option_value(subtype)));
+        }
+        if self.auto_unmount {
+            push("auto_unmount");
         }
+
+        opts
     }
 }
 
+/// Refuse to mount over a backing store of the filesystem, or over one of
+/// its ancestors.
+///
+/// The filesystem would then access the backing store through its own mount,
+/// and each request would wait for itself forever.
+fn check_recursive_mount(mountpoint: &Path, backing_paths: &[PathBuf]) -> io::Result<()> {
+    if backing_paths.is_empty() {
+        return Ok(());
+    }
+    let mountpoint = mountpoint.canonicalize()?;
+    for backing_path in backing_paths {
+        let backing_path = backing_path.canonicalize()?;
+        if backing_path.starts_with(&mountpoint) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "the mountpoint {} hides the backing store {} of the filesystem",
+                    mountpoint.display(),
+                    backing_path.display()
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Refuse to mount on a file whose type differs from the root of the
+/// filesystem.
+///
+/// `fusermount` passes the file type of the mountpoint to the kernel as
+/// `rootmode`, and the kernel marks the root inode as bad if `getattr`
+/// replies another type, so that every access to the mount fails with `EIO`.
+/// A filesystem with a regular file as its root must therefore be mounted on
+/// a regular file.
+fn check_rootmode(mountpoint: &Path, rootmode: Option<u32>) -> io::Result<()> {
+    let rootmode = match rootmode {
+        Some(rootmode) => rootmode,
+        None => return Ok(()),
+    };
+    let mode = fs::metadata(mountpoint)?.mode() & libc::S_IFMT;
+    if mode != rootmode {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "the mountpoint {} has the file type {:o}, but the root is {:o}",
+                mountpoint.display(),
+                mode,
+                rootmode
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Escape the option separators in the value, in the same way as `fuse_opt_add_opt_escaped`.
+fn escape_option_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == ',' || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
 #[derive(Debug)]
 struct Fusermount {
     pid: c_int,
@@ -219,22 +649,7 @@ fn mount(mountpoint: &Path, mountopts: &MountOptions) -> io::Result<(RawFd, Opti
             .unwrap_or_else(|| Path::new(FUSERMOUNT_PROG)),
     );
 
-    let opts = mountopts
-        .options
-        .iter()
-        .map(|opt| opt.as_str())
-        .chain(if mountopts.auto_unmount {
-            Some("auto_unmount")
-        } else {
-            None
-        })
-        .fold(String::new(), |mut opts, opt| {
-            if !opts.is_empty() {
-                opts.push(',');
-            }
-            opts.push_str(&opt);
-            opts
-        });
+    let opts = mountopts.to_option_string();
     if !opts.is_empty() {
         fusermount.arg("-o").arg(opts);
     }
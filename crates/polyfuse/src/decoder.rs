@@ -30,6 +30,11 @@ impl<'a> Decoder<'a> {
         Ok(bytes)
     }
 
+    /// Fetch all the remaining bytes.
+    pub(crate) fn fetch_remaining(&mut self) -> &'a [u8] {
+        mem::take(&mut self.bytes)
+    }
+
     /// Fetch a value of Plain-Old-Data (POD) type by reference.
     pub(crate) fn fetch<T>(&mut self) -> Result<&'a T, DecodeError>
     where
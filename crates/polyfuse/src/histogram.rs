@@ -0,0 +1,169 @@
+//! Histograms of the sizes of the I/O requests.
+
+use crate::decoder::Decoder;
+use polyfuse_kernel::*;
+use std::{
+    convert::TryFrom,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// The number of the buckets in `IoSizeHistogram`.
+const IO_SIZE_BUCKETS: usize = 10;
+
+/// The upper bound of the first bucket in `IoSizeHistogram`.
+const IO_SIZE_MIN_BOUND: u32 = 4096;
+
+/// The counters behind the histograms, updated on each request.
+#[derive(Default)]
+pub(crate) struct IoSizeCounters {
+    reads: [AtomicU64; IO_SIZE_BUCKETS],
+    writes: [AtomicU64; IO_SIZE_BUCKETS],
+}
+
+impl IoSizeCounters {
+    pub(crate) fn record(&self, header: &fuse_in_header, arg: &[u8]) {
+        match fuse_opcode::try_from(header.opcode).ok() {
+            Some(fuse_opcode::FUSE_READ) => {
+                if let Ok(read_in) = Decoder::new(arg).fetch::<fuse_read_in>() {
+                    self.reads[IoSizeHistogram::bucket(read_in.size)]
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Some(fuse_opcode::FUSE_WRITE) => {
+                if let Ok(write_in) = Decoder::new(arg).fetch::<fuse_write_in>() {
+                    self.writes[IoSizeHistogram::bucket(write_in.size)]
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Return the histogram of the sizes of the `read` requests.
+    pub(crate) fn reads(&self) -> IoSizeHistogram {
+        Self::snapshot(&self.reads)
+    }
+
+    /// Return the histogram of the sizes of the `write` requests.
+    pub(crate) fn writes(&self) -> IoSizeHistogram {
+        Self::snapshot(&self.writes)
+    }
+
+    fn snapshot(counters: &[AtomicU64; IO_SIZE_BUCKETS]) -> IoSizeHistogram {
+        let mut counts = [0; IO_SIZE_BUCKETS];
+        for (count, counter) in counts.iter_mut().zip(counters) {
+            *count = counter.load(Ordering::Relaxed);
+        }
+        IoSizeHistogram { counts }
+    }
+}
+
+/// The counts of the I/O requests bucketed by their sizes, see
+/// `KernelConfig::io_size_stats`.
+///
+/// The upper bounds of the buckets are the powers of two from 4 KiB to
+/// 1 MiB, and the last bucket holds the larger sizes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct IoSizeHistogram {
+    counts: [u64; IO_SIZE_BUCKETS],
+}
+
+impl IoSizeHistogram {
+    fn bucket(size: u32) -> usize {
+        let mut bound = IO_SIZE_MIN_BOUND;
+        let mut bucket = 0;
+        while size > bound && bucket < IO_SIZE_BUCKETS - 1 {
+            bound <<= 1;
+            bucket += 1;
+        }
+        bucket
+    }
+
+    /// Return the number of the requests counted in the bucket containing
+    /// `size`.
+    pub fn count(&self, size: u32) -> u64 {
+        self.counts[Self::bucket(size)]
+    }
+
+    /// Return the total number of the requests.
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Iterate over the buckets, as the pairs of the inclusive upper bound of
+    /// the sizes and the number of the requests. The bound of the last bucket
+    /// is `u32::MAX`.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, u64)> + '_ {
+        self.counts.iter().enumerate().map(|(i, &count)| {
+            let bound = if i < IO_SIZE_BUCKETS - 1 {
+                IO_SIZE_MIN_BOUND << i
+            } else {
+                u32::MAX
+            };
+            (bound, count)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem;
+    use zerocopy::AsBytes as _;
+
+    fn header(opcode: fuse_opcode) -> fuse_in_header {
+        fuse_in_header {
+            opcode: opcode as u32,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn buckets() {
+        assert_eq!(IoSizeHistogram::bucket(0), 0);
+        assert_eq!(IoSizeHistogram::bucket(4096), 0);
+        assert_eq!(IoSizeHistogram::bucket(4097), 1);
+        assert_eq!(IoSizeHistogram::bucket(1 << 20), IO_SIZE_BUCKETS - 2);
+        assert_eq!(IoSizeHistogram::bucket((1 << 20) + 1), IO_SIZE_BUCKETS - 1);
+        assert_eq!(IoSizeHistogram::bucket(u32::MAX), IO_SIZE_BUCKETS - 1);
+
+        let bounds: Vec<_> = IoSizeHistogram::default()
+            .iter()
+            .map(|(bound, _)| bound)
+            .collect();
+        assert_eq!(bounds.len(), IO_SIZE_BUCKETS);
+        assert_eq!(bounds[0], 4096);
+        assert_eq!(bounds[IO_SIZE_BUCKETS - 2], 1 << 20);
+        assert_eq!(bounds[IO_SIZE_BUCKETS - 1], u32::MAX);
+    }
+
+    #[test]
+    fn record() {
+        let counters = IoSizeCounters::default();
+        let read_in = fuse_read_in {
+            size: 8192,
+            ..Default::default()
+        };
+        let write_in = fuse_write_in {
+            size: 512,
+            ..Default::default()
+        };
+        counters.record(&header(fuse_opcode::FUSE_READ), read_in.as_bytes());
+        counters.record(&header(fuse_opcode::FUSE_READ), read_in.as_bytes());
+        counters.record(&header(fuse_opcode::FUSE_WRITE), write_in.as_bytes());
+
+        // The other requests and the truncated arguments are not counted.
+        counters.record(&header(fuse_opcode::FUSE_GETATTR), read_in.as_bytes());
+        counters.record(
+            &header(fuse_opcode::FUSE_READ),
+            &read_in.as_bytes()[..mem::size_of::<fuse_read_in>() - 1],
+        );
+
+        let reads = counters.reads();
+        assert_eq!(reads.total(), 2);
+        assert_eq!(reads.count(8192), 2);
+        let writes = counters.writes();
+        assert_eq!(writes.total(), 1);
+        assert_eq!(writes.count(1), 1);
+    }
+}
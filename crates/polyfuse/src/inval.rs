@@ -0,0 +1,315 @@
+//! Batching of the cache invalidations sent to the kernel.
+
+use crate::session::Notifier;
+use std::{
+    cmp,
+    collections::BTreeMap,
+    fmt, io,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// A helper to coalesce the redundant `inval_inode` notifications.
+///
+/// The invalidations are kept pending per inode and merged, so that an inode
+/// modified repeatedly is notified only once. The merged range covers all the
+/// requested ones, so no invalidation is lost by batching, although more data
+/// than necessary may be dropped from the cache.
+///
+/// The pending invalidations are flushed by a background thread once
+/// `window` has elapsed since the oldest pending one, and may be flushed
+/// earlier by calling `flush` explicitly. The remaining ones are flushed on
+/// drop.
+pub struct InvalidationBatcher {
+    batch: Arc<Batch>,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+struct Batch {
+    notifier: Notifier,
+    window: Duration,
+    pending: Mutex<PendingInvalidations>,
+    // Notified when the first invalidation is queued, and on drop.
+    queued: Condvar,
+}
+
+#[derive(Default)]
+struct PendingInvalidations {
+    ranges: BTreeMap<u64, InvalRange>,
+    since: Option<Instant>,
+    closed: bool,
+}
+
+/// The range of an inode to be invalidated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum InvalRange {
+    /// Only the attributes.
+    Attr,
+    /// The attributes and the data in `start..end`, or up to the end of file.
+    Data { start: i64, end: Option<i64> },
+}
+
+impl InvalRange {
+    fn new(off: i64, len: i64) -> Self {
+        if off < 0 {
+            InvalRange::Attr
+        } else if len <= 0 {
+            InvalRange::Data {
+                start: off,
+                end: None,
+            }
+        } else {
+            InvalRange::Data {
+                start: off,
+                end: Some(off.saturating_add(len)),
+            }
+        }
+    }
+
+    /// Return the smallest range covering both ranges.
+    fn merge(self, other: Self) -> Self {
+        match (self, other) {
+            (InvalRange::Attr, range) | (range, InvalRange::Attr) => range,
+            (
+                InvalRange::Data { start, end },
+                InvalRange::Data {
+                    start: other_start,
+                    end: other_end,
+                },
+            ) => InvalRange::Data {
+                start: cmp::min(start, other_start),
+                end: match (end, other_end) {
+                    (Some(end), Some(other_end)) => Some(cmp::max(end, other_end)),
+                    _ => None,
+                },
+            },
+        }
+    }
+
+    /// Return the pair of `off` and `len` passed to the kernel.
+    fn to_off_len(self) -> (i64, i64) {
+        match self {
+            InvalRange::Attr => (-1, 0),
+            InvalRange::Data { start, end: None } => (start, 0),
+            InvalRange::Data {
+                start,
+                end: Some(end),
+            } => (start, end - start),
+        }
+    }
+}
+
+impl fmt::Debug for InvalidationBatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InvalidationBatcher")
+            .field("window", &self.batch.window)
+            .finish()
+    }
+}
+
+impl InvalidationBatcher {
+    /// Create a batcher that sends the notifications through `notifier`.
+    ///
+    /// This spawns the thread flushing the invalidations on the deadline.
+    pub fn new(notifier: Notifier, window: Duration) -> io::Result<Self> {
+        let batch = Arc::new(Batch {
+            notifier,
+            window,
+            pending: Mutex::new(PendingInvalidations::default()),
+            queued: Condvar::new(),
+        });
+        let worker = thread::Builder::new()
+            .name("polyfuse-inval-batcher".into())
+            .spawn({
+                let batch = batch.clone();
+                move || batch.flush_on_deadline()
+            })?;
+        Ok(Self {
+            batch,
+            worker: Some(worker),
+        })
+    }
+
+    /// Request the cache invalidation about an inode.
+    ///
+    /// The arguments have the same meaning as `Notifier::inval_inode`.
+    pub fn inval_inode(&self, ino: u64, off: i64, len: i64) -> io::Result<()> {
+        let range = InvalRange::new(off, len);
+        let mut pending = self.batch.lock();
+        let merged = match pending.ranges.get(&ino) {
+            Some(&current) => current.merge(range),
+            None => range,
+        };
+        pending.ranges.insert(ino, merged);
+        let since = match pending.since {
+            Some(since) => since,
+            None => {
+                let now = Instant::now();
+                pending.since = Some(now);
+                self.batch.queued.notify_one();
+                now
+            }
+        };
+
+        if since.elapsed() >= self.batch.window {
+            self.batch.flush_pending(&mut pending)?;
+        }
+        Ok(())
+    }
+
+    /// Send all the pending invalidations to the kernel.
+    pub fn flush(&self) -> io::Result<()> {
+        let mut pending = self.batch.lock();
+        self.batch.flush_pending(&mut pending)
+    }
+}
+
+impl Batch {
+    fn lock(&self) -> MutexGuard<'_, PendingInvalidations> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn flush_pending(&self, pending: &mut PendingInvalidations) -> io::Result<()> {
+        while let Some((&ino, &range)) = pending.ranges.iter().next() {
+            let (off, len) = range.to_off_len();
+            // Keep the invalidation pending if the notification fails.
+            self.notifier.inval_inode(ino, off, len)?;
+            pending.ranges.remove(&ino);
+        }
+        pending.since = None;
+        Ok(())
+    }
+
+    /// Flush the pending invalidations whenever `window` has elapsed since
+    /// the oldest one, until the batcher is dropped.
+    fn flush_on_deadline(&self) {
+        let mut pending = self.lock();
+        while !pending.closed {
+            let since = match pending.since {
+                Some(since) => since,
+                None => {
+                    pending = self.queued.wait(pending).unwrap_or_else(|e| e.into_inner());
+                    continue;
+                }
+            };
+            let elapsed = since.elapsed();
+            if elapsed < self.window {
+                pending = self
+                    .queued
+                    .wait_timeout(pending, self.window - elapsed)
+                    .unwrap_or_else(|e| e.into_inner())
+                    .0;
+                continue;
+            }
+            if let Err(err) = self.flush_pending(&mut pending) {
+                tracing::error!("failed to flush the pending invalidations: {}", err);
+                // Retry after another window rather than spinning.
+                pending.since = Some(Instant::now());
+            }
+        }
+    }
+}
+
+impl Drop for InvalidationBatcher {
+    fn drop(&mut self) {
+        self.batch.lock().closed = true;
+        self.batch.queued.notify_one();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+        if let Err(err) = self.flush() {
+            tracing::error!("failed to flush the pending invalidations: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        session::tests::{recv_reply, test_session},
+        KernelConfig,
+    };
+    use polyfuse_kernel::*;
+    use std::mem;
+    use zerocopy::AsBytes as _;
+
+    /// Return the frame of the `inval_inode` notification.
+    fn expected(ino: u64, off: i64, len: i64) -> Vec<u8> {
+        let header = fuse_out_header {
+            len: (mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_notify_inval_inode_out>())
+                as u32,
+            error: fuse_notify_code::FUSE_NOTIFY_INVAL_INODE as i32,
+            unique: 0,
+        };
+        let arg = fuse_notify_inval_inode_out { ino, off, len };
+        let mut frame = header.as_bytes().to_vec();
+        frame.extend_from_slice(arg.as_bytes());
+        frame
+    }
+
+    #[test]
+    fn coalesce() {
+        let (session, kernel) = test_session(KernelConfig::default());
+        kernel.set_nonblocking(true).unwrap();
+
+        let nothing_sent = || {
+            let mut buf = [0u8; 1];
+            let err = kernel.recv(&mut buf).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        };
+
+        let batcher =
+            InvalidationBatcher::new(session.notifier(), Duration::from_secs(3600)).unwrap();
+        for i in 0..100 {
+            batcher.inval_inode(42, 4096 * i, 4096).unwrap();
+        }
+        batcher.inval_inode(42, -1, 0).unwrap();
+        batcher.inval_inode(7, -1, 0).unwrap();
+        nothing_sent();
+
+        // Collapsed into one frame per inode, covering all the ranges.
+        batcher.flush().unwrap();
+        assert_eq!(recv_reply(&kernel), expected(7, -1, 0));
+        assert_eq!(recv_reply(&kernel), expected(42, 0, 4096 * 100));
+        nothing_sent();
+
+        // Up to the end of file absorbs the bounded ranges.
+        batcher.inval_inode(42, 8192, 0).unwrap();
+        batcher.inval_inode(42, 0, 10).unwrap();
+        drop(batcher);
+        assert_eq!(recv_reply(&kernel), expected(42, 0, 0));
+        nothing_sent();
+
+        // The pending invalidations are flushed once the window has elapsed.
+        let batcher = InvalidationBatcher::new(session.notifier(), Duration::from_secs(0)).unwrap();
+        batcher.inval_inode(42, -1, 0).unwrap();
+        assert_eq!(recv_reply(&kernel), expected(42, -1, 0));
+    }
+
+    #[test]
+    fn flush_on_deadline() {
+        let (session, kernel) = test_session(KernelConfig::default());
+        kernel
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+
+        // A lone invalidation is sent without any further call.
+        let window = Duration::from_millis(50);
+        let batcher = InvalidationBatcher::new(session.notifier(), window).unwrap();
+        let start = Instant::now();
+        batcher.inval_inode(42, 0, 10).unwrap();
+        assert_eq!(recv_reply(&kernel), expected(42, 0, 10));
+        assert!(start.elapsed() >= window);
+
+        // And so is the next one.
+        batcher.inval_inode(7, -1, 0).unwrap();
+        assert_eq!(recv_reply(&kernel), expected(7, -1, 0));
+
+        drop(batcher);
+        kernel.set_nonblocking(true).unwrap();
+        let err = kernel.recv(&mut [0u8; 1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+}
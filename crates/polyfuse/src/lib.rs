@@ -5,13 +5,27 @@
 
 mod conn;
 mod decoder;
+mod histogram;
+mod inval;
+mod manager;
 mod session;
 
 pub mod bytes;
+pub mod casefold;
+pub mod nodemap;
 pub mod op;
+pub mod proxy;
 pub mod reply;
+pub mod service;
+pub mod symlink;
 
 pub use crate::{
+    histogram::IoSizeHistogram,
+    inval::InvalidationBatcher,
+    manager::{MountId, SessionManager},
     op::Operation,
-    session::{Data, KernelConfig, Notifier, Request, Session},
+    session::{
+        CancellationToken, Data, DeviceHandle, Extensions, InitIn, InitOut, KernelConfig, Notifier,
+        Request, RequestInfo, Session,
+    },
 };
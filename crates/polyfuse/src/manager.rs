@@ -0,0 +1,242 @@
+//! Serving multiple sessions from a single thread.
+
+use crate::{conn::Epoll, service::Service, session::Session};
+use std::{fmt, io, os::unix::prelude::*, time::Duration};
+
+/// The identifier of a session registered to `SessionManager`.
+///
+/// The identifiers are not reused, so the one of an unregistered session
+/// never addresses another session registered later.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct MountId {
+    index: usize,
+    generation: u64,
+}
+
+/// A multiplexer serving multiple sessions from a single thread.
+///
+/// The devices of the registered sessions are watched with `epoll(7)`, and
+/// each incoming request is passed to the service registered together with
+/// its session. The `MountId` of the session is stored in the extensions of
+/// the request before dispatching, so that a service shared among the mounts
+/// can tell them apart.
+pub struct SessionManager {
+    epoll: Epoll,
+    mounts: Vec<Option<Mount>>,
+    generation: u64,
+}
+
+struct Mount {
+    id: MountId,
+    session: Session,
+    service: Box<dyn Service>,
+}
+
+impl fmt::Debug for SessionManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionManager")
+            .field("epoll", &self.epoll)
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl SessionManager {
+    /// Create an empty session manager.
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            epoll: Epoll::new()?,
+            mounts: vec![],
+            generation: 0,
+        })
+    }
+
+    /// Register a session, along with the service handling its requests.
+    pub fn add<S>(&mut self, session: Session, service: S) -> io::Result<MountId>
+    where
+        S: Service + 'static,
+    {
+        let index = self
+            .mounts
+            .iter()
+            .position(Option::is_none)
+            .unwrap_or(self.mounts.len());
+
+        self.epoll.add(session.as_raw_fd(), index as u64)?;
+
+        self.generation += 1;
+        let id = MountId {
+            index,
+            generation: self.generation,
+        };
+        let mount = Mount {
+            id,
+            session,
+            service: Box::new(service),
+        };
+        if index == self.mounts.len() {
+            self.mounts.push(Some(mount));
+        } else {
+            self.mounts[index] = Some(mount);
+        }
+        Ok(id)
+    }
+
+    fn mount(&self, id: MountId) -> Option<&Mount> {
+        self.mounts
+            .get(id.index)
+            .and_then(Option::as_ref)
+            .filter(|mount| mount.id == id)
+    }
+
+    /// Return the session registered with the specified identifier.
+    pub fn session(&self, id: MountId) -> Option<&Session> {
+        self.mount(id).map(|mount| &mount.session)
+    }
+
+    /// Unregister a session and return it.
+    pub fn remove(&mut self, id: MountId) -> Option<Session> {
+        self.mount(id)?;
+        let mount = self.mounts[id.index].take()?;
+        if let Err(err) = self.epoll.delete(mount.session.as_raw_fd()) {
+            tracing::warn!("failed to unregister the session {:?}: {}", id, err);
+        }
+        Some(mount.session)
+    }
+
+    /// Return the number of the registered sessions.
+    pub fn len(&self) -> usize {
+        self.mounts.iter().filter(|mount| mount.is_some()).count()
+    }
+
+    /// Return whether no session is registered.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Serve the registered sessions until all of them are closed.
+    ///
+    /// The loop stops at the first error returned from the services.
+    pub fn run(&mut self) -> io::Result<()> {
+        while !self.is_empty() {
+            self.run_once(None)?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the incoming requests and dispatch them.
+    ///
+    /// At most one request per session is dispatched in a call. The sessions
+    /// whose connection has been closed are unregistered and dropped.
+    /// Return the number of dispatched requests, which is zero if the
+    /// timeout has elapsed.
+    pub fn run_once(&mut self, timeout: Option<Duration>) -> io::Result<usize> {
+        let tokens = match self.epoll.wait(timeout) {
+            Ok(tokens) => tokens,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => return Ok(0),
+            Err(err) => return Err(err),
+        };
+
+        let mut dispatched = 0;
+        for token in tokens {
+            let mount = match self.mounts.get(token as usize).and_then(Option::as_ref) {
+                Some(mount) => mount,
+                None => continue,
+            };
+            let id = mount.id;
+
+            // The device is ready, so this returns immediately.
+            match mount.session.next_request_timeout(Duration::from_secs(0))? {
+                Some(mut req) => {
+                    req.extensions_mut().insert(id);
+                    mount.session.dispatch(&mut req, &*mount.service)?;
+                    dispatched += 1;
+                }
+                None if mount.session.is_closed() => {
+                    tracing::debug!("the session {:?} is closed", id);
+                    self.remove(id);
+                }
+                None => (),
+            }
+        }
+        Ok(dispatched)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        session::tests::{recv_reply, send_request, test_session},
+        KernelConfig, Request,
+    };
+    use polyfuse_kernel::fuse_opcode;
+    use std::{
+        mem,
+        sync::{Arc, Mutex},
+    };
+
+    #[test]
+    fn dispatch() {
+        let events = Arc::new(Mutex::new(vec![]));
+        let handler = |name: &'static str| {
+            let events = events.clone();
+            move |req: &mut Request| {
+                let id = *req.extensions().get::<MountId>().unwrap();
+                events.lock().unwrap().push((name, id, req.unique()));
+                req.reply_error(libc::ENOSYS)
+            }
+        };
+
+        let (session_a, kernel_a) = test_session(KernelConfig::default());
+        let (session_b, kernel_b) = test_session(KernelConfig::default());
+        let mut manager = SessionManager::new().unwrap();
+        let id_a = manager.add(session_a, handler("a")).unwrap();
+        let id_b = manager.add(session_b, handler("b")).unwrap();
+        assert_ne!(id_a, id_b);
+        assert_eq!(manager.len(), 2);
+
+        send_request(&kernel_a, fuse_opcode::FUSE_STATFS, 2, 1, &[]);
+        send_request(&kernel_b, fuse_opcode::FUSE_STATFS, 3, 1, &[]);
+        let mut dispatched = 0;
+        while dispatched < 2 {
+            dispatched += manager.run_once(Some(Duration::from_secs(5))).unwrap();
+        }
+        recv_reply(&kernel_a);
+        recv_reply(&kernel_b);
+
+        let mut events = mem::take(&mut *events.lock().unwrap());
+        events.sort_by_key(|&(_, _, unique)| unique);
+        assert_eq!(events, vec![("a", id_a, 2), ("b", id_b, 3)]);
+
+        // Nothing is pending, so the timeout elapses.
+        assert_eq!(
+            manager.run_once(Some(Duration::from_millis(10))).unwrap(),
+            0
+        );
+        assert_eq!(
+            manager.run_once(Some(Duration::from_micros(100))).unwrap(),
+            0
+        );
+
+        // The identifier of a removed session does not address the one
+        // registered to the same slot afterwards.
+        let session_b = manager.remove(id_b).unwrap();
+        assert!(manager.remove(id_b).is_none());
+        let id_c = manager.add(session_b, handler("c")).unwrap();
+        assert_ne!(id_b, id_c);
+        assert!(manager.session(id_b).is_none());
+        assert!(manager.remove(id_b).is_none());
+        let id_b = id_c;
+
+        // The closed sessions are unregistered.
+        drop(kernel_a);
+        manager.run_once(Some(Duration::from_secs(5))).unwrap();
+        assert!(manager.session(id_a).is_none());
+        assert!(manager.session(id_b).is_some());
+
+        drop(kernel_b);
+        manager.run().unwrap();
+        assert!(manager.is_empty());
+    }
+}
@@ -0,0 +1,215 @@
+//! Mapping between the node IDs and the keys of the filesystem.
+//!
+//! The kernel identifies each inode by the node ID replied in `EntryOut`,
+//! while the filesystems usually address their objects by other keys, such
+//! as paths or the row IDs of a database. `NodeMap` assigns the node IDs to
+//! such keys and keeps the lookup counts, so that a node is evicted once the
+//! kernel has forgotten it.
+
+use polyfuse_kernel::FUSE_ROOT_ID;
+use std::{collections::HashMap, fmt, hash::Hash};
+
+/// A bidirectional map between the node IDs and the keys of type `K`.
+///
+/// The node ID `FUSE_ROOT_ID` (= 1) is reserved for the root key, which is
+/// never evicted. The other node IDs are assigned in increasing order and
+/// never reused during the lifetime of the map, so the generation of the
+/// entries can be left zero.
+pub struct NodeMap<K> {
+    nodes: HashMap<u64, Node<K>>,
+    ids: HashMap<K, u64>,
+    next_id: u64,
+}
+
+struct Node<K> {
+    key: K,
+    nlookup: u64,
+}
+
+impl<K> fmt::Debug for NodeMap<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NodeMap")
+            .field("nodes", &self.nodes.len())
+            .field("next_id", &self.next_id)
+            .finish()
+    }
+}
+
+impl<K> NodeMap<K>
+where
+    K: Eq + Hash + Clone,
+{
+    /// Create a map with the key of the root directory.
+    pub fn new(root: K) -> Self {
+        let mut nodes = HashMap::new();
+        let mut ids = HashMap::new();
+        ids.insert(root.clone(), FUSE_ROOT_ID);
+        nodes.insert(
+            FUSE_ROOT_ID,
+            Node {
+                key: root,
+                nlookup: 0,
+            },
+        );
+        Self {
+            nodes,
+            ids,
+            next_id: FUSE_ROOT_ID + 1,
+        }
+    }
+
+    /// Return the node ID of the key to be replied to the kernel, assigning
+    /// a new one if the key has no node yet.
+    ///
+    /// This increments the lookup count of the node, so it must be called
+    /// exactly once for each reply of the entry, e.g. to `lookup`, `mkdir`
+    /// or `create`, and only if the reply is sent.
+    pub fn lookup(&mut self, key: K) -> u64 {
+        let id = match self.ids.get(&key) {
+            Some(&id) => id,
+            None => {
+                let id = self.next_id;
+                self.next_id += 1;
+                self.ids.insert(key.clone(), id);
+                self.nodes.insert(id, Node { key, nlookup: 0 });
+                id
+            }
+        };
+        if let Some(node) = self.nodes.get_mut(&id) {
+            node.nlookup += 1;
+        }
+        id
+    }
+
+    /// Return the key of the node ID.
+    pub fn get(&self, id: u64) -> Option<&K> {
+        self.nodes.get(&id).map(|node| &node.key)
+    }
+
+    /// Return the node ID assigned to the key, without changing the lookup
+    /// count.
+    pub fn id(&self, key: &K) -> Option<u64> {
+        self.ids.get(key).copied()
+    }
+
+    /// Decrement the lookup count of the node by `nlookup`, as requested by
+    /// a `forget` request (see `op::Forget`).
+    ///
+    /// Return the key of the node if it has been evicted, since the kernel no
+    /// longer refers to it. The root is never evicted.
+    pub fn forget(&mut self, id: u64, nlookup: u64) -> Option<K> {
+        if id == FUSE_ROOT_ID {
+            return None;
+        }
+        let node = self.nodes.get_mut(&id)?;
+        node.nlookup = node.nlookup.saturating_sub(nlookup);
+        if node.nlookup > 0 {
+            return None;
+        }
+        let node = self.nodes.remove(&id)?;
+        // The key may have been taken over by another node with `rekey`.
+        if self.ids.get(&node.key) == Some(&id) {
+            self.ids.remove(&node.key);
+        }
+        Some(node.key)
+    }
+
+    /// Replace the key of the node, such as when the file keyed by its path
+    /// is renamed.
+    ///
+    /// Return the previous key, or `None` if the node does not exist. If
+    /// another node has the new key, that node can no longer be found by
+    /// `id`, while it stays until forgotten.
+    pub fn rekey(&mut self, id: u64, key: K) -> Option<K> {
+        let node = self.nodes.get_mut(&id)?;
+        let old = std::mem::replace(&mut node.key, key.clone());
+        if self.ids.get(&old) == Some(&id) {
+            self.ids.remove(&old);
+        }
+        self.ids.insert(key, id);
+        Some(old)
+    }
+
+    /// Return the number of the nodes, excluding the root.
+    pub fn len(&self) -> usize {
+        self.nodes.len() - 1
+    }
+
+    /// Return whether the map has no node other than the root.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assignment() {
+        let mut nodes = NodeMap::new(0u64);
+        let a = nodes.lookup(100);
+        let b = nodes.lookup(200);
+        assert_ne!(a, b);
+        assert!(a > FUSE_ROOT_ID && b > FUSE_ROOT_ID);
+
+        // The same key keeps the node ID.
+        assert_eq!(nodes.lookup(100), a);
+        assert_eq!(nodes.id(&100), Some(a));
+        assert_eq!(nodes.get(a), Some(&100));
+        assert_eq!(nodes.get(b), Some(&200));
+        assert_eq!(nodes.len(), 2);
+    }
+
+    #[test]
+    fn root() {
+        let mut nodes = NodeMap::new("/".to_owned());
+        assert!(nodes.is_empty());
+        assert_eq!(nodes.len(), 0);
+        assert_eq!(nodes.get(FUSE_ROOT_ID).map(String::as_str), Some("/"));
+        assert_eq!(nodes.lookup("/".to_owned()), FUSE_ROOT_ID);
+        assert_ne!(nodes.lookup("/foo".to_owned()), FUSE_ROOT_ID);
+
+        // The root is never evicted.
+        assert_eq!(nodes.forget(FUSE_ROOT_ID, 10), None);
+        assert_eq!(nodes.id(&"/".to_owned()), Some(FUSE_ROOT_ID));
+    }
+
+    #[test]
+    fn forget() {
+        let mut nodes = NodeMap::new(0u64);
+        let id = nodes.lookup(42);
+        nodes.lookup(42);
+        nodes.lookup(42);
+
+        assert_eq!(nodes.forget(id, 2), None);
+        assert_eq!(nodes.get(id), Some(&42));
+        assert_eq!(nodes.forget(id, 1), Some(42));
+        assert_eq!(nodes.get(id), None);
+        assert_eq!(nodes.id(&42), None);
+        assert!(nodes.is_empty());
+
+        // The evicted node ID is not reused.
+        assert_ne!(nodes.lookup(42), id);
+        assert_eq!(nodes.forget(id, 1), None);
+    }
+
+    #[test]
+    fn rekey() {
+        let mut nodes = NodeMap::new("/".to_owned());
+        let id = nodes.lookup("/foo".to_owned());
+        assert_eq!(nodes.rekey(id, "/bar".to_owned()), Some("/foo".to_owned()));
+        assert_eq!(nodes.id(&"/foo".to_owned()), None);
+        assert_eq!(nodes.id(&"/bar".to_owned()), Some(id));
+        assert_eq!(nodes.get(id).map(String::as_str), Some("/bar"));
+
+        // Renamed over another node, which is forgotten afterwards.
+        let other = nodes.lookup("/baz".to_owned());
+        nodes.rekey(id, "/baz".to_owned());
+        assert_eq!(nodes.id(&"/baz".to_owned()), Some(id));
+        assert_eq!(nodes.forget(other, 1), Some("/baz".to_owned()));
+        assert_eq!(nodes.id(&"/baz".to_owned()), Some(id));
+        assert_eq!(nodes.get(id).map(String::as_str), Some("/baz"));
+        assert_eq!(nodes.len(), 1);
+    }
+}
@@ -1,6 +1,6 @@
 use crate::decoder::Decoder;
 use polyfuse_kernel::*;
-use std::{convert::TryFrom, ffi::OsStr, fmt, time::Duration, u32, u64};
+use std::{cmp, convert::TryFrom, ffi::OsStr, fmt, mem, time::Duration, u32, u64};
 
 #[derive(Debug)]
 pub struct DecodeError {
@@ -22,6 +22,71 @@ impl fmt::Display for DecodeError {
 
 impl std::error::Error for DecodeError {}
 
+impl From<DecodeError> for std::io::Error {
+    fn from(err: DecodeError) -> Self {
+        Self::new(std::io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// A family of the related operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Category {
+    /// The operations that modify the filesystem, such as `write`,
+    /// `mkdir`, `rename` or `setxattr`.
+    Write,
+
+    /// The operations on the extended attributes.
+    Xattr,
+
+    /// The operations on the file locks.
+    Lock,
+}
+
+impl Category {
+    pub(crate) const ALL: [Category; 3] = [Category::Write, Category::Xattr, Category::Lock];
+
+    /// Return whether the operation with the specified opcode belongs to this family.
+    pub(crate) fn contains(self, opcode: u32) -> bool {
+        let opcode = match fuse_opcode::try_from(opcode) {
+            Ok(opcode) => opcode,
+            Err(..) => return false,
+        };
+        match self {
+            Category::Write => matches!(
+                opcode,
+                fuse_opcode::FUSE_SETATTR
+                    | fuse_opcode::FUSE_SYMLINK
+                    | fuse_opcode::FUSE_MKNOD
+                    | fuse_opcode::FUSE_MKDIR
+                    | fuse_opcode::FUSE_UNLINK
+                    | fuse_opcode::FUSE_RMDIR
+                    | fuse_opcode::FUSE_RENAME
+                    | fuse_opcode::FUSE_RENAME2
+                    | fuse_opcode::FUSE_LINK
+                    | fuse_opcode::FUSE_WRITE
+                    | fuse_opcode::FUSE_SETXATTR
+                    | fuse_opcode::FUSE_REMOVEXATTR
+                    | fuse_opcode::FUSE_CREATE
+                    | fuse_opcode::FUSE_TMPFILE
+                    | fuse_opcode::FUSE_FALLOCATE
+                    | fuse_opcode::FUSE_COPY_FILE_RANGE
+            ),
+            Category::Xattr => matches!(
+                opcode,
+                fuse_opcode::FUSE_SETXATTR
+                    | fuse_opcode::FUSE_GETXATTR
+                    | fuse_opcode::FUSE_LISTXATTR
+                    | fuse_opcode::FUSE_REMOVEXATTR
+            ),
+            Category::Lock => matches!(
+                opcode,
+                fuse_opcode::FUSE_GETLK | fuse_opcode::FUSE_SETLK | fuse_opcode::FUSE_SETLKW
+            ),
+        }
+    }
+}
+
 /// The kind of filesystem operation requested by the kernel.
 #[non_exhaustive]
 pub enum Operation<'op, T> {
@@ -60,6 +125,10 @@ pub enum Operation<'op, T> {
     Fallocate(Fallocate<'op>),
     CopyFileRange(CopyFileRange<'op>),
     Poll(Poll<'op>),
+    Syncfs(Syncfs<'op>),
+    Tmpfile(Tmpfile<'op>),
+    Statx(Statx<'op>),
+    CanonicalPath(CanonicalPath<'op>),
 
     Forget(Forgets<'op>),
     Interrupt(Interrupt<'op>),
@@ -109,6 +178,10 @@ where
             Operation::Fallocate(op) => op.fmt(f),
             Operation::CopyFileRange(op) => op.fmt(f),
             Operation::Poll(op) => op.fmt(f),
+            Operation::Syncfs(op) => op.fmt(f),
+            Operation::Tmpfile(op) => op.fmt(f),
+            Operation::Statx(op) => op.fmt(f),
+            Operation::CanonicalPath(op) => op.fmt(f),
             Operation::Forget(op) => op.fmt(f),
             Operation::Interrupt(op) => op.fmt(f),
 
@@ -192,19 +265,37 @@ impl<'op, T> Operation<'op, T> {
             Some(fuse_opcode::FUSE_SYMLINK) => {
                 let name = decoder.fetch_str().map_err(DecodeError::new)?;
                 let link = decoder.fetch_str().map_err(DecodeError::new)?;
-                Ok(Operation::Symlink(Symlink { header, name, link }))
+                let ext = HeaderExtensions::after_names(header, decoder.fetch_remaining());
+                Ok(Operation::Symlink(Symlink {
+                    header,
+                    name,
+                    link,
+                    ext,
+                }))
             }
 
             Some(fuse_opcode::FUSE_MKNOD) => {
                 let arg = decoder.fetch().map_err(DecodeError::new)?;
                 let name = decoder.fetch_str().map_err(DecodeError::new)?;
-                Ok(Operation::Mknod(Mknod { header, arg, name }))
+                let ext = HeaderExtensions::after_names(header, decoder.fetch_remaining());
+                Ok(Operation::Mknod(Mknod {
+                    header,
+                    arg,
+                    name,
+                    ext,
+                }))
             }
 
             Some(fuse_opcode::FUSE_MKDIR) => {
                 let arg = decoder.fetch().map_err(DecodeError::new)?;
                 let name = decoder.fetch_str().map_err(DecodeError::new)?;
-                Ok(Operation::Mkdir(Mkdir { header, arg, name }))
+                let ext = HeaderExtensions::after_names(header, decoder.fetch_remaining());
+                Ok(Operation::Mkdir(Mkdir {
+                    header,
+                    arg,
+                    name,
+                    ext,
+                }))
             }
 
             Some(fuse_opcode::FUSE_UNLINK) => {
@@ -375,7 +466,13 @@ impl<'op, T> Operation<'op, T> {
             Some(fuse_opcode::FUSE_CREATE) => {
                 let arg = decoder.fetch().map_err(DecodeError::new)?;
                 let name = decoder.fetch_str().map_err(DecodeError::new)?;
-                Ok(Operation::Create(Create { header, arg, name }))
+                let ext = HeaderExtensions::after_names(header, decoder.fetch_remaining());
+                Ok(Operation::Create(Create {
+                    header,
+                    arg,
+                    name,
+                    ext,
+                }))
             }
 
             Some(fuse_opcode::FUSE_BMAP) => {
@@ -398,6 +495,29 @@ impl<'op, T> Operation<'op, T> {
                 Ok(Operation::Poll(Poll { header, arg }))
             }
 
+            Some(fuse_opcode::FUSE_SYNCFS) => {
+                let _arg: &fuse_syncfs_in = decoder.fetch().map_err(DecodeError::new)?;
+                Ok(Operation::Syncfs(Syncfs { header }))
+            }
+
+            Some(fuse_opcode::FUSE_TMPFILE) => {
+                let arg = decoder.fetch().map_err(DecodeError::new)?;
+                // The name of the unnamed dentry carries no meaning to the
+                // filesystem, but the extensions follow it.
+                let _name = decoder.fetch_str().map_err(DecodeError::new)?;
+                let ext = HeaderExtensions::after_names(header, decoder.fetch_remaining());
+                Ok(Operation::Tmpfile(Tmpfile { header, arg, ext }))
+            }
+
+            Some(fuse_opcode::FUSE_STATX) => {
+                let arg = decoder.fetch().map_err(DecodeError::new)?;
+                Ok(Operation::Statx(Statx { header, arg }))
+            }
+
+            Some(fuse_opcode::FUSE_CANONICAL_PATH) => {
+                Ok(Operation::CanonicalPath(CanonicalPath { header }))
+            }
+
             _ => {
                 tracing::warn!("unsupported opcode: {}", header.opcode);
                 Ok(Operation::Unknown)
@@ -450,6 +570,478 @@ impl LockOwner {
     }
 }
 
+/// The file mode, consisting of the file type and the permission bits.
+#[repr(transparent)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Mode(u32);
+
+impl fmt::Debug for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Mode({:#o})", self.0)
+    }
+}
+
+impl From<u32> for Mode {
+    #[inline]
+    fn from(mode: u32) -> Self {
+        Self::from_raw(mode)
+    }
+}
+
+impl From<Mode> for u32 {
+    #[inline]
+    fn from(mode: Mode) -> Self {
+        mode.into_raw()
+    }
+}
+
+impl Mode {
+    /// Create a `Mode` from the file type and the permission bits.
+    #[inline]
+    pub fn new(file_type: FileType, permissions: u16) -> Self {
+        Self(file_type.into_mode_bits() | (u32::from(permissions) & 0o7777))
+    }
+
+    /// Create a `Mode` from the raw value.
+    #[inline]
+    pub const fn from_raw(mode: u32) -> Self {
+        Self(mode)
+    }
+
+    /// Take the raw value of this mode.
+    #[inline]
+    pub const fn into_raw(self) -> u32 {
+        self.0
+    }
+
+    /// Return the file type, i.e. the bits masked by `S_IFMT`.
+    ///
+    /// Return `None` if the file type bits are not set or unknown.
+    /// Note that the kernel omits the file type in the `mode` of `mkdir`.
+    #[inline]
+    pub fn file_type(self) -> Option<FileType> {
+        FileType::from_mode_bits(self.0)
+    }
+
+    /// Return the permission bits, including the setuid, setgid and sticky bits.
+    #[inline]
+    pub fn permissions(self) -> u16 {
+        (self.0 & 0o7777) as u16
+    }
+}
+
+/// The flags specified at opening a file, i.e. the `O_*` bits.
+#[repr(transparent)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct OpenFlags(u32);
+
+impl fmt::Debug for OpenFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "OpenFlags({:#o})", self.0)
+    }
+}
+
+impl From<u32> for OpenFlags {
+    #[inline]
+    fn from(flags: u32) -> Self {
+        Self::from_raw(flags)
+    }
+}
+
+impl From<OpenFlags> for u32 {
+    #[inline]
+    fn from(flags: OpenFlags) -> Self {
+        flags.into_raw()
+    }
+}
+
+impl OpenFlags {
+    /// Create an `OpenFlags` from the raw value.
+    #[inline]
+    pub const fn from_raw(flags: u32) -> Self {
+        Self(flags)
+    }
+
+    /// Take the raw value of these flags.
+    #[inline]
+    pub const fn into_raw(self) -> u32 {
+        self.0
+    }
+
+    /// Return whether all of the specified `O_*` bits are set.
+    #[inline]
+    pub fn contains(self, flags: i32) -> bool {
+        self.0 as i32 & flags == flags
+    }
+
+    /// Return the access mode, i.e. one of `O_RDONLY`, `O_WRONLY` and `O_RDWR`.
+    #[inline]
+    pub fn access_mode(self) -> i32 {
+        self.0 as i32 & libc::O_ACCMODE
+    }
+
+    /// Return whether the file is opened for reading.
+    #[inline]
+    pub fn is_readable(self) -> bool {
+        matches!(self.access_mode(), libc::O_RDONLY | libc::O_RDWR)
+    }
+
+    /// Return whether the file is opened for writing.
+    #[inline]
+    pub fn is_writable(self) -> bool {
+        matches!(self.access_mode(), libc::O_WRONLY | libc::O_RDWR)
+    }
+
+    /// Return whether `O_APPEND` is set.
+    #[inline]
+    pub fn is_append(self) -> bool {
+        self.contains(libc::O_APPEND)
+    }
+
+    /// Return whether `O_TRUNC` is set.
+    #[inline]
+    pub fn is_trunc(self) -> bool {
+        self.contains(libc::O_TRUNC)
+    }
+
+    /// Return whether `O_DIRECT` is set.
+    #[inline]
+    pub fn is_direct(self) -> bool {
+        self.contains(libc::O_DIRECT)
+    }
+
+    /// Return whether `O_NONBLOCK` is set.
+    #[inline]
+    pub fn is_nonblock(self) -> bool {
+        self.contains(libc::O_NONBLOCK)
+    }
+
+    /// Return whether `O_SYNC` is set.
+    #[inline]
+    pub fn is_sync(self) -> bool {
+        self.contains(libc::O_SYNC)
+    }
+}
+
+/// The type of a file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum FileType {
+    /// A regular file (`S_IFREG`).
+    RegularFile,
+    /// A directory (`S_IFDIR`).
+    Directory,
+    /// A symbolic link (`S_IFLNK`).
+    Symlink,
+    /// A block device (`S_IFBLK`).
+    BlockDevice,
+    /// A character device (`S_IFCHR`).
+    CharDevice,
+    /// A named pipe (`S_IFIFO`).
+    Fifo,
+    /// A UNIX domain socket (`S_IFSOCK`).
+    Socket,
+}
+
+impl FileType {
+    /// Extract the file type from the `S_IF*` bits of a file mode.
+    pub fn from_mode_bits(mode: u32) -> Option<Self> {
+        match mode & libc::S_IFMT {
+            libc::S_IFREG => Some(FileType::RegularFile),
+            libc::S_IFDIR => Some(FileType::Directory),
+            libc::S_IFLNK => Some(FileType::Symlink),
+            libc::S_IFBLK => Some(FileType::BlockDevice),
+            libc::S_IFCHR => Some(FileType::CharDevice),
+            libc::S_IFIFO => Some(FileType::Fifo),
+            libc::S_IFSOCK => Some(FileType::Socket),
+            _ => None,
+        }
+    }
+
+    /// Return the `S_IF*` bits corresponding to this file type.
+    pub fn into_mode_bits(self) -> u32 {
+        match self {
+            FileType::RegularFile => libc::S_IFREG,
+            FileType::Directory => libc::S_IFDIR,
+            FileType::Symlink => libc::S_IFLNK,
+            FileType::BlockDevice => libc::S_IFBLK,
+            FileType::CharDevice => libc::S_IFCHR,
+            FileType::Fifo => libc::S_IFIFO,
+            FileType::Socket => libc::S_IFSOCK,
+        }
+    }
+
+    /// Convert a `DT_*` value, used as the type of directory entries, into a file type.
+    ///
+    /// Return `None` for `DT_UNKNOWN` and the unknown values.
+    pub fn from_dirent_type(typ: u32) -> Option<Self> {
+        match typ as u8 {
+            _ if typ > u32::from(u8::MAX) => None,
+            libc::DT_REG => Some(FileType::RegularFile),
+            libc::DT_DIR => Some(FileType::Directory),
+            libc::DT_LNK => Some(FileType::Symlink),
+            libc::DT_BLK => Some(FileType::BlockDevice),
+            libc::DT_CHR => Some(FileType::CharDevice),
+            libc::DT_FIFO => Some(FileType::Fifo),
+            libc::DT_SOCK => Some(FileType::Socket),
+            _ => None,
+        }
+    }
+
+    /// Return the `DT_*` value corresponding to this file type, to be
+    /// passed as the type of directory entries (e.g. `ReaddirOut::entry`).
+    ///
+    /// Note that it differs from the `S_IF*` bits returned by `into_mode_bits`.
+    pub fn into_dirent_type(self) -> u32 {
+        let typ = match self {
+            FileType::RegularFile => libc::DT_REG,
+            FileType::Directory => libc::DT_DIR,
+            FileType::Symlink => libc::DT_LNK,
+            FileType::BlockDevice => libc::DT_BLK,
+            FileType::CharDevice => libc::DT_CHR,
+            FileType::Fifo => libc::DT_FIFO,
+            FileType::Socket => libc::DT_SOCK,
+        };
+        u32::from(typ)
+    }
+}
+
+/// The security context of the process creating a file.
+///
+/// When `KernelConfig::security_ctx` is negotiated, the kernel appends
+/// the security label to be applied to the new inode (e.g. the SELinux
+/// context) to the `mknod`, `mkdir`, `symlink`, `create` and `tmpfile`
+/// requests.
+#[derive(Copy, Clone, Debug)]
+pub struct SecurityContext<'op> {
+    name: &'op OsStr,
+    value: &'op [u8],
+}
+
+impl<'op> SecurityContext<'op> {
+    /// Parse the `fuse_secctx_header` and the first `fuse_secctx` entry
+    /// following the arguments of a request.
+    fn parse(bytes: &'op [u8]) -> Option<Self> {
+        // The entries follow the names and are not aligned.
+        fn fetch_u32(decoder: &mut Decoder<'_>) -> Option<u32> {
+            let bytes = decoder.fetch_bytes(4).ok()?;
+            Some(u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        }
+
+        let mut decoder = Decoder::new(bytes);
+        let _size = fetch_u32(&mut decoder)?;
+        let nr_ctx = fetch_u32(&mut decoder)?;
+        if nr_ctx == 0 {
+            return None;
+        }
+
+        let size = fetch_u32(&mut decoder)?;
+        let _padding = fetch_u32(&mut decoder)?;
+        let name = decoder.fetch_str().ok()?;
+        let value = decoder.fetch_bytes(size as usize).ok()?;
+        Some(Self { name, value })
+    }
+
+    /// Find the security context in the request extensions, where the kernels
+    /// since ABI 7.38 put it, using `fuse_secctx_header` as the extension
+    /// header.
+    fn from_extensions(ext: &'op [u8]) -> Option<Self> {
+        let ext = HeaderExtensions::new(ext).find(|ext| ext.typ() <= FUSE_MAX_NR_SECCTX)?;
+        Self::parse(ext.raw)
+    }
+
+    /// Return the name of the security context, e.g. `security.selinux`.
+    #[inline]
+    pub fn name(&self) -> &'op OsStr {
+        self.name
+    }
+
+    /// Return the raw value of the security context.
+    #[inline]
+    pub fn value(&self) -> &'op [u8] {
+        self.value
+    }
+}
+
+/// Split the arguments following the names of a request into the security
+/// context and the request extensions, whose length is given by
+/// `total_extlen` (the lower half of `padding` since ABI 7.38) in units of
+/// 8 bytes.
+pub(crate) fn split_extensions<'op>(
+    header: &fuse_in_header,
+    bytes: &'op [u8],
+) -> (&'op [u8], &'op [u8]) {
+    let padding = header.padding.to_ne_bytes();
+    let total_extlen = usize::from(u16::from_ne_bytes([padding[0], padding[1]])) * 8;
+    if total_extlen > bytes.len() {
+        return (bytes, &[]);
+    }
+    bytes.split_at(bytes.len() - total_extlen)
+}
+
+/// The extensions appended to a request, such as the security context and
+/// the supplementary groups (since ABI 7.38).
+///
+/// The extensions occupy the end of the request, after the arguments of
+/// the operation. Each of them starts with `fuse_ext_header`, whose `size`
+/// includes the header itself and is aligned to 8 bytes. The iteration
+/// stops at the first malformed extension.
+#[derive(Clone, Copy, Default)]
+pub struct HeaderExtensions<'op> {
+    bytes: &'op [u8],
+    // The security context preceding the extensions before ABI 7.38.
+    secctx: &'op [u8],
+}
+
+impl fmt::Debug for HeaderExtensions<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(*self).finish()
+    }
+}
+
+impl<'op> HeaderExtensions<'op> {
+    #[inline]
+    pub(crate) fn new(bytes: &'op [u8]) -> Self {
+        Self { bytes, secctx: &[] }
+    }
+
+    /// Take the extensions from the arguments following the names of a
+    /// request, along with the security context sent in their place by the
+    /// kernels before ABI 7.38.
+    pub(crate) fn after_names(header: &fuse_in_header, bytes: &'op [u8]) -> Self {
+        let (secctx, bytes) = split_extensions(header, bytes);
+        Self { bytes, secctx }
+    }
+
+    /// Return the raw bytes of all the extensions.
+    #[inline]
+    pub fn as_bytes(&self) -> &'op [u8] {
+        self.bytes
+    }
+
+    /// Return the security context to be applied to the inode created by
+    /// the request, i.e. `symlink`, `mknod`, `mkdir`, `create` or `tmpfile`.
+    ///
+    /// Return `None` unless `KernelConfig::security_ctx` is negotiated.
+    pub fn security_context(&self) -> Option<SecurityContext<'op>> {
+        SecurityContext::parse(self.secctx).or_else(|| SecurityContext::from_extensions(self.bytes))
+    }
+
+    /// Return the supplementary group of the caller to be applied to the
+    /// inode created by the request.
+    ///
+    /// Return `None` unless `KernelConfig::create_supp_group` is negotiated.
+    pub fn supplementary_groups(&self) -> Option<SupplementaryGroups<'op>> {
+        SupplementaryGroups::parse(self.bytes)
+    }
+}
+
+impl<'op> Iterator for HeaderExtensions<'op> {
+    type Item = HeaderExtension<'op>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let header_len = mem::size_of::<fuse_ext_header>();
+        let mut decoder = Decoder::new(self.bytes);
+        let size = decoder.fetch_bytes(4).ok()?;
+        let size = u32::from_ne_bytes([size[0], size[1], size[2], size[3]]) as usize;
+        let typ = decoder.fetch_bytes(4).ok()?;
+        let typ = u32::from_ne_bytes([typ[0], typ[1], typ[2], typ[3]]);
+        if size < header_len || size > self.bytes.len() {
+            self.bytes = &[];
+            return None;
+        }
+        let (raw, rest) = self.bytes.split_at(size);
+        self.bytes = rest;
+        Some(HeaderExtension { typ, raw })
+    }
+}
+
+/// An extension appended to a request.
+#[derive(Clone, Copy)]
+pub struct HeaderExtension<'op> {
+    typ: u32,
+    raw: &'op [u8],
+}
+
+impl fmt::Debug for HeaderExtension<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HeaderExtension")
+            .field("typ", &self.typ)
+            .field("len", &self.data().len())
+            .finish()
+    }
+}
+
+impl<'op> HeaderExtension<'op> {
+    /// Return the type of the extension, e.g. `FUSE_EXT_GROUPS`.
+    ///
+    /// The types up to `FUSE_MAX_NR_SECCTX` denote the security context,
+    /// whose header is `fuse_secctx_header` with the type in `nr_ctx`.
+    #[inline]
+    pub fn typ(&self) -> u32 {
+        self.typ
+    }
+
+    /// Return the payload following `fuse_ext_header`, including the padding.
+    #[inline]
+    pub fn data(&self) -> &'op [u8] {
+        &self.raw[mem::size_of::<fuse_ext_header>()..]
+    }
+}
+
+/// The supplementary group of the process creating a file.
+///
+/// When `KernelConfig::create_supp_group` is negotiated and the owner group
+/// of the parent directory differs from the one of the caller, the kernel
+/// appends the group to the `mknod`, `mkdir`, `symlink`, `create` and
+/// `tmpfile` requests if the caller is a member of it. The filesystem uses it to
+/// inherit the group of a set-group-ID directory, as the kernel does for
+/// the local filesystems.
+#[derive(Copy, Clone)]
+pub struct SupplementaryGroups<'op> {
+    groups: &'op [u8],
+}
+
+impl fmt::Debug for SupplementaryGroups<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<'op> SupplementaryGroups<'op> {
+    /// Find the `FUSE_EXT_GROUPS` extension in the request extensions.
+    fn parse(ext: &'op [u8]) -> Option<Self> {
+        let ext = HeaderExtensions::new(ext).find(|ext| ext.typ() == FUSE_EXT_GROUPS)?;
+        let mut decoder = Decoder::new(ext.data());
+        // `fuse_supp_groups`, which may not be aligned in the buffer.
+        let nr_groups = decoder
+            .fetch_bytes(mem::size_of::<fuse_supp_groups>())
+            .ok()?;
+        let nr_groups =
+            u32::from_ne_bytes([nr_groups[0], nr_groups[1], nr_groups[2], nr_groups[3]]) as usize;
+        let groups = decoder.fetch_bytes(nr_groups.checked_mul(4)?).ok()?;
+        Some(Self { groups })
+    }
+
+    /// Return the number of the groups.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.groups.len() / 4
+    }
+
+    /// Return whether no group is included.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    /// Iterate over the group IDs.
+    pub fn iter(&self) -> impl Iterator<Item = u32> + 'op {
+        self.groups
+            .chunks_exact(4)
+            .map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+    }
+}
+
 /// A set of forget information removed from the kernel's internal caches.
 pub struct Forgets<'op> {
     inner: ForgetsInner<'op>,
@@ -577,6 +1169,10 @@ impl<'op> Interrupt<'op> {
 /// of the corresponding inode is incremented on success.
 ///
 /// See also the documentation of `ReplyEntry` for tuning the reply parameters.
+///
+/// The kernel caches the entry by the name as queried, even if the filesystem
+/// resolves it case-insensitively. See the `casefold` module for keeping
+/// such entry caches consistent.
 pub struct Lookup<'op> {
     header: &'op fuse_in_header,
     name: &'op OsStr,
@@ -638,7 +1234,12 @@ impl<'op> Getattr<'op> {
 /// Set file attributes.
 ///
 /// When the setting of attribute values succeeds, the filesystem replies its value
-/// to the kernel using `ReplyAttr`.
+/// to the kernel using `AttrOut`.
+///
+/// The replied attributes must reflect the state *after* the change, such as
+/// the new size after truncation and the updated `mtime`, since the kernel
+/// stores them in its attribute cache instead of sending a `getattr` request.
+/// Replying the stale attributes makes the cache inconsistent with the filesystem.
 pub struct Setattr<'op> {
     header: &'op fuse_in_header,
     arg: &'op fuse_setattr_in,
@@ -674,8 +1275,8 @@ impl<'op> Setattr<'op> {
 
     /// Return the file mode to be set.
     #[inline]
-    pub fn mode(&self) -> Option<u32> {
-        self.get(FATTR_MODE, |arg| arg.mode)
+    pub fn mode(&self) -> Option<Mode> {
+        self.get(FATTR_MODE, |arg| Mode::from_raw(arg.mode))
     }
 
     /// Return the user id to be set.
@@ -745,6 +1346,9 @@ pub enum SetAttrTime {
 }
 
 /// Read a symbolic link.
+///
+/// The target should be replied by `Request::reply_readlink`, which rejects
+/// the targets longer than `PATH_MAX - 1`.
 pub struct Readlink<'op> {
     header: &'op fuse_in_header,
 }
@@ -772,6 +1376,7 @@ pub struct Symlink<'op> {
     header: &'op fuse_in_header,
     name: &'op OsStr,
     link: &'op OsStr,
+    ext: HeaderExtensions<'op>,
 }
 
 impl fmt::Debug for Symlink<'_> {
@@ -782,6 +1387,13 @@ impl fmt::Debug for Symlink<'_> {
 }
 
 impl<'op> Symlink<'op> {
+    /// Return the extensions appended to the request, such as the security
+    /// context of the new inode.
+    #[inline]
+    pub fn header_extensions(&self) -> HeaderExtensions<'op> {
+        self.ext
+    }
+
     /// Return the inode number of the parent directory.
     #[inline]
     pub fn parent(&self) -> u64 {
@@ -795,6 +1407,9 @@ impl<'op> Symlink<'op> {
     }
 
     /// Return the contents of the symbolic link.
+    ///
+    /// The length is at most `KernelConfig::max_symlink_len`, which is
+    /// `PATH_MAX - 1` by default; the longer ones are rejected by the session.
     #[inline]
     pub fn link(&self) -> &OsStr {
         self.link
@@ -809,6 +1424,7 @@ pub struct Mknod<'op> {
     header: &'op fuse_in_header,
     arg: &'op fuse_mknod_in,
     name: &'op OsStr,
+    ext: HeaderExtensions<'op>,
 }
 
 impl fmt::Debug for Mknod<'_> {
@@ -819,6 +1435,13 @@ impl fmt::Debug for Mknod<'_> {
 }
 
 impl<'op> Mknod<'op> {
+    /// Return the extensions appended to the request, such as the security
+    /// context of the new inode.
+    #[inline]
+    pub fn header_extensions(&self) -> HeaderExtensions<'op> {
+        self.ext
+    }
+
     /// Return the inode number of the parent directory.
     #[inline]
     pub fn parent(&self) -> u64 {
@@ -833,8 +1456,8 @@ impl<'op> Mknod<'op> {
 
     /// Return the file type and permissions used when creating the new file.
     #[inline]
-    pub fn mode(&self) -> u32 {
-        self.arg.mode
+    pub fn mode(&self) -> Mode {
+        Mode::from_raw(self.arg.mode)
     }
 
     /// Return the device number for special file.
@@ -860,6 +1483,7 @@ pub struct Mkdir<'op> {
     header: &'op fuse_in_header,
     arg: &'op fuse_mkdir_in,
     name: &'op OsStr,
+    ext: HeaderExtensions<'op>,
 }
 
 impl fmt::Debug for Mkdir<'_> {
@@ -870,6 +1494,13 @@ impl fmt::Debug for Mkdir<'_> {
 }
 
 impl<'op> Mkdir<'op> {
+    /// Return the extensions appended to the request, such as the security
+    /// context of the new inode.
+    #[inline]
+    pub fn header_extensions(&self) -> HeaderExtensions<'op> {
+        self.ext
+    }
+
     /// Return the inode number of the parent directory where the directory is created.
     #[inline]
     pub fn parent(&self) -> u64 {
@@ -882,10 +1513,12 @@ impl<'op> Mkdir<'op> {
         self.name
     }
 
-    /// Return the file type and permissions used when creating the new directory.
+    /// Return the permissions used when creating the new directory.
+    ///
+    /// The file type bits are usually not set by the kernel.
     #[inline]
-    pub fn mode(&self) -> u32 {
-        self.arg.mode
+    pub fn mode(&self) -> Mode {
+        Mode::from_raw(self.arg.mode)
     }
 
     #[doc(hidden)] // TODO: dox
@@ -953,6 +1586,12 @@ impl<'op> Rmdir<'op> {
 }
 
 /// Rename a file.
+///
+/// The kernel sends `FUSE_RENAME2` instead of `FUSE_RENAME` when the flags
+/// such as `RENAME_NOREPLACE` are specified, and both are decoded into this
+/// operation. Unless `RENAME_NOREPLACE` is given, an existing entry at the
+/// new name must be replaced atomically. The completion must be replied
+/// with an empty reply.
 pub struct Rename<'op> {
     header: &'op fuse_in_header,
     arg: RenameArg<'op>,
@@ -1088,8 +1727,8 @@ impl<'op> Open<'op> {
     /// handle these flags and return an `EACCES` error when provided access mode is
     /// invalid.
     #[inline]
-    pub fn flags(&self) -> u32 {
-        self.arg.flags
+    pub fn flags(&self) -> OpenFlags {
+        OpenFlags::from_raw(self.arg.flags)
     }
 }
 
@@ -1102,8 +1741,16 @@ impl<'op> Open<'op> {
 ///
 /// When the file is not opened in `direct_io` mode (i.e. the page caching is enabled),
 /// the filesystem should send *exactly* the specified range of file content to the
-/// kernel. If the length of the passed data is shorter than `size`, the rest of
-/// the data will be substituted with zeroes.
+/// kernel, unless the range reaches the end of file.
+///
+/// A reply shorter than `size` is the correct way to signal the end of file;
+/// the data is sent as is and never padded by this crate. When the requested range
+/// extends past the end of file, only the available bytes should be replied
+/// (see `slice`), and an empty reply means that `offset` is at or beyond the end.
+///
+/// A request with zero `size` is legal and must be replied with empty data
+/// rather than an error. The filesystem does not need to access the backing
+/// store in that case.
 pub struct Read<'op> {
     header: &'op fuse_in_header,
     arg: &'op fuse_read_in,
@@ -1141,10 +1788,21 @@ impl<'op> Read<'op> {
         self.arg.size
     }
 
+    /// Return the part of `content` requested by this operation.
+    ///
+    /// `content` is the whole content of the file. The returned slice is
+    /// shorter than `size` if the requested range extends past the end of
+    /// file, and is empty if `offset` is at or beyond the end of file.
+    pub fn slice<'a>(&self, content: &'a [u8]) -> &'a [u8] {
+        let offset = cmp::min(self.arg.offset, content.len() as u64) as usize;
+        let content = &content[offset..];
+        &content[..cmp::min(content.len(), self.arg.size as usize)]
+    }
+
     /// Return the flags specified at opening the file.
     #[inline]
-    pub fn flags(&self) -> u32 {
-        self.arg.flags
+    pub fn flags(&self) -> OpenFlags {
+        OpenFlags::from_raw(self.arg.flags)
     }
 
     /// Return the identifier of lock owner.
@@ -1168,6 +1826,10 @@ impl<'op> Read<'op> {
 ///
 /// When the file is not opened in `direct_io` mode (i.e. the page caching is enabled),
 /// the filesystem should receive *exactly* the specified range of file content from the kernel.
+///
+/// A request with zero `size` carries no data and is legal as well. It must be
+/// replied with a written size of zero, without forwarding the request to
+/// a backend that might reject such writes.
 pub struct Write<'op> {
     header: &'op fuse_in_header,
     arg: &'op fuse_write_in,
@@ -1207,8 +1869,8 @@ impl<'op> Write<'op> {
 
     /// Return the flags specified at opening the file.
     #[inline]
-    pub fn flags(&self) -> u32 {
-        self.arg.flags
+    pub fn flags(&self) -> OpenFlags {
+        OpenFlags::from_raw(self.arg.flags)
     }
 
     /// Return the identifier of lock owner.
@@ -1220,6 +1882,20 @@ impl<'op> Write<'op> {
             None
         }
     }
+
+    /// Return whether the setuid and setgid bits of the file must be cleared.
+    ///
+    /// The kernel sets this flag on a write by a process without `CAP_FSETID`
+    /// if `KernelConfig::handle_killpriv` is enabled, in which case it leaves
+    /// the clearing of the bits to the filesystem instead of sending a
+    /// `setattr` request beforehand. When it is `true`, the handler must
+    /// remove `S_ISUID`, and `S_ISGID` if the file is group-executable, from
+    /// the mode of the file as a part of the write, so that an unprivileged
+    /// user cannot modify a privileged executable while keeping its privilege.
+    #[inline]
+    pub fn kill_priv(&self) -> bool {
+        self.arg.write_flags & FUSE_WRITE_KILL_PRIV != 0
+    }
 }
 
 /// Release an opened file.
@@ -1250,8 +1926,8 @@ impl<'op> Release<'op> {
 
     /// Return the flags specified at opening the file.
     #[inline]
-    pub fn flags(&self) -> u32 {
-        self.arg.flags
+    pub fn flags(&self) -> OpenFlags {
+        OpenFlags::from_raw(self.arg.flags)
     }
 
     /// Return the identifier of lock owner.
@@ -1289,6 +1965,10 @@ impl fmt::Debug for Statfs<'_> {
 
 impl<'op> Statfs<'op> {
     /// Return the inode number or `0` which means "undefined".
+    ///
+    /// The kernel sends the inode of the queried path, so the filesystem
+    /// can report different statistics per subtree (e.g. a union
+    /// filesystem answering with the backing store that holds the inode).
     #[inline]
     pub fn ino(&self) -> u64 {
         self.header.nodeid
@@ -1419,7 +2099,8 @@ impl<'op> Getxattr<'op> {
 ///
 /// Each element of the attribute names list must be null-terminated.
 /// As with `Getxattr`, the filesystem must send the data length of the attribute
-/// names using `ReplyXattr` if `size` is zero.
+/// names using `ReplyXattr` if `size` is zero. `XattrNames` builds the list
+/// and `Request::reply_xattr_names` replies it in both cases.
 pub struct Listxattr<'op> {
     header: &'op fuse_in_header,
     arg: &'op fuse_getxattr_in,
@@ -1519,6 +2200,9 @@ impl<'op> Flush<'op> {
 ///
 /// If the directory is successfully opened, the filesystem must send
 /// the identifier to the opened directory handle using `ReplyOpen`.
+///
+/// Unlike `Open`, the `cache_dir` flag of the reply is honored here,
+/// which allows the kernel to cache the entries read from this handle.
 pub struct Opendir<'op> {
     header: &'op fuse_in_header,
     arg: &'op fuse_open_in,
@@ -1540,8 +2224,8 @@ impl<'op> Opendir<'op> {
 
     /// Return the open flags.
     #[inline]
-    pub fn flags(&self) -> u32 {
-        self.arg.flags
+    pub fn flags(&self) -> OpenFlags {
+        OpenFlags::from_raw(self.arg.flags)
     }
 }
 
@@ -1590,9 +2274,17 @@ impl<'op> Readdir<'op> {
         self.arg.size
     }
 
+    /// Return the mode of the entries to be replied.
+    ///
+    /// This is `ReaddirMode::Normal` for a `readdirplus` request once the
+    /// filesystem has replied `ENOSYS` to one, see `Session::readdirplus_fallback`.
     pub fn mode(&self) -> ReaddirMode {
         self.mode
     }
+
+    pub(crate) fn set_mode(&mut self, mode: ReaddirMode) {
+        self.mode = mode;
+    }
 }
 
 /// Release an opened directory.
@@ -1623,8 +2315,8 @@ impl<'op> Releasedir<'op> {
 
     /// Return the flags specified at opening the directory.
     #[inline]
-    pub fn flags(&self) -> u32 {
-        self.arg.flags
+    pub fn flags(&self) -> OpenFlags {
+        OpenFlags::from_raw(self.arg.flags)
     }
 }
 
@@ -1860,6 +2552,7 @@ pub struct Create<'op> {
     header: &'op fuse_in_header,
     arg: &'op fuse_create_in,
     name: &'op OsStr,
+    ext: HeaderExtensions<'op>,
 }
 
 impl fmt::Debug for Create<'_> {
@@ -1870,6 +2563,13 @@ impl fmt::Debug for Create<'_> {
 }
 
 impl<'op> Create<'op> {
+    /// Return the extensions appended to the request, such as the security
+    /// context of the new inode.
+    #[inline]
+    pub fn header_extensions(&self) -> HeaderExtensions<'op> {
+        self.ext
+    }
+
     /// Return the inode number of the parent directory.
     ///
     /// This is the same as `Mknod::parent`.
@@ -1890,16 +2590,16 @@ impl<'op> Create<'op> {
     ///
     /// This is the same as `Mknod::mode`.
     #[inline]
-    pub fn mode(&self) -> u32 {
-        self.arg.mode
+    pub fn mode(&self) -> Mode {
+        Mode::from_raw(self.arg.mode)
     }
 
     /// Return the open flags.
     ///
     /// This is the same as `Open::flags`.
     #[inline]
-    pub fn open_flags(&self) -> u32 {
-        self.arg.flags
+    pub fn open_flags(&self) -> OpenFlags {
+        OpenFlags::from_raw(self.arg.flags)
     }
 
     #[doc(hidden)] // TODO: dox
@@ -2117,3 +2817,459 @@ impl<'op> Poll<'op> {
         }
     }
 }
+
+/// Synchronize the whole filesystem.
+///
+/// The kernel sends this request when `syncfs(2)` is called on the mounted
+/// filesystem (since ABI 7.34), so that the filesystem can flush all the dirty
+/// state to the backing store. The completion must be replied with an empty
+/// reply. If the filesystem replies `ENOSYS`, the kernel stops sending it.
+pub struct Syncfs<'op> {
+    header: &'op fuse_in_header,
+}
+
+impl fmt::Debug for Syncfs<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // TODO: add fields
+        f.debug_struct("Syncfs").finish()
+    }
+}
+
+impl<'op> Syncfs<'op> {
+    /// Return the inode number of the root of the synchronized filesystem.
+    #[inline]
+    pub fn ino(&self) -> u64 {
+        self.header.nodeid
+    }
+}
+
+/// Create an unnamed temporary file.
+///
+/// The kernel sends this request when a file is opened with `O_TMPFILE`
+/// (since ABI 7.37), and only if enabled by `KernelConfig::tmpfile`. The new
+/// inode has no name in the directory, but can be linked later by `linkat(2)`
+/// unless opened with `O_EXCL`.
+///
+/// As with `Create`, the pair of `EntryOut` and `OpenOut` describing the new
+/// inode and the file handle must be replied. If the filesystem replies
+/// `ENOSYS`, the kernel stops sending it and `open(2)` fails with `EOPNOTSUPP`.
+pub struct Tmpfile<'op> {
+    header: &'op fuse_in_header,
+    arg: &'op fuse_create_in,
+    ext: HeaderExtensions<'op>,
+}
+
+impl fmt::Debug for Tmpfile<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tmpfile")
+            .field("parent", &self.parent())
+            .field("mode", &self.mode())
+            .field("umask", &self.umask())
+            .field("flags", &self.flags())
+            .finish()
+    }
+}
+
+impl<'op> Tmpfile<'op> {
+    /// Return the extensions appended to the request, such as the security
+    /// context of the new inode.
+    #[inline]
+    pub fn header_extensions(&self) -> HeaderExtensions<'op> {
+        self.ext
+    }
+
+    /// Return the inode number of the directory where the file is created.
+    #[inline]
+    pub fn parent(&self) -> u64 {
+        self.header.nodeid
+    }
+
+    /// Return the file type and permissions used when creating the new file.
+    #[inline]
+    pub fn mode(&self) -> Mode {
+        Mode::from_raw(self.arg.mode)
+    }
+
+    /// Return the mask of the permissions to be cleared from `mode`.
+    #[inline]
+    pub fn umask(&self) -> u32 {
+        self.arg.umask
+    }
+
+    /// Return the open flags.
+    #[inline]
+    pub fn flags(&self) -> OpenFlags {
+        OpenFlags::from_raw(self.arg.flags)
+    }
+}
+
+/// Get the extended file attributes, for `statx(2)`.
+///
+/// The kernel sends this request (since ABI 7.39) in place of `Getattr` when
+/// the caller wants the fields not covered by `stat(2)`, such as the birth
+/// time, and only if enabled by `KernelConfig::statx`. The attributes must be
+/// replied using `StatxOut`, whose mask reports which fields are filled.
+///
+/// If the filesystem replies `ENOSYS`, the kernel stops sending it and falls
+/// back to `Getattr`, reporting only the basic fields.
+pub struct Statx<'op> {
+    header: &'op fuse_in_header,
+    arg: &'op fuse_statx_in,
+}
+
+impl fmt::Debug for Statx<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Statx")
+            .field("ino", &self.ino())
+            .field("fh", &self.fh())
+            .field("flags", &format_args!("{:#x}", self.flags()))
+            .field("mask", &format_args!("{:#x}", self.mask()))
+            .finish()
+    }
+}
+
+impl<'op> Statx<'op> {
+    /// Return the inode number for obtaining the attribute values.
+    #[inline]
+    pub fn ino(&self) -> u64 {
+        self.header.nodeid
+    }
+
+    /// Return the handle of opened file, if specified.
+    #[inline]
+    pub fn fh(&self) -> Option<u64> {
+        if self.arg.getattr_flags & FUSE_GETATTR_FH != 0 {
+            Some(self.arg.fh)
+        } else {
+            None
+        }
+    }
+
+    /// Return the flags passed to `statx(2)`, i.e. the `AT_STATX_*` bits
+    /// controlling the synchronization with the underlying storage.
+    #[inline]
+    pub fn flags(&self) -> u32 {
+        self.arg.sx_flags
+    }
+
+    /// Return the mask of the wanted fields, i.e. the `STATX_*` bits.
+    ///
+    /// The filesystem may fill more or fewer fields than requested.
+    #[inline]
+    pub fn mask(&self) -> u32 {
+        self.arg.sx_mask
+    }
+}
+
+/// Resolve the canonical path of an inode.
+///
+/// This request is sent only by the Android kernels, e.g. for resolving the
+/// underlying path of a file watched by `inotify`, so the other filesystems
+/// never receive it. The path must be replied by
+/// `Request::reply_canonical_path`, which appends the terminating NUL expected
+/// by the kernel. Replying `ENOSYS` makes the kernel fall back to the path of
+/// the file itself.
+pub struct CanonicalPath<'op> {
+    header: &'op fuse_in_header,
+}
+
+impl fmt::Debug for CanonicalPath<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CanonicalPath")
+            .field("ino", &self.ino())
+            .finish()
+    }
+}
+
+impl<'op> CanonicalPath<'op> {
+    /// Return the inode number to be resolved.
+    #[inline]
+    pub fn ino(&self) -> u64 {
+        self.header.nodeid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem;
+    use zerocopy::AsBytes;
+
+    #[test]
+    fn open_flags() {
+        let flags = OpenFlags::from_raw(
+            (libc::O_WRONLY | libc::O_APPEND | libc::O_DIRECT | libc::O_NONBLOCK) as u32,
+        );
+        assert_eq!(flags.access_mode(), libc::O_WRONLY);
+        assert!(!flags.is_readable());
+        assert!(flags.is_writable());
+        assert!(flags.is_append());
+        assert!(flags.is_direct());
+        assert!(flags.is_nonblock());
+        assert!(!flags.is_trunc());
+        assert!(!flags.is_sync());
+        assert!(flags.contains(libc::O_APPEND | libc::O_DIRECT));
+        assert!(!flags.contains(libc::O_APPEND | libc::O_TRUNC));
+
+        let flags = OpenFlags::from((libc::O_RDWR | libc::O_TRUNC | libc::O_SYNC) as u32);
+        assert_eq!(flags.access_mode(), libc::O_RDWR);
+        assert!(flags.is_readable() && flags.is_writable());
+        assert!(flags.is_trunc());
+        assert!(flags.is_sync());
+        assert!(!flags.is_append());
+        assert_eq!(
+            u32::from(flags),
+            (libc::O_RDWR | libc::O_TRUNC | libc::O_SYNC) as u32
+        );
+
+        let arg = fuse_open_in {
+            flags: (libc::O_RDONLY | libc::O_NONBLOCK) as u32,
+            ..Default::default()
+        };
+        let header = fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + mem::size_of::<fuse_open_in>()) as u32,
+            opcode: fuse_opcode::FUSE_OPEN as u32,
+            unique: 2,
+            nodeid: 42,
+            ..Default::default()
+        };
+        match Operation::decode(&header, arg.as_bytes(), ()).unwrap() {
+            Operation::Open(op) => {
+                assert_eq!(op.flags().access_mode(), libc::O_RDONLY);
+                assert!(op.flags().is_nonblock());
+            }
+            _ => panic!("unexpected operation"),
+        }
+    }
+
+    #[test]
+    fn write_kill_priv() {
+        let write_flags = |write_flags| {
+            let arg = fuse_write_in {
+                size: 0,
+                write_flags,
+                ..Default::default()
+            };
+            let header = fuse_in_header {
+                len: (mem::size_of::<fuse_in_header>() + mem::size_of::<fuse_write_in>()) as u32,
+                opcode: fuse_opcode::FUSE_WRITE as u32,
+                unique: 2,
+                nodeid: 42,
+                ..Default::default()
+            };
+            match Operation::decode(&header, arg.as_bytes(), ()).unwrap() {
+                Operation::Write(op, ()) => op.kill_priv(),
+                _ => panic!("unexpected operation"),
+            }
+        };
+        assert!(!write_flags(0));
+        assert!(!write_flags(FUSE_WRITE_CACHE | FUSE_WRITE_LOCKOWNER));
+        assert!(write_flags(FUSE_WRITE_KILL_PRIV));
+        assert!(write_flags(FUSE_WRITE_CACHE | FUSE_WRITE_KILL_PRIV));
+    }
+
+    #[test]
+    fn statfs_ino() {
+        let header = fuse_in_header {
+            len: mem::size_of::<fuse_in_header>() as u32,
+            opcode: fuse_opcode::FUSE_STATFS as u32,
+            unique: 2,
+            nodeid: 42,
+            ..Default::default()
+        };
+        match Operation::decode(&header, &[], ()).unwrap() {
+            Operation::Statfs(op) => assert_eq!(op.ino(), 42),
+            _ => panic!("unexpected operation"),
+        }
+    }
+
+    #[test]
+    fn opendir_is_not_open() {
+        let arg = fuse_open_in {
+            flags: libc::O_RDONLY as u32 | libc::O_DIRECTORY as u32,
+            ..Default::default()
+        };
+        let header = |opcode: fuse_opcode| fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + mem::size_of::<fuse_open_in>()) as u32,
+            opcode: opcode as u32,
+            unique: 2,
+            nodeid: 42,
+            ..Default::default()
+        };
+
+        let opendir = header(fuse_opcode::FUSE_OPENDIR);
+        match Operation::decode(&opendir, arg.as_bytes(), ()).unwrap() {
+            Operation::Opendir(op) => {
+                assert_eq!(op.ino(), 42);
+                assert_eq!(op.flags().into_raw(), arg.flags);
+            }
+            _ => panic!("unexpected operation"),
+        }
+
+        let open = header(fuse_opcode::FUSE_OPEN);
+        match Operation::decode(&open, arg.as_bytes(), ()).unwrap() {
+            Operation::Open(op) => assert_eq!(op.ino(), 42),
+            _ => panic!("unexpected operation"),
+        }
+    }
+
+    #[test]
+    fn read_slice_past_eof() {
+        let header = fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + mem::size_of::<fuse_read_in>()) as u32,
+            opcode: fuse_opcode::FUSE_READ as u32,
+            unique: 2,
+            nodeid: 2,
+            ..Default::default()
+        };
+        let read = |offset: u64, size: u32| fuse_read_in {
+            offset,
+            size,
+            ..Default::default()
+        };
+        let slice = |arg: &fuse_read_in| match Operation::decode(&header, arg.as_bytes(), ()) {
+            Ok(Operation::Read(op)) => op.slice(b"hello, world\n").to_vec(),
+            _ => panic!("unexpected operation"),
+        };
+
+        assert_eq!(slice(&read(0, 5)), b"hello");
+        assert_eq!(slice(&read(7, 4096)), b"world\n");
+        assert_eq!(slice(&read(13, 4096)), b"");
+        assert_eq!(slice(&read(4096, 4096)), b"");
+    }
+
+    #[test]
+    fn rename_names() {
+        let decode = |opcode: fuse_opcode, arg: &[u8], names: &[u8]| {
+            let mut payload = arg.to_vec();
+            payload.extend_from_slice(names);
+            let header = fuse_in_header {
+                len: (mem::size_of::<fuse_in_header>() + payload.len()) as u32,
+                opcode: opcode as u32,
+                unique: 2,
+                nodeid: 10,
+                ..Default::default()
+            };
+            match Operation::decode(&header, &payload[..], ()).unwrap() {
+                Operation::Rename(op) => (
+                    op.parent(),
+                    op.name().to_owned(),
+                    op.newparent(),
+                    op.newname().to_owned(),
+                    op.flags(),
+                ),
+                _ => panic!("unexpected operation"),
+            }
+        };
+
+        let rename_in = fuse_rename_in { newdir: 20 };
+        assert_eq!(
+            decode(
+                fuse_opcode::FUSE_RENAME,
+                rename_in.as_bytes(),
+                b"a\0renamed-file.txt\0"
+            ),
+            (10, "a".into(), 20, "renamed-file.txt".into(), 0)
+        );
+
+        let rename2_in = fuse_rename2_in {
+            newdir: 20,
+            flags: libc::RENAME_NOREPLACE,
+            padding: 0,
+        };
+        assert_eq!(
+            decode(
+                fuse_opcode::FUSE_RENAME2,
+                rename2_in.as_bytes(),
+                b"long-original-name\0b\0"
+            ),
+            (
+                10,
+                "long-original-name".into(),
+                20,
+                "b".into(),
+                libc::RENAME_NOREPLACE
+            )
+        );
+
+        // The second name without the terminating nul is rejected.
+        let header = fuse_in_header {
+            opcode: fuse_opcode::FUSE_RENAME as u32,
+            ..Default::default()
+        };
+        let mut payload = rename_in.as_bytes().to_vec();
+        payload.extend_from_slice(b"a\0b");
+        assert!(Operation::decode(&header, &payload[..], ()).is_err());
+    }
+
+    #[test]
+    fn mode_file_types() {
+        for &(bits, file_type) in &[
+            (libc::S_IFREG, FileType::RegularFile),
+            (libc::S_IFDIR, FileType::Directory),
+            (libc::S_IFLNK, FileType::Symlink),
+            (libc::S_IFBLK, FileType::BlockDevice),
+            (libc::S_IFCHR, FileType::CharDevice),
+            (libc::S_IFIFO, FileType::Fifo),
+            (libc::S_IFSOCK, FileType::Socket),
+        ] {
+            let mode = Mode::from_raw(bits | 0o4755);
+            assert_eq!(mode.file_type(), Some(file_type));
+            assert_eq!(mode.permissions(), 0o4755);
+            assert_eq!(file_type.into_mode_bits(), bits);
+            assert_eq!(Mode::new(file_type, 0o4755), mode);
+
+            // DT_* is the file type bits shifted down.
+            assert_eq!(file_type.into_dirent_type(), bits >> 12);
+            assert_eq!(
+                FileType::from_dirent_type(file_type.into_dirent_type()),
+                Some(file_type)
+            );
+        }
+
+        assert_eq!(Mode::from_raw(0o755).file_type(), None);
+        assert_eq!(
+            Mode::new(FileType::RegularFile, 0o170644).permissions(),
+            0o644
+        );
+    }
+
+    #[test]
+    fn mkdir_without_security_context() {
+        let mut arg = fuse_mkdir_in {
+            mode: 0o755,
+            umask: 0o022,
+        }
+        .as_bytes()
+        .to_vec();
+        arg.extend_from_slice(b"foo\0");
+        let header = fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + arg.len()) as u32,
+            opcode: fuse_opcode::FUSE_MKDIR as u32,
+            unique: 2,
+            nodeid: 1,
+            ..Default::default()
+        };
+
+        match Operation::decode(&header, &arg[..], ()).unwrap() {
+            Operation::Mkdir(op) => {
+                assert_eq!(op.name(), "foo");
+                assert!(op.header_extensions().security_context().is_none());
+            }
+            _ => panic!("unexpected operation"),
+        }
+
+        // No context is attached although the header is present.
+        arg.extend_from_slice(
+            fuse_secctx_header {
+                size: mem::size_of::<fuse_secctx_header>() as u32,
+                nr_ctx: 0,
+            }
+            .as_bytes(),
+        );
+        match Operation::decode(&header, &arg[..], ()).unwrap() {
+            Operation::Mkdir(op) => assert!(op.header_extensions().security_context().is_none()),
+            _ => panic!("unexpected operation"),
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Forwarding the requests to another FUSE server.
+//!
+//! A `Forwarder` sends the received requests verbatim to a downstream
+//! transport, such as a socket connected to another FUSE server, and relays
+//! its replies back to the kernel. This is the building block of the proxy,
+//! union or caching filesystems that delegate some of the operations.
+
+use crate::session::{max_reply_size, Request};
+use polyfuse_kernel::*;
+use std::{convert::TryFrom, fmt, io, mem};
+use zerocopy::AsBytes as _;
+
+/// A forwarder of the requests to a downstream transport.
+///
+/// The downstream uses its own space of the unique IDs, so the IDs of the
+/// forwarded requests are remapped and those of the replies are restored.
+/// Each message must be transferred by a single `read` or `write`, as with
+/// the FUSE device itself (e.g. a `SOCK_SEQPACKET` socket).
+///
+/// The requests are forwarded one by one and the downstream must reply to
+/// them in order. The interrupt requests are not forwarded, since the target
+/// requests have already been completed by then.
+pub struct Forwarder<T> {
+    downstream: T,
+    next_unique: u64,
+    buf: Vec<u8>,
+}
+
+impl<T> fmt::Debug for Forwarder<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Forwarder")
+            .field("next_unique", &self.next_unique)
+            .finish()
+    }
+}
+
+impl<T> Forwarder<T>
+where
+    T: io::Read + io::Write,
+{
+    /// Create a forwarder to the specified downstream transport.
+    pub fn new(downstream: T) -> Self {
+        Self {
+            downstream,
+            next_unique: 1,
+            buf: vec![0u8; max_reply_size()],
+        }
+    }
+
+    /// Return a reference to the downstream transport.
+    pub fn get_ref(&self) -> &T {
+        &self.downstream
+    }
+
+    /// Consume the forwarder and return the downstream transport.
+    pub fn into_inner(self) -> T {
+        self.downstream
+    }
+
+    /// Forward the request to the downstream and relay the reply to the kernel.
+    ///
+    /// The messages without the unique ID (i.e. notifications) received from
+    /// the downstream while waiting for the reply are discarded.
+    pub fn forward(&mut self, req: &Request) -> io::Result<()> {
+        let mut header = fuse_in_header::default();
+        header.as_bytes_mut().copy_from_slice(req.raw_header());
+
+        let opcode = fuse_opcode::try_from(header.opcode).ok();
+        if opcode == Some(fuse_opcode::FUSE_INTERRUPT) {
+            return Ok(());
+        }
+
+        let unique = self.next_unique;
+        self.next_unique = self.next_unique.wrapping_add(1).max(1);
+        header.unique = unique;
+
+        let mut msg = Vec::with_capacity(header.len as usize);
+        msg.extend_from_slice(header.as_bytes());
+        msg.extend_from_slice(req.raw_body());
+        let written = self.downstream.write(&msg[..])?;
+        if written != msg.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "the request message is partially forwarded",
+            ));
+        }
+
+        match opcode {
+            Some(fuse_opcode::FUSE_FORGET) | Some(fuse_opcode::FUSE_BATCH_FORGET) => {
+                return Ok(());
+            }
+            _ => (),
+        }
+
+        let header_len = mem::size_of::<fuse_out_header>();
+        loop {
+            let len = self.downstream.read(&mut self.buf[..])?;
+            if len == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "the downstream is closed",
+                ));
+            }
+            if len < header_len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the reply message is too short",
+                ));
+            }
+
+            let mut out_header = fuse_out_header::default();
+            out_header
+                .as_bytes_mut()
+                .copy_from_slice(&self.buf[..header_len]);
+
+            if out_header.unique == 0 {
+                tracing::debug!("discard the notification from the downstream");
+                continue;
+            }
+            if out_header.unique != unique || out_header.len as usize != len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unexpected reply from the downstream",
+                ));
+            }
+
+            return req.reply_raw(-out_header.error, &self.buf[header_len..len]);
+        }
+    }
+}
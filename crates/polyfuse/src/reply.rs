@@ -1,8 +1,141 @@
-use crate::bytes::{Bytes, FillBytes};
+use crate::{
+    bytes::{Bytes, FillBytes},
+    op::{FileType, Mode, ReaddirMode},
+};
 use polyfuse_kernel::*;
-use std::{convert::TryInto as _, ffi::OsStr, fmt, mem, os::unix::prelude::*, time::Duration};
+use std::{
+    convert::TryInto as _,
+    ffi::OsStr,
+    fmt, mem,
+    os::unix::prelude::*,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use zerocopy::AsBytes as _;
 
+/// The name of the extended attribute that marks a directory as opaque
+/// in overlay filesystems.
+///
+/// An opaque directory hides the entries of the same directory in the lower
+/// layers. Its value is `OVERLAY_OPAQUE_VALUE`.
+pub const OVERLAY_OPAQUE_XATTR: &str = "trusted.overlay.opaque";
+
+/// The value of `OVERLAY_OPAQUE_XATTR` for an opaque directory.
+pub const OVERLAY_OPAQUE_VALUE: &[u8] = b"y";
+
+/// The name of the extended attribute storing the access ACL of a file.
+///
+/// See `KernelConfig::posix_acl` for the handling of the ACL attributes.
+pub const POSIX_ACL_ACCESS_XATTR: &str = "system.posix_acl_access";
+
+/// The name of the extended attribute storing the default ACL of a directory,
+/// inherited by the files created in it.
+pub const POSIX_ACL_DEFAULT_XATTR: &str = "system.posix_acl_default";
+
+macro_rules! define_errno {
+    ($( $(#[$m:meta])* $Variant:ident = $errno:ident, )*) => {
+        /// The error numbers commonly replied by the filesystems.
+        ///
+        /// This is a readable alternative to the raw `libc` constants, and
+        /// converts into the `i32` accepted by `Request::reply_error`. The
+        /// other error numbers can still be replied as the raw values.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        #[non_exhaustive]
+        pub enum Errno {
+            $(
+                $(#[$m])*
+                $Variant,
+            )*
+        }
+
+        impl Errno {
+            /// Return the raw error number.
+            pub fn raw(self) -> i32 {
+                match self {
+                    $( Errno::$Variant => libc::$errno, )*
+                }
+            }
+        }
+
+        #[cfg(test)]
+        const ERRNO_TABLE: &[(Errno, i32)] = &[
+            $( (Errno::$Variant, libc::$errno), )*
+        ];
+    };
+}
+
+define_errno! {
+    /// `EPERM`: the operation is not permitted.
+    NotPermitted = EPERM,
+    /// `ENOENT`: no such file or directory.
+    NotFound = ENOENT,
+    /// `EINTR`: the operation has been interrupted.
+    Interrupted = EINTR,
+    /// `EIO`: an I/O error on the backing store.
+    Io = EIO,
+    /// `EBADF`: the file handle is invalid.
+    BadFileHandle = EBADF,
+    /// `EAGAIN`: the resource is temporarily unavailable.
+    Again = EAGAIN,
+    /// `ENOMEM`: out of memory.
+    NoMemory = ENOMEM,
+    /// `EACCES`: the permission is denied.
+    PermissionDenied = EACCES,
+    /// `EBUSY`: the resource is busy.
+    Busy = EBUSY,
+    /// `EEXIST`: the file already exists.
+    Exists = EEXIST,
+    /// `EXDEV`: the link or rename crosses the filesystems.
+    CrossDevice = EXDEV,
+    /// `ENOTDIR`: not a directory.
+    NotDirectory = ENOTDIR,
+    /// `EISDIR`: is a directory.
+    IsDirectory = EISDIR,
+    /// `EINVAL`: an invalid argument.
+    InvalidArgument = EINVAL,
+    /// `EFBIG`: the file is too large.
+    FileTooLarge = EFBIG,
+    /// `ENOSPC`: no space left on the device.
+    NoSpace = ENOSPC,
+    /// `EROFS`: the filesystem is read-only.
+    ReadOnly = EROFS,
+    /// `EMLINK`: too many links.
+    TooManyLinks = EMLINK,
+    /// `ERANGE`: the buffer is too small, e.g. for `getxattr`.
+    Range = ERANGE,
+    /// `ENAMETOOLONG`: the file name is too long.
+    NameTooLong = ENAMETOOLONG,
+    /// `ENOLCK`: no locks available.
+    NoLock = ENOLCK,
+    /// `ENOSYS`: the operation is not implemented.
+    NotImplemented = ENOSYS,
+    /// `ENOTEMPTY`: the directory is not empty.
+    NotEmpty = ENOTEMPTY,
+    /// `ELOOP`: too many levels of symbolic links.
+    Loop = ELOOP,
+    /// `ENODATA`: the extended attribute does not exist.
+    NoData = ENODATA,
+    /// `EOPNOTSUPP`: the operation is not supported.
+    NotSupported = EOPNOTSUPP,
+    /// `ETIMEDOUT`: the operation has timed out, e.g. on a remote backend.
+    TimedOut = ETIMEDOUT,
+    /// `ESTALE`: the file handle is stale.
+    Stale = ESTALE,
+    /// `EDQUOT`: the disk quota is exceeded.
+    QuotaExceeded = EDQUOT,
+}
+
+impl From<Errno> for i32 {
+    #[inline]
+    fn from(errno: Errno) -> Self {
+        errno.raw()
+    }
+}
+
+/// The maximum size of an extended attribute value, or the list of the names,
+/// accepted by the kernel.
+pub const XATTR_SIZE_MAX: u32 = 65536;
+
 /// Attributes about a file.
 #[repr(transparent)]
 pub struct FileAttr {
@@ -27,10 +160,10 @@ impl FileAttr {
         self.attr.size = size;
     }
 
-    /// Set the permission of the inode.
+    /// Set the file type and permissions of the inode.
     #[inline]
-    pub fn mode(&mut self, mode: u32) {
-        self.attr.mode = mode;
+    pub fn mode(&mut self, mode: impl Into<Mode>) {
+        self.attr.mode = mode.into().into_raw();
     }
 
     /// Set the number of hard links.
@@ -51,25 +184,64 @@ impl FileAttr {
         self.attr.gid = gid;
     }
 
-    /// Set the device ID.
+    /// Set the device ID of a character or block device, reported as
+    /// `st_rdev`.
+    ///
+    /// This is not the device containing the file (`st_dev`), which cannot
+    /// be set per inode; see `EntryOut::submount` instead.
     #[inline]
     pub fn rdev(&mut self, rdev: u32) {
         self.attr.rdev = rdev;
     }
 
-    /// Set the block size.
+    /// Mark the file as a whiteout, i.e. a character device with the device
+    /// number 0/0 and no permissions.
+    ///
+    /// In overlay filesystems, a whiteout hides the file of the same name in
+    /// the lower layers, representing its deletion. The corresponding entry
+    /// of `readdir` should have the type `FileType::CharDevice`.
+    pub fn whiteout(&mut self) {
+        self.attr.mode = libc::S_IFCHR;
+        self.attr.rdev = 0;
+    }
+
+    /// Set the attribute flags, such as `FUSE_ATTR_DAX`.
+    ///
+    /// The flags are understood by the kernels supporting ABI 7.32 or later.
+    /// `Request::reply_attr` and `Request::reply_entry` clear them for the
+    /// older kernels, to which the field is a padding. It is zero unless set.
+    #[inline]
+    pub fn attr_flags(&mut self, flags: u32) {
+        self.attr.padding = flags;
+    }
+
+    /// Set the preferred block size for I/O, reported as `st_blksize`.
+    ///
+    /// The applications and tools such as `cp(1)` size their buffers by this
+    /// value, so a filesystem favoring large transfers can report e.g. 1 MiB.
+    /// The kernel rounds the value down to a power of two. If it is zero,
+    /// which is the default, the block size of the mount (usually 4 KiB) is
+    /// reported instead.
     #[inline]
     pub fn blksize(&mut self, blksize: u32) {
         self.attr.blksize = blksize;
     }
 
-    /// Set the number of allocated blocks.
+    /// Set the number of allocated blocks, in the units of 512 bytes.
+    ///
+    /// The value is reported as `st_blocks` verbatim, regardless of `blksize`,
+    /// and is never derived from `size`. For a sparse file, only the blocks
+    /// actually backed by the storage should be counted, so that e.g. `du(1)`
+    /// reports the real usage. It is zero unless set.
     #[inline]
     pub fn blocks(&mut self, blocks: u64) {
         self.attr.blocks = blocks;
     }
 
-    /// Set the last accessed time.
+    /// Set the last accessed time, as the duration since the UNIX epoch.
+    ///
+    /// The sub-second part is sent in nanoseconds, and the precision the
+    /// kernel keeps is determined by `KernelConfig::time_gran`.
     #[inline]
     pub fn atime(&mut self, atime: Duration) {
         self.attr.atime = atime.as_secs();
@@ -89,6 +261,98 @@ impl FileAttr {
         self.attr.ctime = ctime.as_secs();
         self.attr.ctimensec = ctime.subsec_nanos();
     }
+
+    /// Set the last accessed time from a `SystemTime`, e.g. one obtained
+    /// from `std::fs::Metadata`.
+    ///
+    /// The times before the UNIX epoch are clamped to the epoch.
+    #[inline]
+    pub fn atime_at(&mut self, atime: SystemTime) {
+        self.atime(since_epoch(atime));
+    }
+
+    /// Set the last modification time from a `SystemTime`.
+    ///
+    /// See `atime_at` for details.
+    #[inline]
+    pub fn mtime_at(&mut self, mtime: SystemTime) {
+        self.mtime(since_epoch(mtime));
+    }
+
+    /// Set the last created time from a `SystemTime`.
+    ///
+    /// See `atime_at` for details.
+    #[inline]
+    pub fn ctime_at(&mut self, ctime: SystemTime) {
+        self.ctime(since_epoch(ctime));
+    }
+
+    /// Set all the attributes from a `Stat`.
+    ///
+    /// The attribute flags are left as they are.
+    pub fn stat(&mut self, stat: &Stat) {
+        self.ino(stat.ino);
+        self.size(stat.size);
+        self.blocks(stat.blocks);
+        self.atime_at(stat.atime);
+        self.mtime_at(stat.mtime);
+        self.ctime_at(stat.ctime);
+        self.attr.mode = stat.mode;
+        self.nlink(stat.nlink);
+        self.uid(stat.uid);
+        self.gid(stat.gid);
+        self.rdev(stat.rdev);
+        self.blksize(stat.blksize);
+    }
+}
+
+/// The attributes of a file, independent of the layout of `libc::stat`
+/// on the platform.
+///
+/// This allows the filesystems to compute the attributes of their files
+/// without `stat(2)`, e.g. in unit tests, and pass them to `FileAttr::stat`.
+/// The times before the UNIX epoch are clamped to the epoch, as with
+/// `FileAttr::atime_at`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stat {
+    pub ino: u64,
+    pub size: u64,
+    /// The number of allocated blocks, in the units of 512 bytes.
+    pub blocks: u64,
+    pub atime: SystemTime,
+    pub mtime: SystemTime,
+    pub ctime: SystemTime,
+    /// The file type and permissions, i.e. the `S_IF*` bits and the mode bits.
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev: u32,
+    pub blksize: u32,
+}
+
+impl Default for Stat {
+    fn default() -> Self {
+        Self {
+            ino: 0,
+            size: 0,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            mode: 0,
+            nlink: 0,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: 0,
+        }
+    }
+}
+
+#[inline]
+fn since_epoch(time: SystemTime) -> Duration {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default()
 }
 
 #[derive(Default)]
@@ -153,9 +417,13 @@ impl EntryOut {
     /// The operations should set this value to very large
     /// when the changes of inode attributes are caused
     /// only by FUSE requests.
-    pub fn ttl_attr(&mut self, ttl: Duration) {
-        self.out.attr_valid = ttl.as_secs();
-        self.out.attr_valid_nsec = ttl.subsec_nanos();
+    ///
+    /// `None` means that the attributes are not cached, and is sent as
+    /// the zero timeout, which is also the default.
+    pub fn ttl_attr(&mut self, ttl: impl Into<Option<Duration>>) {
+        let (secs, nsecs) = encode_ttl(ttl.into());
+        self.out.attr_valid = secs;
+        self.out.attr_valid_nsec = nsecs;
     }
 
     /// Set the validity timeout for the name.
@@ -163,15 +431,44 @@ impl EntryOut {
     /// The operations should set this value to very large
     /// when the changes/deletions of directory entries are
     /// caused only by FUSE requests.
-    pub fn ttl_entry(&mut self, ttl: Duration) {
-        self.out.entry_valid = ttl.as_secs();
-        self.out.entry_valid_nsec = ttl.subsec_nanos();
+    ///
+    /// `None` means that the name is not cached, as with `ttl_attr`.
+    pub fn ttl_entry(&mut self, ttl: impl Into<Option<Duration>>) {
+        let (secs, nsecs) = encode_ttl(ttl.into());
+        self.out.entry_valid = secs;
+        self.out.entry_valid_nsec = nsecs;
+    }
+
+    #[inline]
+    pub(crate) fn clear_attr_flags(&mut self) {
+        self.out.attr.padding = 0;
+    }
+
+    /// Mark this entry as a submount.
+    ///
+    /// The kernel honors the mark only on the directories, and only if
+    /// `KernelConfig::submounts` has been negotiated (see
+    /// `Session::submounts_support`). Otherwise it is ignored.
+    ///
+    /// This is the only way to present distinct devices: `st_dev` is not
+    /// part of the attributes, and the kernel reports the device number of
+    /// the superblock, which is fixed per mount. Each submount gets a
+    /// superblock of its own, so the files below it share a new `st_dev`,
+    /// e.g. for an overlay to tell its layers apart. The mark is honored in
+    /// the entries of `ReaddirPlusOut` as well as in the replies to `lookup`.
+    pub fn submount(&mut self, enabled: bool) {
+        if enabled {
+            self.out.attr.padding |= FUSE_ATTR_SUBMOUNT;
+        } else {
+            self.out.attr.padding &= !FUSE_ATTR_SUBMOUNT;
+        }
     }
 }
 
 #[derive(Default)]
 pub struct AttrOut {
     out: fuse_attr_out,
+    ttl_set: bool,
 }
 
 impl fmt::Debug for AttrOut {
@@ -189,9 +486,56 @@ impl AttrOut {
     }
 
     /// Set the validity timeout for this attribute.
-    pub fn ttl(&mut self, ttl: Duration) {
-        self.out.attr_valid = ttl.as_secs();
-        self.out.attr_valid_nsec = ttl.subsec_nanos();
+    ///
+    /// `None` means that the attributes are not cached, as with
+    /// `EntryOut::ttl_attr`. Any timeout set here, including `None` and zero,
+    /// is replied as is rather than the one of `KernelConfig::attr_ttl`.
+    pub fn ttl(&mut self, ttl: impl Into<Option<Duration>>) {
+        let (secs, nsecs) = encode_ttl(ttl.into());
+        self.out.attr_valid = secs;
+        self.out.attr_valid_nsec = nsecs;
+        self.ttl_set = true;
+    }
+
+    #[inline]
+    pub(crate) fn get_ino(&self) -> u64 {
+        self.out.attr.ino
+    }
+
+    /// Build the entry of the inode described by these attributes, whose
+    /// entry and attribute timeouts are both the one of the attributes.
+    pub(crate) fn to_entry_out(&self) -> EntryOut {
+        EntryOut {
+            out: fuse_entry_out {
+                nodeid: self.out.attr.ino,
+                entry_valid: self.out.attr_valid,
+                entry_valid_nsec: self.out.attr_valid_nsec,
+                attr_valid: self.out.attr_valid,
+                attr_valid_nsec: self.out.attr_valid_nsec,
+                attr: self.out.attr,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[inline]
+    pub(crate) fn get_mode(&self) -> Mode {
+        Mode::from_raw(self.out.attr.mode)
+    }
+
+    #[inline]
+    pub(crate) fn clear_attr_flags(&mut self) {
+        self.out.attr.padding = 0;
+    }
+
+    #[inline]
+    pub(crate) fn has_ttl(&self) -> bool {
+        self.ttl_set
+    }
+
+    #[inline]
+    pub(crate) fn as_attr_out(&self) -> &fuse_attr_out {
+        &self.out
     }
 }
 
@@ -212,6 +556,210 @@ impl Bytes for AttrOut {
     }
 }
 
+/// Extended attributes about a file, replied to `statx` requests.
+///
+/// Each setter also marks the corresponding `STATX_*` bit in the mask of the
+/// reply, so the kernel reports only the fields actually filled.
+#[repr(transparent)]
+pub struct StatxAttr {
+    stat: fuse_statx,
+}
+
+impl StatxAttr {
+    #[inline]
+    fn from_statx_mut(stat: &mut fuse_statx) -> &mut StatxAttr {
+        unsafe { &mut *(stat as *mut fuse_statx as *mut StatxAttr) }
+    }
+
+    /// Set the inode number.
+    #[inline]
+    pub fn ino(&mut self, ino: u64) {
+        self.stat.ino = ino;
+        self.stat.mask |= libc::STATX_INO;
+    }
+
+    /// Set the size of content.
+    #[inline]
+    pub fn size(&mut self, size: u64) {
+        self.stat.size = size;
+        self.stat.mask |= libc::STATX_SIZE;
+    }
+
+    /// Set the file type and permissions of the inode.
+    #[inline]
+    pub fn mode(&mut self, mode: impl Into<Mode>) {
+        self.stat.mode = mode.into().into_raw() as u16;
+        self.stat.mask |= libc::STATX_TYPE | libc::STATX_MODE;
+    }
+
+    /// Set the number of hard links.
+    #[inline]
+    pub fn nlink(&mut self, nlink: u32) {
+        self.stat.nlink = nlink;
+        self.stat.mask |= libc::STATX_NLINK;
+    }
+
+    /// Set the user ID.
+    #[inline]
+    pub fn uid(&mut self, uid: u32) {
+        self.stat.uid = uid;
+        self.stat.mask |= libc::STATX_UID;
+    }
+
+    /// Set the group ID.
+    #[inline]
+    pub fn gid(&mut self, gid: u32) {
+        self.stat.gid = gid;
+        self.stat.mask |= libc::STATX_GID;
+    }
+
+    /// Set the number of allocated blocks, in the units of 512 bytes.
+    #[inline]
+    pub fn blocks(&mut self, blocks: u64) {
+        self.stat.blocks = blocks;
+        self.stat.mask |= libc::STATX_BLOCKS;
+    }
+
+    /// Set the preferred block size for I/O.
+    ///
+    /// This field is always reported and has no bit in the mask.
+    #[inline]
+    pub fn blksize(&mut self, blksize: u32) {
+        self.stat.blksize = blksize;
+    }
+
+    /// Set the `STATX_ATTR_*` attribute bits of the file, such as
+    /// `STATX_ATTR_IMMUTABLE`, along with the mask of the bits supported
+    /// by the filesystem.
+    #[inline]
+    pub fn attributes(&mut self, attributes: u64, attributes_mask: u64) {
+        self.stat.attributes = attributes;
+        self.stat.attributes_mask = attributes_mask;
+    }
+
+    /// Set the device ID of a special file.
+    #[inline]
+    pub fn rdev(&mut self, major: u32, minor: u32) {
+        self.stat.rdev_major = major;
+        self.stat.rdev_minor = minor;
+    }
+
+    /// Set the last accessed time, as the duration since the UNIX epoch.
+    #[inline]
+    pub fn atime(&mut self, atime: Duration) {
+        self.stat.atime = sx_time(atime);
+        self.stat.mask |= libc::STATX_ATIME;
+    }
+
+    /// Set the last modification time.
+    #[inline]
+    pub fn mtime(&mut self, mtime: Duration) {
+        self.stat.mtime = sx_time(mtime);
+        self.stat.mask |= libc::STATX_MTIME;
+    }
+
+    /// Set the last status change time.
+    #[inline]
+    pub fn ctime(&mut self, ctime: Duration) {
+        self.stat.ctime = sx_time(ctime);
+        self.stat.mask |= libc::STATX_CTIME;
+    }
+
+    /// Set the creation time of the file.
+    ///
+    /// The birth time is only reported through `statx(2)`, since `FileAttr`
+    /// has no field for it.
+    #[inline]
+    pub fn btime(&mut self, btime: Duration) {
+        self.stat.btime = sx_time(btime);
+        self.stat.mask |= libc::STATX_BTIME;
+    }
+
+    /// Set the creation time from a `SystemTime`, e.g. one obtained
+    /// from `std::fs::Metadata::created`.
+    ///
+    /// The times before the UNIX epoch are clamped to the epoch.
+    #[inline]
+    pub fn btime_at(&mut self, btime: SystemTime) {
+        self.btime(since_epoch(btime));
+    }
+}
+
+#[inline]
+fn sx_time(time: Duration) -> fuse_sx_time {
+    fuse_sx_time {
+        tv_sec: time.as_secs() as i64,
+        tv_nsec: time.subsec_nanos(),
+        __reserved: 0,
+    }
+}
+
+#[derive(Default)]
+pub struct StatxOut {
+    out: fuse_statx_out,
+    ttl_set: bool,
+}
+
+impl fmt::Debug for StatxOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StatxOut")
+            .field("mask", &format_args!("{:#x}", self.out.stat.mask))
+            .finish()
+    }
+}
+
+impl StatxOut {
+    /// Return the object to fill the extended attribute values.
+    #[inline]
+    pub fn attr(&mut self) -> &mut StatxAttr {
+        StatxAttr::from_statx_mut(&mut self.out.stat)
+    }
+
+    /// Set the validity timeout for these attributes, or `None` not to cache
+    /// them.
+    ///
+    /// As with `AttrOut::ttl`, any timeout set here is replied as is rather
+    /// than the one of `KernelConfig::attr_ttl`.
+    pub fn ttl(&mut self, ttl: impl Into<Option<Duration>>) {
+        let (secs, nsecs) = encode_ttl(ttl.into());
+        self.out.attr_valid = secs;
+        self.out.attr_valid_nsec = nsecs;
+        self.ttl_set = true;
+    }
+
+    #[inline]
+    pub(crate) fn get_ino(&self) -> u64 {
+        self.out.stat.ino
+    }
+
+    #[inline]
+    pub(crate) fn get_mode(&self) -> Mode {
+        Mode::from_raw(u32::from(self.out.stat.mode))
+    }
+
+    #[inline]
+    pub(crate) fn has_ttl(&self) -> bool {
+        self.ttl_set
+    }
+}
+
+impl Bytes for StatxOut {
+    #[inline]
+    fn size(&self) -> usize {
+        self.out.as_bytes().len()
+    }
+
+    #[inline]
+    fn count(&self) -> usize {
+        1
+    }
+
+    #[inline]
+    fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
+        dst.put(self.out.as_bytes());
+    }
+}
+
 #[derive(Default)]
 pub struct OpenOut {
     out: fuse_open_out,
@@ -257,6 +805,18 @@ impl OpenOut {
     }
 
     /// Indicates that the direct I/O is used on this file.
+    ///
+    /// The page cache is bypassed and each `read(2)` or `write(2)` is sent
+    /// as requests. If `KernelConfig::async_dio` is negotiated, the requests
+    /// for a single I/O may be in flight concurrently.
+    ///
+    /// The flag applies to both reads and writes on the handle; the ABI has
+    /// no way to bypass the cache in one direction only. The closest to the
+    /// cached reads with direct writes is to leave this flag unset without
+    /// `KernelConfig::writeback_cache`: the reads are then served from the page
+    /// cache (kept across opens with `keep_cache`), while each write is sent
+    /// to the filesystem synchronously as well as updating the cache. The
+    /// opposite, direct reads with cached writes, is not possible.
     pub fn direct_io(&mut self, enabled: bool) {
         self.set_flag(FOPEN_DIRECT_IO, enabled);
     }
@@ -272,12 +832,84 @@ impl OpenOut {
         self.set_flag(FOPEN_NONSEEKABLE, enabled);
     }
 
+    /// Indicates that the opened file is a stream, like a pipe or a socket.
+    ///
+    /// Unlike `nonseekable`, which only makes `lseek(2)` fail, the kernel does
+    /// not maintain the file position at all, so the offsets of `read` and
+    /// `write` requests are meaningless and the concurrent reads and writes
+    /// on the file are not serialized by the position lock. This is suitable
+    /// for the generated or streamed contents. It is usually combined with
+    /// `direct_io` so that the data is not cached by the offset.
+    ///
+    /// This flag is supported since ABI 7.31. The older kernels simply
+    /// ignore it and treat the file as a regular one.
+    pub fn stream(&mut self, enabled: bool) {
+        self.set_flag(FOPEN_STREAM, enabled);
+    }
+
     /// Enable caching of entries returned by `readdir`.
     ///
     /// This flag is meaningful only for `opendir` operations.
     pub fn cache_dir(&mut self, enabled: bool) {
         self.set_flag(FOPEN_CACHE_DIR, enabled);
     }
+
+    /// Indicates that the kernel does not have to send `flush` requests
+    /// when closing this file.
+    ///
+    /// This is useful for read-only or stateless files, for which
+    /// `flush` requests are meaningless round-trips.
+    ///
+    /// This flag is supported since ABI 7.35, and `Request::reply_open`
+    /// omits it for the older kernels, which continue to send `flush`
+    /// requests.
+    pub fn no_flush(&mut self, enabled: bool) {
+        self.set_flag(FOPEN_NOFLUSH, enabled);
+    }
+
+    /// Allow the concurrent direct writes on this file.
+    ///
+    /// The kernel serializes the writes to an inode by its lock, even with
+    /// `direct_io`. With this flag, the direct writes not extending the file
+    /// are sent in parallel, which benefits e.g. a database file written by
+    /// many threads at distinct offsets. The filesystem must then handle the
+    /// overlapping writes by itself.
+    ///
+    /// This flag is supported since ABI 7.38, and `Request::reply_open` omits
+    /// it for the older kernels.
+    pub fn parallel_direct_writes(&mut self, enabled: bool) {
+        self.set_flag(FOPEN_PARALLEL_DIRECT_WRITES, enabled);
+    }
+
+    /// Serve the I/O on this file directly from the backing file registered
+    /// by `Session::open_backing`, without sending the requests.
+    ///
+    /// This requires the passthrough to be negotiated with
+    /// `KernelConfig::passthrough`, and `Request::reply_open` omits it
+    /// otherwise. The backing file may be unregistered by
+    /// `Session::close_backing` once the reply is sent, since the kernel
+    /// holds its own reference for the opened file.
+    pub fn passthrough(&mut self, backing_id: u32) {
+        self.set_flag(FOPEN_PASSTHROUGH, true);
+        // `backing_id` is stored in `padding` since ABI 7.40.
+        self.out.padding = backing_id;
+    }
+
+    #[inline]
+    pub(crate) fn clear_no_flush(&mut self) {
+        self.set_flag(FOPEN_NOFLUSH, false);
+    }
+
+    #[inline]
+    pub(crate) fn clear_parallel_direct_writes(&mut self) {
+        self.set_flag(FOPEN_PARALLEL_DIRECT_WRITES, false);
+    }
+
+    #[inline]
+    pub(crate) fn clear_passthrough(&mut self) {
+        self.set_flag(FOPEN_PASSTHROUGH, false);
+        self.out.padding = 0;
+    }
 }
 
 #[derive(Default)]
@@ -310,9 +942,26 @@ impl Bytes for WriteOut {
 }
 
 impl WriteOut {
+    /// Set the number of bytes written.
+    ///
+    /// Zero is a valid value, e.g. as the reply to a zero-length write.
+    ///
+    /// A value less than the requested size is a short write, e.g. when the
+    /// backing store runs out of space or quota partway. The caller of
+    /// `write(2)` gets the short count and the error is reported by its next
+    /// attempt. A short write is not an error, however, and when nothing has
+    /// been written the error itself (such as `ENOSPC`) should be replied
+    /// instead: the kernel reports `EIO` for a write through the page cache
+    /// replied with zero, and `write(2)` returns zero for a direct I/O one,
+    /// which the callers may retry forever.
     pub fn size(&mut self, size: u32) {
         self.out.size = size;
     }
+
+    #[inline]
+    pub(crate) fn get_size(&self) -> u32 {
+        self.out.size
+    }
 }
 
 #[derive(Default)]
@@ -433,9 +1082,95 @@ impl Bytes for XattrOut {
 }
 
 impl XattrOut {
+    /// Set the size of the attribute value, or the list of the names.
+    ///
+    /// The size must not exceed `XATTR_SIZE_MAX`; see `Request::reply_xattr`.
     pub fn size(&mut self, size: u32) {
         self.out.size = size;
     }
+
+    #[inline]
+    pub(crate) fn get_size(&self) -> u32 {
+        self.out.size
+    }
+}
+
+/// A builder of the list of extended attribute names replied to `listxattr`.
+///
+/// Each name is terminated by a NUL, including the last one, as `listxattr(2)`
+/// reports. The same list should be used for both the size query and the
+/// value so that they always agree; see `Request::reply_xattr_names`.
+#[derive(Default)]
+pub struct XattrNames {
+    buf: Vec<u8>,
+}
+
+impl fmt::Debug for XattrNames {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.iter().map(|name| name.to_string_lossy()))
+            .finish()
+    }
+}
+
+impl XattrNames {
+    /// Create an empty list.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append an attribute name.
+    ///
+    /// # Panics
+    /// It causes a panic if the name is empty or contains a NUL.
+    pub fn push(&mut self, name: impl AsRef<OsStr>) {
+        let name = name.as_ref().as_bytes();
+        assert!(
+            !name.is_empty() && !name.contains(&0),
+            "the attribute name must be non-empty and must not contain a NUL"
+        );
+        self.buf.extend_from_slice(name);
+        self.buf.push(0);
+    }
+
+    /// Return the length of the list in bytes, including the terminating NULs.
+    ///
+    /// This is the size replied to the query of the buffer size.
+    #[inline]
+    pub fn total_len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Return whether no name has been appended.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Return the list in the format replied to the kernel.
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..]
+    }
+
+    /// Return an iterator over the appended names.
+    pub fn iter(&self) -> impl Iterator<Item = &OsStr> + '_ {
+        self.buf
+            .split(|&b| b == 0)
+            .filter(|name| !name.is_empty())
+            .map(OsStr::from_bytes)
+    }
+}
+
+impl<T> Extend<T> for XattrNames
+where
+    T: AsRef<OsStr>,
+{
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for name in iter {
+            self.push(name);
+        }
+    }
 }
 
 #[derive(Default)]
@@ -577,6 +1312,16 @@ impl PollOut {
 
 pub struct ReaddirOut {
     buf: Vec<u8>,
+    capacity: usize,
+    pool: Option<ReaddirPool>,
+}
+
+impl Drop for ReaddirOut {
+    fn drop(&mut self) {
+        if let Some(ref pool) = self.pool {
+            pool.release(mem::take(&mut self.buf));
+        }
+    }
 }
 
 impl fmt::Debug for ReaddirOut {
@@ -606,15 +1351,27 @@ impl ReaddirOut {
     pub fn new(capacity: usize) -> Self {
         Self {
             buf: Vec::with_capacity(capacity),
+            capacity,
+            pool: None,
         }
     }
 
+    /// Add an entry.
+    ///
+    /// `typ` is a `DT_*` value, not the `S_IF*` bits of the file mode;
+    /// use `FileType::into_dirent_type` to obtain it from a file type.
+    ///
+    /// Return `true` if the entry does not fit in the remaining capacity.
     pub fn entry(&mut self, name: &OsStr, ino: u64, typ: u32, off: u64) -> bool {
+        debug_assert!(
+            typ <= 0o17,
+            "typ must be a DT_* value (found {:#o}, perhaps S_IF* bits)",
+            typ
+        );
         let name = name.as_bytes();
-        let remaining = self.buf.capacity() - self.buf.len();
+        let remaining = self.remaining_capacity();
 
-        let entry_size = mem::size_of::<fuse_dirent>() + name.len();
-        let aligned_entry_size = aligned(entry_size);
+        let aligned_entry_size = dirent_size(name.len());
 
         if remaining < aligned_entry_size {
             return true;
@@ -631,12 +1388,846 @@ impl ReaddirOut {
         self.buf.extend_from_slice(dirent.as_bytes());
         self.buf.extend_from_slice(name);
         self.buf.resize(lenbefore + aligned_entry_size, 0);
+        debug_assert_eq!(self.buf.len() % mem::size_of::<u64>(), 0);
 
         false
     }
+
+    /// Add an entry of the specified file type.
+    ///
+    /// This is the same as `entry`, except that the type is given as `FileType`
+    /// rather than the raw `DT_*` value.
+    pub fn typed_entry(&mut self, name: &OsStr, ino: u64, file_type: FileType, off: u64) -> bool {
+        self.entry(name, ino, file_type.into_dirent_type(), off)
+    }
+
+    /// Return the number of bytes that can still be added to this reply.
+    ///
+    /// An entry occupies the size of `fuse_dirent` plus the length of
+    /// its name, rounded up to a multiple of 8 bytes.
+    #[inline]
+    pub fn remaining_capacity(&self) -> usize {
+        self.capacity - self.buf.len()
+    }
+
+    /// Add the `"."` and `".."` entries at the beginning of the directory.
+    ///
+    /// The entries are placed at the offsets 0 and 1, so the subsequent entries
+    /// should start at the offset 2. `offset` is the one requested by `readdir`,
+    /// and the entries already returned before it are skipped. Since the root
+    /// directory is its own parent, `parent` is ignored for the root inode.
+    ///
+    /// The return value has the same meaning as `entry`.
+    pub fn dot_entries(&mut self, offset: u64, ino: u64, parent: u64) -> bool {
+        let parent = if ino == FUSE_ROOT_ID {
+            FUSE_ROOT_ID
+        } else {
+            parent
+        };
+        let typ = FileType::Directory;
+
+        if offset < 1 && self.typed_entry(OsStr::new("."), ino, typ, 1) {
+            return true;
+        }
+        if offset < 2 && self.typed_entry(OsStr::new(".."), parent, typ, 2) {
+            return true;
+        }
+
+        false
+    }
+}
+
+/// The reply to a `readdirplus` request, containing the entries
+/// along with their attributes.
+///
+/// When created by `with_mode` for a plain `readdir` request, the entries
+/// are encoded without the attributes. This allows a filesystem to implement
+/// only `readdirplus` and still serve the kernels that have not negotiated
+/// `FUSE_DO_READDIRPLUS` and therefore send `readdir` requests only.
+pub struct ReaddirPlusOut {
+    buf: Vec<u8>,
+    capacity: usize,
+    mode: ReaddirMode,
+    pool: Option<ReaddirPool>,
+}
+
+impl Drop for ReaddirPlusOut {
+    fn drop(&mut self) {
+        if let Some(ref pool) = self.pool {
+            pool.release(mem::take(&mut self.buf));
+        }
+    }
+}
+
+impl fmt::Debug for ReaddirPlusOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // TODO: add fields.
+        f.debug_struct("ReaddirPlusOut").finish()
+    }
+}
+
+impl Bytes for ReaddirPlusOut {
+    #[inline]
+    fn size(&self) -> usize {
+        self.buf.size()
+    }
+
+    #[inline]
+    fn count(&self) -> usize {
+        self.buf.count()
+    }
+
+    fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
+        self.buf.fill_bytes(dst)
+    }
+}
+
+impl ReaddirPlusOut {
+    pub fn new(capacity: usize) -> Self {
+        Self::with_mode(capacity, ReaddirMode::Plus)
+    }
+
+    /// Create a reply for the directory read request of the specified mode,
+    /// usually the one returned from `Readdir::mode`.
+    pub fn with_mode(capacity: usize, mode: ReaddirMode) -> Self {
+        Self {
+            buf: Vec::with_capacity(capacity),
+            capacity,
+            mode,
+            pool: None,
+        }
+    }
+
+    /// Add an entry, whose inode number and file type are taken from
+    /// the attributes in `entry`.
+    ///
+    /// The kernel populates its caches from `entry` exactly as from the reply
+    /// to `lookup`: the attributes and the name are cached for `ttl_attr` and
+    /// `ttl_entry` respectively, and the pair of the inode number and the
+    /// generation must identify the inode in the same way. In particular, each
+    /// entry with a non-zero inode number counts as a lookup, which the kernel
+    /// balances later by `forget`, except for `.` and `..`. The attributes do
+    /// not have to be as complete as those replied to `getattr`, as long as the
+    /// timeout reflects how long they remain accurate. An entry whose inode
+    /// number is zero (see `EntryOut::ino`) is listed without touching the caches.
+    ///
+    /// Return `true` if the entry does not fit in the remaining capacity.
+    pub fn entry(&mut self, name: &OsStr, off: u64, entry: &EntryOut) -> bool {
+        let name = name.as_bytes();
+        let remaining = self.remaining_capacity();
+
+        let aligned_entry_size = match self.mode {
+            ReaddirMode::Plus => direntplus_size(name.len()),
+            ReaddirMode::Normal => dirent_size(name.len()),
+        };
+
+        if remaining < aligned_entry_size {
+            return true;
+        }
+
+        let dirent = fuse_dirent {
+            ino: entry.out.attr.ino,
+            off,
+            namelen: name.len().try_into().expect("name length is too long"),
+            typ: (entry.out.attr.mode & libc::S_IFMT) >> 12,
+            name: [],
+        };
+        let lenbefore = self.buf.len();
+        match self.mode {
+            ReaddirMode::Plus => {
+                let direntplus = fuse_direntplus {
+                    entry_out: entry.out,
+                    dirent,
+                };
+                self.buf.extend_from_slice(direntplus.as_bytes());
+            }
+            ReaddirMode::Normal => self.buf.extend_from_slice(dirent.as_bytes()),
+        }
+        self.buf.extend_from_slice(name);
+        self.buf.resize(lenbefore + aligned_entry_size, 0);
+        debug_assert_eq!(self.buf.len() % mem::size_of::<u64>(), 0);
+
+        false
+    }
+
+    /// Return the number of bytes that can still be added to this reply.
+    ///
+    /// Since an entry carries the attributes, it occupies much more space
+    /// than in `readdir`, and the filesystem can use this value to stop
+    /// looking up the attributes which would not fit in the reply.
+    #[inline]
+    pub fn remaining_capacity(&self) -> usize {
+        self.capacity - self.buf.len()
+    }
+}
+
+/// A pool of the buffers shared by the directory replies.
+///
+/// Each directory reply allocates a buffer as large as the size offered
+/// by the kernel, so the memory usage spikes when many large directories
+/// are listed at the same time. The replies created from a pool reuse its
+/// buffers, and at most `max_buffers` of them exist at once; the creation
+/// of a reply blocks until another one is dropped.
+///
+/// The pool is cheaply cloneable and can be shared between worker threads.
+#[derive(Clone)]
+pub struct ReaddirPool {
+    inner: Arc<ReaddirPoolInner>,
+}
+
+struct ReaddirPoolInner {
+    max_buffers: usize,
+    state: Mutex<ReaddirPoolState>,
+    released: Condvar,
+}
+
+struct ReaddirPoolState {
+    idle: Vec<Vec<u8>>,
+    in_use: usize,
+}
+
+impl fmt::Debug for ReaddirPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReaddirPool")
+            .field("max_buffers", &self.inner.max_buffers)
+            .finish()
+    }
+}
+
+impl ReaddirPool {
+    /// Create a pool holding at most `max_buffers` buffers.
+    ///
+    /// # Panics
+    /// It causes a panic if `max_buffers` is zero.
+    pub fn new(max_buffers: usize) -> Self {
+        assert!(max_buffers > 0, "max_buffers must be positive");
+        Self {
+            inner: Arc::new(ReaddirPoolInner {
+                max_buffers,
+                state: Mutex::new(ReaddirPoolState {
+                    idle: Vec::with_capacity(max_buffers),
+                    in_use: 0,
+                }),
+                released: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Create a `readdir` reply backed by a buffer of this pool.
+    pub fn readdir(&self, capacity: usize) -> ReaddirOut {
+        ReaddirOut {
+            buf: self.acquire(capacity),
+            capacity,
+            pool: Some(self.clone()),
+        }
+    }
+
+    /// Create a `readdirplus` reply backed by a buffer of this pool.
+    pub fn readdirplus(&self, capacity: usize) -> ReaddirPlusOut {
+        ReaddirPlusOut {
+            buf: self.acquire(capacity),
+            capacity,
+            mode: ReaddirMode::Plus,
+            pool: Some(self.clone()),
+        }
+    }
+
+    fn acquire(&self, capacity: usize) -> Vec<u8> {
+        let mut state = self.inner.state.lock().unwrap_or_else(|e| e.into_inner());
+        while state.in_use >= self.inner.max_buffers {
+            state = self
+                .inner
+                .released
+                .wait(state)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+        state.in_use += 1;
+        let mut buf = state.idle.pop().unwrap_or_default();
+        drop(state);
+
+        buf.clear();
+        buf.reserve(capacity);
+        buf
+    }
+
+    fn release(&self, buf: Vec<u8>) {
+        let mut state = self.inner.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.in_use -= 1;
+        state.idle.push(buf);
+        drop(state);
+        self.inner.released.notify_one();
+    }
+}
+
+/// Return the size of a `readdir` entry with the name of `namelen` bytes.
+///
+/// Each entry is padded to a multiple of 8 bytes, as the kernel expects the
+/// next one at the aligned offset.
+#[inline]
+pub const fn dirent_size(namelen: usize) -> usize {
+    aligned(mem::size_of::<fuse_dirent>() + namelen)
+}
+
+/// Return the size of a `readdirplus` entry with the name of `namelen` bytes.
+///
+/// See `dirent_size` for the padding.
+#[inline]
+pub const fn direntplus_size(namelen: usize) -> usize {
+    aligned(mem::size_of::<fuse_direntplus>() + namelen)
+}
+
+/// Split the validity timeout into the seconds and nanoseconds on the wire,
+/// where `None` is the zero timeout, i.e. no caching.
+#[inline]
+fn encode_ttl(ttl: Option<Duration>) -> (u64, u32) {
+    match ttl {
+        Some(ttl) => (ttl.as_secs(), ttl.subsec_nanos()),
+        None => (0, 0),
+    }
 }
 
 #[inline]
 const fn aligned(len: usize) -> usize {
     (len + mem::size_of::<u64>() - 1) & !(mem::size_of::<u64>() - 1)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_vec<T: Bytes>(bytes: T) -> Vec<u8> {
+        struct Collect(Vec<u8>);
+        impl<'a> FillBytes<'a> for Collect {
+            fn put(&mut self, chunk: &'a [u8]) {
+                self.0.extend_from_slice(chunk);
+            }
+        }
+        let mut collect = Collect(Vec::with_capacity(bytes.size()));
+        bytes.fill_bytes(&mut collect);
+        assert_eq!(collect.0.len(), bytes.size());
+        collect.0
+    }
+
+    #[test]
+    fn ttl_none_is_zero() {
+        let mut out = EntryOut::default();
+        out.ttl_attr(Duration::new(5, 500));
+        out.ttl_entry(Some(Duration::from_secs(u64::MAX)));
+        assert_eq!(out.out.attr_valid, 5);
+        assert_eq!(out.out.attr_valid_nsec, 500);
+        assert_eq!(out.out.entry_valid, u64::MAX);
+
+        out.ttl_attr(None);
+        out.ttl_entry(None);
+        assert_eq!(out.out.attr_valid, 0);
+        assert_eq!(out.out.attr_valid_nsec, 0);
+        assert_eq!(out.out.entry_valid, 0);
+        assert_eq!(out.out.entry_valid_nsec, 0);
+        assert_eq!(to_vec(&out), to_vec(EntryOut::default()));
+
+        let mut out = AttrOut::default();
+        assert!(!out.has_ttl());
+        out.ttl(Duration::from_millis(1500));
+        assert!(out.has_ttl());
+        // An explicit `None` stays distinguishable from the unset timeout.
+        out.ttl(None);
+        assert!(out.has_ttl());
+        assert_eq!(to_vec(&out), to_vec(AttrOut::default()));
+
+        let mut out = StatxOut::default();
+        out.ttl(Duration::from_secs(1));
+        out.ttl(None);
+        assert_eq!(out.out.attr_valid, 0);
+        assert_eq!(out.out.attr_valid_nsec, 0);
+    }
+
+    #[test]
+    fn xattr_names() {
+        let mut names = XattrNames::new();
+        assert!(names.is_empty());
+        assert_eq!(names.total_len(), 0);
+
+        names.push("user.foo");
+        names.extend(vec!["security.selinux", "trusted.overlay.opaque"]);
+        assert_eq!(
+            names.as_bytes(),
+            &b"user.foo\0security.selinux\0trusted.overlay.opaque\0"[..]
+        );
+        assert_eq!(names.total_len(), names.as_bytes().len());
+        assert_eq!(
+            names.iter().collect::<Vec<_>>(),
+            vec!["user.foo", "security.selinux", "trusted.overlay.opaque"]
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn xattr_names_reject_nul() {
+        XattrNames::new().push("user.\0foo");
+    }
+
+    fn open_flags(out: &OpenOut) -> u32 {
+        let bytes = to_vec(out);
+        u32::from_ne_bytes([bytes[8], bytes[9], bytes[10], bytes[11]])
+    }
+
+    #[test]
+    fn open_no_flush() {
+        let mut out = OpenOut::default();
+        out.fh(42);
+        assert_eq!(open_flags(&out) & FOPEN_NOFLUSH, 0);
+
+        out.no_flush(true);
+        out.keep_cache(true);
+        assert_eq!(open_flags(&out), FOPEN_NOFLUSH | FOPEN_KEEP_CACHE);
+
+        out.no_flush(false);
+        assert_eq!(open_flags(&out), FOPEN_KEEP_CACHE);
+    }
+
+    #[test]
+    fn open_stream() {
+        let mut out = OpenOut::default();
+        out.nonseekable(true);
+        assert_eq!(open_flags(&out), FOPEN_NONSEEKABLE);
+
+        out.stream(true);
+        out.direct_io(true);
+        assert_eq!(
+            open_flags(&out),
+            FOPEN_NONSEEKABLE | FOPEN_STREAM | FOPEN_DIRECT_IO
+        );
+
+        out.stream(false);
+        assert_eq!(open_flags(&out), FOPEN_NONSEEKABLE | FOPEN_DIRECT_IO);
+    }
+
+    #[test]
+    fn open_cache_dir() {
+        let mut out = OpenOut::default();
+        out.fh(1);
+        out.cache_dir(true);
+        let bytes = to_vec(&out);
+        assert_eq!(bytes[..8], 1u64.to_ne_bytes());
+        assert_eq!(open_flags(&out), FOPEN_CACHE_DIR);
+    }
+
+    fn dirents(out: &ReaddirOut) -> Vec<(u64, u64, u32, Vec<u8>)> {
+        let bytes = to_vec(out);
+        let mut entries = vec![];
+        let mut bytes = &bytes[..];
+        while !bytes.is_empty() {
+            let u64_at = |i: usize| {
+                let mut buf = [0u8; 8];
+                buf.copy_from_slice(&bytes[i..i + 8]);
+                u64::from_ne_bytes(buf)
+            };
+            let u32_at = |i: usize| {
+                let mut buf = [0u8; 4];
+                buf.copy_from_slice(&bytes[i..i + 4]);
+                u32::from_ne_bytes(buf)
+            };
+            let namelen = u32_at(16) as usize;
+            let header_len = mem::size_of::<fuse_dirent>();
+            entries.push((
+                u64_at(0),
+                u64_at(8),
+                u32_at(20),
+                bytes[header_len..header_len + namelen].to_vec(),
+            ));
+            bytes = &bytes[aligned(header_len + namelen)..];
+        }
+        entries
+    }
+
+    #[test]
+    fn readdir_dot_entries() {
+        let dir = libc::DT_DIR as u32;
+
+        let mut out = ReaddirOut::new(4096);
+        assert!(!out.dot_entries(0, 42, 7));
+        assert_eq!(
+            dirents(&out),
+            vec![
+                (42, 1, dir, b".".to_vec()), //
+                (7, 2, dir, b"..".to_vec()),
+            ]
+        );
+
+        // The parent of the root is the root itself.
+        let mut out = ReaddirOut::new(4096);
+        assert!(!out.dot_entries(0, FUSE_ROOT_ID, 0));
+        assert_eq!(
+            dirents(&out),
+            vec![
+                (FUSE_ROOT_ID, 1, dir, b".".to_vec()),
+                (FUSE_ROOT_ID, 2, dir, b"..".to_vec()),
+            ]
+        );
+
+        // "." has already been returned.
+        let mut out = ReaddirOut::new(4096);
+        assert!(!out.dot_entries(1, 42, 7));
+        assert_eq!(dirents(&out), vec![(7, 2, dir, b"..".to_vec())]);
+
+        // No room for "..".
+        let mut out = ReaddirOut::new(mem::size_of::<fuse_dirent>() + 8);
+        assert!(out.dot_entries(0, 42, 7));
+        assert_eq!(dirents(&out), vec![(42, 1, dir, b".".to_vec())]);
+    }
+
+    #[test]
+    fn readdir_remaining_capacity() {
+        let dirent = mem::size_of::<fuse_dirent>();
+
+        let mut out = ReaddirOut::new(4096);
+        assert_eq!(out.remaining_capacity(), 4096);
+        assert!(!out.entry(OsStr::new("a"), 2, 0, 1));
+        assert_eq!(out.remaining_capacity(), 4096 - aligned(dirent + 1));
+        assert!(!out.entry(OsStr::new("abcdefghi"), 3, 0, 2));
+        assert_eq!(
+            out.remaining_capacity(),
+            4096 - aligned(dirent + 1) - aligned(dirent + 9)
+        );
+        assert_eq!(out.size(), 4096 - out.remaining_capacity());
+
+        let direntplus = mem::size_of::<fuse_direntplus>();
+        let mut entry = EntryOut::default();
+        entry.ino(2);
+        entry.attr().ino(2);
+        entry.attr().mode(libc::S_IFREG | 0o644);
+
+        let mut out = ReaddirPlusOut::new(2 * direntplus + 16);
+        assert!(!out.entry(OsStr::new("a"), 1, &entry));
+        assert_eq!(
+            out.remaining_capacity(),
+            2 * direntplus + 16 - aligned(direntplus + 1)
+        );
+        assert!(!out.entry(OsStr::new("b"), 2, &entry));
+        assert_eq!(
+            out.remaining_capacity(),
+            2 * direntplus + 16 - 2 * aligned(direntplus + 1)
+        );
+        assert!(out.entry(OsStr::new("c"), 3, &entry));
+        assert_eq!(out.size(), 2 * aligned(direntplus + 1));
+
+        // The dirent part follows the entry_out.
+        let bytes = to_vec(&out);
+        let entry_out = mem::size_of::<fuse_entry_out>();
+        assert_eq!(bytes[entry_out..entry_out + 8], 2u64.to_ne_bytes());
+        assert_eq!(
+            bytes[entry_out + 20..entry_out + 24],
+            (libc::S_IFREG >> 12).to_ne_bytes()
+        );
+    }
+
+    #[test]
+    fn readdir_pool_bounds_buffers() {
+        use std::{
+            collections::HashSet, sync::atomic::AtomicUsize, sync::atomic::Ordering, thread,
+        };
+
+        let pool = ReaddirPool::new(2);
+        let in_use = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let buffers = Arc::new(Mutex::new(HashSet::new()));
+
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let pool = pool.clone();
+                let in_use = in_use.clone();
+                let peak = peak.clone();
+                let buffers = buffers.clone();
+                thread::spawn(move || {
+                    for j in 0..10 {
+                        let mut out = pool.readdir(4096);
+                        let n = in_use.fetch_add(1, Ordering::SeqCst) + 1;
+                        peak.fetch_max(n, Ordering::SeqCst);
+
+                        assert_eq!(out.remaining_capacity(), 4096);
+                        assert!(!out.entry(OsStr::new("a"), i, 0, j));
+                        buffers.lock().unwrap().insert(out.buf.as_ptr() as usize);
+                        thread::yield_now();
+
+                        in_use.fetch_sub(1, Ordering::SeqCst);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert!(peak.load(Ordering::SeqCst) <= 2);
+        assert!(buffers.lock().unwrap().len() <= 2);
+
+        // A reused buffer may be larger than requested, but the reply never
+        // exceeds the capacity offered by the kernel.
+        let mut out = pool.readdirplus(mem::size_of::<fuse_direntplus>() + 8);
+        let entry = EntryOut::default();
+        assert!(!out.entry(OsStr::new("a"), 1, &entry));
+        assert!(out.entry(OsStr::new("b"), 2, &entry));
+    }
+
+    #[test]
+    fn errno_raw_values() {
+        assert_eq!(Errno::NotFound.raw(), 2);
+        assert_eq!(i32::from(Errno::NoData), libc::ENODATA);
+        for &(errno, raw) in ERRNO_TABLE {
+            assert_eq!(errno.raw(), raw, "{:?}", errno);
+            assert_eq!(i32::from(errno), raw, "{:?}", errno);
+        }
+
+        let mut raws: Vec<i32> = ERRNO_TABLE.iter().map(|&(_, raw)| raw).collect();
+        raws.sort_unstable();
+        raws.dedup();
+        assert_eq!(raws.len(), ERRNO_TABLE.len(), "duplicated error numbers");
+    }
+
+    #[test]
+    fn dirent_alignment() {
+        assert_eq!(dirent_size(0), 24);
+        assert_eq!(dirent_size(1), 32);
+        assert_eq!(dirent_size(8), 32);
+        assert_eq!(dirent_size(9), 40);
+        assert_eq!(direntplus_size(1), mem::size_of::<fuse_direntplus>() + 8);
+
+        let names: Vec<String> = (1..=17).map(|n| "x".repeat(n)).collect();
+
+        let mut out = ReaddirOut::new(4096);
+        let mut plus = ReaddirPlusOut::new(8192);
+        let mut expected_len = 0;
+        for (i, name) in names.iter().enumerate() {
+            let mut entry = EntryOut::default();
+            entry.ino(i as u64 + 2);
+            assert!(!out.entry(OsStr::new(name), i as u64 + 2, 0, i as u64 + 1));
+            assert!(!plus.entry(OsStr::new(name), i as u64 + 1, &entry));
+            expected_len += dirent_size(name.len());
+            assert_eq!(out.size(), expected_len);
+            assert_eq!(out.size() % 8, 0);
+            assert_eq!(plus.size() % 8, 0);
+        }
+
+        let entries = dirents(&out);
+        assert_eq!(entries.len(), names.len());
+        for (entry, name) in entries.iter().zip(&names) {
+            assert_eq!(entry.3, name.as_bytes());
+        }
+    }
+
+    #[test]
+    fn readdir_typed_entry() {
+        let mut out = ReaddirOut::new(4096);
+        assert!(!out.typed_entry(OsStr::new("dir"), 2, FileType::Directory, 1));
+        assert!(!out.typed_entry(OsStr::new("file.txt"), 3, FileType::RegularFile, 2));
+
+        let mut expected = ReaddirOut::new(4096);
+        expected.entry(OsStr::new("dir"), 2, u32::from(libc::DT_DIR), 1);
+        expected.entry(OsStr::new("file.txt"), 3, u32::from(libc::DT_REG), 2);
+        assert_eq!(to_vec(&out), to_vec(&expected));
+
+        assert_eq!(
+            dirents(&out),
+            vec![
+                (2, 1, u32::from(libc::DT_DIR), b"dir".to_vec()),
+                (3, 2, u32::from(libc::DT_REG), b"file.txt".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn readdirplus_entry_attrs() {
+        let mut out = ReaddirPlusOut::new(4096);
+
+        let mut dir = EntryOut::default();
+        dir.ino(2);
+        dir.generation(7);
+        dir.ttl_entry(Duration::from_secs(60));
+        dir.ttl_attr(Duration::from_secs(1));
+        dir.attr().ino(2);
+        dir.attr().mode(libc::S_IFDIR | 0o755);
+        dir.attr().nlink(2);
+        assert!(!out.entry(OsStr::new("dir"), 1, &dir));
+
+        let mut file = EntryOut::default();
+        file.ino(3);
+        file.ttl_entry(Duration::from_secs(5));
+        file.attr().ino(3);
+        file.attr().mode(libc::S_IFREG | 0o644);
+        file.attr().size(1024);
+        assert!(!out.entry(OsStr::new("file.txt"), 2, &file));
+
+        let buf = to_vec(&out);
+        let mut pos = 0;
+        let mut entries = vec![];
+        while pos < buf.len() {
+            let mut direntplus = fuse_direntplus::default();
+            direntplus
+                .as_bytes_mut()
+                .copy_from_slice(&buf[pos..pos + mem::size_of::<fuse_direntplus>()]);
+            pos += direntplus_size(direntplus.dirent.namelen as usize);
+            entries.push(direntplus);
+        }
+        assert_eq!(entries.len(), 2);
+
+        // Each entry carries its own attributes and entry options.
+        let (d, f) = (&entries[0], &entries[1]);
+        assert_eq!(d.entry_out.nodeid, 2);
+        assert_eq!(d.entry_out.generation, 7);
+        assert_eq!(d.entry_out.entry_valid, 60);
+        assert_eq!(d.entry_out.attr_valid, 1);
+        assert_eq!(d.entry_out.attr.nlink, 2);
+        assert_eq!(d.dirent.ino, 2);
+        assert_eq!(d.dirent.typ, u32::from(libc::DT_DIR));
+
+        assert_eq!(f.entry_out.nodeid, 3);
+        assert_eq!(f.entry_out.generation, 0);
+        assert_eq!(f.entry_out.entry_valid, 5);
+        assert_eq!(f.entry_out.attr_valid, 0);
+        assert_eq!(f.entry_out.attr.size, 1024);
+        assert_eq!(f.dirent.off, 2);
+        assert_eq!(f.dirent.typ, u32::from(libc::DT_REG));
+    }
+
+    #[test]
+    fn readdirplus_submount() {
+        let mut out = ReaddirPlusOut::new(4096);
+        let mut layer = EntryOut::default();
+        layer.ino(2);
+        layer.attr().ino(2);
+        layer.attr().mode(libc::S_IFDIR | 0o755);
+        layer.submount(true);
+        assert!(!out.entry(OsStr::new("lower"), 1, &layer));
+
+        layer.ino(3);
+        layer.attr().ino(3);
+        layer.submount(false);
+        assert!(!out.entry(OsStr::new("upper"), 2, &layer));
+
+        let buf = to_vec(&out);
+        let mut first = fuse_direntplus::default();
+        first
+            .as_bytes_mut()
+            .copy_from_slice(&buf[..mem::size_of::<fuse_direntplus>()]);
+        assert_eq!(first.entry_out.attr.padding, FUSE_ATTR_SUBMOUNT);
+
+        let offset = direntplus_size(5);
+        let mut second = fuse_direntplus::default();
+        second
+            .as_bytes_mut()
+            .copy_from_slice(&buf[offset..offset + mem::size_of::<fuse_direntplus>()]);
+        assert_eq!(second.entry_out.nodeid, 3);
+        assert_eq!(second.entry_out.attr.padding, 0);
+    }
+
+    #[test]
+    fn readdir_file_types() {
+        let file_types = [
+            FileType::RegularFile,
+            FileType::Directory,
+            FileType::Symlink,
+            FileType::BlockDevice,
+            FileType::CharDevice,
+            FileType::Fifo,
+            FileType::Socket,
+        ];
+
+        let mut out = ReaddirOut::new(4096);
+        for (i, file_type) in file_types.iter().enumerate() {
+            let i = i as u64;
+            assert!(!out.entry(OsStr::new("x"), i + 2, file_type.into_dirent_type(), i + 1));
+        }
+
+        let types: Vec<_> = dirents(&out)
+            .into_iter()
+            .map(|(_, _, typ, _)| FileType::from_dirent_type(typ))
+            .collect();
+        assert_eq!(
+            types,
+            file_types.iter().copied().map(Some).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn attr_from_stat() {
+        let stat = Stat {
+            ino: 42,
+            size: 5000,
+            blocks: 16,
+            atime: UNIX_EPOCH + Duration::new(1_600_000_000, 1),
+            mtime: UNIX_EPOCH + Duration::new(1_600_000_001, 2),
+            ctime: UNIX_EPOCH - Duration::from_secs(1),
+            mode: libc::S_IFREG | 0o640,
+            nlink: 2,
+            uid: 1000,
+            gid: 100,
+            rdev: 0,
+            blksize: 4096,
+        };
+        let mut out = AttrOut::default();
+        out.attr().stat(&stat);
+
+        let expected = fuse_attr_out {
+            attr: fuse_attr {
+                ino: 42,
+                size: 5000,
+                blocks: 16,
+                atime: 1_600_000_000,
+                atimensec: 1,
+                mtime: 1_600_000_001,
+                mtimensec: 2,
+                ctime: 0,
+                ctimensec: 0,
+                mode: libc::S_IFREG | 0o640,
+                nlink: 2,
+                uid: 1000,
+                gid: 100,
+                rdev: 0,
+                blksize: 4096,
+                padding: 0,
+            },
+            ..Default::default()
+        };
+        assert_eq!(to_vec(&out), expected.as_bytes());
+    }
+
+    #[test]
+    fn whiteout_attr() {
+        let mut out = AttrOut::default();
+        out.attr().ino(42);
+        out.attr().mode(libc::S_IFREG | 0o644);
+        out.attr().rdev(0x0801);
+        out.attr().whiteout();
+
+        let bytes = to_vec(&out);
+        let mut attr_out = fuse_attr_out::default();
+        attr_out.as_bytes_mut().copy_from_slice(&bytes[..]);
+        assert_eq!(attr_out.attr.ino, 42);
+        assert_eq!(attr_out.attr.mode, libc::S_IFCHR);
+        assert_eq!(attr_out.attr.rdev, 0);
+        assert_eq!(attr_out.attr.rdev as libc::dev_t, libc::makedev(0, 0));
+        assert_eq!(
+            Mode::from_raw(attr_out.attr.mode).file_type(),
+            Some(FileType::CharDevice)
+        );
+    }
+
+    #[test]
+    fn attr_nanosecond_times() {
+        let mut out = AttrOut::default();
+        out.attr().atime(Duration::new(1_600_000_000, 1));
+        out.attr()
+            .mtime_at(UNIX_EPOCH + Duration::new(1_600_000_001, 123_456_789));
+        out.attr().ctime_at(UNIX_EPOCH - Duration::from_secs(1));
+
+        let bytes = to_vec(&out);
+        let mut attr_out = fuse_attr_out::default();
+        attr_out.as_bytes_mut().copy_from_slice(&bytes[..]);
+        assert_eq!(attr_out.attr.atime, 1_600_000_000);
+        assert_eq!(attr_out.attr.atimensec, 1);
+        assert_eq!(attr_out.attr.mtime, 1_600_000_001);
+        assert_eq!(attr_out.attr.mtimensec, 123_456_789);
+        assert_eq!(attr_out.attr.ctime, 0);
+        assert_eq!(attr_out.attr.ctimensec, 0);
+    }
+}
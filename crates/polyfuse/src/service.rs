@@ -0,0 +1,247 @@
+//! Composable request handlers.
+//!
+//! A `Service` handles a single request, typically by decoding its operation
+//! and sending the reply. Cross-cutting concerns such as logging, access control
+//! or rate limiting are implemented as services wrapping another service, so
+//! that they can be stacked in front of the filesystem and driven by `Session::run`.
+//!
+//! ```no_run
+//! use polyfuse::{service::Service, KernelConfig, Operation, Request, Session};
+//! use std::io;
+//!
+//! struct Logging<S>(S);
+//!
+//! impl<S: Service> Service for Logging<S> {
+//!     fn call(&self, req: &mut Request) -> io::Result<()> {
+//!         tracing::info!(unique = req.unique(), "start");
+//!         let res = self.0.call(req);
+//!         tracing::info!(unique = req.unique(), "done");
+//!         res
+//!     }
+//! }
+//!
+//! fn filesystem(req: &mut Request) -> io::Result<()> {
+//!     match req.operation()? {
+//!         Operation::Getattr(..) => req.reply_error(libc::ENOENT),
+//!         _ => req.reply_error(libc::ENOSYS),
+//!     }
+//! }
+//!
+//! # fn main() -> io::Result<()> {
+//! let session = Session::mount("/mnt".into(), KernelConfig::default())?;
+//! session.run(Logging(filesystem))?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::session::{expects_reply, Request};
+use std::{
+    collections::HashMap,
+    fmt, io,
+    rc::Rc,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// A handler of the FUSE requests.
+pub trait Service {
+    /// Handle a request.
+    ///
+    /// The service is responsible for replying to the request, either by itself
+    /// or by passing it to the inner service. Returning an error aborts the
+    /// loop in `Session::run`, so it should be reserved for the failures of
+    /// the connection rather than the filesystem errors.
+    fn call(&self, req: &mut Request) -> io::Result<()>;
+}
+
+impl<F> Service for F
+where
+    F: Fn(&mut Request) -> io::Result<()>,
+{
+    #[inline]
+    fn call(&self, req: &mut Request) -> io::Result<()> {
+        (*self)(req)
+    }
+}
+
+macro_rules! impl_service_for_pointers {
+    ($($t:ty),*) => {$(
+        impl<S: ?Sized> Service for $t
+        where
+            S: Service,
+        {
+            #[inline]
+            fn call(&self, req: &mut Request) -> io::Result<()> {
+                (**self).call(req)
+            }
+        }
+    )*};
+}
+
+impl_service_for_pointers!(Rc<S>, Arc<S>);
+
+/// A service limiting the rate of the requests passed to the inner service.
+///
+/// Each opcode configured by `limit` has a token bucket of its own, which
+/// holds up to `burst` tokens and is refilled by `rate` tokens per second.
+/// A request consumes a token, and the request arriving at an empty bucket
+/// is delayed until a token is available, or replied `EAGAIN` if `reject`
+/// is enabled. The requests of the other opcodes, and those the kernel does
+/// not expect a reply to (such as `forget`), are passed as is.
+///
+/// Since `Session::run` handles the requests one by one, a delayed request
+/// also delays the following ones. The delay is therefore bounded by
+/// `max_delay`, beyond which the requests are replied `EAGAIN` as well.
+///
+/// ```no_run
+/// use polyfuse::{service::RateLimiter, KernelConfig, Request, Session};
+/// use polyfuse_kernel::fuse_opcode;
+/// use std::io;
+///
+/// # fn filesystem(req: &mut Request) -> io::Result<()> { req.reply_error(libc::ENOSYS) }
+/// # fn main() -> io::Result<()> {
+/// let mut limiter = RateLimiter::new(filesystem);
+/// limiter.limit(fuse_opcode::FUSE_GETATTR as u32, 100, 10);
+///
+/// let session = Session::mount("/mnt".into(), KernelConfig::default())?;
+/// session.run(limiter)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct RateLimiter<S> {
+    inner: S,
+    buckets: Mutex<HashMap<u32, Bucket>>,
+    reject: bool,
+    max_delay: Duration,
+}
+
+/// The default upper bound of the delay of a request.
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(1);
+
+struct Bucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Take a token and return the duration to wait for it, or `None` if the
+    /// wait would exceed `max_delay`.
+    fn acquire(&mut self, now: Instant, max_delay: Duration) -> Option<Duration> {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Some(Duration::from_secs(0));
+        }
+        let wait = Duration::from_secs_f64((1.0 - self.tokens) / self.rate);
+        if wait > max_delay {
+            return None;
+        }
+
+        // Reserve the token in advance so that the following requests wait
+        // behind this one. The debt is bounded by `max_delay`.
+        self.tokens -= 1.0;
+        Some(wait)
+    }
+}
+
+impl<S> fmt::Debug for RateLimiter<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("reject", &self.reject)
+            .field("max_delay", &self.max_delay)
+            .finish()
+    }
+}
+
+impl<S> RateLimiter<S> {
+    /// Create a rate limiter in front of the service, without any limit.
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            buckets: Mutex::new(HashMap::new()),
+            reject: false,
+            max_delay: DEFAULT_MAX_DELAY,
+        }
+    }
+
+    /// Limit the requests of the raw `opcode` to `rate` per second, allowing
+    /// bursts of up to `burst` requests.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `rate` or `burst` is zero.
+    pub fn limit(&mut self, opcode: u32, rate: u32, burst: u32) -> &mut Self {
+        assert!(rate > 0, "rate must be positive");
+        assert!(burst > 0, "burst must be positive");
+        self.buckets.get_mut().unwrap().insert(
+            opcode,
+            Bucket {
+                rate: f64::from(rate),
+                burst: f64::from(burst),
+                tokens: f64::from(burst),
+                updated: Instant::now(),
+            },
+        );
+        self
+    }
+
+    /// Reply `EAGAIN` to the requests over the rate, instead of delaying them.
+    pub fn reject(&mut self, enabled: bool) -> &mut Self {
+        self.reject = enabled;
+        self
+    }
+
+    /// Set the longest delay of a request over the rate, which defaults to
+    /// one second. The requests that would wait longer are replied `EAGAIN`.
+    pub fn max_delay(&mut self, max_delay: Duration) -> &mut Self {
+        self.max_delay = max_delay;
+        self
+    }
+}
+
+impl<S> Service for RateLimiter<S>
+where
+    S: Service,
+{
+    fn call(&self, req: &mut Request) -> io::Result<()> {
+        // The requests without a reply cannot be rejected.
+        if !expects_reply(req.opcode()) {
+            return self.inner.call(req);
+        }
+
+        let max_delay = if self.reject {
+            Duration::from_secs(0)
+        } else {
+            self.max_delay
+        };
+        let wait = {
+            let mut buckets = self.buckets.lock().unwrap();
+            match buckets.get_mut(&req.opcode()) {
+                Some(bucket) => bucket.acquire(Instant::now(), max_delay),
+                None => Some(Duration::from_secs(0)),
+            }
+        };
+
+        match wait {
+            None => {
+                tracing::debug!(
+                    "reject the request over the rate (opcode={}, unique={})",
+                    req.opcode(),
+                    req.unique()
+                );
+                req.reply_error(libc::EAGAIN)
+            }
+            Some(wait) => {
+                if wait > Duration::from_secs(0) {
+                    std::thread::sleep(wait);
+                }
+                self.inner.call(req)
+            }
+        }
+    }
+}
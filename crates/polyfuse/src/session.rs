@@ -1,23 +1,31 @@
 use crate::{
     bytes::{Bytes, FillBytes},
-    conn::{Connection, MountOptions},
+    conn::{Connection, MountOptions, Pipe},
     decoder::Decoder,
-    op::{DecodeError, Operation},
+    histogram::{IoSizeCounters, IoSizeHistogram},
+    op::{split_extensions, Category, DecodeError, HeaderExtensions, Mode, Operation, ReaddirMode},
+    reply::{AttrOut, EntryOut, OpenOut, StatxOut, WriteOut, XattrNames, XattrOut, XATTR_SIZE_MAX},
+    service::Service,
 };
 use polyfuse_kernel::*;
 use std::{
+    any::{Any, TypeId},
+    cell::Cell,
     cmp,
+    collections::{HashMap, VecDeque},
     convert::{TryFrom, TryInto as _},
-    ffi::OsStr,
+    ffi::{OsStr, OsString},
     fmt,
     io::{self, prelude::*, IoSlice, IoSliceMut},
     mem::{self, MaybeUninit},
     os::unix::prelude::*,
     path::{Path, PathBuf},
+    ptr,
     sync::{
-        atomic::{AtomicBool, AtomicU64, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Arc, Mutex,
     },
+    time::{Duration, Instant},
 };
 use zerocopy::AsBytes as _;
 
@@ -32,6 +40,12 @@ const MAX_MAX_PAGES: usize = 256;
 //const DEFAULT_MAX_PAGES_PER_REQ: usize = 32;
 const BUFFER_HEADER_SIZE: usize = 0x1000;
 
+/// The maximum length of a symbolic link target, excluding the terminating NUL.
+const MAX_SYMLINK_LEN: usize = libc::PATH_MAX as usize - 1;
+
+/// The upper bound of the interval between the retries of a reply write.
+const MAX_WRITE_BACKOFF: Duration = Duration::from_millis(1);
+
 // TODO: add FUSE_IOCTL_DIR
 const DEFAULT_INIT_FLAGS: u32 = FUSE_ASYNC_READ
     | FUSE_PARALLEL_DIROPS
@@ -53,7 +67,15 @@ const INIT_FLAGS_MASK: u32 = FUSE_ASYNC_READ
     | FUSE_WRITEBACK_CACHE
     | FUSE_POSIX_ACL
     | FUSE_DO_READDIRPLUS
-    | FUSE_READDIRPLUS_AUTO;
+    | FUSE_READDIRPLUS_AUTO
+    | FUSE_SPLICE_WRITE
+    | FUSE_SPLICE_MOVE
+    | FUSE_CACHE_SYMLINKS
+    | FUSE_EXPLICIT_INVAL_DATA
+    | FUSE_SUBMOUNTS;
+
+const INIT_FLAGS2_MASK: u32 =
+    ((FUSE_SECURITY_CTX | FUSE_PASSTHROUGH | FUSE_CREATE_SUPP_GROUP) >> 32) as u32;
 
 // ==== KernelConfig ====
 
@@ -62,13 +84,55 @@ const INIT_FLAGS_MASK: u32 = FUSE_ASYNC_READ
 pub struct KernelConfig {
     mountopts: MountOptions,
     init_out: fuse_init_out,
+    metrics: Option<Arc<MetricsFn>>,
+    before_dispatch: Option<Arc<BeforeDispatchFn>>,
+    after_dispatch: Option<Arc<AfterDispatchFn>>,
+    unsupported_errors: Vec<(Category, i32)>,
+    root_attr: Option<AttrOut>,
+    root_dotdot: bool,
+    absent_xattrs: Vec<OsString>,
+    attr_ttl: Option<Arc<AttrTtlFn>>,
+    disable_attr_cache: bool,
+    max_symlink_len: usize,
+    op_timeout: Option<Duration>,
+    stateless_open: bool,
+    tmpfile: bool,
+    statx: bool,
+    skip_flush: bool,
+    reply_queue: bool,
+    io_size_stats: bool,
+    init_callback: Option<Box<InitFn>>,
 }
 
+type MetricsFn = dyn Fn(u32, Duration, i32) + Send + Sync + 'static;
+type BeforeDispatchFn = dyn Fn(&RequestInfo<'_>) + Send + Sync + 'static;
+type AfterDispatchFn = dyn Fn(&RequestInfo<'_>, &io::Result<()>) + Send + Sync + 'static;
+type AttrTtlFn = dyn Fn(u64, Mode) -> Option<Duration> + Send + Sync + 'static;
+type InitFn = dyn Fn(&InitIn, &mut InitOut) -> io::Result<()> + Send + Sync + 'static;
+
 impl Default for KernelConfig {
     fn default() -> Self {
         Self {
             mountopts: MountOptions::default(),
             init_out: default_init_out(),
+            metrics: None,
+            before_dispatch: None,
+            after_dispatch: None,
+            unsupported_errors: vec![],
+            root_attr: None,
+            root_dotdot: true,
+            absent_xattrs: vec![],
+            attr_ttl: None,
+            disable_attr_cache: false,
+            max_symlink_len: MAX_SYMLINK_LEN,
+            op_timeout: None,
+            stateless_open: false,
+            tmpfile: false,
+            statx: false,
+            skip_flush: false,
+            reply_queue: false,
+            io_size_stats: false,
+            init_callback: None,
         }
     }
 }
@@ -93,6 +157,49 @@ impl KernelConfig {
         self
     }
 
+    /// Specify the name of the mounted filesystem source.
+    ///
+    /// This is shown as the device column of `/proc/mounts` and `df`
+    /// instead of the default `/dev/fuse`.
+    pub fn fsname(&mut self, name: &str) -> &mut Self {
+        self.mountopts.fsname = Some(name.to_owned());
+        self
+    }
+
+    /// Specify the subtype of the filesystem.
+    ///
+    /// The filesystem type is then reported as `fuse.<subtype>`.
+    pub fn subtype(&mut self, subtype: &str) -> &mut Self {
+        self.mountopts.subtype = Some(subtype.to_owned());
+        self
+    }
+
+    /// Register a path the filesystem reads its contents from, such as the
+    /// source directory of a passthrough filesystem.
+    ///
+    /// `Session::mount` refuses with `InvalidInput` to mount on the path or on
+    /// any of its ancestors, as the filesystem would then access the path
+    /// through its own mount and hang. The paths are compared after resolving
+    /// the symbolic links, so both must exist at the time of mounting.
+    pub fn backing_path(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        self.mountopts.backing_paths.push(path.into());
+        self
+    }
+
+    /// Specify the file type of the root, which is a directory by default.
+    ///
+    /// The root of a filesystem may be another type of file, such as a
+    /// regular file exposed alone. Since the kernel takes the type of the root
+    /// from the mountpoint, `Session::mount` refuses with `InvalidInput` to
+    /// mount on a file of a different type, rather than leaving the root
+    /// inaccessible. The permission bits in `mode` are ignored.
+    ///
+    /// If not specified, the type is taken from the mode set by `root_attr`.
+    pub fn rootmode(&mut self, mode: impl Into<Mode>) -> &mut Self {
+        self.mountopts.rootmode = Some(mode.into().into_raw() & libc::S_IFMT);
+        self
+    }
+
     #[doc(hidden)] // TODO: dox
     pub fn fusermount_path(&mut self, program: impl AsRef<OsStr>) -> &mut Self {
         let program = Path::new(program.as_ref());
@@ -119,6 +226,16 @@ impl KernelConfig {
         }
     }
 
+    fn set_init_flag2(&mut self, flag: u64, enabled: bool) {
+        // `flags2` is stored in the first element of `unused`.
+        let flag = (flag >> 32) as u32;
+        if enabled {
+            self.init_out.unused[0] |= flag;
+        } else {
+            self.init_out.unused[0] &= !flag;
+        }
+    }
+
     /// Specify that the filesystem supports asynchronous read requests.
     ///
     /// Enabled by default.
@@ -137,14 +254,24 @@ impl KernelConfig {
 
     /// Specify that the kernel check the validity of attributes on every read.
     ///
-    /// Enabled by default.
+    /// Enabled by default. This option is exclusive with
+    /// `explicit_inval_data`, which is disabled when this is enabled.
     pub fn auto_inval_data(&mut self, enabled: bool) -> &mut Self {
         self.set_init_flag(FUSE_AUTO_INVAL_DATA, enabled);
+        if enabled {
+            self.set_init_flag(FUSE_EXPLICIT_INVAL_DATA, false);
+        }
         self
     }
 
     /// Specify that the filesystem supports asynchronous direct I/O submission.
     ///
+    /// When enabled, the kernel may split a direct I/O on a file opened with
+    /// `OpenOut::direct_io` into multiple `read` or `write` requests and keep
+    /// them in flight at the same time, instead of waiting for each of them.
+    /// The session does not serialize these requests, so the filesystem can
+    /// receive and reply to them concurrently and in any order.
+    ///
     /// Enabled by default.
     pub fn async_dio(&mut self, enabled: bool) -> &mut Self {
         self.set_init_flag(FUSE_ASYNC_DIO, enabled);
@@ -162,6 +289,8 @@ impl KernelConfig {
     /// Specify that the filesystem is responsible for unsetting setuid and setgid bits
     /// when a file is written, truncated, or its owner is changed.
     ///
+    /// The writes requiring it are marked by `op::Write::kill_priv`.
+    ///
     /// Enabled by default.
     pub fn handle_killpriv(&mut self, enabled: bool) -> &mut Self {
         self.set_init_flag(FUSE_HANDLE_KILLPRIV, enabled);
@@ -194,12 +323,24 @@ impl KernelConfig {
     }
 
     /// Specify that the kernel should enable writeback caching.
+    ///
+    /// Without this option, the writes to the files opened without
+    /// `OpenOut::direct_io` are write-through: the page cache is updated, and
+    /// the data is sent to the filesystem before `write(2)` returns.
     pub fn writeback_cache(&mut self, enabled: bool) -> &mut Self {
         self.set_init_flag(FUSE_WRITEBACK_CACHE, enabled);
         self
     }
 
     /// Specify that the filesystem supports POSIX access control lists.
+    ///
+    /// When negotiated, the kernel checks the permissions against the ACLs
+    /// by itself, as with `default_permissions`, which should be specified in
+    /// the mount options as well. The ACLs are read and written through the
+    /// `getxattr` and `setxattr` requests for `POSIX_ACL_ACCESS_XATTR` and
+    /// `POSIX_ACL_DEFAULT_XATTR`, in the binary format of the kernel, and the
+    /// filesystem is responsible for storing their values verbatim. The kernel
+    /// also updates the mode of the file through `setattr` when the ACL is set.
     pub fn posix_acl(&mut self, enabled: bool) -> &mut Self {
         self.set_init_flag(FUSE_POSIX_ACL, enabled);
         self
@@ -219,6 +360,134 @@ impl KernelConfig {
         self
     }
 
+    /// Specify that the filesystem writes the replies to the FUSE device with `splice(2)`.
+    ///
+    /// When enabled, `Request::reply_splice` moves the data without copying it
+    /// through the userspace buffer.
+    pub fn splice_write(&mut self, enabled: bool) -> &mut Self {
+        self.set_init_flag(FUSE_SPLICE_WRITE, enabled);
+        self
+    }
+
+    /// Specify that the pages spliced into the FUSE device may be moved
+    /// rather than copied by the kernel.
+    ///
+    /// This option is meaningful only if `splice_write` is enabled.
+    pub fn splice_move(&mut self, enabled: bool) -> &mut Self {
+        self.set_init_flag(FUSE_SPLICE_MOVE, enabled);
+        self
+    }
+
+    /// Specify that the kernel may cache the targets of symbolic links.
+    ///
+    /// Once a target is cached, the kernel does not send `readlink` requests
+    /// for the link until the cache is invalidated by `Notifier::inval_inode`,
+    /// which the filesystem must call when the target changes.
+    pub fn cache_symlinks(&mut self, enabled: bool) -> &mut Self {
+        self.set_init_flag(FUSE_CACHE_SYMLINKS, enabled);
+        self
+    }
+
+    /// Specify that the filesystem may present directories as submounts.
+    ///
+    /// When negotiated, a directory looked up with `EntryOut::submount` is
+    /// automatically mounted by the kernel as a separate filesystem instance
+    /// served by the same session, so that it gets its own device number and
+    /// the mounts below it are isolated. This requires Linux 5.10 or later.
+    pub fn submounts(&mut self, enabled: bool) -> &mut Self {
+        self.set_init_flag(FUSE_SUBMOUNTS, enabled);
+        self
+    }
+
+    /// Specify that the kernel should send the security context of the process
+    /// creating a file, so that the filesystem can label the new inode.
+    ///
+    /// When negotiated, the context is available from
+    /// `HeaderExtensions::security_context` of the `mknod`, `mkdir`,
+    /// `symlink`, `create` and `tmpfile` operations. This requires the kernel
+    /// supporting `FUSE_INIT_EXT` (Linux 5.17 or later).
+    pub fn security_ctx(&mut self, enabled: bool) -> &mut Self {
+        self.set_init_flag2(FUSE_SECURITY_CTX, enabled);
+        self
+    }
+
+    /// Specify that the kernel should send the supplementary group of the
+    /// process creating a file, when the owner group of the parent directory
+    /// differs from the one of the process and the process is a member of it.
+    ///
+    /// When negotiated, the group is available from
+    /// `HeaderExtensions::supplementary_groups` of the `mknod`, `mkdir`,
+    /// `symlink`, `create` and `tmpfile` operations. This requires Linux 6.6
+    /// or later.
+    pub fn create_supp_group(&mut self, enabled: bool) -> &mut Self {
+        self.set_init_flag2(FUSE_CREATE_SUPP_GROUP, enabled);
+        self
+    }
+
+    /// Specify the maximum stack depth of the passthrough backing files,
+    /// or disable the passthrough with zero.
+    ///
+    /// The passthrough lets the kernel serve the I/O on a file directly from
+    /// a backing file registered by `Session::open_backing`, which is
+    /// associated with the opened file by `OpenOut::passthrough`. The stack
+    /// depth of the filesystem is that of the backing files plus one, so a
+    /// filesystem whose backing files live on another FUSE mount needs a
+    /// depth of 2. The negotiated value is returned from
+    /// `InitOut::max_stack_depth`. This requires Linux 6.9 or later, and the
+    /// kernel refuses the passthrough along with `writeback_cache`.
+    ///
+    /// # Panic
+    /// It causes an assertion panic if the value exceeds `FILESYSTEM_MAX_STACK_DEPTH`.
+    pub fn passthrough(&mut self, max_stack_depth: u32) -> &mut Self {
+        assert!(
+            max_stack_depth <= FILESYSTEM_MAX_STACK_DEPTH,
+            "max_stack_depth must be less or equal to {}",
+            FILESYSTEM_MAX_STACK_DEPTH,
+        );
+        self.set_init_flag2(FUSE_PASSTHROUGH, max_stack_depth > 0);
+        // `max_stack_depth` is stored in the second element of `unused`.
+        self.init_out.unused[1] = max_stack_depth;
+        self
+    }
+
+    /// Specify that the cached file data is invalidated only by the filesystem.
+    ///
+    /// By default, the kernel drops the cached pages of a file when it notices
+    /// that the size or the modification time has changed. When enabled, the
+    /// pages are kept across such changes and dropped only by the explicit
+    /// `Notifier::inval_inode` calls with a non-negative offset.
+    ///
+    /// This option is exclusive with `auto_inval_data`, since the kernel
+    /// ignores it while the latter is negotiated. Enabling it therefore
+    /// disables `auto_inval_data`, and vice versa.
+    pub fn explicit_inval_data(&mut self, enabled: bool) -> &mut Self {
+        self.set_init_flag(FUSE_EXPLICIT_INVAL_DATA, enabled);
+        if enabled {
+            self.set_init_flag(FUSE_AUTO_INVAL_DATA, false);
+        }
+        self
+    }
+
+    /// Enable all the capabilities implemented by polyfuse.
+    ///
+    /// During the initialization, the requested flags are intersected with
+    /// those offered by the kernel, so the result is the set of capabilities
+    /// both sides understand. This is intended for the filesystems
+    /// implementing the full set of operations, since it includes the options
+    /// changing what the filesystem must handle, such as `writeback_cache`,
+    /// `dont_mask` and `posix_acl`, and the request extensions enabled by
+    /// `security_ctx` and `create_supp_group`. The passthrough is left
+    /// disabled as it requires the stack depth given to `passthrough`. Of
+    /// the two exclusive invalidation modes, `auto_inval_data` is chosen and
+    /// `explicit_inval_data` is left disabled. The individual options can
+    /// still be changed afterwards.
+    pub fn enable_all_supported(&mut self) -> &mut Self {
+        self.init_out.flags |= INIT_FLAGS_MASK & !FUSE_EXPLICIT_INVAL_DATA;
+        self.set_init_flag2(FUSE_SECURITY_CTX, true);
+        self.set_init_flag2(FUSE_CREATE_SUPP_GROUP, true);
+        self
+    }
+
     /// Set the maximum readahead.
     pub fn max_readahead(&mut self, value: u32) -> &mut Self {
         self.init_out.max_readahead = value;
@@ -274,1004 +543,7687 @@ impl KernelConfig {
         self.init_out.time_gran = time_gran;
         self
     }
-}
 
-// ==== Session ====
+    /// Set the error number replied by `Request::reply_unsupported` to the
+    /// operations of the specified family.
+    ///
+    /// By default, the unsupported operations are replied with `ENOSYS`.
+    /// When an operation belongs to multiple families, `Category::Write` takes
+    /// precedence over `Category::Xattr` (e.g. for `setxattr`).
+    pub fn unsupported_error(&mut self, category: Category, errno: i32) -> &mut Self {
+        self.unsupported_errors.retain(|&(c, _)| c != category);
+        self.unsupported_errors.push((category, errno));
+        self
+    }
 
-/// The object containing the contextrual information about a FUSE session.
-pub struct Session {
-    inner: Arc<SessionInner>,
-}
+    /// Set the attributes of the root directory replied by the session itself.
+    ///
+    /// The root directory always has the node ID `FUSE_ROOT_ID` (= 1), and the
+    /// kernel queries its attributes before any lookup. When this option is
+    /// set, the `getattr` requests for the root are replied with the specified
+    /// attributes and never returned from `next_request`, so that a filesystem
+    /// with a static root does not have to handle them. The inode number
+    /// in the attributes is overwritten by `FUSE_ROOT_ID`.
+    ///
+    /// The lookups of `..` in the root directory are also replied with the
+    /// root itself unless disabled by `root_dotdot`.
+    pub fn root_attr(&mut self, mut attr: AttrOut) -> &mut Self {
+        attr.attr().ino(FUSE_ROOT_ID);
+        self.root_attr = Some(attr);
+        self
+    }
 
-impl fmt::Debug for Session {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Session").finish()
+    /// Specify whether the session replies the lookups of `..` in the root
+    /// directory by itself, which is enabled by default.
+    ///
+    /// The parent of the root is the root itself, so that `cd ..` at the mount
+    /// point stays there. The kernel resolves `..` on its own in most cases,
+    /// but the lookup is sent e.g. when the filesystem is exported through NFS.
+    /// The entry is built from the attributes set by `root_attr`, and without
+    /// them the lookup is returned from `next_request` as usual. Since the
+    /// kernel increments the lookup count of the root, the filesystem should
+    /// ignore the `forget` requests for `FUSE_ROOT_ID`.
+    ///
+    /// Disable it if the filesystem handles the lookups of `..` by itself.
+    pub fn root_dotdot(&mut self, enabled: bool) -> &mut Self {
+        self.root_dotdot = enabled;
+        self
     }
-}
 
-struct SessionInner {
-    conn: Connection,
-    init_out: fuse_init_out,
-    bufsize: usize,
-    exited: AtomicBool,
-    notify_unique: AtomicU64,
-}
+    /// Register the name of an extended attribute that no file has.
+    ///
+    /// The kernel probes some attributes, such as `security.capability` on
+    /// every write, regardless of whether the filesystem supports them. The
+    /// `getxattr` requests for the registered names are replied with
+    /// `ENODATA` by the session itself and never returned from `next_request`.
+    pub fn absent_xattr(&mut self, name: impl AsRef<OsStr>) -> &mut Self {
+        let name = name.as_ref();
+        if !self.absent_xattrs.iter().any(|n| n == name) {
+            self.absent_xattrs.push(name.to_owned());
+        }
+        self
+    }
 
-impl SessionInner {
-    #[inline]
-    fn exited(&self) -> bool {
-        // FIXME: choose appropriate atomic ordering.
-        self.exited.load(Ordering::SeqCst)
+    /// Register a callback invoked every time a request is replied.
+    ///
+    /// The callback receives the raw opcode of the request, the elapsed time
+    /// since the request was received, and the error number of the reply
+    /// (`0` on success). It is called on the thread that sends the reply,
+    /// so it should be cheap, e.g. updating counters or histograms.
+    ///
+    /// The requests that are never replied, such as `forget`, are not reported.
+    /// When no callback is registered, the requests are not timestamped at all.
+    pub fn metrics<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(u32, Duration, i32) + Send + Sync + 'static,
+    {
+        self.metrics = Some(Arc::new(f));
+        self
     }
 
-    #[inline]
-    fn exit(&self) {
-        // FIXME: choose appropriate atomic ordering.
-        self.exited.store(true, Ordering::SeqCst)
+    /// Count the sizes of the `read` and `write` requests, which are returned
+    /// from `Session::io_size_stats`.
+    ///
+    /// The sizes reflect how the kernel splits the I/O of the applications,
+    /// e.g. by the readahead and `max_write`, and help to tune those limits
+    /// and the buffers of the backend. The requests replied by the session
+    /// itself are not counted.
+    pub fn io_size_stats(&mut self, enabled: bool) -> &mut Self {
+        self.io_size_stats = enabled;
+        self
     }
-}
 
-impl Drop for Session {
-    fn drop(&mut self) {
-        self.inner.exit();
+    /// Register a hook invoked by `Session::run` before passing each request
+    /// to the service.
+    ///
+    /// The hook receives a read-only view of the request, which cannot be
+    /// used for replying, so it suits auditing or tracing every operation.
+    pub fn before_dispatch<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&RequestInfo<'_>) + Send + Sync + 'static,
+    {
+        self.before_dispatch = Some(Arc::new(f));
+        self
     }
-}
 
-impl AsRawFd for Session {
-    fn as_raw_fd(&self) -> RawFd {
-        self.inner.conn.as_raw_fd()
+    /// Register a hook invoked by `Session::run` after the service has
+    /// handled each request, along with the result returned from the service.
+    pub fn after_dispatch<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&RequestInfo<'_>, &io::Result<()>) + Send + Sync + 'static,
+    {
+        self.after_dispatch = Some(Arc::new(f));
+        self
     }
-}
 
-impl Session {
-    /// Start a FUSE daemon mount on the specified path.
-    pub fn mount(mountpoint: PathBuf, config: KernelConfig) -> io::Result<Self> {
-        let KernelConfig {
-            mountopts,
-            mut init_out,
-        } = config;
+    /// Register a policy supplying the validity timeout of the attributes.
+    ///
+    /// The policy receives the inode number and the mode in the attributes
+    /// replied by `Request::reply_attr` or `Request::reply_statx`, and is
+    /// consulted only when the handler has not set the timeout by
+    /// `AttrOut::ttl` or `StatxOut::ttl`. This allows, for
+    /// instance, caching static files much longer than volatile ones without
+    /// repeating the decision in every handler. Returning `None` leaves the
+    /// timeout unset, which means that the attributes are not cached.
+    pub fn attr_ttl<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(u64, Mode) -> Option<Duration> + Send + Sync + 'static,
+    {
+        self.attr_ttl = Some(Arc::new(f));
+        self
+    }
 
-        let conn = Connection::open(mountpoint, mountopts)?;
+    /// Disable the caching of the attributes in the kernel.
+    ///
+    /// When enabled, the validity timeouts of the attributes replied by
+    /// `Request::reply_attr` and `root_attr` are forced to zero regardless of
+    /// the ones set by the handler or `attr_ttl`, so the kernel asks for the
+    /// attributes on every access. This is intended for the filesystems whose
+    /// contents are modified externally, e.g. by other nodes in a cluster,
+    /// where stale attributes are not acceptable.
+    ///
+    /// The other replies, such as `EntryOut`, are sent as constructed.
+    pub fn disable_attr_cache(&mut self, enabled: bool) -> &mut Self {
+        self.disable_attr_cache = enabled;
+        self
+    }
 
-        init_session(&mut init_out, &conn, &conn)?;
-        let bufsize = BUFFER_HEADER_SIZE + init_out.max_write as usize;
+    /// Set the maximum length of the symbolic link targets.
+    ///
+    /// The `symlink` requests with a longer target are replied with
+    /// `ENAMETOOLONG` by the session itself, and so are the targets passed to
+    /// `Request::reply_readlink`. The default and the upper bound is
+    /// `PATH_MAX - 1`, as the kernel does not accept longer paths.
+    pub fn max_symlink_len(&mut self, len: usize) -> &mut Self {
+        assert!(
+            len <= MAX_SYMLINK_LEN,
+            "the maximum length of the symlink targets must not exceed PATH_MAX - 1"
+        );
+        self.max_symlink_len = len;
+        self
+    }
 
-        Ok(Self {
-            inner: Arc::new(SessionInner {
-                conn,
-                init_out,
-                bufsize,
-                exited: AtomicBool::new(false),
-                notify_unique: AtomicU64::new(0),
-            }),
-        })
+    /// Set the time limit for the requests to be replied.
+    ///
+    /// When a request is not replied within the limit, the session replies
+    /// `EIO` on behalf of the handler so that the calling process does not
+    /// hang, and logs its unique ID and opcode. The reply sent by the handler
+    /// afterwards is dropped. This also covers the requests dropped without
+    /// replying. The limit is checked by a background thread at a fraction of
+    /// its period, so the actual timeouts may be slightly longer.
+    pub fn op_timeout(&mut self, timeout: Duration) -> &mut Self {
+        assert!(
+            timeout > Duration::from_secs(0),
+            "the timeout must be positive"
+        );
+        self.op_timeout = Some(timeout);
+        self
     }
 
-    /// Return whether the kernel supports for zero-message opens.
+    /// Declare that the files in the filesystem need no state per open.
     ///
-    /// When the returned value is `true`, the kernel treat an `ENOSYS`
-    /// error for a `FUSE_OPEN` request as successful and does not send
-    /// subsequent `open` requests.  Otherwise, the filesystem should
-    /// implement the handler for `open` requests appropriately.
-    pub fn no_open_support(&self) -> bool {
-        self.inner.init_out.flags & FUSE_NO_OPEN_SUPPORT != 0
+    /// When the kernel supports zero-message opens (see
+    /// `Session::no_open_support`), the session replies `ENOSYS` to the first
+    /// `open` request by itself, and then the kernel sends neither `open` nor
+    /// `release` requests for the rest of the session. The following requests
+    /// on the files, such as `read`, carry the file handle `0`. On the older
+    /// kernels, the `open` requests are returned from `next_request` as usual.
+    pub fn stateless_open(&mut self, enabled: bool) -> &mut Self {
+        self.stateless_open = enabled;
+        self
     }
 
-    /// Return whether the kernel supports for zero-message opendirs.
+    /// Declare that the filesystem has nothing to do on `flush`, e.g. since it
+    /// is read-only or writes the data through.
     ///
-    /// See the documentation of `no_open_support` for details.
-    pub fn no_opendir_support(&self) -> bool {
-        self.inner.init_out.flags & FUSE_NO_OPENDIR_SUPPORT != 0
+    /// When enabled, the session replies `ENOSYS` to the first `flush` request
+    /// by itself, and then the kernel treats every `close(2)` as successful
+    /// without sending the request for the rest of the session. This is
+    /// independent of `stateless_open`: the kernel sends `flush` also on the
+    /// files opened without a message, with the file handle `0`. Since the
+    /// locks of `KernelConfig::posix_locks` owned by the closing process are
+    /// released on `flush`, the filesystems managing such locks should not
+    /// enable this option. To skip `flush` only on some of the files, use
+    /// `OpenOut::no_flush` instead.
+    pub fn skip_flush(&mut self, enabled: bool) -> &mut Self {
+        self.skip_flush = enabled;
+        self
     }
 
-    /// Receive an incoming FUSE request from the kernel.
-    pub fn next_request(&self) -> io::Result<Option<Request>> {
-        let mut conn = &self.inner.conn;
+    /// Enable the creation of the unnamed temporary files by `O_TMPFILE`.
+    ///
+    /// When enabled, the `tmpfile` requests are returned from `next_request`
+    /// as `Operation::Tmpfile`. Otherwise the session replies `ENOSYS` to them
+    /// by itself, and the kernel reports `EOPNOTSUPP` to the callers of `open(2)`.
+    /// The older kernels never send the request regardless of this setting.
+    pub fn tmpfile(&mut self, enabled: bool) -> &mut Self {
+        self.tmpfile = enabled;
+        self
+    }
 
-        // FIXME: Align the allocated region in `arg` with the FUSE argument types.
-        let mut header = fuse_in_header::default();
-        let mut arg = vec![0u8; self.inner.bufsize - mem::size_of::<fuse_in_header>()];
+    /// Enable the extended attributes requested by `statx(2)`.
+    ///
+    /// When enabled, the `statx` requests are returned from `next_request`
+    /// as `Operation::Statx` and should be replied by `Request::reply_statx`.
+    /// Otherwise the session replies `ENOSYS` to them by itself, and the kernel
+    /// falls back to `getattr` for the rest of the session, so the fields such
+    /// as the birth time are never reported.
+    pub fn statx(&mut self, enabled: bool) -> &mut Self {
+        self.statx = enabled;
+        self
+    }
 
-        loop {
-            match conn.read_vectored(&mut [
-                io::IoSliceMut::new(header.as_bytes_mut()),
-                io::IoSliceMut::new(&mut arg[..]),
-            ]) {
-                Ok(len) => {
-                    if len < mem::size_of::<fuse_in_header>() {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            "dequeued request message is too short",
-                        ));
-                    }
-                    unsafe {
-                        arg.set_len(len - mem::size_of::<fuse_in_header>());
-                    }
+    /// Queue the replies instead of waiting when the FUSE device cannot accept
+    /// them in the non-blocking mode (see `Session::set_nonblocking`).
+    ///
+    /// By default, a reply is retried until written, which blocks the thread
+    /// completing the request. When enabled, such a reply is copied into the
+    /// queue of the session and the call returns immediately; the following
+    /// replies are queued behind it to keep the order. The queue is drained by
+    /// `Session::flush_replies`, which an event loop calls when the descriptor
+    /// becomes writable, while `Session::has_queued_replies` tells whether to
+    /// wait for the writability. The notifications sent by `Notifier` are
+    /// queued in the same way instead of failing with
+    /// `io::ErrorKind::WouldBlock`. The replies spliced into the device, by
+    /// `Request::reply_splice` or `Request::reply_mapped`, bypass the queue.
+    ///
+    /// The writes to `/dev/fuse` never fail with `EAGAIN`, since the kernel
+    /// accepts the messages immediately, so the queue only takes effect on
+    /// the socket transports passed to `Session::from_raw_fd`.
+    pub fn reply_queue(&mut self, enabled: bool) -> &mut Self {
+        self.reply_queue = enabled;
+        self
+    }
 
-                    break;
-                }
+    /// Register a callback deciding the reply to the `init` request.
+    ///
+    /// The callback receives the proposal of the kernel, and the reply
+    /// filled with the values configured so far, which it may modify, e.g.
+    /// to enable a capability only on the specific kernel versions. Returning
+    /// an error refuses the initialization, and the error is returned from
+    /// `Session::mount`. The reply is then negotiated as usual: the flags are
+    /// intersected with those offered by the kernel and implemented by
+    /// polyfuse, and `max_readahead` is limited to the proposed value.
+    pub fn init_callback<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&InitIn, &mut InitOut) -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.init_callback = Some(Box::new(f));
+        self
+    }
+}
 
-                Err(err) => match err.raw_os_error() {
-                    Some(libc::ENODEV) => {
-                        tracing::debug!("ENODEV");
-                        return Ok(None);
-                    }
-                    Some(libc::ENOENT) => {
-                        tracing::debug!("ENOENT");
-                        continue;
-                    }
-                    _ => return Err(err),
-                },
-            }
-        }
+/// The parameters of the `init` request proposed by the kernel.
+///
+/// See `KernelConfig::init_callback`.
+#[derive(Debug)]
+pub struct InitIn {
+    major: u32,
+    minor: u32,
+    max_readahead: u32,
+    flags: u64,
+}
 
-        Ok(Some(Request {
-            session: self.inner.clone(),
-            header,
-            arg,
-        }))
+impl InitIn {
+    /// Return the major version of the protocol.
+    pub fn major(&self) -> u32 {
+        self.major
     }
 
-    /// Create an instance of `Notifier` corresponding to this session.
-    pub fn notifier(&self) -> Notifier {
-        Notifier {
-            session: self.inner.clone(),
-        }
+    /// Return the minor version of the protocol.
+    pub fn minor(&self) -> u32 {
+        self.minor
     }
-}
 
-fn init_session<R, W>(init_out: &mut fuse_init_out, mut reader: R, mut writer: W) -> io::Result<()>
-where
-    R: io::Read,
-    W: io::Write,
-{
-    // FIXME: align the allocated buffer in `buf` with FUSE argument types.
-    let mut header = fuse_in_header::default();
-    let mut arg = vec![0u8; pagesize() * MAX_MAX_PAGES];
+    /// Return the maximum readahead.
+    pub fn max_readahead(&self) -> u32 {
+        self.max_readahead
+    }
 
-    for _ in 0..10 {
-        let len = reader.read_vectored(&mut [
-            io::IoSliceMut::new(header.as_bytes_mut()),
-            io::IoSliceMut::new(&mut arg[..]),
-        ])?;
-        if len < mem::size_of::<fuse_in_header>() {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "request message is too short",
-            ));
-        }
+    /// Return the capability flags offered by the kernel.
+    ///
+    /// The upper 32 bits are the extended flags (`flags2`), which are zero
+    /// unless the kernel supports `FUSE_INIT_EXT`.
+    pub fn flags(&self) -> u64 {
+        self.flags
+    }
+}
 
-        let mut decoder = Decoder::new(&arg[..]);
+/// The reply to the `init` request, modified by `KernelConfig::init_callback`.
+#[repr(transparent)]
+pub struct InitOut {
+    out: fuse_init_out,
+}
 
-        match fuse_opcode::try_from(header.opcode) {
-            Ok(fuse_opcode::FUSE_INIT) => {
-                let init_in = decoder
-                    .fetch::<fuse_init_in>() //
-                    .map_err(|_| {
-                        io::Error::new(io::ErrorKind::Other, "failed to decode fuse_init_in")
-                    })?;
+impl fmt::Debug for InitOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InitOut")
+            .field("flags", &self.flags())
+            .field("max_readahead", &self.max_readahead())
+            .field("max_write", &self.max_write())
+            .field("max_background", &self.max_background())
+            .field("congestion_threshold", &self.congestion_threshold())
+            .field("time_gran", &self.time_gran())
+            .finish()
+    }
+}
 
-                let capable = init_in.flags & INIT_FLAGS_MASK;
-                let readonly_flags = init_in.flags & !INIT_FLAGS_MASK;
+impl InitOut {
+    #[inline]
+    fn from_init_out_ref(out: &fuse_init_out) -> &InitOut {
+        unsafe { &*(out as *const fuse_init_out as *const InitOut) }
+    }
 
-                tracing::debug!("INIT request:");
-                tracing::debug!("  proto = {}.{}:", init_in.major, init_in.minor);
-                tracing::debug!("  flags = 0x{:08x} ({:?})", init_in.flags, capable);
-                tracing::debug!("  max_readahead = 0x{:08X}", init_in.max_readahead);
-                tracing::debug!("  max_pages = {}", readonly_flags & FUSE_MAX_PAGES != 0);
-                tracing::debug!(
-                    "  no_open_support = {}",
-                    readonly_flags & FUSE_NO_OPEN_SUPPORT != 0
-                );
-                tracing::debug!(
-                    "  no_opendir_support = {}",
-                    readonly_flags & FUSE_NO_OPENDIR_SUPPORT != 0
-                );
+    /// Return the major version of the protocol.
+    pub fn major(&self) -> u32 {
+        self.out.major
+    }
 
-                if init_in.major > 7 {
-                    tracing::debug!("wait for a second INIT request with an older version.");
-                    let init_out = fuse_init_out {
-                        major: FUSE_KERNEL_VERSION,
-                        minor: FUSE_KERNEL_MINOR_VERSION,
-                        ..Default::default()
-                    };
-                    write_bytes(
-                        &mut writer,
-                        Reply::new(header.unique, 0, init_out.as_bytes()),
-                    )?;
-                    continue;
-                }
+    /// Return the minor version of the protocol.
+    ///
+    /// In `KernelConfig::init_callback`, this is the latest version supported
+    /// by polyfuse, which is then lowered to `InitIn::minor` if older.
+    pub fn minor(&self) -> u32 {
+        self.out.minor
+    }
 
-                if init_in.major < 7 || init_in.minor < MINIMUM_SUPPORTED_MINOR_VERSION {
-                    tracing::warn!(
-                        "polyfuse supports only ABI 7.{} or later. {}.{} is not supported",
-                        MINIMUM_SUPPORTED_MINOR_VERSION,
-                        init_in.major,
-                        init_in.minor
-                    );
-                    write_bytes(&mut writer, Reply::new(header.unique, libc::EPROTO, ()))?;
-                    continue;
-                }
+    /// Return the maximum number of pages in a request, or zero if the
+    /// kernel does not support `FUSE_MAX_PAGES`.
+    ///
+    /// It is derived from `max_write` when replying to the kernel.
+    pub fn max_pages(&self) -> u16 {
+        self.out.max_pages
+    }
 
-                init_out.minor = cmp::min(init_out.minor, init_in.minor);
+    /// Return the maximum size of a request message, including the header.
+    ///
+    /// The largest requests are the `write` ones, whose data is limited by
+    /// `max_write`, and also by `max_pages` if `FUSE_MAX_PAGES` has been
+    /// negotiated. The session reads the requests into the buffers of this
+    /// size, and the code relaying them, such as a proxy, can size its own
+    /// buffers by the value of `Session::init_out`.
+    pub fn max_request_size(&self) -> usize {
+        let mut max_data = self.out.max_write as usize;
+        if self.out.flags & FUSE_MAX_PAGES != 0 {
+            max_data = cmp::min(max_data, self.out.max_pages as usize * pagesize());
+        }
+        BUFFER_HEADER_SIZE + max_data
+    }
 
-                init_out.max_readahead = cmp::min(init_out.max_readahead, init_in.max_readahead);
+    /// Return the capability flags to be enabled.
+    ///
+    /// The upper 32 bits are the extended flags, as in `InitIn::flags`.
+    pub fn flags(&self) -> u64 {
+        u64::from(self.out.flags) | u64::from(self.out.unused[0]) << 32
+    }
 
-                init_out.flags &= capable;
-                init_out.flags |= FUSE_BIG_WRITES; // the flag was superseded by `max_write`.
+    /// Set the capability flags to be enabled.
+    pub fn set_flags(&mut self, flags: u64) {
+        self.out.flags = flags as u32;
+        self.out.unused[0] = (flags >> 32) as u32;
+    }
 
-                if init_in.flags & FUSE_MAX_PAGES != 0 {
-                    init_out.flags |= FUSE_MAX_PAGES;
-                    init_out.max_pages = cmp::min(
-                        (init_out.max_write - 1) / (pagesize() as u32) + 1,
-                        u16::max_value() as u32,
-                    ) as u16;
-                }
+    /// Return the maximum readahead.
+    pub fn max_readahead(&self) -> u32 {
+        self.out.max_readahead
+    }
 
-                debug_assert_eq!(init_out.major, FUSE_KERNEL_VERSION);
-                debug_assert!(init_out.minor >= MINIMUM_SUPPORTED_MINOR_VERSION);
+    /// Set the maximum readahead.
+    pub fn set_max_readahead(&mut self, value: u32) {
+        self.out.max_readahead = value;
+    }
 
-                tracing::debug!("Reply to INIT:");
-                tracing::debug!("  proto = {}.{}:", init_out.major, init_out.minor);
-                tracing::debug!("  flags = 0x{:08x}", init_out.flags);
-                tracing::debug!("  max_readahead = 0x{:08X}", init_out.max_readahead);
-                tracing::debug!("  max_write = 0x{:08X}", init_out.max_write);
-                tracing::debug!("  max_background = 0x{:04X}", init_out.max_background);
-                tracing::debug!(
-                    "  congestion_threshold = 0x{:04X}",
-                    init_out.congestion_threshold
-                );
-                tracing::debug!("  time_gran = {}", init_out.time_gran);
-                write_bytes(writer, Reply::new(header.unique, 0, init_out.as_bytes()))?;
+    /// Return the maximum size of the write buffer.
+    pub fn max_write(&self) -> u32 {
+        self.out.max_write
+    }
 
-                init_out.flags |= readonly_flags;
+    /// Set the maximum size of the write buffer.
+    ///
+    /// # Panic
+    /// It causes an assertion panic if the setting value is less than the absolute minimum.
+    pub fn set_max_write(&mut self, value: u32) {
+        assert!(
+            value >= MIN_MAX_WRITE,
+            "max_write must be greater or equal to {}",
+            MIN_MAX_WRITE,
+        );
+        self.out.max_write = value;
+    }
 
-                return Ok(());
-            }
+    /// Return the maximum number of pending background requests.
+    pub fn max_background(&self) -> u16 {
+        self.out.max_background
+    }
 
-            _ => {
-                tracing::warn!(
-                    "ignoring an operation before init (opcode={:?})",
-                    header.opcode
-                );
-                write_bytes(&mut writer, Reply::new(header.unique, libc::EIO, ()))?;
-                continue;
-            }
+    /// Set the maximum number of pending background requests.
+    pub fn set_max_background(&mut self, value: u16) {
+        self.out.max_background = value;
+    }
+
+    /// Return the congestion threshold of the background requests.
+    pub fn congestion_threshold(&self) -> u16 {
+        self.out.congestion_threshold
+    }
+
+    /// Set the congestion threshold of the background requests.
+    pub fn set_congestion_threshold(&mut self, value: u16) {
+        self.out.congestion_threshold = value;
+    }
+
+    /// Return the timestamp resolution in nanoseconds.
+    pub fn time_gran(&self) -> u32 {
+        self.out.time_gran
+    }
+
+    /// Return the maximum stack depth of the passthrough backing files,
+    /// or `None` if the passthrough is not enabled.
+    ///
+    /// See `KernelConfig::passthrough` for details.
+    pub fn max_stack_depth(&self) -> Option<u32> {
+        if self.out.unused[0] & (FUSE_PASSTHROUGH >> 32) as u32 != 0 {
+            // `max_stack_depth` is stored in the second element of `unused`.
+            Some(self.out.unused[1])
+        } else {
+            None
         }
     }
 
-    Err(io::Error::new(
-        io::ErrorKind::ConnectionRefused,
-        "session initialization is aborted",
-    ))
+    /// Set the timestamp resolution in nanoseconds.
+    pub fn set_time_gran(&mut self, value: u32) {
+        self.out.time_gran = value;
+    }
 }
 
-// ==== Request ====
+// ==== Session ====
 
-/// Context about an incoming FUSE request.
-pub struct Request {
-    session: Arc<SessionInner>,
-    header: fuse_in_header,
-    arg: Vec<u8>,
+/// The object containing the contextrual information about a FUSE session.
+pub struct Session {
+    inner: Arc<SessionInner>,
 }
 
-impl Request {
-    /// Return the unique ID of the request.
-    #[inline]
-    pub fn unique(&self) -> u64 {
-        self.header.unique
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Session").finish()
     }
+}
 
-    /// Return the user ID of the calling process.
-    #[inline]
-    pub fn uid(&self) -> u32 {
-        self.header.uid
+struct SessionInner {
+    conn: Connection,
+    init_out: fuse_init_out,
+    bufsize: usize,
+    exited: AtomicBool,
+    closed: AtomicBool,
+    read_only: AtomicBool,
+    background: AtomicUsize,
+    started: Instant,
+    // The time of the last received request, in nanoseconds since `started`.
+    last_activity: AtomicU64,
+    notify_unique: AtomicU64,
+    metrics: Option<Arc<MetricsFn>>,
+    before_dispatch: Option<Arc<BeforeDispatchFn>>,
+    after_dispatch: Option<Arc<AfterDispatchFn>>,
+    unsupported_errors: Vec<(Category, i32)>,
+    root_attr: Option<AttrOut>,
+    root_entry: Option<EntryOut>,
+    absent_xattrs: Vec<OsString>,
+    attr_ttl: Option<Arc<AttrTtlFn>>,
+    disable_attr_cache: bool,
+    max_symlink_len: usize,
+    op_timeout: Option<Duration>,
+    pending_replies: Mutex<Vec<Arc<PendingReply>>>,
+    stateless_open: bool,
+    tmpfile: bool,
+    statx: bool,
+    skip_flush: bool,
+    in_flight: InFlight,
+    readdir_states: Mutex<HashMap<(u64, u64), ReaddirState>>,
+    zero_message_opendir: AtomicBool,
+    readdirplus_fallback: AtomicBool,
+    reply_queue: Option<Mutex<VecDeque<QueuedReply>>>,
+    io_sizes: Option<IoSizeCounters>,
+    init_in: InitIn,
+}
+
+/// A reply waiting for the FUSE device to become writable, see
+/// `KernelConfig::reply_queue`.
+struct QueuedReply {
+    device: Option<Arc<Connection>>,
+    bytes: Vec<u8>,
+}
+
+/// The continuation of a directory stream saved by `Request::save_readdir_state`.
+struct ReaddirState {
+    offset: u64,
+    value: Box<dyn Any + Send>,
+}
+
+/// The state of a request awaiting its reply.
+struct PendingReply {
+    unique: u64,
+    opcode: u32,
+    received: Instant,
+    state: AtomicU8,
+    cancelled: AtomicBool,
+    device: Option<Arc<Connection>>,
+}
+
+// The values of `PendingReply::state`, and of `Request::state` until the
+// request is tracked by the session.
+const REPLY_PENDING: u8 = 0;
+const REPLIED: u8 = 1;
+const REPLIED_BY_SESSION: u8 = 2;
+// The state of a `Request` whose reply is claimed through its `PendingReply`.
+const REPLY_TRACKED: u8 = 3;
+
+/// The number of the interrupts kept by `InFlight` for the requests not
+/// tracked yet.
+const MAX_INTERRUPTED: usize = 64;
+
+/// The number of the shards of `InFlight`, which spread the lock contention
+/// among the threads receiving and dropping the requests.
+const IN_FLIGHT_SHARDS: usize = 16;
+
+/// The requests tracked by the session until they are replied, keyed by the
+/// unique ID.
+///
+/// A request is tracked once its cancellation token is taken, or on receipt
+/// if `KernelConfig::op_timeout` is set, so the other requests cost no
+/// allocation nor locking here.
+struct InFlight {
+    shards: Vec<Mutex<HashMap<u64, Arc<PendingReply>>>>,
+    // The largest unique ID received so far, without `FUSE_UNIQUE_RESEND`.
+    received: AtomicU64,
+    // The targets of the interrupts received before they are tracked.
+    interrupted: Mutex<VecDeque<u64>>,
+}
+
+impl InFlight {
+    fn new() -> Self {
+        Self {
+            shards: (0..IN_FLIGHT_SHARDS)
+                .map(|_| Mutex::new(HashMap::new()))
+                .collect(),
+            received: AtomicU64::new(0),
+            interrupted: Mutex::new(VecDeque::new()),
+        }
     }
 
-    /// Return the group ID of the calling process.
-    #[inline]
-    pub fn gid(&self) -> u32 {
-        self.header.gid
+    /// Record the receipt of a request other than `interrupt`.
+    fn receive(&self, unique: u64) {
+        self.received
+            .fetch_max(unique & !FUSE_UNIQUE_RESEND, Ordering::SeqCst);
     }
 
-    /// Return the process ID of the calling process.
-    #[inline]
-    pub fn pid(&self) -> u32 {
-        self.header.pid
+    fn shard(&self, unique: u64) -> &Mutex<HashMap<u64, Arc<PendingReply>>> {
+        // The kernel steps the unique IDs by 2, as the lowest bit marks
+        // the `interrupt` requests.
+        &self.shards[(unique >> 1) as usize % IN_FLIGHT_SHARDS]
     }
 
-    /// Decode the argument of this request.
-    pub fn operation(&self) -> Result<Operation<'_, Data<'_>>, DecodeError> {
-        if self.session.exited() {
-            return Ok(Operation::unknown());
+    /// Track the request, which is cancelled at once if it has already been
+    /// interrupted.
+    fn insert(&self, pending: Arc<PendingReply>) {
+        let mut interrupted = self.interrupted.lock().unwrap();
+        if let Some(i) = interrupted.iter().position(|&u| u == pending.unique) {
+            interrupted.remove(i);
+            pending.cancelled.store(true, Ordering::Release);
         }
+        self.shard(pending.unique)
+            .lock()
+            .unwrap()
+            .insert(pending.unique, pending);
+    }
 
-        let (arg, data) = match fuse_opcode::try_from(self.header.opcode).ok() {
-            Some(fuse_opcode::FUSE_WRITE) | Some(fuse_opcode::FUSE_NOTIFY_REPLY) => {
-                self.arg.split_at(mem::size_of::<fuse_write_in>())
+    /// Remove the request, unless the unique ID has been reused by another one.
+    fn remove(&self, pending: &Arc<PendingReply>) {
+        let mut shard = self.shard(pending.unique).lock().unwrap();
+        if let Some(registered) = shard.get(&pending.unique) {
+            if Arc::ptr_eq(registered, pending) {
+                shard.remove(&pending.unique);
             }
-            _ => (&self.arg[..], &[] as &[_]),
-        };
-
-        Operation::decode(&self.header, arg, Data { data })
+        }
     }
 
-    pub fn reply<T>(&self, arg: T) -> io::Result<()>
-    where
-        T: Bytes,
-    {
-        write_bytes(&self.session.conn, Reply::new(self.unique(), 0, arg))
+    /// Mark the request as cancelled, or remember the interrupt until the
+    /// request is tracked.
+    ///
+    /// Return `false` if the request has not been received yet.
+    fn interrupt(&self, unique: u64) -> bool {
+        let mut interrupted = self.interrupted.lock().unwrap();
+        if let Some(pending) = self.shard(unique).lock().unwrap().get(&unique) {
+            pending.cancelled.store(true, Ordering::Release);
+            return true;
+        }
+        if unique & !FUSE_UNIQUE_RESEND > self.received.load(Ordering::SeqCst) {
+            return false;
+        }
+        if interrupted.len() == MAX_INTERRUPTED {
+            interrupted.pop_front();
+        }
+        interrupted.push_back(unique);
+        true
     }
 
-    pub fn reply_error(&self, code: i32) -> io::Result<()> {
-        write_bytes(&self.session.conn, Reply::new(self.unique(), code, ()))
+    fn drain(&self) -> Vec<Arc<PendingReply>> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                let mut shard = shard.lock().unwrap();
+                shard
+                    .drain()
+                    .map(|(_, pending)| pending)
+                    .collect::<Vec<_>>()
+            })
+            .collect()
     }
 }
 
-/// The remaining part of request message.
-pub struct Data<'op> {
-    data: &'op [u8],
-}
-
-impl fmt::Debug for Data<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Data").finish()
+impl PendingReply {
+    /// Take over the reply from the handler.
+    ///
+    /// Return `false` if the request has already been replied.
+    fn claim_by_session(&self) -> bool {
+        self.state
+            .compare_exchange(
+                REPLY_PENDING,
+                REPLIED_BY_SESSION,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            )
+            .is_ok()
     }
 }
 
-impl<'op> io::Read for Data<'op> {
+impl SessionInner {
     #[inline]
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        io::Read::read(&mut self.data, buf)
+    fn exited(&self) -> bool {
+        // FIXME: choose appropriate atomic ordering.
+        self.exited.load(Ordering::SeqCst)
     }
 
     #[inline]
-    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
-        io::Read::read_vectored(&mut self.data, bufs)
+    fn exit(&self) {
+        // FIXME: choose appropriate atomic ordering.
+        self.exited.store(true, Ordering::SeqCst)
     }
-}
 
-impl<'op> BufRead for Data<'op> {
+    /// Return whether the kernel reads the flags in `fuse_attr` (since ABI 7.32).
     #[inline]
-    fn fill_buf(&mut self) -> io::Result<&[u8]> {
-        io::BufRead::fill_buf(&mut self.data)
+    fn supports_attr_flags(&self) -> bool {
+        self.init_in.minor >= 32
     }
 
+    /// Return whether the kernel skips `flush` for the files opened with
+    /// `FOPEN_NOFLUSH` (since ABI 7.35).
     #[inline]
-    fn consume(&mut self, amt: usize) {
-        io::BufRead::consume(&mut self.data, amt)
+    fn supports_no_flush(&self) -> bool {
+        self.init_in.minor >= 35
     }
-}
-
-// ==== Notifier ====
 
-#[derive(Clone)]
-pub struct Notifier {
-    session: Arc<SessionInner>,
-}
+    /// Return whether the kernel allows the parallel direct writes (since ABI 7.38).
+    #[inline]
+    fn supports_parallel_direct_writes(&self) -> bool {
+        self.init_in.minor >= 38
+    }
 
-impl Notifier {
-    /// Notify the cache invalidation about an inode to the kernel.
-    pub fn inval_inode(&self, ino: u64, off: i64, len: i64) -> io::Result<()> {
-        let total_len = u32::try_from(
-            mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_notify_inval_inode_out>(),
-        )
-        .unwrap();
+    /// Return whether the passthrough has been negotiated (since ABI 7.40).
+    #[inline]
+    fn supports_passthrough(&self) -> bool {
+        self.init_out.unused[0] & (FUSE_PASSTHROUGH >> 32) as u32 != 0
+    }
 
-        return write_bytes(
-            &self.session.conn,
-            InvalInode {
-                header: fuse_out_header {
-                    len: total_len,
-                    error: fuse_notify_code::FUSE_NOTIFY_INVAL_INODE as i32,
-                    unique: 0,
-                },
-                arg: fuse_notify_inval_inode_out { ino, off, len },
-            },
-        );
+    #[inline]
+    fn read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
 
-        struct InvalInode {
-            header: fuse_out_header,
-            arg: fuse_notify_inval_inode_out,
-        }
-        impl Bytes for InvalInode {
-            fn size(&self) -> usize {
-                self.header.len as usize
+    /// Write the message to the device, or append it to the queue of
+    /// `KernelConfig::reply_queue` if the device is not writable or the
+    /// earlier messages are still queued.
+    fn send_queued<T>(
+        &self,
+        queue: &Mutex<VecDeque<QueuedReply>>,
+        device: Option<&Arc<Connection>>,
+        bytes: T,
+    ) -> io::Result<()>
+    where
+        T: Bytes,
+    {
+        // Keep the lock while writing, so that the messages are not reordered.
+        let mut queue = queue.lock().unwrap();
+        if queue.is_empty() {
+            let conn = device.map_or(&self.conn, |device| &**device);
+            match write_bytes(conn, &bytes) {
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => (),
+                res => return res,
             }
+        }
 
-            fn count(&self) -> usize {
-                2
-            }
+        let mut collect = CollectBytes(Vec::with_capacity(bytes.size()));
+        bytes.fill_bytes(&mut collect);
+        queue.push_back(QueuedReply {
+            device: device.cloned(),
+            bytes: collect.0,
+        });
+        Ok(())
+    }
+}
 
-            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
-                dst.put(self.header.as_bytes());
-                dst.put(self.arg.as_bytes());
-            }
-        }
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.inner.exit();
     }
+}
 
-    /// Notify the invalidation about a directory entry to the kernel.
-    pub fn inval_entry<T>(&self, parent: u64, name: T) -> io::Result<()>
+impl AsRawFd for Session {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.conn.as_raw_fd()
+    }
+}
+
+impl Session {
+    /// Start a FUSE daemon mount on the specified path.
+    pub fn mount(mountpoint: PathBuf, mut config: KernelConfig) -> io::Result<Self> {
+        let mut mountopts = mem::take(&mut config.mountopts);
+        if mountopts.rootmode.is_none() {
+            mountopts.rootmode = config
+                .root_attr
+                .as_ref()
+                .map(|attr| attr.as_attr_out().attr.mode & libc::S_IFMT)
+                .filter(|&mode| mode != 0);
+        }
+        let conn = Connection::open(mountpoint, mountopts)?;
+        Self::init(conn, config)
+    }
+
+    /// Start a FUSE daemon over an already opened and mounted FUSE device.
+    ///
+    /// This is intended for the privilege separation, where a privileged process
+    /// opens `/dev/fuse`, mounts the filesystem and passes the descriptor to
+    /// an unprivileged worker (e.g. by `SCM_RIGHTS` or inheritance). The session
+    /// performs the `INIT` handshake on the descriptor, so the kernel must not
+    /// have been initialized through it yet. The mount options in `config` are
+    /// ignored, and the filesystem is not unmounted when the session is dropped;
+    /// unmounting is left to the process that mounted it.
+    ///
+    /// # Safety
+    /// `fd` must be an open file descriptor owned by the caller. The ownership
+    /// is transferred to the session, which closes it on drop.
+    pub unsafe fn from_raw_fd(fd: RawFd, config: KernelConfig) -> io::Result<Self> {
+        Self::init(Connection::from_fd(fd), config)
+    }
+
+    fn init(conn: Connection, config: KernelConfig) -> io::Result<Self> {
+        let KernelConfig {
+            mut init_out,
+            metrics,
+            before_dispatch,
+            after_dispatch,
+            unsupported_errors,
+            mut root_attr,
+            root_dotdot,
+            absent_xattrs,
+            attr_ttl,
+            disable_attr_cache,
+            max_symlink_len,
+            op_timeout,
+            stateless_open,
+            tmpfile,
+            statx,
+            skip_flush,
+            reply_queue,
+            io_size_stats,
+            init_callback,
+            ..
+        } = config;
+
+        if disable_attr_cache {
+            if let Some(ref mut root_attr) = root_attr {
+                root_attr.ttl(Duration::from_secs(0));
+            }
+        }
+        let root_entry = match root_attr {
+            Some(ref root_attr) if root_dotdot => Some(root_attr.to_entry_out()),
+            _ => None,
+        };
+
+        let init_in = init_session(&mut init_out, init_callback.as_deref(), &conn, &conn)?;
+        let bufsize = InitOut::from_init_out_ref(&init_out).max_request_size();
+
+        let session = Self {
+            inner: Arc::new(SessionInner {
+                conn,
+                init_out,
+                bufsize,
+                exited: AtomicBool::new(false),
+                closed: AtomicBool::new(false),
+                read_only: AtomicBool::new(false),
+                background: AtomicUsize::new(0),
+                started: Instant::now(),
+                last_activity: AtomicU64::new(0),
+                notify_unique: AtomicU64::new(0),
+                metrics,
+                before_dispatch,
+                after_dispatch,
+                unsupported_errors,
+                root_entry,
+                root_attr,
+                absent_xattrs,
+                attr_ttl,
+                disable_attr_cache,
+                max_symlink_len,
+                op_timeout,
+                pending_replies: Mutex::new(vec![]),
+                stateless_open: stateless_open && init_out.flags & FUSE_NO_OPEN_SUPPORT != 0,
+                tmpfile,
+                statx,
+                skip_flush,
+                in_flight: InFlight::new(),
+                readdir_states: Mutex::new(HashMap::new()),
+                zero_message_opendir: AtomicBool::new(false),
+                readdirplus_fallback: AtomicBool::new(false),
+                io_sizes: if io_size_stats {
+                    Some(IoSizeCounters::default())
+                } else {
+                    None
+                },
+                reply_queue: if reply_queue {
+                    Some(Mutex::new(VecDeque::new()))
+                } else {
+                    None
+                },
+                init_in,
+            }),
+        };
+
+        if let Some(timeout) = op_timeout {
+            let inner = Arc::downgrade(&session.inner);
+            std::thread::Builder::new()
+                .name("polyfuse-op-timeout".into())
+                .spawn(move || watch_op_timeout(inner, timeout))?;
+        }
+
+        Ok(session)
+    }
+
+    /// Return the `init` request sent by the kernel, i.e. its version and
+    /// the capabilities it offered.
+    ///
+    /// As with `init_out`, this is available as soon as the session is
+    /// created, before any request is received.
+    pub fn init_in(&self) -> &InitIn {
+        &self.inner.init_in
+    }
+
+    /// Return the result of the negotiation with the kernel, as replied to
+    /// the `init` request.
+    ///
+    /// The version is the one both sides support, and the flags are the
+    /// capabilities enabled for this session along with those only informed
+    /// by the kernel, such as `FUSE_NO_OPEN_SUPPORT`. The diagnostic tools can
+    /// inspect them right after mounting.
+    pub fn init_out(&self) -> &InitOut {
+        InitOut::from_init_out_ref(&self.inner.init_out)
+    }
+
+    /// Return the histograms of the sizes of the `read` and `write` requests
+    /// received so far, or `None` unless `KernelConfig::io_size_stats` is
+    /// enabled.
+    pub fn io_size_stats(&self) -> Option<(IoSizeHistogram, IoSizeHistogram)> {
+        self.inner
+            .io_sizes
+            .as_ref()
+            .map(|counters| (counters.reads(), counters.writes()))
+    }
+
+    /// Return whether the kernel supports for zero-message opens.
+    ///
+    /// When the returned value is `true`, the kernel treat an `ENOSYS`
+    /// error for a `FUSE_OPEN` request as successful and does not send
+    /// subsequent `open` requests.  Otherwise, the filesystem should
+    /// implement the handler for `open` requests appropriately.
+    pub fn no_open_support(&self) -> bool {
+        self.inner.init_out.flags & FUSE_NO_OPEN_SUPPORT != 0
+    }
+
+    /// Return whether the kernel supports for zero-message opendirs.
+    ///
+    /// See the documentation of `no_open_support` for details.
+    pub fn no_opendir_support(&self) -> bool {
+        self.inner.init_out.flags & FUSE_NO_OPENDIR_SUPPORT != 0
+    }
+
+    /// Return whether the kernel is able to resend the pending requests on
+    /// `Notifier::resend`.
+    pub fn resend_support(&self) -> bool {
+        self.inner.init_in.flags() & FUSE_HAS_RESEND != 0
+    }
+
+    /// Return whether `readdirplus` has been negotiated with the kernel.
+    ///
+    /// When the returned value is `false`, the kernel sends only `readdir`
+    /// requests. A filesystem implementing `readdirplus` alone can serve them
+    /// by creating the reply with `ReaddirPlusOut::with_mode`.
+    pub fn readdirplus_support(&self) -> bool {
+        self.inner.init_out.flags & FUSE_DO_READDIRPLUS != 0
+    }
+
+    /// Return whether the filesystem has replied `ENOSYS` to a `readdirplus`
+    /// request, and the following ones are served as plain `readdir`.
+    ///
+    /// Unlike `open`, the kernel does not remember `ENOSYS` from `readdirplus`
+    /// and keeps sending it, so the session emulates the fallback: such
+    /// requests are decoded with `ReaddirMode::Normal`, and the plain entries
+    /// replied to them are converted to the `readdirplus` ones without the
+    /// attributes, which the kernel skips instead of creating the dentries.
+    /// The directory read receiving `ENOSYS` itself still fails.
+    pub fn readdirplus_fallback(&self) -> bool {
+        self.inner.readdirplus_fallback.load(Ordering::Acquire)
+    }
+
+    /// Return whether the POSIX ACLs have been negotiated with the kernel.
+    pub fn posix_acl_support(&self) -> bool {
+        self.inner.init_out.flags & FUSE_POSIX_ACL != 0
+    }
+
+    /// Return whether the submounts have been negotiated with the kernel.
+    pub fn submounts_support(&self) -> bool {
+        self.inner.init_out.flags & FUSE_SUBMOUNTS != 0
+    }
+
+    /// Return whether the filesystem is currently marked as read-only.
+    pub fn read_only(&self) -> bool {
+        self.inner.read_only()
+    }
+
+    /// Mark the filesystem as read-only, or writable again, at runtime.
+    ///
+    /// While the flag is set, the requests that modify the filesystem
+    /// (e.g. `write`, `mkdir` or opening a file for writing) are replied
+    /// with `EROFS` by the session itself and never returned from
+    /// `next_request`. This is useful when the backing store becomes
+    /// unwritable after mounting, such as a full disk or a lost write lease.
+    pub fn set_read_only(&self, enabled: bool) {
+        self.inner.read_only.store(enabled, Ordering::SeqCst);
+    }
+
+    /// Return the number of the background requests that have been received
+    /// but not replied yet.
+    ///
+    /// The background requests are the ones that the kernel may issue
+    /// without a waiting process, such as readahead and writeback, and whose
+    /// number in flight is limited by `max_background`. Since the request
+    /// message does not tell whether it was issued in the background, all
+    /// `read` and `write` requests are counted.
+    pub fn background_requests(&self) -> usize {
+        self.inner.background.load(Ordering::SeqCst)
+    }
+
+    /// Return the elapsed time since the last request was received from the kernel.
+    ///
+    /// The time is counted from the initialization until the first request
+    /// arrives. Every request, including those replied by the session itself,
+    /// resets it.
+    pub fn idle_time(&self) -> Duration {
+        let last_activity = Duration::from_nanos(self.inner.last_activity.load(Ordering::Relaxed));
+        self.inner
+            .started
+            .elapsed()
+            .checked_sub(last_activity)
+            .unwrap_or_default()
+    }
+
+    /// Return whether no request has been received within the specified period.
+    ///
+    /// The kernel does not send any heartbeat on the connection, so a long
+    /// idle period does not necessarily mean that the connection is wedged;
+    /// the application should combine this with its own knowledge of the
+    /// expected workload (e.g. the files known to be open) before treating
+    /// the mount as unhealthy.
+    pub fn is_idle(&self, timeout: Duration) -> bool {
+        self.idle_time() >= timeout
+    }
+
+    /// Return whether the connection with the kernel has been closed,
+    /// e.g. by unmounting the filesystem.
+    ///
+    /// Once closed, `next_request` always returns `Ok(None)`.
+    pub fn is_closed(&self) -> bool {
+        self.inner.closed.load(Ordering::SeqCst)
+    }
+
+    /// Register a file as the backing file of the passthrough, and return
+    /// its ID passed to `OpenOut::passthrough`.
+    ///
+    /// The kernel holds a reference to the file until the ID is unregistered
+    /// by `close_backing`, so `fd` may be closed after this returns. This
+    /// requires the passthrough to be negotiated (see
+    /// `KernelConfig::passthrough`) and `CAP_SYS_ADMIN`.
+    pub fn open_backing(&self, fd: RawFd) -> io::Result<u32> {
+        self.inner.conn.backing_open(fd)
+    }
+
+    /// Unregister a backing file registered by `open_backing`.
+    ///
+    /// The files already opened with the ID keep using the backing file.
+    pub fn close_backing(&self, backing_id: u32) -> io::Result<()> {
+        self.inner.conn.backing_close(backing_id)
+    }
+
+    /// Open a clone of the FUSE device bound to the same connection.
+    ///
+    /// The kernel distributes the requests among all the devices of the
+    /// connection, so each worker thread can receive the requests from its
+    /// own device without contending with the others. The requests received
+    /// from a clone are replied through it. This requires the session to be
+    /// established on `/dev/fuse`, rather than e.g. a socket.
+    pub fn clone_device(&self) -> io::Result<DeviceHandle> {
+        let conn = self.inner.conn.clone_device()?;
+        Ok(DeviceHandle {
+            session: self.inner.clone(),
+            conn: Arc::new(conn),
+        })
+    }
+
+    /// Receive an incoming FUSE request from the kernel.
+    pub fn next_request(&self) -> io::Result<Option<Request>> {
+        receive_request(&self.inner, None, None)
+    }
+
+    /// Receive an incoming FUSE request, waiting at most for the specified duration.
+    ///
+    /// Return `Ok(None)` if no request has arrived before the timeout, or the
+    /// session has been closed; the latter is told by `is_closed`. This allows
+    /// a server to do periodic maintenance while it is idle.
+    pub fn next_request_timeout(&self, timeout: Duration) -> io::Result<Option<Request>> {
+        receive_request(&self.inner, None, Some(Instant::now() + timeout))
+    }
+
+    /// Pass the incoming requests to the service until the session is closed.
+    ///
+    /// The loop stops at the first error returned from the service.
+    /// The hooks registered by `KernelConfig::before_dispatch` and
+    /// `KernelConfig::after_dispatch` are invoked around each call.
+    pub fn run<S>(&self, service: S) -> io::Result<()>
     where
-        T: AsRef<OsStr>,
+        S: Service,
     {
-        let namelen = u32::try_from(name.as_ref().len()).expect("provided name is too long");
+        while let Some(mut req) = self.next_request()? {
+            self.dispatch(&mut req, &service)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn dispatch<S>(&self, req: &mut Request, service: &S) -> io::Result<()>
+    where
+        S: Service + ?Sized,
+    {
+        if let Some(ref before_dispatch) = self.inner.before_dispatch {
+            before_dispatch(&RequestInfo { req });
+        }
+        let res = service.call(req);
+        if let Some(ref after_dispatch) = self.inner.after_dispatch {
+            after_dispatch(&RequestInfo { req }, &res);
+        }
+        res
+    }
+
+    /// Enable or disable the non-blocking mode of the FUSE device.
+    ///
+    /// In the non-blocking mode, the operations on the device fail with
+    /// `io::ErrorKind::WouldBlock` instead of waiting: `next_request` when no
+    /// request is pending, and the notifications when the kernel cannot accept
+    /// the message yet. The replies are retried until written, since the
+    /// request would never complete otherwise. This allows integrating the
+    /// session into an event loop by registering the descriptor returned from
+    /// `as_raw_fd`.
+    pub fn set_nonblocking(&self, enabled: bool) -> io::Result<()> {
+        self.inner.conn.set_nonblocking(enabled)
+    }
+
+    /// Write the replies queued while the FUSE device was not writable, in
+    /// the order they have been sent.
+    ///
+    /// Return `true` if the queue has been drained, or `false` if the device
+    /// has stopped accepting the replies again, in which case the call should
+    /// be repeated on the next writability. See `KernelConfig::reply_queue`.
+    pub fn flush_replies(&self) -> io::Result<bool> {
+        let queue = match self.inner.reply_queue {
+            Some(ref queue) => queue,
+            None => return Ok(true),
+        };
+        let mut queue = queue.lock().unwrap();
+        while let Some(reply) = queue.front() {
+            let conn = reply.device.as_deref().unwrap_or(&self.inner.conn);
+            match write_bytes(conn, &reply.bytes[..]) {
+                Ok(()) => {
+                    queue.pop_front();
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Return whether any reply is waiting for `flush_replies`.
+    pub fn has_queued_replies(&self) -> bool {
+        match self.inner.reply_queue {
+            Some(ref queue) => !queue.lock().unwrap().is_empty(),
+            None => false,
+        }
+    }
+
+    /// Stop the session, replying `EINTR` to the requests not replied yet.
+    ///
+    /// Otherwise the processes waiting for those requests would hang until
+    /// the filesystem is forcibly unmounted. Only the requests tracked by the
+    /// session are replied: all of them if `KernelConfig::op_timeout` is set,
+    /// and otherwise those whose `Request::cancellation_token` has been taken. The requests may be still being
+    /// handled by other threads: their replies are dropped, and decoding
+    /// their operations fails since the session is exited. Return the number
+    /// of the requests replied by this call. All the replies are attempted
+    /// even if some of them fail, and the first error is returned.
+    pub fn shutdown(&self) -> io::Result<usize> {
+        self.inner.exit();
+
+        let in_flight = self.inner.in_flight.drain();
+
+        let mut replied = 0;
+        let mut res = Ok(());
+        for pending in in_flight {
+            if !pending.claim_by_session() {
+                continue;
+            }
+            tracing::debug!(
+                "reply EINTR to the in-flight request on shutdown (unique={})",
+                pending.unique
+            );
+            let conn = pending.device.as_deref().unwrap_or(&self.inner.conn);
+            match write_reply(conn, Reply::new(pending.unique, libc::EINTR, ())) {
+                Ok(()) => replied += 1,
+                Err(err) => {
+                    if res.is_ok() {
+                        res = Err(err);
+                    }
+                }
+            }
+        }
+        res.map(|()| replied)
+    }
+
+    /// Create an instance of `Notifier` corresponding to this session.
+    pub fn notifier(&self) -> Notifier {
+        Notifier {
+            session: self.inner.clone(),
+        }
+    }
+}
+
+fn receive_request(
+    inner: &Arc<SessionInner>,
+    device: Option<&Arc<Connection>>,
+    deadline: Option<Instant>,
+) -> io::Result<Option<Request>> {
+    let mut conn: &Connection = device.map_or(&inner.conn, |device| &**device);
+
+    // FIXME: Align the allocated region in `arg` with the FUSE argument types.
+    let mut header = fuse_in_header::default();
+    let mut arg = vec![0u8; inner.bufsize - mem::size_of::<fuse_in_header>()];
+
+    let arg_len = loop {
+        if let Some(deadline) = deadline {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if !conn.poll_readable(timeout)? {
+                return Ok(None);
+            }
+        }
+
+        let res = if conn.is_stream() {
+            read_framed(conn, &mut header, &mut arg[..])
+        } else {
+            conn.read_vectored(&mut [
+                io::IoSliceMut::new(header.as_bytes_mut()),
+                io::IoSliceMut::new(&mut arg[..]),
+            ])
+        };
+        match res {
+            Ok(0) => {
+                tracing::debug!("the connection is closed");
+                inner.closed.store(true, Ordering::SeqCst);
+                return Ok(None);
+            }
+            Ok(len) => {
+                if len < mem::size_of::<fuse_in_header>() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "dequeued request message is too short",
+                    ));
+                }
+                if len != header.len as usize {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "dequeued request message does not match its declared length",
+                    ));
+                }
+                let arg_len = len - mem::size_of::<fuse_in_header>();
+                inner
+                    .last_activity
+                    .store(inner.started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+                if header.opcode != fuse_opcode::FUSE_INTERRUPT as u32 {
+                    inner.in_flight.receive(header.unique);
+                }
+
+                if reject_read_only(inner.read_only(), &header, &arg[..arg_len], conn)? {
+                    continue;
+                }
+
+                if reply_root_attr(inner.root_attr.as_ref(), &header, conn)? {
+                    continue;
+                }
+
+                if reply_root_dotdot(inner.root_entry.as_ref(), &header, &arg[..arg_len], conn)? {
+                    continue;
+                }
+
+                if reply_stateless_open(inner.stateless_open, &header, conn)? {
+                    continue;
+                }
+
+                if reject_disabled_op(inner.tmpfile, fuse_opcode::FUSE_TMPFILE, &header, conn)? {
+                    continue;
+                }
+
+                if reject_disabled_op(inner.statx, fuse_opcode::FUSE_STATX, &header, conn)? {
+                    continue;
+                }
+
+                if reject_disabled_op(!inner.skip_flush, fuse_opcode::FUSE_FLUSH, &header, conn)? {
+                    continue;
+                }
+
+                if reject_long_symlink(inner.max_symlink_len, &header, &arg[..arg_len], conn)? {
+                    continue;
+                }
+
+                if reply_absent_xattr(&inner.absent_xattrs, &header, &arg[..arg_len], conn)? {
+                    continue;
+                }
+
+                if requeue_interrupt(&inner.in_flight, &header, &arg[..arg_len], conn)? {
+                    continue;
+                }
+
+                break arg_len;
+            }
+
+            Err(err) => match err.raw_os_error() {
+                Some(libc::ENODEV) => {
+                    tracing::debug!("ENODEV");
+                    inner.closed.store(true, Ordering::SeqCst);
+                    return Ok(None);
+                }
+                Some(libc::ENOENT) => {
+                    tracing::debug!("ENOENT");
+                    continue;
+                }
+                _ => return Err(err),
+            },
+        }
+    };
+    unsafe {
+        arg.set_len(arg_len);
+    }
+
+    let background = is_background(header.opcode);
+    if background {
+        inner.background.fetch_add(1, Ordering::SeqCst);
+    }
+
+    if let Some(ref io_sizes) = inner.io_sizes {
+        io_sizes.record(&header, &arg[..]);
+    }
+
+    if header.opcode == fuse_opcode::FUSE_RELEASEDIR as u32 {
+        if let Ok(release_in) = Decoder::new(&arg[..]).fetch::<fuse_release_in>() {
+            inner
+                .readdir_states
+                .lock()
+                .unwrap()
+                .remove(&(header.nodeid, release_in.fh));
+        }
+    }
+
+    let req = Request {
+        session: inner.clone(),
+        header,
+        arg,
+        received: inner.metrics.as_ref().map(|_| Instant::now()),
+        background: AtomicBool::new(background),
+        state: AtomicU8::new(REPLY_PENDING),
+        pending: AtomicPtr::new(ptr::null_mut()),
+        device: device.cloned(),
+        extensions: Extensions::new(),
+        // Latched on receipt so that the decoding and the reply agree even if
+        // another request turns the fallback on meanwhile.
+        readdirplus_fallback: header.opcode == fuse_opcode::FUSE_READDIRPLUS as u32
+            && inner.readdirplus_fallback.load(Ordering::Acquire),
+    };
+    if inner.op_timeout.is_some() {
+        req.track();
+    }
+    Ok(Some(req))
+}
+
+/// Read a request from a stream transport, and return its length or zero at
+/// the end of the stream.
+///
+/// Unlike the FUSE device, a stream socket may deliver a request by several
+/// reads, or along with the following ones. The header and the argument are
+/// therefore read by their exact lengths, so that no byte of the next
+/// request is consumed.
+fn read_framed(
+    conn: &Connection,
+    header: &mut fuse_in_header,
+    arg: &mut [u8],
+) -> io::Result<usize> {
+    let header_len = mem::size_of::<fuse_in_header>();
+    match read_full(conn, header.as_bytes_mut())? {
+        0 => return Ok(0),
+        n if n < header_len => return Err(truncated_request()),
+        _ => (),
+    }
+
+    let total = header.len as usize;
+    if total < header_len || total > header_len + arg.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the declared request length is out of range",
+        ));
+    }
+    let arg_len = total - header_len;
+    if read_full(conn, &mut arg[..arg_len])? < arg_len {
+        return Err(truncated_request());
+    }
+    Ok(total)
+}
+
+/// Fill the buffer from the connection, and return the length read before
+/// the end of the stream.
+fn read_full(mut conn: &Connection, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match conn.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len)
+}
+
+fn truncated_request() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "the connection is closed in the middle of a request",
+    )
+}
+
+/// Return whether the kernel waits for the reply to the request.
+pub(crate) fn expects_reply(opcode: u32) -> bool {
+    !matches!(
+        fuse_opcode::try_from(opcode).ok(),
+        Some(fuse_opcode::FUSE_FORGET)
+            | Some(fuse_opcode::FUSE_BATCH_FORGET)
+            | Some(fuse_opcode::FUSE_INTERRUPT)
+            | Some(fuse_opcode::FUSE_NOTIFY_REPLY)
+    )
+}
+
+/// Reply `EIO` to the requests not replied within `timeout`, until the session is dropped.
+fn watch_op_timeout(inner: std::sync::Weak<SessionInner>, timeout: Duration) {
+    let interval = cmp::max(timeout / 4, Duration::from_millis(1));
+    loop {
+        std::thread::sleep(interval);
+
+        let inner = match inner.upgrade() {
+            Some(inner) if !inner.exited() => inner,
+            _ => return,
+        };
+
+        let expired: Vec<_> = {
+            let mut pending_replies = inner.pending_replies.lock().unwrap();
+            let (expired, pending) = mem::take(&mut *pending_replies)
+                .into_iter()
+                .filter(|pending| pending.state.load(Ordering::SeqCst) == REPLY_PENDING)
+                .partition(|pending| pending.received.elapsed() >= timeout);
+            *pending_replies = pending;
+            expired
+        };
+
+        for pending in expired {
+            if !pending.claim_by_session() {
+                continue;
+            }
+            tracing::warn!(
+                "the request is not replied within {:?} (unique={}, opcode={})",
+                timeout,
+                pending.unique,
+                pending.opcode
+            );
+            let conn = pending.device.as_deref().unwrap_or(&inner.conn);
+            if let Err(err) = write_reply(conn, Reply::new(pending.unique, libc::EIO, ())) {
+                tracing::error!("failed to reply EIO to the stuck request: {}", err);
+            }
+        }
+    }
+}
+
+/// Return whether the request is counted as a background request.
+fn is_background(opcode: u32) -> bool {
+    matches!(
+        fuse_opcode::try_from(opcode).ok(),
+        Some(fuse_opcode::FUSE_READ) | Some(fuse_opcode::FUSE_WRITE)
+    )
+}
+
+/// Return whether the request modifies the filesystem.
+fn is_modifying(header: &fuse_in_header, arg: &[u8]) -> bool {
+    if Category::Write.contains(header.opcode) {
+        return true;
+    }
+
+    match fuse_opcode::try_from(header.opcode).ok() {
+        Some(fuse_opcode::FUSE_OPEN) => match Decoder::new(arg).fetch::<fuse_open_in>() {
+            Ok(arg) => {
+                let flags = arg.flags as i32;
+                flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0
+            }
+            Err(..) => false,
+        },
+        _ => false,
+    }
+}
+
+/// Reply `EROFS` to the request if it modifies the read-only filesystem.
+///
+/// Return `true` if the request has been replied.
+fn reject_read_only<W>(
+    read_only: bool,
+    header: &fuse_in_header,
+    arg: &[u8],
+    writer: W,
+) -> io::Result<bool>
+where
+    W: io::Write,
+{
+    if !read_only || !is_modifying(header, arg) {
+        return Ok(false);
+    }
+
+    tracing::debug!(
+        "reject the request with EROFS (unique={}, opcode={})",
+        header.unique,
+        header.opcode
+    );
+    write_reply(writer, Reply::new(header.unique, libc::EROFS, ()))?;
+
+    Ok(true)
+}
+
+/// Cancel the request interrupted by the `interrupt` request, or reply
+/// `EAGAIN` to the latter if the request is unknown.
+///
+/// The request may not have been registered yet, such as when it is being
+/// received by another thread, so the kernel is asked to queue the
+/// `interrupt` request again. If the request has already been replied
+/// instead, the kernel discards the reply with `ENOENT`.
+///
+/// Return `true` if the request has been replied.
+fn requeue_interrupt<W>(
+    in_flight: &InFlight,
+    header: &fuse_in_header,
+    arg: &[u8],
+    writer: W,
+) -> io::Result<bool>
+where
+    W: io::Write,
+{
+    if header.opcode != fuse_opcode::FUSE_INTERRUPT as u32 {
+        return Ok(false);
+    }
+    let interrupt_in = match Decoder::new(arg).fetch::<fuse_interrupt_in>() {
+        Ok(interrupt_in) => interrupt_in,
+        Err(..) => return Ok(false),
+    };
+    if in_flight.interrupt(interrupt_in.unique) {
+        return Ok(false);
+    }
+
+    tracing::debug!(
+        "requeue the interrupt for the request not received yet (unique={})",
+        interrupt_in.unique
+    );
+    match write_reply(writer, Reply::new(header.unique, libc::EAGAIN, ())) {
+        Err(ref err) if err.raw_os_error() == Some(libc::ENOENT) => (),
+        res => res?,
+    }
+    Ok(true)
+}
+
+/// Reply the configured attributes to the `getattr` request for the root directory.
+///
+/// Return `true` if the request has been replied.
+fn reply_root_attr<W>(
+    root_attr: Option<&AttrOut>,
+    header: &fuse_in_header,
+    writer: W,
+) -> io::Result<bool>
+where
+    W: io::Write,
+{
+    let root_attr = match root_attr {
+        Some(root_attr) => root_attr,
+        None => return Ok(false),
+    };
+    if header.nodeid != FUSE_ROOT_ID || header.opcode != fuse_opcode::FUSE_GETATTR as u32 {
+        return Ok(false);
+    }
+
+    tracing::debug!(
+        "reply the attributes of the root directory (unique={})",
+        header.unique
+    );
+    write_reply(writer, Reply::new(header.unique, 0, root_attr))?;
+
+    Ok(true)
+}
+
+/// Reply the root itself to the lookup of `..` in the root directory.
+///
+/// Return `true` if the request has been replied.
+fn reply_root_dotdot<W>(
+    root_entry: Option<&EntryOut>,
+    header: &fuse_in_header,
+    arg: &[u8],
+    writer: W,
+) -> io::Result<bool>
+where
+    W: io::Write,
+{
+    let root_entry = match root_entry {
+        Some(root_entry) => root_entry,
+        None => return Ok(false),
+    };
+    if header.nodeid != FUSE_ROOT_ID
+        || header.opcode != fuse_opcode::FUSE_LOOKUP as u32
+        || arg != b"..\0"
+    {
+        return Ok(false);
+    }
+
+    tracing::debug!(
+        "reply the root to the lookup of its parent (unique={})",
+        header.unique
+    );
+    write_reply(writer, Reply::new(header.unique, 0, root_entry))?;
+
+    Ok(true)
+}
+
+/// Reply `ENOSYS` to the `open` request so that the kernel stops sending it.
+///
+/// Return `true` if the request has been replied.
+fn reply_stateless_open<W>(
+    stateless_open: bool,
+    header: &fuse_in_header,
+    writer: W,
+) -> io::Result<bool>
+where
+    W: io::Write,
+{
+    if !stateless_open || header.opcode != fuse_opcode::FUSE_OPEN as u32 {
+        return Ok(false);
+    }
+
+    tracing::debug!(
+        "reply ENOSYS to the open request for the stateless files (unique={})",
+        header.unique
+    );
+    write_reply(writer, Reply::new(header.unique, libc::ENOSYS, ()))?;
+
+    Ok(true)
+}
+
+/// Reply `ENOSYS` to the request of `opcode` unless enabled in `KernelConfig`,
+/// such as by `KernelConfig::tmpfile`.
+///
+/// Return `true` if the request has been replied.
+fn reject_disabled_op<W>(
+    enabled: bool,
+    opcode: fuse_opcode,
+    header: &fuse_in_header,
+    writer: W,
+) -> io::Result<bool>
+where
+    W: io::Write,
+{
+    if enabled || header.opcode != opcode as u32 {
+        return Ok(false);
+    }
+
+    tracing::debug!(
+        "reply ENOSYS to the disabled request (opcode={}, unique={})",
+        header.opcode,
+        header.unique
+    );
+    write_reply(writer, Reply::new(header.unique, libc::ENOSYS, ()))?;
+
+    Ok(true)
+}
+
+/// Reply `ENODATA` to the `getxattr` request for a registered absent attribute.
+///
+/// Return `true` if the request has been replied.
+fn reply_absent_xattr<W>(
+    absent_xattrs: &[OsString],
+    header: &fuse_in_header,
+    arg: &[u8],
+    writer: W,
+) -> io::Result<bool>
+where
+    W: io::Write,
+{
+    if absent_xattrs.is_empty() || header.opcode != fuse_opcode::FUSE_GETXATTR as u32 {
+        return Ok(false);
+    }
+
+    let mut decoder = Decoder::new(arg);
+    let name = match decoder
+        .fetch::<fuse_getxattr_in>()
+        .and_then(|_| decoder.fetch_str())
+    {
+        Ok(name) => name,
+        Err(..) => return Ok(false),
+    };
+    if !absent_xattrs.iter().any(|n| n == name) {
+        return Ok(false);
+    }
+
+    tracing::debug!(
+        "reply ENODATA to the absent xattr {:?} (unique={})",
+        name,
+        header.unique
+    );
+    write_reply(writer, Reply::new(header.unique, libc::ENODATA, ()))?;
+
+    Ok(true)
+}
+
+/// Reply `ENAMETOOLONG` to the `symlink` request if the target is too long.
+///
+/// Return `true` if the request has been replied.
+fn reject_long_symlink<W>(
+    max_len: usize,
+    header: &fuse_in_header,
+    arg: &[u8],
+    writer: W,
+) -> io::Result<bool>
+where
+    W: io::Write,
+{
+    if header.opcode != fuse_opcode::FUSE_SYMLINK as u32 {
+        return Ok(false);
+    }
+
+    let mut decoder = Decoder::new(arg);
+    let link = match decoder.fetch_str().and_then(|_| decoder.fetch_str()) {
+        Ok(link) => link,
+        Err(..) => return Ok(false),
+    };
+    if link.len() <= max_len {
+        return Ok(false);
+    }
+
+    tracing::debug!(
+        "reject the symlink target of {} bytes (unique={})",
+        link.len(),
+        header.unique
+    );
+    write_reply(writer, Reply::new(header.unique, libc::ENAMETOOLONG, ()))?;
+
+    Ok(true)
+}
+
+/// Negotiate the parameters with the kernel, and return the minor version
+/// proposed by the kernel.
+fn init_session<R, W>(
+    init_out: &mut fuse_init_out,
+    init_callback: Option<&InitFn>,
+    mut reader: R,
+    mut writer: W,
+) -> io::Result<InitIn>
+where
+    R: io::Read,
+    W: io::Write,
+{
+    // FIXME: align the allocated buffer in `buf` with FUSE argument types.
+    let mut header = fuse_in_header::default();
+    let mut arg = vec![0u8; pagesize() * MAX_MAX_PAGES];
+
+    for _ in 0..10 {
+        let len = reader.read_vectored(&mut [
+            io::IoSliceMut::new(header.as_bytes_mut()),
+            io::IoSliceMut::new(&mut arg[..]),
+        ])?;
+        if len < mem::size_of::<fuse_in_header>() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request message is too short",
+            ));
+        }
+
+        let mut decoder = Decoder::new(&arg[..]);
+
+        match fuse_opcode::try_from(header.opcode) {
+            Ok(fuse_opcode::FUSE_INIT) => {
+                let init_in = decoder
+                    .fetch::<fuse_init_in>() //
+                    .map_err(|_| {
+                        io::Error::new(io::ErrorKind::Other, "failed to decode fuse_init_in")
+                    })?;
+
+                let flags2 = if init_in.flags & FUSE_INIT_EXT != 0 {
+                    match decoder.fetch_bytes(4) {
+                        Ok(b) => u32::from_ne_bytes([b[0], b[1], b[2], b[3]]),
+                        Err(..) => 0,
+                    }
+                } else {
+                    0
+                };
+
+                let capable = init_in.flags & INIT_FLAGS_MASK;
+                let readonly_flags = init_in.flags & !INIT_FLAGS_MASK;
+
+                tracing::debug!("INIT request:");
+                tracing::debug!("  proto = {}.{}:", init_in.major, init_in.minor);
+                tracing::debug!("  flags = 0x{:08x} ({:?})", init_in.flags, capable);
+                tracing::debug!("  flags2 = 0x{:08x}", flags2);
+                tracing::debug!("  max_readahead = 0x{:08X}", init_in.max_readahead);
+                tracing::debug!("  max_pages = {}", readonly_flags & FUSE_MAX_PAGES != 0);
+                tracing::debug!(
+                    "  no_open_support = {}",
+                    readonly_flags & FUSE_NO_OPEN_SUPPORT != 0
+                );
+                tracing::debug!(
+                    "  no_opendir_support = {}",
+                    readonly_flags & FUSE_NO_OPENDIR_SUPPORT != 0
+                );
+
+                if init_in.major > 7 {
+                    tracing::debug!("wait for a second INIT request with an older version.");
+                    let init_out = fuse_init_out {
+                        major: FUSE_KERNEL_VERSION,
+                        minor: FUSE_KERNEL_MINOR_VERSION,
+                        ..Default::default()
+                    };
+                    write_reply(
+                        &mut writer,
+                        Reply::new(header.unique, 0, init_out.as_bytes()),
+                    )?;
+                    continue;
+                }
+
+                if init_in.major < 7 || init_in.minor < MINIMUM_SUPPORTED_MINOR_VERSION {
+                    tracing::warn!(
+                        "polyfuse supports only ABI 7.{} or later. {}.{} is not supported",
+                        MINIMUM_SUPPORTED_MINOR_VERSION,
+                        init_in.major,
+                        init_in.minor
+                    );
+                    write_reply(&mut writer, Reply::new(header.unique, libc::EPROTO, ()))?;
+                    continue;
+                }
+
+                let proposal = InitIn {
+                    major: init_in.major,
+                    minor: init_in.minor,
+                    max_readahead: init_in.max_readahead,
+                    flags: u64::from(init_in.flags) | u64::from(flags2) << 32,
+                };
+
+                if let Some(init_callback) = init_callback {
+                    let mut out = InitOut { out: *init_out };
+                    if let Err(err) = init_callback(&proposal, &mut out) {
+                        tracing::warn!("the initialization is refused by the callback: {}", err);
+                        write_reply(&mut writer, Reply::new(header.unique, libc::EPROTO, ()))?;
+                        return Err(err);
+                    }
+                    *init_out = out.out;
+                }
+
+                init_out.minor = cmp::min(init_out.minor, init_in.minor);
+
+                init_out.max_readahead = cmp::min(init_out.max_readahead, init_in.max_readahead);
+
+                init_out.flags &= capable;
+                init_out.flags |= FUSE_BIG_WRITES; // the flag was superseded by `max_write`.
+
+                init_out.unused[0] &= flags2 & INIT_FLAGS2_MASK;
+                if init_out.unused[0] != 0 {
+                    init_out.flags |= FUSE_INIT_EXT;
+                }
+                if init_out.unused[0] & (FUSE_PASSTHROUGH >> 32) as u32 == 0 {
+                    init_out.unused[1] = 0;
+                }
+
+                if init_in.flags & FUSE_MAX_PAGES != 0 {
+                    init_out.flags |= FUSE_MAX_PAGES;
+                    init_out.max_pages = cmp::min(
+                        (init_out.max_write - 1) / (pagesize() as u32) + 1,
+                        u16::max_value() as u32,
+                    ) as u16;
+                }
+
+                debug_assert_eq!(init_out.major, FUSE_KERNEL_VERSION);
+                debug_assert!(init_out.minor >= MINIMUM_SUPPORTED_MINOR_VERSION);
+
+                tracing::debug!("Reply to INIT:");
+                tracing::debug!("  proto = {}.{}:", init_out.major, init_out.minor);
+                tracing::debug!("  flags = 0x{:08x}", init_out.flags);
+                tracing::debug!("  max_readahead = 0x{:08X}", init_out.max_readahead);
+                tracing::debug!("  max_write = 0x{:08X}", init_out.max_write);
+                tracing::debug!("  max_background = 0x{:04X}", init_out.max_background);
+                tracing::debug!(
+                    "  congestion_threshold = 0x{:04X}",
+                    init_out.congestion_threshold
+                );
+                tracing::debug!("  time_gran = {}", init_out.time_gran);
+                write_reply(writer, Reply::new(header.unique, 0, init_out.as_bytes()))?;
+
+                init_out.flags |= readonly_flags;
+
+                return Ok(proposal);
+            }
+
+            _ => {
+                tracing::warn!(
+                    "ignoring an operation before init (opcode={:?})",
+                    header.opcode
+                );
+                write_reply(&mut writer, Reply::new(header.unique, libc::EIO, ()))?;
+                continue;
+            }
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::ConnectionRefused,
+        "session initialization is aborted",
+    ))
+}
+
+// ==== DeviceHandle ====
+
+/// A clone of the FUSE device, created by `Session::clone_device`.
+pub struct DeviceHandle {
+    session: Arc<SessionInner>,
+    conn: Arc<Connection>,
+}
+
+impl fmt::Debug for DeviceHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeviceHandle")
+            .field("fd", &self.conn.as_raw_fd())
+            .finish()
+    }
+}
+
+impl AsRawFd for DeviceHandle {
+    fn as_raw_fd(&self) -> RawFd {
+        self.conn.as_raw_fd()
+    }
+}
+
+impl DeviceHandle {
+    /// Receive an incoming FUSE request from this device.
+    ///
+    /// This behaves the same as `Session::next_request`.
+    pub fn next_request(&self) -> io::Result<Option<Request>> {
+        receive_request(&self.session, Some(&self.conn), None)
+    }
+}
+
+// ==== Request ====
+
+/// A flag telling that a request has been interrupted by the kernel.
+///
+/// See `Request::cancellation_token` for details.
+#[derive(Clone)]
+pub struct CancellationToken {
+    pending: Option<Arc<PendingReply>>,
+}
+
+impl fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CancellationToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+impl CancellationToken {
+    /// Return whether the request has been interrupted.
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        match self.pending {
+            Some(ref pending) => pending.cancelled.load(Ordering::Acquire),
+            None => false,
+        }
+    }
+}
+
+/// Context about an incoming FUSE request.
+pub struct Request {
+    session: Arc<SessionInner>,
+    header: fuse_in_header,
+    arg: Vec<u8>,
+    received: Option<Instant>,
+    background: AtomicBool,
+    state: AtomicU8,
+    // The entry tracked by the session, owning a reference of the `Arc`.
+    pending: AtomicPtr<PendingReply>,
+    device: Option<Arc<Connection>>,
+    extensions: Extensions,
+    readdirplus_fallback: bool,
+}
+
+impl Drop for Request {
+    fn drop(&mut self) {
+        // The request is never replied.
+        self.release_background();
+
+        let pending = *self.pending.get_mut();
+        if !pending.is_null() {
+            let pending = unsafe { Arc::from_raw(pending) };
+            // The unique ID may already be reused by the next request.
+            self.session.in_flight.remove(&pending);
+        }
+    }
+}
+
+/// A read-only view of a request passed to the dispatch hooks.
+///
+/// Unlike `Request`, it provides no way to reply to the request.
+pub struct RequestInfo<'a> {
+    req: &'a Request,
+}
+
+impl fmt::Debug for RequestInfo<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RequestInfo")
+            .field("unique", &self.req.unique())
+            .field("opcode", &self.req.header.opcode)
+            .finish()
+    }
+}
+
+impl RequestInfo<'_> {
+    /// Return the unique ID of the request.
+    #[inline]
+    pub fn unique(&self) -> u64 {
+        self.req.unique()
+    }
+
+    /// Return the raw opcode of the request.
+    #[inline]
+    pub fn opcode(&self) -> u32 {
+        self.req.opcode()
+    }
+
+    /// Return the inode number the request targets.
+    #[inline]
+    pub fn nodeid(&self) -> u64 {
+        self.req.nodeid()
+    }
+
+    /// Return the user ID of the calling process.
+    #[inline]
+    pub fn uid(&self) -> u32 {
+        self.req.uid()
+    }
+
+    /// Return the group ID of the calling process.
+    #[inline]
+    pub fn gid(&self) -> u32 {
+        self.req.gid()
+    }
+
+    /// Return the process ID of the calling process.
+    #[inline]
+    pub fn pid(&self) -> u32 {
+        self.req.pid()
+    }
+
+    /// Return a reference to the extensions attached to the request.
+    #[inline]
+    pub fn extensions(&self) -> &Extensions {
+        self.req.extensions()
+    }
+
+    /// Decode the argument of the request.
+    pub fn operation(&self) -> Result<Operation<'_, Data<'_>>, DecodeError> {
+        self.req.operation()
+    }
+}
+
+impl Request {
+    /// Return the unique ID of the request.
+    #[inline]
+    pub fn unique(&self) -> u64 {
+        self.header.unique
+    }
+
+    /// Return the raw opcode of the request.
+    #[inline]
+    pub fn opcode(&self) -> u32 {
+        self.header.opcode
+    }
+
+    /// Return the extensions appended to the request, which are empty unless
+    /// the kernel supports ABI 7.38 or later and has any to attach.
+    ///
+    /// The extensions known to the crate are decoded by its methods, such as
+    /// `HeaderExtensions::security_context`.
+    pub fn header_extensions(&self) -> HeaderExtensions<'_> {
+        HeaderExtensions::new(split_extensions(&self.header, &self.arg[..]).1)
+    }
+
+    /// Return whether the request has been resent by the kernel on
+    /// `Notifier::resend`.
+    ///
+    /// The kernel resends every request the filesystem has not replied yet,
+    /// including those already received, e.g. after the server has been
+    /// restored from a checkpoint. A resent request has the same unique ID as
+    /// the original one except this mark, and may be handled twice, so the
+    /// handlers of the non-idempotent operations such as `mkdir` or `unlink`
+    /// should tolerate the result of the first attempt, such as `EEXIST`.
+    #[inline]
+    pub fn is_resent(&self) -> bool {
+        self.header.unique & FUSE_UNIQUE_RESEND != 0
+    }
+
+    /// Return the inode number the request targets, i.e. `nodeid` in the header.
+    ///
+    /// This is available regardless of the operation. The inode numbers
+    /// exposed by the operations are derived from it: `ino` of the operations
+    /// on an inode, such as `Getattr::ino`, and `parent` of the operations on
+    /// a directory entry, such as `Lookup::parent`, return this value. The
+    /// exceptions are the inode numbers carried in the argument, e.g.
+    /// `Link::ino`, `Rename::newparent` or `CopyFileRange::ino_out`. It is zero
+    /// for the requests not bound to an inode, such as `interrupt` and the
+    /// batched `forget`.
+    #[inline]
+    pub fn nodeid(&self) -> u64 {
+        self.header.nodeid
+    }
+
+    /// Return the user ID of the calling process.
+    #[inline]
+    pub fn uid(&self) -> u32 {
+        self.header.uid
+    }
+
+    /// Return the group ID of the calling process.
+    #[inline]
+    pub fn gid(&self) -> u32 {
+        self.header.gid
+    }
+
+    /// Return the process ID of the calling process.
+    #[inline]
+    pub fn pid(&self) -> u32 {
+        self.header.pid
+    }
+
+    /// Return the raw bytes of the request header, i.e. `fuse_in_header`.
+    ///
+    /// Together with `raw_body`, this is the request message as read from
+    /// the FUSE device, which can be forwarded verbatim, e.g. by a proxy.
+    /// The bytes are borrowed from the buffer owned by this request.
+    #[inline]
+    pub fn raw_header(&self) -> &[u8] {
+        self.header.as_bytes()
+    }
+
+    /// Return the raw bytes of the request body following the header.
+    ///
+    /// The length of the returned slice is `len` in the header minus
+    /// the size of the header.
+    #[inline]
+    pub fn raw_body(&self) -> &[u8] {
+        &self.arg[..]
+    }
+
+    /// Return a reference to the extensions attached to this request.
+    #[inline]
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// Return a mutable reference to the extensions attached to this request.
+    #[inline]
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    /// Take the continuation state saved for the directory handle of this
+    /// `readdir` or `readdirplus` request.
+    ///
+    /// The state is returned only if it was saved for the offset requested
+    /// by the kernel, i.e. the request continues the stream from where the
+    /// previous reply ended. Otherwise, such as after `rewinddir(3)` or
+    /// `seekdir(3)`, the stale state is discarded and the handler has to seek
+    /// by the offset as usual. It is also `None` for the other requests or if
+    /// the state is not of type `T`.
+    ///
+    /// The states are kept per directory and handle, and not at all for the
+    /// handle `0`, which cannot tell the streams apart; it is used by the
+    /// filesystems without directory handles and by the kernel after the
+    /// zero-message `opendir` (see `Session::no_opendir_support`).
+    pub fn take_readdir_state<T>(&self) -> Option<T>
+    where
+        T: Send + 'static,
+    {
+        let (key, offset) = self.readdir_key()?;
+        let state = self.session.readdir_states.lock().unwrap().remove(&key)?;
+        if state.offset != offset {
+            return None;
+        }
+        state.value.downcast().ok().map(|value| *value)
+    }
+
+    /// Save the continuation state of the directory stream, such as an
+    /// iterator over the backend, for the next `readdir` request on the same
+    /// directory handle.
+    ///
+    /// The `offset` must be the one of the last entry in the reply to this
+    /// request, from which the kernel continues reading. This makes reading
+    /// a huge directory linear even if the backend cannot seek by the offset.
+    /// The state replaces the previous one, and is dropped when the handle is
+    /// released by `releasedir`. It is ignored for the other requests.
+    pub fn save_readdir_state<T>(&self, offset: u64, state: T)
+    where
+        T: Send + 'static,
+    {
+        if let Some((key, _)) = self.readdir_key() {
+            self.session.readdir_states.lock().unwrap().insert(
+                key,
+                ReaddirState {
+                    offset,
+                    value: Box::new(state),
+                },
+            );
+        }
+    }
+
+    /// Return the key of the saved directory stream and the offset requested
+    /// by this `readdir` or `readdirplus` request.
+    fn readdir_key(&self) -> Option<((u64, u64), u64)> {
+        let read_in = self.readdir_in()?;
+        if read_in.fh == 0 || self.session.zero_message_opendir.load(Ordering::Acquire) {
+            return None;
+        }
+        Some(((self.header.nodeid, read_in.fh), read_in.offset))
+    }
+
+    fn readdir_in(&self) -> Option<&fuse_read_in> {
+        match fuse_opcode::try_from(self.header.opcode).ok()? {
+            fuse_opcode::FUSE_READDIR | fuse_opcode::FUSE_READDIRPLUS => {
+                Decoder::new(&self.arg[..]).fetch().ok()
+            }
+            _ => None,
+        }
+    }
+
+    /// Return the token cancelled when the kernel interrupts this request.
+    ///
+    /// The token is cancelled as soon as the session receives the matching
+    /// `interrupt` request, which is still returned from `next_request`. An
+    /// `interrupt` request arriving before the request itself, such as when
+    /// they are received by different threads, is replied with `EAGAIN`
+    /// instead, so that the kernel sends it again.
+    /// A handler doing slow work can check it periodically and give up with
+    /// `EINTR`. The tokens of the requests not expecting a reply, such as
+    /// `forget`, or taken after the reply are never cancelled.
+    ///
+    /// The request is tracked by the session from the first call, which also
+    /// makes `Session::shutdown` reply to it.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        CancellationToken {
+            pending: self.track(),
+        }
+    }
+
+    /// Decode the argument of this request.
+    pub fn operation(&self) -> Result<Operation<'_, Data<'_>>, DecodeError> {
+        if self.session.exited() {
+            return Ok(Operation::unknown());
+        }
+
+        let (arg, data) = match fuse_opcode::try_from(self.header.opcode).ok() {
+            Some(fuse_opcode::FUSE_WRITE) | Some(fuse_opcode::FUSE_NOTIFY_REPLY) => {
+                self.arg.split_at(mem::size_of::<fuse_write_in>())
+            }
+            _ => (&self.arg[..], &[] as &[_]),
+        };
+
+        let mut op = Operation::decode(&self.header, arg, Data { data })?;
+        if let Operation::Readdir(ref mut op) = op {
+            if self.is_readdirplus_fallback() {
+                op.set_mode(ReaddirMode::Normal);
+            }
+        }
+        Ok(op)
+    }
+
+    pub fn reply<T>(&self, arg: T) -> io::Result<()>
+    where
+        T: Bytes,
+    {
+        if !self.claim_reply() {
+            return Ok(());
+        }
+        if self.is_readdirplus_fallback() {
+            let mut collect = CollectBytes(Vec::with_capacity(arg.size()));
+            arg.fill_bytes(&mut collect);
+            let size = match Decoder::new(&self.arg[..]).fetch::<fuse_read_in>() {
+                Ok(read_in) => read_in.size as usize,
+                Err(..) => usize::MAX,
+            };
+            let plus = dirents_to_plus(&collect.0, size);
+            self.send_reply(Reply::new(self.unique(), 0, plus))?;
+            self.complete(0);
+            return Ok(());
+        }
+        self.send_reply(Reply::new(self.unique(), 0, arg))?;
+        self.complete(0);
+        Ok(())
+    }
+
+    /// Reply to a `getattr` or `setattr` request with the attributes of the inode.
+    ///
+    /// In debug builds, this panics if the inode number in the attributes
+    /// differs from the one the request is for, which is usually a sign of
+    /// stat-ing the wrong inode. The check is omitted in release builds.
+    ///
+    /// If the validity timeout is not set by `AttrOut::ttl`, the one supplied
+    /// by the policy registered with `KernelConfig::attr_ttl` is used. If the attribute
+    /// caching is disabled by `KernelConfig::disable_attr_cache`, the timeout
+    /// is always zero.
+    pub fn reply_attr(&self, mut out: AttrOut) -> io::Result<()> {
+        debug_assert!(
+            !matches!(
+                fuse_opcode::try_from(self.header.opcode).ok(),
+                Some(fuse_opcode::FUSE_GETATTR) | Some(fuse_opcode::FUSE_SETATTR)
+            ) || out.get_ino() == self.header.nodeid,
+            "the inode number in the attributes ({}) does not match the request ({})",
+            out.get_ino(),
+            self.header.nodeid,
+        );
+        if let Some(ref attr_ttl) = self.session.attr_ttl {
+            if !out.has_ttl() {
+                if let Some(ttl) = attr_ttl(out.get_ino(), out.get_mode()) {
+                    out.ttl(ttl);
+                }
+            }
+        }
+        if self.session.disable_attr_cache {
+            out.ttl(Duration::from_secs(0));
+        }
+        if !self.session.supports_attr_flags() {
+            out.clear_attr_flags();
+        }
+        self.reply(out)
+    }
+
+    /// Reply to a `getattr` or `setattr` request with the attributes already
+    /// encoded as `fuse_attr`, such as those cached by the filesystem.
+    ///
+    /// The bytes are sent as is, without being copied, following the validity
+    /// timeout, where `None` means that the attributes are not cached. Unlike
+    /// `reply_attr`, neither the policy of `KernelConfig::attr_ttl` nor the
+    /// clearing of the unsupported attribute flags applies, while the timeout
+    /// is still zeroed by `KernelConfig::disable_attr_cache`.
+    ///
+    /// In debug builds, this panics if the length of `attr` differs from the
+    /// size of `fuse_attr`.
+    pub fn reply_attr_raw(&self, attr: &[u8], ttl: impl Into<Option<Duration>>) -> io::Result<()> {
+        debug_assert_eq!(
+            attr.len(),
+            mem::size_of::<fuse_attr>(),
+            "the length of the encoded attributes is not the size of fuse_attr"
+        );
+        let mut out = AttrOut::default();
+        if !self.session.disable_attr_cache {
+            out.ttl(ttl);
+        }
+        let prefix_len = mem::size_of::<fuse_attr_out>() - mem::size_of::<fuse_attr>();
+        let out = out.as_attr_out();
+        self.reply((&out.as_bytes()[..prefix_len], attr))
+    }
+
+    /// Reply to a request creating or looking up an entry, such as `lookup`
+    /// or `mkdir`.
+    ///
+    /// The attribute flags (see `FileAttr::attr_flags`) are cleared if the
+    /// kernel does not support them.
+    pub fn reply_entry(&self, mut out: EntryOut) -> io::Result<()> {
+        if !self.session.supports_attr_flags() {
+            out.clear_attr_flags();
+        }
+        self.reply(out)
+    }
+
+    /// Reply to an `open` or `opendir` request with the opened handle.
+    ///
+    /// The flags not supported by the kernel, such as `OpenOut::no_flush`
+    /// and `OpenOut::parallel_direct_writes`, are omitted from the reply.
+    pub fn reply_open(&self, mut out: OpenOut) -> io::Result<()> {
+        if !self.session.supports_no_flush() {
+            out.clear_no_flush();
+        }
+        if !self.session.supports_parallel_direct_writes() {
+            out.clear_parallel_direct_writes();
+        }
+        if !self.session.supports_passthrough() {
+            out.clear_passthrough();
+        }
+        self.reply(out)
+    }
+
+    /// Reply to a `statx` request with the extended attributes.
+    ///
+    /// As with `reply_attr`, the policy of `KernelConfig::attr_ttl` supplies
+    /// the validity timeout if it is not set by `StatxOut::ttl`, and the
+    /// timeout is reset to zero if `KernelConfig::disable_attr_cache` is set.
+    pub fn reply_statx(&self, mut out: StatxOut) -> io::Result<()> {
+        if let Some(ref attr_ttl) = self.session.attr_ttl {
+            if !out.has_ttl() {
+                if let Some(ttl) = attr_ttl(out.get_ino(), out.get_mode()) {
+                    out.ttl(ttl);
+                }
+            }
+        }
+        if self.session.disable_attr_cache {
+            out.ttl(Duration::from_secs(0));
+        }
+        self.reply(out)
+    }
+
+    /// Reply to a `write` request with the number of bytes written.
+    ///
+    /// In debug builds, this panics if the replied size exceeds the length
+    /// of the data sent by the kernel, which would corrupt the accounting of
+    /// the dirty pages in the kernel. The check is omitted in release builds.
+    /// A smaller size is replied as a short write; see `WriteOut::size`.
+    pub fn reply_write(&self, out: WriteOut) -> io::Result<()> {
+        if cfg!(debug_assertions) && self.header.opcode == fuse_opcode::FUSE_WRITE as u32 {
+            if let Ok(arg) = Decoder::new(&self.arg[..]).fetch::<fuse_write_in>() {
+                assert!(
+                    out.get_size() <= arg.size,
+                    "the replied size ({}) exceeds the written data ({})",
+                    out.get_size(),
+                    arg.size,
+                );
+            }
+        }
+        self.reply(out)
+    }
+
+    /// Reply with the data split across multiple buffers, e.g. for a `read`
+    /// request spanning several cache blocks.
+    ///
+    /// The reply header and the chunks are written to the device by a single
+    /// vectored write, without concatenating the chunks. If there are more
+    /// chunks than the kernel accepts in a vectored write (`UIO_MAXIOV`),
+    /// they are copied into a single buffer instead.
+    pub fn reply_data_chunks(&self, chunks: &[&[u8]]) -> io::Result<()> {
+        if chunks.len() < libc::UIO_MAXIOV as usize {
+            self.reply(chunks)
+        } else {
+            self.reply(chunks.concat())
+        }
+    }
+
+    /// Reply to a `readlink` request with the target of the symbolic link.
+    ///
+    /// If the target is longer than `KernelConfig::max_symlink_len`, the
+    /// request is replied with `ENAMETOOLONG` instead, since the kernel would
+    /// reject or truncate it.
+    pub fn reply_readlink(&self, target: impl AsRef<OsStr>) -> io::Result<()> {
+        let target = target.as_ref();
+        if target.len() > self.session.max_symlink_len {
+            return self.reply_error(libc::ENAMETOOLONG);
+        }
+        self.reply(target)
+    }
+
+    /// Reply to a `getxattr` or `listxattr` request with the size of the value.
+    ///
+    /// This is the reply to the request whose `size` is zero, i.e. a query
+    /// of the buffer size. The values larger than `XATTR_SIZE_MAX` cannot be
+    /// transferred by the kernel, so the request is replied with `E2BIG`
+    /// instead.
+    pub fn reply_xattr(&self, out: XattrOut) -> io::Result<()> {
+        if out.get_size() > XATTR_SIZE_MAX {
+            return self.reply_error(libc::E2BIG);
+        }
+        self.reply(out)
+    }
+
+    /// Reply to a `getxattr` or `listxattr` request with the value.
+    ///
+    /// The request is replied with `E2BIG` if the value is larger than
+    /// `XATTR_SIZE_MAX`, or with `ERANGE` if it does not fit in the buffer
+    /// size specified by the request.
+    pub fn reply_xattr_value(&self, value: &[u8]) -> io::Result<()> {
+        if value.len() > XATTR_SIZE_MAX as usize {
+            return self.reply_error(libc::E2BIG);
+        }
+        match fuse_opcode::try_from(self.header.opcode).ok() {
+            Some(fuse_opcode::FUSE_GETXATTR) | Some(fuse_opcode::FUSE_LISTXATTR) => {
+                if let Ok(arg) = Decoder::new(&self.arg[..]).fetch::<fuse_getxattr_in>() {
+                    if value.len() > arg.size as usize {
+                        return self.reply_error(libc::ERANGE);
+                    }
+                }
+            }
+            _ => (),
+        }
+        self.reply(value)
+    }
+
+    /// Reply to a `listxattr` request with the list of the attribute names.
+    ///
+    /// If the request queries the buffer size, i.e. its `size` is zero, the
+    /// length of the list is replied as by `reply_xattr`. Otherwise the list
+    /// itself is replied as by `reply_xattr_value`.
+    pub fn reply_xattr_names(&self, names: &XattrNames) -> io::Result<()> {
+        let size = match Decoder::new(&self.arg[..]).fetch::<fuse_getxattr_in>() {
+            Ok(arg) => arg.size,
+            Err(..) => return self.reply_error(libc::EINVAL),
+        };
+        if size == 0 {
+            let mut out = XattrOut::default();
+            XattrOut::size(&mut out, names.total_len().try_into().unwrap_or(u32::MAX));
+            self.reply_xattr(out)
+        } else {
+            self.reply_xattr_value(names.as_bytes())
+        }
+    }
+
+    /// Reply to a `canonical_path` request with the resolved path.
+    ///
+    /// The path is sent with a terminating NUL, as the kernel reads it as a C
+    /// string. If it does not fit in `PATH_MAX`, the request is replied with
+    /// `ENAMETOOLONG` instead.
+    pub fn reply_canonical_path(&self, path: impl AsRef<OsStr>) -> io::Result<()> {
+        let path = path.as_ref();
+        if path.len() > MAX_SYMLINK_LEN {
+            return self.reply_error(libc::ENAMETOOLONG);
+        }
+        self.reply((path, &b"\0"[..]))
+    }
+
+    /// Reply with the raw bytes of the reply body, e.g. relayed from another server.
+    pub(crate) fn reply_raw(&self, code: i32, body: &[u8]) -> io::Result<()> {
+        if !self.claim_reply() {
+            return Ok(());
+        }
+        self.send_reply(Reply::new(self.unique(), code, body))?;
+        self.complete(code);
+        Ok(())
+    }
+
+    /// Reply to the request with an error number.
+    ///
+    /// The code is either a raw positive error number, such as `libc::ENOENT`,
+    /// or an `Errno`.
+    pub fn reply_error(&self, code: impl Into<i32>) -> io::Result<()> {
+        let code = code.into();
+        if !self.claim_reply() {
+            return Ok(());
+        }
+        if code == libc::ENOSYS && self.header.opcode == fuse_opcode::FUSE_READDIRPLUS as u32 {
+            tracing::debug!("fall back to readdir for the following readdirplus requests");
+            self.session
+                .readdirplus_fallback
+                .store(true, Ordering::Release);
+        }
+        if code == libc::ENOSYS
+            && self.header.opcode == fuse_opcode::FUSE_OPENDIR as u32
+            && self.session.init_out.flags & FUSE_NO_OPENDIR_SUPPORT != 0
+        {
+            // The kernel no longer sends `opendir` nor `releasedir`.
+            self.session
+                .zero_message_opendir
+                .store(true, Ordering::Release);
+        }
+        self.send_reply(Reply::new(self.unique(), code, ()))?;
+        self.complete(code);
+        Ok(())
+    }
+
+    /// Reply to the request whose operation is not implemented by the filesystem.
+    ///
+    /// The error number is `ENOSYS` unless another one is configured for the
+    /// family of the operation by `KernelConfig::unsupported_error`.
+    pub fn reply_unsupported(&self) -> io::Result<()> {
+        let errno = Category::ALL
+            .iter()
+            .filter(|category| category.contains(self.header.opcode))
+            .find_map(|category| {
+                self.session
+                    .unsupported_errors
+                    .iter()
+                    .find(|(c, _)| c == category)
+                    .map(|&(_, errno)| errno)
+            })
+            .unwrap_or(libc::ENOSYS);
+        self.reply_error(errno)
+    }
+
+    /// Reply to a `read` request with the contents of a file.
+    ///
+    /// At most `len` bytes starting at `offset` are transferred from the file
+    /// referred by `fd`, and fewer bytes are replied if the end of file is
+    /// reached. If `splice_write` has been negotiated, the data is moved into
+    /// the FUSE device by `splice(2)` without copying it through the userspace.
+    /// Otherwise, the data is read into a temporary buffer and sent as usual.
+    ///
+    /// If the transfer fails midway, e.g. reading the file, `EIO` is replied
+    /// so that the request does not hang, and the error is returned.
+    pub fn reply_splice(&self, fd: RawFd, offset: u64, len: usize) -> io::Result<()> {
+        let header_len = mem::size_of::<fuse_out_header>();
+
+        let (pipe, n) = match self.fill_splice(fd, offset, len) {
+            Ok(Spliced::Pipe(pipe, n)) => (pipe, n),
+            Ok(Spliced::Buffer(buf)) => return self.reply(&buf[..]),
+            Err(err) => {
+                tracing::debug!("failed to read the file contents: {}", err);
+                let _ = self.reply_error(libc::EIO);
+                return Err(err);
+            }
+        };
+
+        let flags = if self.session.init_out.flags & FUSE_SPLICE_MOVE != 0 {
+            libc::SPLICE_F_MOVE
+        } else {
+            0
+        };
+        if !self.claim_reply() {
+            return Ok(());
+        }
+        if let Err(err) = self.conn().splice_from(&pipe, header_len + n, flags) {
+            // The reply has been claimed, so write the error here instead of
+            // `reply_error`, which would refuse it.
+            tracing::debug!("failed to splice the reply: {}", err);
+            let _ = self.send_reply(Reply::new(self.unique(), libc::EIO, ()));
+            self.complete(libc::EIO);
+            return Err(err);
+        }
+        self.complete(0);
+        Ok(())
+    }
+
+    /// Read the contents of the file to be replied by `reply_splice`, before
+    /// the reply is claimed.
+    fn fill_splice(&self, fd: RawFd, offset: u64, len: usize) -> io::Result<Spliced> {
+        let header_len = mem::size_of::<fuse_out_header>();
+
+        let pipe = match Pipe::with_capacity(header_len + len) {
+            Ok(pipe) => pipe,
+            Err(err) => {
+                tracing::debug!("failed to create the pipe for splice: {}", err);
+                let mut buf = vec![0u8; len];
+                let n = read_at(fd, &mut buf[..], offset)?;
+                buf.truncate(n);
+                return Ok(Spliced::Buffer(buf));
+            }
+        };
+
+        let header = fuse_out_header {
+            len: (header_len + len) as u32,
+            error: 0,
+            unique: self.unique(),
+        };
+        pipe.write_all(header.as_bytes())?;
+        let n = pipe.splice_from(fd, offset as i64, len)?;
+
+        if self.session.init_out.flags & FUSE_SPLICE_WRITE != 0 && n == len {
+            Ok(Spliced::Pipe(pipe, n))
+        } else {
+            // The header in the pipe does not match the actual length
+            // of the data, or splicing into the device is not allowed.
+            let mut buf = vec![0u8; header_len + n];
+            pipe.read_exact(&mut buf[..])?;
+            buf.drain(..header_len);
+            Ok(Spliced::Buffer(buf))
+        }
+    }
+
+    /// Reply to a `read` request with the data in a memory-mapped region,
+    /// e.g. of the file backing the content.
+    ///
+    /// If `splice_write` has been negotiated, the pages of `data` are mapped
+    /// into a pipe by `vmsplice(2)` and spliced into the FUSE device, so the
+    /// data is copied only once, by the kernel into the pages of the request.
+    /// Otherwise, or if mapping or splicing the pages fails, `data` is passed
+    /// to `writev(2)` as is, which does not copy it in the userspace either.
+    ///
+    /// The reply is complete when this returns, so `data` only has to outlive
+    /// the call, as the borrow ensures. However, the mapping of a file that is
+    /// truncated by another process meanwhile raises `SIGBUS` on access, both
+    /// here and anywhere else; the filesystem must prevent that, e.g. by
+    /// serving only the immutable files or holding a lease.
+    pub fn reply_mapped(&self, data: &[u8]) -> io::Result<()> {
+        let init_flags = self.session.init_out.flags;
+        let header_len = mem::size_of::<fuse_out_header>();
+        if init_flags & FUSE_SPLICE_WRITE == 0 || data.is_empty() {
+            return self.reply(data);
+        }
+
+        let pipe = match Pipe::with_capacity(header_len + data.len()) {
+            Ok(pipe) => pipe,
+            Err(err) => {
+                tracing::debug!("failed to create the pipe for vmsplice: {}", err);
+                return self.reply(data);
+            }
+        };
+
+        let header = fuse_out_header {
+            len: (header_len + data.len()) as u32,
+            error: 0,
+            unique: self.unique(),
+        };
+        let res = pipe
+            .write_all(header.as_bytes())
+            .and_then(|()| pipe.vmsplice_all(data));
+        if let Err(err) = res {
+            tracing::debug!("failed to vmsplice the reply: {}", err);
+            return self.reply(data);
+        }
+
+        if !self.claim_reply() {
+            return Ok(());
+        }
+        // The pages of user memory cannot be moved, so SPLICE_F_MOVE is not set.
+        if let Err(err) = self.conn().splice_from(&pipe, header_len + data.len(), 0) {
+            // The reply has been claimed, so write it here instead of `reply`.
+            tracing::debug!("failed to splice the reply: {}", err);
+            self.send_reply(Reply::new(self.unique(), 0, data))?;
+        }
+        self.complete(0);
+        Ok(())
+    }
+
+    /// Write the reply to the device, or queue it if the device is not
+    /// writable and `KernelConfig::reply_queue` is enabled.
+    fn send_reply<T>(&self, bytes: T) -> io::Result<()>
+    where
+        T: Bytes,
+    {
+        match self.session.reply_queue {
+            Some(ref queue) => self.session.send_queued(queue, self.device.as_ref(), bytes),
+            None => write_reply(self.conn(), bytes),
+        }
+    }
+
+    /// Return whether the request is a `readdirplus` served as plain `readdir`,
+    /// see `Session::readdirplus_fallback`.
+    #[inline]
+    fn is_readdirplus_fallback(&self) -> bool {
+        self.readdirplus_fallback
+    }
+
+    #[inline]
+    fn complete(&self, code: i32) {
+        self.release_background();
+        if let (Some(metrics), Some(received)) = (&self.session.metrics, self.received) {
+            metrics(self.header.opcode, received.elapsed(), code);
+        }
+    }
+
+    /// Return the device the request has been received from, to which
+    /// the reply must be sent.
+    #[inline]
+    fn conn(&self) -> &Connection {
+        self.device.as_deref().unwrap_or(&self.session.conn)
+    }
+
+    /// Return whether the reply can be sent, i.e. the request has been replied
+    /// neither by the handler nor by the session due to
+    /// `KernelConfig::op_timeout` or `Session::shutdown`.
+    fn claim_reply(&self) -> bool {
+        let state = match self.state.compare_exchange(
+            REPLY_PENDING,
+            REPLIED,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        ) {
+            Err(REPLY_TRACKED) => self.tracked().state.compare_exchange(
+                REPLY_PENDING,
+                REPLIED,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ),
+            state => state,
+        };
+        match state {
+            Ok(..) => true,
+            Err(REPLIED_BY_SESSION) => {
+                tracing::warn!(
+                    "drop the reply to the request already replied by the session (unique={})",
+                    self.unique()
+                );
+                false
+            }
+            Err(..) => {
+                tracing::warn!(
+                    "drop the reply to the request already replied (unique={})",
+                    self.unique()
+                );
+                false
+            }
+        }
+    }
+
+    /// Track the request by the session, so that it is cancelled by the
+    /// `interrupt` requests and replied on `op_timeout` or `shutdown`.
+    ///
+    /// Return `None` if the request expects no reply or has been replied.
+    fn track(&self) -> Option<Arc<PendingReply>> {
+        if !expects_reply(self.header.opcode) {
+            return None;
+        }
+        match self.state.compare_exchange(
+            REPLY_PENDING,
+            REPLY_TRACKED,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        ) {
+            Ok(..) => {
+                let pending = Arc::new(PendingReply {
+                    unique: self.unique(),
+                    opcode: self.header.opcode,
+                    received: self.received.unwrap_or_else(Instant::now),
+                    state: AtomicU8::new(REPLY_PENDING),
+                    cancelled: AtomicBool::new(false),
+                    device: self.device.clone(),
+                });
+                self.session.in_flight.insert(pending.clone());
+                if self.session.op_timeout.is_some() {
+                    self.session
+                        .pending_replies
+                        .lock()
+                        .unwrap()
+                        .push(pending.clone());
+                }
+                self.pending
+                    .store(Arc::into_raw(pending.clone()) as *mut _, Ordering::Release);
+                Some(pending)
+            }
+            Err(REPLY_TRACKED) => Some(self.tracked()),
+            Err(..) => None,
+        }
+    }
+
+    /// Return the entry of the request tracked by the session.
+    ///
+    /// The entry is published by `track` right after the state is switched,
+    /// so another thread observing the switch waits for it here.
+    fn tracked(&self) -> Arc<PendingReply> {
+        loop {
+            let pending = self.pending.load(Ordering::Acquire);
+            if !pending.is_null() {
+                // The reference owned by `self.pending` is kept.
+                let pending = mem::ManuallyDrop::new(unsafe { Arc::from_raw(pending) });
+                return Arc::clone(&pending);
+            }
+            std::thread::yield_now();
+        }
+    }
+
+    #[inline]
+    fn release_background(&self) {
+        if self.background.swap(false, Ordering::SeqCst) {
+            self.session.background.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// A type map of the values attached to a `Request`.
+///
+/// The extensions are used to pass arbitrary context, such as the identity
+/// resolved by an authentication layer, from the dispatch code to the handlers.
+/// At most one value is stored for each type.
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions").finish()
+    }
+}
+
+impl Extensions {
+    /// Create an empty extensions.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            map: HashMap::new(),
+        }
+    }
+
+    /// Insert a value, returning the previous one of the same type if any.
+    pub fn insert<T>(&mut self, value: T) -> Option<T>
+    where
+        T: Send + Sync + 'static,
+    {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|prev| prev.downcast().ok().map(|prev| *prev))
+    }
+
+    /// Return a reference to the value of type `T`.
+    pub fn get<T>(&self) -> Option<&T>
+    where
+        T: Send + Sync + 'static,
+    {
+        self.map
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+    }
+
+    /// Return a mutable reference to the value of type `T`.
+    pub fn get_mut<T>(&mut self) -> Option<&mut T>
+    where
+        T: Send + Sync + 'static,
+    {
+        self.map
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_mut())
+    }
+
+    /// Remove the value of type `T` and return it.
+    pub fn remove<T>(&mut self) -> Option<T>
+    where
+        T: Send + Sync + 'static,
+    {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast().ok().map(|value| *value))
+    }
+
+    /// Remove all the values.
+    #[inline]
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+}
+
+/// The remaining part of request message.
+pub struct Data<'op> {
+    data: &'op [u8],
+}
+
+impl fmt::Debug for Data<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Data").finish()
+    }
+}
+
+impl<'op> io::Read for Data<'op> {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        io::Read::read(&mut self.data, buf)
+    }
+
+    #[inline]
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        io::Read::read_vectored(&mut self.data, bufs)
+    }
+}
+
+impl<'op> BufRead for Data<'op> {
+    #[inline]
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        io::BufRead::fill_buf(&mut self.data)
+    }
+
+    #[inline]
+    fn consume(&mut self, amt: usize) {
+        io::BufRead::consume(&mut self.data, amt)
+    }
+}
+
+// ==== Notifier ====
+
+#[derive(Clone)]
+pub struct Notifier {
+    session: Arc<SessionInner>,
+}
+
+impl AsRawFd for Notifier {
+    fn as_raw_fd(&self) -> RawFd {
+        self.session.conn.as_raw_fd()
+    }
+}
+
+impl Notifier {
+    /// Wait until a notification can be sent without blocking.
+    ///
+    /// Return `false` if `timeout` has elapsed before the FUSE device becomes
+    /// writable. A zero timeout checks the readiness without waiting, which is
+    /// useful to apply backpressure when `Session::set_nonblocking` is enabled
+    /// and a notification has failed with `io::ErrorKind::WouldBlock`.
+    pub fn poll_writable(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        self.session.conn.poll_writable(timeout)
+    }
+
+    /// Register a backing file of the passthrough, as `Session::open_backing`.
+    ///
+    /// This allows the handlers of `open` to register the backing files.
+    pub fn open_backing(&self, fd: RawFd) -> io::Result<u32> {
+        self.session.conn.backing_open(fd)
+    }
+
+    /// Unregister a backing file, as `Session::close_backing`.
+    pub fn close_backing(&self, backing_id: u32) -> io::Result<()> {
+        self.session.conn.backing_close(backing_id)
+    }
+
+    /// Write the notification, or queue it if `KernelConfig::reply_queue`
+    /// is enabled.
+    fn send<T>(&self, bytes: T) -> io::Result<()>
+    where
+        T: Bytes,
+    {
+        match self.session.reply_queue {
+            Some(ref queue) => self.session.send_queued(queue, None, bytes),
+            None => write_bytes(&self.session.conn, bytes),
+        }
+    }
+
+    /// Notify the cache invalidation about an inode to the kernel.
+    ///
+    /// The attributes of the inode are always invalidated. In addition, the
+    /// cached data in the range of `len` bytes from `off` is dropped, where
+    /// a zero `len` means up to the end of file. A negative `off` invalidates
+    /// the attributes only and keeps the cached data, which is useful along
+    /// with `KernelConfig::explicit_inval_data` because the kernel then never
+    /// drops the data on its own.
+    pub fn inval_inode(&self, ino: u64, off: i64, len: i64) -> io::Result<()> {
+        let total_len = u32::try_from(
+            mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_notify_inval_inode_out>(),
+        )
+        .unwrap();
+
+        return self.send(InvalInode {
+            header: fuse_out_header {
+                len: total_len,
+                error: fuse_notify_code::FUSE_NOTIFY_INVAL_INODE as i32,
+                unique: 0,
+            },
+            arg: fuse_notify_inval_inode_out { ino, off, len },
+        });
+
+        struct InvalInode {
+            header: fuse_out_header,
+            arg: fuse_notify_inval_inode_out,
+        }
+        impl Bytes for InvalInode {
+            fn size(&self) -> usize {
+                self.header.len as usize
+            }
+
+            fn count(&self) -> usize {
+                2
+            }
+
+            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
+                dst.put(self.header.as_bytes());
+                dst.put(self.arg.as_bytes());
+            }
+        }
+    }
+
+    /// Ask the kernel to resend the requests not replied yet.
+    ///
+    /// The resent requests are told by `Request::is_resent`. This requires the
+    /// support of the kernel, see `Session::resend_support`.
+    pub fn resend(&self) -> io::Result<()> {
+        return self.send(Resend {
+            header: fuse_out_header {
+                len: u32::try_from(mem::size_of::<fuse_out_header>()).unwrap(),
+                error: fuse_notify_code::FUSE_NOTIFY_RESEND as i32,
+                unique: 0,
+            },
+        });
+
+        struct Resend {
+            header: fuse_out_header,
+        }
+        impl Bytes for Resend {
+            fn size(&self) -> usize {
+                self.header.len as usize
+            }
+
+            fn count(&self) -> usize {
+                1
+            }
+
+            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
+                dst.put(self.header.as_bytes());
+            }
+        }
+    }
+
+    /// Notify the invalidation about a directory entry to the kernel.
+    pub fn inval_entry<T>(&self, parent: u64, name: T) -> io::Result<()>
+    where
+        T: AsRef<OsStr>,
+    {
+        let namelen = u32::try_from(name.as_ref().len()).expect("provided name is too long");
+
+        let total_len = u32::try_from(
+            mem::size_of::<fuse_out_header>()
+                + mem::size_of::<fuse_notify_inval_entry_out>()
+                + name.as_ref().len()
+                + 1,
+        )
+        .unwrap();
+
+        return self.send(InvalEntry {
+            header: fuse_out_header {
+                len: total_len,
+                error: fuse_notify_code::FUSE_NOTIFY_INVAL_ENTRY as i32,
+                unique: 0,
+            },
+            arg: fuse_notify_inval_entry_out {
+                parent,
+                namelen,
+                padding: 0,
+            },
+            name,
+        });
+
+        struct InvalEntry<T>
+        where
+            T: AsRef<OsStr>,
+        {
+            header: fuse_out_header,
+            arg: fuse_notify_inval_entry_out,
+            name: T,
+        }
+        impl<T> Bytes for InvalEntry<T>
+        where
+            T: AsRef<OsStr>,
+        {
+            fn size(&self) -> usize {
+                self.header.len as usize
+            }
+
+            fn count(&self) -> usize {
+                4
+            }
+
+            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
+                dst.put(self.header.as_bytes());
+                dst.put(self.arg.as_bytes());
+                dst.put(self.name.as_ref().as_bytes());
+                dst.put(b"\0"); // null terminator
+            }
+        }
+    }
+
+    /// Notify the invalidation about a directory entry to the kernel.
+    ///
+    /// The role of this notification is similar to `notify_inval_entry`.
+    /// Additionally, when the provided `child` inode matches the inode
+    /// in the dentry cache, the inotify will inform the deletion to
+    /// watchers if exists.
+    pub fn delete<T>(&self, parent: u64, child: u64, name: T) -> io::Result<()>
+    where
+        T: AsRef<OsStr>,
+    {
+        let namelen = u32::try_from(name.as_ref().len()).expect("provided name is too long");
+
+        let total_len = u32::try_from(
+            mem::size_of::<fuse_out_header>()
+                + mem::size_of::<fuse_notify_delete_out>()
+                + name.as_ref().len()
+                + 1,
+        )
+        .expect("payload is too long");
+
+        return self.send(Delete {
+            header: fuse_out_header {
+                len: total_len,
+                error: fuse_notify_code::FUSE_NOTIFY_DELETE as i32,
+                unique: 0,
+            },
+            arg: fuse_notify_delete_out {
+                parent,
+                child,
+                namelen,
+                padding: 0,
+            },
+            name,
+        });
+
+        struct Delete<T>
+        where
+            T: AsRef<OsStr>,
+        {
+            header: fuse_out_header,
+            arg: fuse_notify_delete_out,
+            name: T,
+        }
+        impl<T> Bytes for Delete<T>
+        where
+            T: AsRef<OsStr>,
+        {
+            fn size(&self) -> usize {
+                self.header.len as usize
+            }
+
+            fn count(&self) -> usize {
+                4
+            }
+
+            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
+                dst.put(self.header.as_bytes());
+                dst.put(self.arg.as_bytes());
+                dst.put(self.name.as_ref().as_bytes());
+                dst.put(b"\0"); // null terminator
+            }
+        }
+    }
+
+    /// Push the data in an inode for updating the kernel cache.
+    pub fn store<T>(&self, ino: u64, offset: u64, data: T) -> io::Result<()>
+    where
+        T: Bytes,
+    {
+        let size = u32::try_from(data.size()).expect("provided data is too large");
+
+        let total_len = u32::try_from(
+            mem::size_of::<fuse_out_header>()
+                + mem::size_of::<fuse_notify_store_out>()
+                + data.size(),
+        )
+        .expect("payload is too long");
+
+        return self.send(Store {
+            header: fuse_out_header {
+                len: total_len,
+                error: fuse_notify_code::FUSE_NOTIFY_STORE as i32,
+                unique: 0,
+            },
+            arg: fuse_notify_store_out {
+                nodeid: ino,
+                offset,
+                size,
+                padding: 0,
+            },
+            data,
+        });
+
+        struct Store<T>
+        where
+            T: Bytes,
+        {
+            header: fuse_out_header,
+            arg: fuse_notify_store_out,
+            data: T,
+        }
+        impl<T> Bytes for Store<T>
+        where
+            T: Bytes,
+        {
+            fn size(&self) -> usize {
+                self.header.len as usize
+            }
+
+            fn count(&self) -> usize {
+                2 + self.data.count()
+            }
+
+            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
+                dst.put(self.header.as_bytes());
+                dst.put(self.arg.as_bytes());
+                self.data.fill_bytes(dst);
+            }
+        }
+    }
+
+    /// Retrieve data in an inode from the kernel cache.
+    pub fn retrieve(&self, ino: u64, offset: u64, size: u32) -> io::Result<u64> {
+        let total_len = u32::try_from(
+            mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_notify_retrieve_out>(),
+        )
+        .unwrap();
+
+        // FIXME: choose appropriate memory ordering.
+        let notify_unique = self.session.notify_unique.fetch_add(1, Ordering::SeqCst);
+
+        self.send(Retrieve {
+            header: fuse_out_header {
+                len: total_len,
+                error: fuse_notify_code::FUSE_NOTIFY_RETRIEVE as i32,
+                unique: 0,
+            },
+            arg: fuse_notify_retrieve_out {
+                nodeid: ino,
+                offset,
+                size,
+                notify_unique,
+                padding: 0,
+            },
+        })?;
+
+        return Ok(notify_unique);
+
+        struct Retrieve {
+            header: fuse_out_header,
+            arg: fuse_notify_retrieve_out,
+        }
+        impl Bytes for Retrieve {
+            fn size(&self) -> usize {
+                self.header.len as usize
+            }
+
+            fn count(&self) -> usize {
+                2
+            }
+
+            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
+                dst.put(self.header.as_bytes());
+                dst.put(self.arg.as_bytes());
+            }
+        }
+    }
+
+    /// Send I/O readiness to the kernel.
+    pub fn poll_wakeup(&self, kh: u64) -> io::Result<()> {
+        let total_len = u32::try_from(
+            mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_notify_poll_wakeup_out>(),
+        )
+        .unwrap();
+
+        return self.send(PollWakeup {
+            header: fuse_out_header {
+                len: total_len,
+                error: fuse_notify_code::FUSE_NOTIFY_POLL as i32,
+                unique: 0,
+            },
+            arg: fuse_notify_poll_wakeup_out { kh },
+        });
+
+        struct PollWakeup {
+            header: fuse_out_header,
+            arg: fuse_notify_poll_wakeup_out,
+        }
+        impl Bytes for PollWakeup {
+            fn size(&self) -> usize {
+                self.header.len as usize
+            }
+
+            fn count(&self) -> usize {
+                2
+            }
+
+            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
+                dst.put(self.header.as_bytes());
+                dst.put(self.arg.as_bytes());
+            }
+        }
+    }
+}
+
+// ==== utils ====
+
+struct Reply<T> {
+    header: fuse_out_header,
+    arg: T,
+}
+impl<T> Reply<T>
+where
+    T: Bytes,
+{
+    #[inline]
+    fn new(unique: u64, error: i32, arg: T) -> Self {
+        let len = (mem::size_of::<fuse_out_header>() + arg.size())
+            .try_into()
+            .expect("Argument size is too large");
+        Self {
+            header: fuse_out_header {
+                len,
+                error: -error,
+                unique,
+            },
+            arg,
+        }
+    }
+}
+impl<T> Bytes for Reply<T>
+where
+    T: Bytes,
+{
+    #[inline]
+    fn size(&self) -> usize {
+        self.header.len as usize
+    }
+
+    #[inline]
+    fn count(&self) -> usize {
+        self.arg.count() + 1
+    }
+
+    fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
+        dst.put(self.header.as_bytes());
+        self.arg.fill_bytes(dst);
+    }
+}
+
+/// Write a notification message, returning `WouldBlock` to the caller.
+#[inline]
+fn write_bytes<W, T>(writer: W, bytes: T) -> io::Result<()>
+where
+    W: io::Write,
+    T: Bytes,
+{
+    send_bytes(writer, bytes, false)
+}
+
+/// Write a reply message.
+///
+/// A reply dropped on `WouldBlock` would leave the request pending in the
+/// kernel forever, so the write is retried until the device accepts it.
+#[inline]
+fn write_reply<W, T>(writer: W, bytes: T) -> io::Result<()>
+where
+    W: io::Write,
+    T: Bytes,
+{
+    send_bytes(writer, bytes, true)
+}
+
+fn send_bytes<W, T>(mut writer: W, bytes: T, retry_would_block: bool) -> io::Result<()>
+where
+    W: io::Write,
+    T: Bytes,
+{
+    let size = bytes.size();
+    let count = bytes.count();
+
+    let written;
+
+    macro_rules! small_write {
+        ($n:expr) => {{
+            let mut vec: [MaybeUninit<IoSlice<'_>>; $n] =
+                unsafe { MaybeUninit::uninit().assume_init() };
+            bytes.fill_bytes(&mut FillWriteBytes {
+                vec: &mut vec[..],
+                offset: 0,
+            });
+            let vec = unsafe { slice_assume_init_ref(&vec[..]) };
+
+            written = write_vectored_retry(&mut writer, vec, retry_would_block)?;
+        }};
+    }
+
+    match count {
+        // Skip writing.
+        0 => return Ok(()),
+
+        // Avoid heap allocation if count is small.
+        1 => small_write!(1),
+        2 => small_write!(2),
+        3 => small_write!(3),
+        4 => small_write!(4),
+
+        // Reuse the I/O vectors of this thread, so that the replies with
+        // many chunks do not allocate in the steady state.
+        count => {
+            let mut iovecs = REPLY_IOVECS.try_with(Cell::take).unwrap_or_default();
+            iovecs.clear();
+            iovecs.reserve(count);
+
+            // `IoSlice` is ABI compatible with `iovec`.
+            let res = unsafe {
+                let dst = std::slice::from_raw_parts_mut(
+                    iovecs.as_mut_ptr().cast::<MaybeUninit<IoSlice<'_>>>(),
+                    count,
+                );
+                bytes.fill_bytes(&mut FillWriteBytes {
+                    vec: &mut dst[..],
+                    offset: 0,
+                });
+                write_vectored_retry(&mut writer, slice_assume_init_ref(dst), retry_would_block)
+            };
+
+            let _ = REPLY_IOVECS.try_with(|cell| cell.set(iovecs));
+            written = res?;
+        }
+    }
+
+    if written < size {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "written data is too short",
+        ));
+    }
+
+    Ok(())
+}
+
+fn write_vectored_retry<W>(
+    writer: &mut W,
+    bufs: &[IoSlice<'_>],
+    retry_would_block: bool,
+) -> io::Result<usize>
+where
+    W: io::Write,
+{
+    let mut backoff = Duration::from_micros(1);
+    loop {
+        match writer.write_vectored(bufs) {
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(ref err) if retry_would_block && err.kind() == io::ErrorKind::WouldBlock => {
+                std::thread::sleep(backoff);
+                backoff = cmp::min(backoff * 2, MAX_WRITE_BACKOFF);
+            }
+            res => return res,
+        }
+    }
+}
+
+/// The largest number of the I/O vectors accepted by `writev(2)`, i.e.
+/// `UIO_MAXIOV` of Linux.
+const MAX_REPLY_IOVECS: usize = 1024;
+
+thread_local! {
+    // Sized to the largest reply up front, so that it is never reallocated.
+    static REPLY_IOVECS: Cell<Vec<libc::iovec>> =
+        Cell::new(Vec::with_capacity(MAX_REPLY_IOVECS));
+}
+
+struct FillWriteBytes<'a, 'vec> {
+    vec: &'vec mut [MaybeUninit<IoSlice<'a>>],
+    offset: usize,
+}
+
+impl<'a, 'vec> FillBytes<'a> for FillWriteBytes<'a, 'vec> {
+    fn put(&mut self, chunk: &'a [u8]) {
+        self.vec[self.offset] = MaybeUninit::new(IoSlice::new(chunk));
+        self.offset += 1;
+    }
+}
+
+struct CollectBytes(Vec<u8>);
+
+impl<'a> FillBytes<'a> for CollectBytes {
+    fn put(&mut self, chunk: &'a [u8]) {
+        self.0.extend_from_slice(chunk);
+    }
+}
+
+/// Convert the `readdir` entries in `buf` to the `readdirplus` ones with
+/// zeroed `fuse_entry_out`, dropping the entries beyond `size` bytes.
+///
+/// The kernel continues from the offset of the last entry it received, so
+/// the dropped entries are read by the next request.
+fn dirents_to_plus(buf: &[u8], size: usize) -> Vec<u8> {
+    let mut plus = Vec::with_capacity(cmp::min(size, buf.len() * 2));
+    let mut pos = 0;
+    while pos + mem::size_of::<fuse_dirent>() <= buf.len() {
+        let mut dirent = fuse_dirent::default();
+        dirent
+            .as_bytes_mut()
+            .copy_from_slice(&buf[pos..pos + mem::size_of::<fuse_dirent>()]);
+        let namelen = dirent.namelen as usize;
+        let name_start = pos + mem::size_of::<fuse_dirent>();
+        if name_start + namelen > buf.len() {
+            break;
+        }
+        let entry_size = crate::reply::direntplus_size(namelen);
+        if plus.len() + entry_size > size {
+            break;
+        }
+
+        let entry_start = plus.len();
+        plus.extend_from_slice(fuse_entry_out::default().as_bytes());
+        plus.extend_from_slice(dirent.as_bytes());
+        plus.extend_from_slice(&buf[name_start..name_start + namelen]);
+        plus.resize(entry_start + entry_size, 0);
+
+        pos += crate::reply::dirent_size(namelen);
+    }
+    plus
+}
+
+// FIXME: replace with stabilized MaybeUninit::slice_assume_init_ref.
+#[inline(always)]
+unsafe fn slice_assume_init_ref<T>(slice: &[MaybeUninit<T>]) -> &[T] {
+    #[allow(unused_unsafe)]
+    unsafe {
+        &*(slice as *const [MaybeUninit<T>] as *const [T])
+    }
+}
+
+/// The file contents read by `Request::reply_splice`.
+enum Spliced {
+    /// The reply in a pipe, to be spliced into the device.
+    Pipe(Pipe, usize),
+    /// The data to be written as usual.
+    Buffer(Vec<u8>),
+}
+
+fn read_at(fd: RawFd, mut buf: &mut [u8], mut offset: u64) -> io::Result<usize> {
+    // Borrow the file descriptor without taking its ownership.
+    let file = mem::ManuallyDrop::new(unsafe { std::fs::File::from_raw_fd(fd) });
+
+    let mut nread = 0;
+    while !buf.is_empty() {
+        match file.read_at(buf, offset) {
+            Ok(0) => break,
+            Ok(n) => {
+                buf = &mut buf[n..];
+                nread += n;
+                offset += n as u64;
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(nread)
+}
+
+/// Return the size of the buffer large enough to receive any reply message.
+#[inline]
+pub(crate) fn max_reply_size() -> usize {
+    BUFFER_HEADER_SIZE + pagesize() * MAX_MAX_PAGES
+}
+
+#[inline]
+fn pagesize() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+#[inline]
+const fn default_init_out() -> fuse_init_out {
+    fuse_init_out {
+        major: FUSE_KERNEL_VERSION,
+        minor: FUSE_KERNEL_MINOR_VERSION,
+        max_readahead: u32::MAX,
+        flags: DEFAULT_INIT_FLAGS,
+        max_background: 0,
+        congestion_threshold: 0,
+        max_write: DEFAULT_MAX_WRITE,
+        time_gran: 1,
+        max_pages: 0,
+        padding: 0,
+        unused: [0; 8],
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::{env, fs, mem, os::unix::net::UnixDatagram, process, ptr};
+
+    /// Establish a session whose peer socket plays the role of the kernel.
+    pub(crate) fn test_session(config: KernelConfig) -> (Session, UnixDatagram) {
+        test_session_with_flags(config, INIT_FLAGS_MASK)
+    }
+
+    /// Establish a session with the kernel sending `flags` in the INIT request.
+    fn test_session_with_flags(config: KernelConfig, flags: u32) -> (Session, UnixDatagram) {
+        // SOCK_SEQPACKET preserves the message boundaries like the FUSE device,
+        // and reports the end of file when the kernel side is closed.
+        let mut fds = [0; 2];
+        let res = unsafe {
+            libc::socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            )
+        };
+        assert_eq!(res, 0, "socketpair: {}", io::Error::last_os_error());
+        let kernel = unsafe { UnixDatagram::from_raw_fd(fds[0]) };
+        let daemon = unsafe { UnixDatagram::from_raw_fd(fds[1]) };
+
+        let init_in = fuse_init_in {
+            major: 7,
+            minor: FUSE_KERNEL_MINOR_VERSION,
+            max_readahead: 40,
+            flags,
+        };
+        send_request(&kernel, fuse_opcode::FUSE_INIT, 1, 0, init_in.as_bytes());
+
+        let session = unsafe { Session::from_raw_fd(daemon.into_raw_fd(), config) }
+            .expect("initialization failed");
+
+        // Discard the reply to INIT.
+        recv_reply(&kernel);
+
+        (session, kernel)
+    }
+
+    pub(crate) fn send_request(
+        kernel: &UnixDatagram,
+        opcode: fuse_opcode,
+        unique: u64,
+        nodeid: u64,
+        arg: &[u8],
+    ) {
+        let header = fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + arg.len()) as u32,
+            opcode: opcode as u32,
+            unique,
+            nodeid,
+            ..Default::default()
+        };
+        let mut msg = header.as_bytes().to_vec();
+        msg.extend_from_slice(arg);
+        kernel.send(&msg).unwrap();
+    }
+
+    pub(crate) fn recv_reply(kernel: &UnixDatagram) -> Vec<u8> {
+        let mut buf = vec![0u8; pagesize() * MAX_MAX_PAGES];
+        let len = kernel.recv(&mut buf).unwrap();
+        buf.truncate(len);
+        buf
+    }
+
+    /// Establish a session with the kernel speaking the ABI 7.`minor`.
+    fn init_minor(config: KernelConfig, minor: u32) -> (Session, UnixDatagram) {
+        let (kernel, daemon) = UnixDatagram::pair().unwrap();
+        let init_in = fuse_init_in {
+            major: 7,
+            minor,
+            max_readahead: 40,
+            flags: INIT_FLAGS_MASK,
+        };
+        send_request(&kernel, fuse_opcode::FUSE_INIT, 1, 0, init_in.as_bytes());
+        let session = unsafe { Session::from_raw_fd(daemon.into_raw_fd(), config) }.unwrap();
+        recv_reply(&kernel);
+        (session, kernel)
+    }
+
+    /// Establish a session with the kernel sending `flags2` in the extended INIT request.
+    fn init_ext(config: KernelConfig, flags2: u32) -> (Session, UnixDatagram, fuse_init_out) {
+        let (kernel, daemon) = UnixDatagram::pair().unwrap();
+
+        // fuse_init_in since ABI 7.36: flags2 and unused[11] follow.
+        let init_in = fuse_init_in {
+            major: 7,
+            minor: 40,
+            max_readahead: 40,
+            flags: INIT_FLAGS_MASK | FUSE_INIT_EXT,
+        };
+        let mut arg = init_in.as_bytes().to_vec();
+        arg.extend_from_slice(&flags2.to_ne_bytes());
+        arg.extend_from_slice(&[0u8; 44]);
+        send_request(&kernel, fuse_opcode::FUSE_INIT, 1, 0, &arg);
+
+        let session = unsafe { Session::from_raw_fd(daemon.into_raw_fd(), config) }.unwrap();
+        let reply = recv_reply(&kernel);
+        let mut init_out = fuse_init_out::default();
+        init_out
+            .as_bytes_mut()
+            .copy_from_slice(&reply[mem::size_of::<fuse_out_header>()..]);
+        (session, kernel, init_out)
+    }
+
+    #[test]
+    fn enable_all_supported() {
+        let (kernel, daemon) = UnixDatagram::pair().unwrap();
+
+        // Offer everything but POSIX locks, including the flags polyfuse
+        // does not implement.
+        let offered = (INIT_FLAGS_MASK & !FUSE_POSIX_LOCKS)
+            | FUSE_HAS_IOCTL_DIR
+            | FUSE_ABORT_ERROR
+            | FUSE_INIT_EXT;
+        let init_in = fuse_init_in {
+            major: 7,
+            minor: 40,
+            max_readahead: 40,
+            flags: offered,
+        };
+        let mut arg = init_in.as_bytes().to_vec();
+        arg.extend_from_slice(&INIT_FLAGS2_MASK.to_ne_bytes());
+        arg.extend_from_slice(&[0u8; 44]);
+        send_request(&kernel, fuse_opcode::FUSE_INIT, 1, 0, &arg);
+
+        let mut config = KernelConfig::default();
+        config.enable_all_supported();
+        let _session = unsafe { Session::from_raw_fd(daemon.into_raw_fd(), config) }.unwrap();
+        let reply = recv_reply(&kernel);
+        let mut init_out = fuse_init_out::default();
+        init_out
+            .as_bytes_mut()
+            .copy_from_slice(&reply[mem::size_of::<fuse_out_header>()..]);
+
+        assert_eq!(
+            init_out.flags,
+            (offered & INIT_FLAGS_MASK & !FUSE_EXPLICIT_INVAL_DATA)
+                | FUSE_BIG_WRITES
+                | FUSE_INIT_EXT
+        );
+        assert!(init_out.flags & FUSE_AUTO_INVAL_DATA != 0);
+
+        // All the extended flags but the passthrough, which needs a stack depth.
+        assert_eq!(
+            init_out.unused[0],
+            INIT_FLAGS2_MASK & !(FUSE_PASSTHROUGH >> 32) as u32
+        );
+        assert_eq!(
+            init_out.unused[0],
+            ((FUSE_SECURITY_CTX | FUSE_CREATE_SUPP_GROUP) >> 32) as u32
+        );
+        assert_eq!(init_out.unused[1], 0);
+    }
+
+    #[test]
+    fn init_negotiation() {
+        let mut config = KernelConfig::default();
+        config.readdirplus(true).max_write(MIN_MAX_WRITE);
+        let (session, _kernel, init_out) = init_ext(config, 0);
+
+        // The results are available before receiving any request.
+        assert_eq!(session.init_in().major(), 7);
+        assert_eq!(session.init_in().minor(), 40);
+        assert_eq!(session.init_in().max_readahead(), 40);
+        assert_eq!(
+            session.init_in().flags(),
+            u64::from(INIT_FLAGS_MASK | FUSE_INIT_EXT)
+        );
+
+        assert_eq!(session.init_out().major(), FUSE_KERNEL_VERSION);
+        assert_eq!(
+            session.init_out().minor(),
+            cmp::min(FUSE_KERNEL_MINOR_VERSION, 40)
+        );
+        assert_eq!(session.init_out().minor(), init_out.minor);
+        assert_eq!(session.init_out().max_readahead(), 40);
+        assert_eq!(session.init_out().max_write(), MIN_MAX_WRITE);
+        assert_eq!(session.init_out().max_pages(), init_out.max_pages);
+        assert_ne!(
+            session.init_out().flags() & u64::from(FUSE_DO_READDIRPLUS),
+            0
+        );
+        assert_eq!(
+            session.init_out().flags() as u32 & INIT_FLAGS_MASK,
+            init_out.flags & INIT_FLAGS_MASK
+        );
+    }
+
+    #[test]
+    fn max_request_size() {
+        let (session, _kernel) = test_session(KernelConfig::default());
+        assert_eq!(
+            session.init_out().max_request_size(),
+            BUFFER_HEADER_SIZE + DEFAULT_MAX_WRITE as usize
+        );
+        assert_eq!(session.init_out().max_request_size(), session.inner.bufsize);
+
+        let mut config = KernelConfig::default();
+        config.max_write(1024 * 1024);
+        let (session, _kernel) = test_session_with_flags(config, FUSE_MAX_PAGES);
+        assert_eq!(
+            session.init_out().max_request_size(),
+            BUFFER_HEADER_SIZE + 1024 * 1024
+        );
+
+        // The number of pages cannot cover the huge `max_write`.
+        let max_write = u16::MAX as usize * pagesize() * 2;
+        let mut config = KernelConfig::default();
+        config.max_write(max_write as u32);
+        let (session, _kernel) = test_session_with_flags(config, FUSE_MAX_PAGES);
+        assert_eq!(session.init_out().max_pages(), u16::MAX);
+        assert_eq!(
+            session.init_out().max_request_size(),
+            BUFFER_HEADER_SIZE + u16::MAX as usize * pagesize()
+        );
+        assert_eq!(session.init_out().max_request_size(), session.inner.bufsize);
+    }
+
+    #[test]
+    fn init_callback() {
+        let init = |config: KernelConfig, minor: u32| {
+            let (kernel, daemon) = UnixDatagram::pair().unwrap();
+            let init_in = fuse_init_in {
+                major: 7,
+                minor,
+                max_readahead: 4096,
+                flags: INIT_FLAGS_MASK,
+            };
+            send_request(&kernel, fuse_opcode::FUSE_INIT, 1, 0, init_in.as_bytes());
+            let res = unsafe { Session::from_raw_fd(daemon.into_raw_fd(), config) };
+            (res, recv_reply(&kernel))
+        };
+
+        let proposal = Arc::new(Mutex::new(None));
+        let mut config = KernelConfig::default();
+        config.init_callback({
+            let proposal = proposal.clone();
+            move |init_in, init_out| {
+                *proposal.lock().unwrap() = Some((
+                    init_in.major(),
+                    init_in.minor(),
+                    init_in.max_readahead(),
+                    init_in.flags(),
+                ));
+                // FUSE_HAS_IOCTL_DIR is not implemented, so it is never enabled.
+                let flags = init_out.flags() & !u64::from(FUSE_ASYNC_READ)
+                    | u64::from(FUSE_WRITEBACK_CACHE)
+                    | u64::from(FUSE_HAS_IOCTL_DIR);
+                init_out.set_flags(flags);
+                init_out.set_max_write(1024 * 1024);
+                init_out.set_max_readahead(u32::MAX);
+                init_out.set_time_gran(1000);
+                Ok(())
+            }
+        });
+
+        let (res, reply) = init(config, 31);
+        let session = res.unwrap();
+        assert_eq!(
+            *proposal.lock().unwrap(),
+            Some((7, 31, 4096, u64::from(INIT_FLAGS_MASK)))
+        );
+        let mut init_out = fuse_init_out::default();
+        init_out
+            .as_bytes_mut()
+            .copy_from_slice(&reply[mem::size_of::<fuse_out_header>()..]);
+        assert_eq!(
+            init_out.flags,
+            DEFAULT_INIT_FLAGS & !FUSE_ASYNC_READ | FUSE_WRITEBACK_CACHE | FUSE_BIG_WRITES
+        );
+        assert_eq!(init_out.max_write, 1024 * 1024);
+        assert_eq!(init_out.max_readahead, 4096);
+        assert_eq!(init_out.time_gran, 1000);
+        assert_eq!(session.inner.init_out.max_write, 1024 * 1024);
+
+        // Refused by the callback.
+        let mut config = KernelConfig::default();
+        config.init_callback(|init_in, _| {
+            if init_in.minor() < 28 {
+                return Err(io::Error::from_raw_os_error(libc::ENOTSUP));
+            }
+            Ok(())
+        });
+        let (res, reply) = init(config, 27);
+        assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::ENOTSUP));
+        let mut out_header = fuse_out_header::default();
+        out_header.as_bytes_mut().copy_from_slice(&reply[..]);
+        assert_eq!(out_header.error, -libc::EPROTO);
+    }
+
+    #[test]
+    fn init_default() {
+        let input_len = mem::size_of::<fuse_in_header>() + mem::size_of::<fuse_init_in>();
+        let in_header = fuse_in_header {
+            len: input_len as u32,
+            opcode: fuse_opcode::FUSE_INIT as u32,
+            unique: 2,
+            nodeid: 0,
+            uid: 100,
+            gid: 100,
+            pid: 12,
+            padding: 0,
+        };
+        let init_in = fuse_init_in {
+            major: 7,
+            minor: 23,
+            max_readahead: 40,
+            flags: INIT_FLAGS_MASK
+                | FUSE_MAX_PAGES
+                | FUSE_NO_OPEN_SUPPORT
+                | FUSE_NO_OPENDIR_SUPPORT,
+        };
+
+        let mut input = Vec::with_capacity(input_len);
+        input.extend_from_slice(in_header.as_bytes());
+        input.extend_from_slice(init_in.as_bytes());
+        assert_eq!(input.len(), input_len);
+
+        let mut output = Vec::<u8>::new();
+
+        let mut init_out = default_init_out();
+        init_session(&mut init_out, None, &input[..], &mut output).expect("initialization failed");
+
+        let expected_max_pages = (DEFAULT_MAX_WRITE / (pagesize() as u32)) as u16;
+
+        assert_eq!(init_out.major, 7);
+        assert_eq!(init_out.minor, 23);
+        assert_eq!(init_out.max_readahead, 40);
+        assert_eq!(init_out.max_background, 0);
+        assert_eq!(init_out.congestion_threshold, 0);
+        assert_eq!(init_out.max_write, DEFAULT_MAX_WRITE);
+        assert_eq!(init_out.max_pages, expected_max_pages);
+        assert_eq!(init_out.time_gran, 1);
+        assert!(init_out.flags & FUSE_NO_OPEN_SUPPORT != 0);
+        assert!(init_out.flags & FUSE_NO_OPENDIR_SUPPORT != 0);
+
+        let output_len = mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_init_out>();
+        let out_header = fuse_out_header {
+            len: output_len as u32,
+            error: 0,
+            unique: 2,
+        };
+        let init_out = fuse_init_out {
+            major: 7,
+            minor: 23,
+            max_readahead: 40,
+            flags: DEFAULT_INIT_FLAGS | FUSE_MAX_PAGES | FUSE_BIG_WRITES,
+            max_background: 0,
+            congestion_threshold: 0,
+            max_write: DEFAULT_MAX_WRITE,
+            time_gran: 1,
+            max_pages: expected_max_pages,
+            padding: 0,
+            unused: [0; 8],
+        };
+
+        let mut expected = Vec::with_capacity(output_len);
+        expected.extend_from_slice(out_header.as_bytes());
+        expected.extend_from_slice(init_out.as_bytes());
+        assert_eq!(output.len(), output_len);
+
+        assert_eq!(expected[0..4], output[0..4], "out_header.len");
+        assert_eq!(expected[4..8], output[4..8], "out_header.error");
+        assert_eq!(expected[8..16], output[8..16], "out_header.unique");
+
+        let expected = &expected[mem::size_of::<fuse_out_header>()..];
+        let output = &output[mem::size_of::<fuse_out_header>()..];
+        assert_eq!(expected[0..4], output[0..4], "init_out.major");
+        assert_eq!(expected[4..8], output[4..8], "init_out.minor");
+        assert_eq!(expected[8..12], output[8..12], "init_out.max_readahead");
+        assert_eq!(expected[12..16], output[12..16], "init_out.flags");
+        assert_eq!(expected[16..18], output[16..18], "init_out.max_background");
+        assert_eq!(
+            expected[18..20],
+            output[18..20],
+            "init_out.congestion_threshold"
+        );
+        assert_eq!(expected[20..24], output[20..24], "init_out.max_write");
+        assert_eq!(expected[24..28], output[24..28], "init_out.time_gran");
+        assert_eq!(expected[28..30], output[28..30], "init_out.max_pages");
+        assert!(
+            output[30..30 + 2 + 4 * 8].iter().all(|&b| b == 0x00),
+            "init_out.paddings"
+        );
+    }
+
+    #[inline]
+    fn bytes(bytes: &[u8]) -> &[u8] {
+        bytes
+    }
+    macro_rules! b {
+        ($($b:expr),*$(,)?) => ( *bytes(&[$($b),*]) );
+    }
+
+    #[test]
+    fn send_msg_empty() {
+        let mut buf = vec![0u8; 0];
+        write_bytes(&mut buf, Reply::new(42, -4, &[])).unwrap();
+        assert_eq!(buf[0..4], b![0x10, 0x00, 0x00, 0x00], "header.len");
+        assert_eq!(buf[4..8], b![0x04, 0x00, 0x00, 0x00], "header.error");
+        assert_eq!(
+            buf[8..16],
+            b![0x2a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            "header.unique"
+        );
+    }
+
+    #[test]
+    fn send_msg_single_data() {
+        let mut buf = vec![0u8; 0];
+        write_bytes(&mut buf, Reply::new(42, 0, "hello")).unwrap();
+        assert_eq!(buf[0..4], b![0x15, 0x00, 0x00, 0x00], "header.len");
+        assert_eq!(buf[4..8], b![0x00, 0x00, 0x00, 0x00], "header.error");
+        assert_eq!(
+            buf[8..16],
+            b![0x2a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            "header.unique"
+        );
+        assert_eq!(buf[16..], b![0x68, 0x65, 0x6c, 0x6c, 0x6f], "payload");
+    }
+
+    #[test]
+    fn send_msg_chunked_data() {
+        let payload: &[&[u8]] = &[
+            "hello, ".as_ref(), //
+            "this ".as_ref(),
+            "is a ".as_ref(),
+            "message.".as_ref(),
+        ];
+        let mut buf = vec![0u8; 0];
+        write_bytes(&mut buf, Reply::new(26, 0, payload)).unwrap();
+        assert_eq!(buf[0..4], b![0x29, 0x00, 0x00, 0x00], "header.len");
+        assert_eq!(buf[4..8], b![0x00, 0x00, 0x00, 0x00], "header.error");
+        assert_eq!(
+            buf[8..16],
+            b![0x1a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            "header.unique"
+        );
+        assert_eq!(buf[16..], *b"hello, this is a message.", "payload");
+    }
+
+    #[derive(Default)]
+    struct RecordingWriter {
+        iovecs: Vec<Vec<u8>>,
+    }
+
+    impl io::Write for RecordingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write_vectored(&[IoSlice::new(buf)])
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            self.iovecs.extend(bufs.iter().map(|buf| buf.to_vec()));
+            Ok(bufs.iter().map(|buf| buf.len()).sum())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn send_msg_vectored() {
+        let payload: Vec<Vec<u8>> = (0..8u8).map(|i| vec![i; 4096]).collect();
+
+        let mut writer = RecordingWriter::default();
+        write_bytes(&mut writer, Reply::new(26, 0, &payload)).unwrap();
+
+        // The header and each chunk of the payload are written as separate iovecs.
+        assert_eq!(writer.iovecs.len(), payload.len() + 1);
+        assert_eq!(writer.iovecs[0].len(), mem::size_of::<fuse_out_header>());
+        assert_eq!(writer.iovecs[1..], payload[..]);
+
+        let mut concatenated = vec![];
+        write_bytes(&mut concatenated, Reply::new(26, 0, &payload)).unwrap();
+        assert_eq!(writer.iovecs.concat(), concatenated);
+    }
+
+    struct FlakyWriter {
+        errors: Vec<io::ErrorKind>,
+        buf: Vec<u8>,
+    }
+
+    impl io::Write for FlakyWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write_vectored(&[IoSlice::new(buf)])
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            if let Some(kind) = self.errors.pop() {
+                return Err(kind.into());
+            }
+            self.buf.write_vectored(bufs)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn send_msg_retry() {
+        let mut writer = FlakyWriter {
+            errors: vec![io::ErrorKind::Interrupted, io::ErrorKind::WouldBlock],
+            buf: vec![],
+        };
+        write_reply(&mut writer, Reply::new(42, 0, "hello")).unwrap();
+        assert!(writer.errors.is_empty());
+        assert_eq!(writer.buf.len(), 0x15);
+        assert_eq!(writer.buf[16..], *b"hello", "payload");
+
+        // The notifications report `WouldBlock` to the caller.
+        let mut writer = FlakyWriter {
+            errors: vec![io::ErrorKind::WouldBlock, io::ErrorKind::Interrupted],
+            buf: vec![],
+        };
+        let err = write_bytes(&mut writer, Reply::new(0, 1, "hello")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(writer.buf.is_empty());
+    }
+
+    #[test]
+    fn read_only_rejects_writes() {
+        let header = |opcode: fuse_opcode| fuse_in_header {
+            len: mem::size_of::<fuse_in_header>() as u32,
+            opcode: opcode as u32,
+            unique: 42,
+            ..Default::default()
+        };
+        let open_in = |flags: i32| fuse_open_in {
+            flags: flags as u32,
+            ..Default::default()
+        };
+
+        let write = header(fuse_opcode::FUSE_WRITE);
+        let mkdir = header(fuse_opcode::FUSE_MKDIR);
+        let tmpfile = header(fuse_opcode::FUSE_TMPFILE);
+        let read = header(fuse_opcode::FUSE_READ);
+        let open = header(fuse_opcode::FUSE_OPEN);
+        let rdonly = open_in(libc::O_RDONLY);
+        let rdwr = open_in(libc::O_RDWR);
+
+        // writable: nothing is rejected.
+        let mut output = Vec::<u8>::new();
+        assert!(!reject_read_only(false, &write, &[], &mut output).unwrap());
+        assert!(!reject_read_only(false, &open, rdwr.as_bytes(), &mut output).unwrap());
+        assert!(output.is_empty());
+
+        // read-only: write-family requests are replied with EROFS.
+        for (header, arg) in &[
+            (&write, &[][..]),
+            (&mkdir, &[][..]),
+            (&tmpfile, &[][..]),
+            (&open, rdwr.as_bytes()),
+        ] {
+            let mut output = Vec::<u8>::new();
+            assert!(reject_read_only(true, header, arg, &mut output).unwrap());
+
+            let expected = fuse_out_header {
+                len: mem::size_of::<fuse_out_header>() as u32,
+                error: -libc::EROFS,
+                unique: 42,
+            };
+            assert_eq!(output, expected.as_bytes());
+        }
+
+        // read-only: read requests still pass through.
+        let mut output = Vec::<u8>::new();
+        assert!(!reject_read_only(true, &read, &[], &mut output).unwrap());
+        assert!(!reject_read_only(true, &open, rdonly.as_bytes(), &mut output).unwrap());
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn metrics_per_request() {
+        let records = Arc::new(Mutex::new(vec![]));
+
+        let mut config = KernelConfig::default();
+        config.metrics({
+            let records = records.clone();
+            move |opcode, _latency, error| records.lock().unwrap().push((opcode, error))
+        });
+        let (session, kernel) = test_session(config);
+
+        let getattr_in = fuse_getattr_in::default();
+        send_request(
+            &kernel,
+            fuse_opcode::FUSE_GETATTR,
+            2,
+            1,
+            getattr_in.as_bytes(),
+        );
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 3, 1, &[]);
+
+        let req = session.next_request().unwrap().unwrap();
+        req.reply_error(libc::ENOENT).unwrap();
+        recv_reply(&kernel);
+
+        let req = session.next_request().unwrap().unwrap();
+        req.reply(()).unwrap();
+        recv_reply(&kernel);
+
+        assert_eq!(
+            *records.lock().unwrap(),
+            vec![
+                (fuse_opcode::FUSE_GETATTR as u32, libc::ENOENT),
+                (fuse_opcode::FUSE_STATFS as u32, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn splice_read() {
+        let mut config = KernelConfig::default();
+        config.splice_write(true).splice_move(true);
+        let (session, kernel) = test_session(config);
+        assert!(session.inner.init_out.flags & FUSE_SPLICE_WRITE != 0);
+        assert!(session.inner.init_out.flags & FUSE_SPLICE_MOVE != 0);
+        assert!(session.inner.init_out.flags & FUSE_SPLICE_READ == 0);
+
+        let path = env::temp_dir().join(format!("polyfuse-splice-read-{}", process::id()));
+        fs::write(&path, b"hello, world\n").unwrap();
+        let file = fs::File::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let expected_reply = |unique: u64, data: &[u8]| {
+            let header = fuse_out_header {
+                len: (mem::size_of::<fuse_out_header>() + data.len()) as u32,
+                error: 0,
+                unique,
+            };
+            let mut reply = header.as_bytes().to_vec();
+            reply.extend_from_slice(data);
+            reply
+        };
+
+        let read_in = fuse_read_in::default();
+        send_request(&kernel, fuse_opcode::FUSE_READ, 2, 2, read_in.as_bytes());
+        send_request(&kernel, fuse_opcode::FUSE_READ, 3, 2, read_in.as_bytes());
+
+        // spliced
+        let req = session.next_request().unwrap().unwrap();
+        req.reply_splice(file.as_raw_fd(), 7, 5).unwrap();
+        assert_eq!(recv_reply(&kernel), expected_reply(2, b"world"));
+
+        // short read at the end of file
+        let req = session.next_request().unwrap().unwrap();
+        req.reply_splice(file.as_raw_fd(), 7, 4096).unwrap();
+        assert_eq!(recv_reply(&kernel), expected_reply(3, b"world\n"));
+
+        // failed midway, since a directory cannot be read
+        send_request(&kernel, fuse_opcode::FUSE_READ, 4, 2, read_in.as_bytes());
+        let dir = fs::File::open(env::temp_dir()).unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        assert!(req.reply_splice(dir.as_raw_fd(), 0, 5).is_err());
+        let reply = recv_reply(&kernel);
+        let mut header = fuse_out_header::default();
+        header.as_bytes_mut().copy_from_slice(&reply[..]);
+        assert_eq!(header.unique, 4);
+        assert_eq!(header.error, -libc::EIO);
+
+        // failed to splice into the device, which splice(2) refuses in the
+        // append mode while write(2) still goes through
+        let fd = session.as_raw_fd();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        assert_ne!(
+            unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_APPEND) },
+            -1
+        );
+        send_request(&kernel, fuse_opcode::FUSE_READ, 5, 2, read_in.as_bytes());
+        let req = session.next_request().unwrap().unwrap();
+        assert!(req.reply_splice(file.as_raw_fd(), 7, 5).is_err());
+        let reply = recv_reply(&kernel);
+        let mut header = fuse_out_header::default();
+        header.as_bytes_mut().copy_from_slice(&reply[..]);
+        assert_eq!(header.unique, 5);
+        assert_eq!(header.error, -libc::EIO);
+
+        // exactly one reply is sent for each failure
+        kernel.set_nonblocking(true).unwrap();
+        let mut buf = [0u8; 64];
+        assert_eq!(
+            kernel.recv(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+
+    #[test]
+    fn reply_mapped() {
+        let contents = b"hello, mapped world\n";
+        let len = pagesize();
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(map, libc::MAP_FAILED);
+        let mapped = unsafe { std::slice::from_raw_parts_mut(map as *mut u8, len) };
+        mapped[..contents.len()].copy_from_slice(contents);
+
+        let expected_reply = |unique: u64, data: &[u8]| {
+            let header = fuse_out_header {
+                len: (mem::size_of::<fuse_out_header>() + data.len()) as u32,
+                error: 0,
+                unique,
+            };
+            let mut reply = header.as_bytes().to_vec();
+            reply.extend_from_slice(data);
+            reply
+        };
+        let read_in = fuse_read_in::default();
+
+        // Written with writev(2).
+        let (session, kernel) = test_session(KernelConfig::default());
+        send_request(&kernel, fuse_opcode::FUSE_READ, 2, 2, read_in.as_bytes());
+        let req = session.next_request().unwrap().unwrap();
+        req.reply_mapped(&mapped[7..7 + 6]).unwrap();
+        assert_eq!(recv_reply(&kernel), expected_reply(2, b"mapped"));
+
+        // Spliced from the mapping.
+        let mut config = KernelConfig::default();
+        config.splice_write(true);
+        let (session, kernel) = test_session(config);
+        send_request(&kernel, fuse_opcode::FUSE_READ, 3, 2, read_in.as_bytes());
+        let req = session.next_request().unwrap().unwrap();
+        req.reply_mapped(&mapped[..contents.len()]).unwrap();
+        assert_eq!(recv_reply(&kernel), expected_reply(3, contents));
+
+        unsafe {
+            libc::munmap(map, len);
+        }
+    }
+
+    #[test]
+    fn short_read_is_not_padded() {
+        let (session, kernel) = test_session(KernelConfig::default());
+
+        let read_in = fuse_read_in {
+            offset: 7,
+            size: 4096,
+            ..Default::default()
+        };
+        send_request(&kernel, fuse_opcode::FUSE_READ, 2, 2, read_in.as_bytes());
+
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::Read(op) => req.reply(op.slice(b"hello, world\n")).unwrap(),
+            _ => panic!("unexpected operation"),
+        }
+
+        let reply = recv_reply(&kernel);
+        let header_len = mem::size_of::<fuse_out_header>();
+        assert_eq!(reply.len(), header_len + 6);
+        assert_eq!(reply[..4], ((header_len + 6) as u32).to_ne_bytes());
+        assert_eq!(reply[header_len..], b"world\n"[..]);
+    }
+
+    #[test]
+    fn zero_length_read() {
+        let (session, kernel) = test_session(KernelConfig::default());
+
+        let read_in = fuse_read_in {
+            offset: 7,
+            size: 0,
+            ..Default::default()
+        };
+        send_request(&kernel, fuse_opcode::FUSE_READ, 2, 2, read_in.as_bytes());
+
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::Read(op) => {
+                assert_eq!(op.size(), 0);
+                assert!(op.slice(b"hello, world\n").is_empty());
+                req.reply(&[] as &[u8]).unwrap();
+            }
+            _ => panic!("unexpected operation"),
+        }
+
+        let expected = fuse_out_header {
+            len: mem::size_of::<fuse_out_header>() as u32,
+            error: 0,
+            unique: 2,
+        };
+        assert_eq!(recv_reply(&kernel), expected.as_bytes());
+    }
+
+    #[test]
+    fn zero_length_write() {
+        let (session, kernel) = test_session(KernelConfig::default());
+
+        let write_in = fuse_write_in {
+            offset: 7,
+            size: 0,
+            ..Default::default()
+        };
+        send_request(&kernel, fuse_opcode::FUSE_WRITE, 2, 2, write_in.as_bytes());
+
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::Write(op, mut data) => {
+                assert_eq!(op.size(), 0);
+                let mut buf = vec![];
+                assert_eq!(data.read_to_end(&mut buf).unwrap(), 0);
+
+                // `Bytes::size` is in scope here.
+                let mut out = WriteOut::default();
+                WriteOut::size(&mut out, 0);
+                req.reply(out).unwrap();
+            }
+            _ => panic!("unexpected operation"),
+        }
+
+        let header = fuse_out_header {
+            len: (mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_write_out>()) as u32,
+            error: 0,
+            unique: 2,
+        };
+        let mut expected = header.as_bytes().to_vec();
+        expected.extend_from_slice(fuse_write_out::default().as_bytes());
+        assert_eq!(recv_reply(&kernel), expected);
+    }
+
+    #[test]
+    fn cache_symlinks() {
+        let (session, _kernel) = test_session(KernelConfig::default());
+        assert!(session.inner.init_out.flags & FUSE_CACHE_SYMLINKS == 0);
+
+        let mut config = KernelConfig::default();
+        config.cache_symlinks(true);
+        let (session, kernel) = test_session(config);
+        assert!(session.inner.init_out.flags & FUSE_CACHE_SYMLINKS != 0);
+
+        // The cached target is dropped by invalidating the link inode.
+        session.notifier().inval_inode(42, 0, 0).unwrap();
+
+        let expected = {
+            let header = fuse_out_header {
+                len: (mem::size_of::<fuse_out_header>()
+                    + mem::size_of::<fuse_notify_inval_inode_out>()) as u32,
+                error: fuse_notify_code::FUSE_NOTIFY_INVAL_INODE as i32,
+                unique: 0,
+            };
+            let arg = fuse_notify_inval_inode_out {
+                ino: 42,
+                off: 0,
+                len: 0,
+            };
+            let mut msg = header.as_bytes().to_vec();
+            msg.extend_from_slice(arg.as_bytes());
+            msg
+        };
+        assert_eq!(recv_reply(&kernel), expected);
+    }
+
+    #[test]
+    fn request_extensions() {
+        #[derive(Debug, PartialEq)]
+        struct Identity(&'static str);
+
+        // An authentication layer resolves the caller once.
+        fn authenticate(req: &mut Request) {
+            let name = if req.uid() == 0 { "root" } else { "nobody" };
+            req.extensions_mut().insert(Identity(name));
+        }
+
+        fn getattr(req: &Request) -> io::Result<()> {
+            match req.extensions().get::<Identity>() {
+                Some(Identity("root")) => req.reply(()),
+                _ => req.reply_error(libc::EACCES),
+            }
+        }
+
+        let (session, kernel) = test_session(KernelConfig::default());
+
+        let getattr_in = fuse_getattr_in::default();
+        send_request(
+            &kernel,
+            fuse_opcode::FUSE_GETATTR,
+            2,
+            1,
+            getattr_in.as_bytes(),
+        );
+
+        let mut req = session.next_request().unwrap().unwrap();
+        assert!(req.extensions().get::<Identity>().is_none());
+        authenticate(&mut req);
+        assert_eq!(req.extensions().get::<Identity>(), Some(&Identity("root")));
+        assert_eq!(req.extensions_mut().insert(42u32), None);
+        assert_eq!(req.extensions_mut().insert(7u32), Some(42));
+        getattr(&req).unwrap();
+
+        let reply = recv_reply(&kernel);
+        assert_eq!(reply[4..8], 0i32.to_ne_bytes(), "error");
+
+        assert_eq!(req.extensions_mut().remove::<u32>(), Some(7));
+        req.extensions_mut().clear();
+        assert!(req.extensions().get::<Identity>().is_none());
+    }
+
+    #[test]
+    fn dispatch_hooks() {
+        let events = Arc::new(Mutex::new(vec![]));
+
+        let mut config = KernelConfig::default();
+        config
+            .before_dispatch({
+                let events = events.clone();
+                move |info| {
+                    let name = match info.operation().unwrap() {
+                        Operation::Statfs(..) => "statfs",
+                        _ => "other",
+                    };
+                    let event = format!("before {} {}", info.unique(), name);
+                    events.lock().unwrap().push(event);
+                }
+            })
+            .after_dispatch({
+                let events = events.clone();
+                move |info, res| {
+                    let event = format!("after {} {}", info.unique(), res.is_ok());
+                    events.lock().unwrap().push(event);
+                }
+            });
+        let (session, kernel) = test_session(config);
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 2, 1, &[]);
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 3, 1, &[]);
+        kernel.shutdown(std::net::Shutdown::Write).unwrap();
+
+        session
+            .run({
+                let events = events.clone();
+                move |req: &mut Request| {
+                    events
+                        .lock()
+                        .unwrap()
+                        .push(format!("handler {}", req.unique()));
+                    req.reply_error(libc::ENOSYS)
+                }
+            })
+            .unwrap();
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                "before 2 statfs",
+                "handler 2",
+                "after 2 true",
+                "before 3 statfs",
+                "handler 3",
+                "after 3 true",
+            ]
+        );
+    }
+
+    #[test]
+    fn run_layered_services() {
+        struct Layer<S> {
+            name: &'static str,
+            events: Arc<Mutex<Vec<String>>>,
+            inner: S,
+        }
+
+        impl<S: Service> Service for Layer<S> {
+            fn call(&self, req: &mut Request) -> io::Result<()> {
+                let unique = req.unique();
+                let push = |event: &str| {
+                    let event = format!("{} {} {}", self.name, event, unique);
+                    self.events.lock().unwrap().push(event);
+                };
+                push("before");
+                let res = self.inner.call(req);
+                push("after");
+                res
+            }
+        }
+
+        let events = Arc::new(Mutex::new(vec![]));
+        let filesystem = {
+            let events = events.clone();
+            move |req: &mut Request| {
+                events
+                    .lock()
+                    .unwrap()
+                    .push(format!("handler {}", req.unique()));
+                req.reply_error(libc::ENOSYS)
+            }
+        };
+        let service = Layer {
+            name: "outer",
+            events: events.clone(),
+            inner: Layer {
+                name: "inner",
+                events: events.clone(),
+                inner: filesystem,
+            },
+        };
+
+        let (session, kernel) = test_session(KernelConfig::default());
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 2, 1, &[]);
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 3, 1, &[]);
+        kernel.shutdown(std::net::Shutdown::Write).unwrap();
+
+        session.run(service).unwrap();
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                "outer before 2",
+                "inner before 2",
+                "handler 2",
+                "inner after 2",
+                "outer after 2",
+                "outer before 3",
+                "inner before 3",
+                "handler 3",
+                "inner after 3",
+                "outer after 3",
+            ]
+        );
+    }
+
+    #[test]
+    fn rate_limiter() {
+        use crate::service::RateLimiter;
+
+        let passed = Arc::new(AtomicUsize::new(0));
+        let filesystem = {
+            let passed = passed.clone();
+            move |req: &mut Request| {
+                passed.fetch_add(1, Ordering::SeqCst);
+                req.reply_error(libc::ENOENT)
+            }
+        };
+
+        // The requests over the burst are rejected, while the other opcodes
+        // are not limited.
+        let mut limiter = RateLimiter::new(filesystem.clone());
+        limiter
+            .limit(fuse_opcode::FUSE_GETATTR as u32, 1, 2)
+            .reject(true);
+
+        let (session, kernel) = test_session(KernelConfig::default());
+        for unique in 2..5 {
+            send_request(&kernel, fuse_opcode::FUSE_GETATTR, unique, 1, &[0u8; 16]);
+        }
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 5, 1, &[]);
+        kernel.shutdown(std::net::Shutdown::Write).unwrap();
+        session.run(limiter).unwrap();
+
+        let errors: Vec<i32> = (0..4)
+            .map(|_| {
+                let reply = recv_reply(&kernel);
+                let mut header = fuse_out_header::default();
+                header
+                    .as_bytes_mut()
+                    .copy_from_slice(&reply[..mem::size_of::<fuse_out_header>()]);
+                header.error
+            })
+            .collect();
+        assert_eq!(
+            errors,
+            vec![-libc::ENOENT, -libc::ENOENT, -libc::EAGAIN, -libc::ENOENT]
+        );
+        assert_eq!(passed.load(Ordering::SeqCst), 3);
+
+        // Without `reject`, the request over the burst waits for a token.
+        passed.store(0, Ordering::SeqCst);
+        let mut limiter = RateLimiter::new(filesystem.clone());
+        limiter.limit(fuse_opcode::FUSE_GETATTR as u32, 20, 1);
+
+        let (session, kernel) = test_session(KernelConfig::default());
+        send_request(&kernel, fuse_opcode::FUSE_GETATTR, 2, 1, &[0u8; 16]);
+        send_request(&kernel, fuse_opcode::FUSE_GETATTR, 3, 1, &[0u8; 16]);
+        kernel.shutdown(std::net::Shutdown::Write).unwrap();
+
+        let start = Instant::now();
+        session.run(limiter).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        assert_eq!(passed.load(Ordering::SeqCst), 2);
+
+        // The requests waiting longer than `max_delay` are rejected, and
+        // those without a reply are never limited.
+        passed.store(0, Ordering::SeqCst);
+        let mut limiter = RateLimiter::new({
+            let passed = passed.clone();
+            move |req: &mut Request| {
+                if req.opcode() == fuse_opcode::FUSE_FORGET as u32 {
+                    passed.fetch_add(1, Ordering::SeqCst);
+                    return Ok(());
+                }
+                filesystem(req)
+            }
+        });
+        limiter
+            .limit(fuse_opcode::FUSE_GETATTR as u32, 1, 1)
+            .limit(fuse_opcode::FUSE_FORGET as u32, 1, 1)
+            .max_delay(Duration::from_millis(100));
+
+        let (session, kernel) = test_session(KernelConfig::default());
+        send_request(&kernel, fuse_opcode::FUSE_GETATTR, 2, 1, &[0u8; 16]);
+        send_request(&kernel, fuse_opcode::FUSE_GETATTR, 3, 1, &[0u8; 16]);
+        for unique in 4..7 {
+            let forget_in = fuse_forget_in { nlookup: 1 };
+            send_request(
+                &kernel,
+                fuse_opcode::FUSE_FORGET,
+                unique,
+                2,
+                forget_in.as_bytes(),
+            );
+        }
+        kernel.shutdown(std::net::Shutdown::Write).unwrap();
+
+        let start = Instant::now();
+        session.run(limiter).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert_eq!(passed.load(Ordering::SeqCst), 4);
+        let errors: Vec<i32> = (0..2)
+            .map(|_| {
+                let reply = recv_reply(&kernel);
+                let mut header = fuse_out_header::default();
+                header
+                    .as_bytes_mut()
+                    .copy_from_slice(&reply[..mem::size_of::<fuse_out_header>()]);
+                header.error
+            })
+            .collect();
+        assert_eq!(errors, vec![-libc::ENOENT, -libc::EAGAIN]);
+    }
+
+    #[test]
+    fn canonical_path() {
+        let (session, kernel) = test_session(KernelConfig::default());
+        send_request(&kernel, fuse_opcode::FUSE_CANONICAL_PATH, 2, 42, &[]);
+
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::CanonicalPath(op) => {
+                assert_eq!(op.ino(), 42);
+                req.reply_canonical_path("/data/media/0/foo").unwrap();
+            }
+            _ => panic!("unexpected operation"),
+        }
+
+        let reply = recv_reply(&kernel);
+        let mut out_header = fuse_out_header::default();
+        out_header
+            .as_bytes_mut()
+            .copy_from_slice(&reply[..mem::size_of::<fuse_out_header>()]);
+        assert_eq!(out_header.len as usize, reply.len());
+        assert_eq!(out_header.unique, 2);
+        assert_eq!(out_header.error, 0);
+        assert_eq!(
+            reply[mem::size_of::<fuse_out_header>()..],
+            *b"/data/media/0/foo\0"
+        );
+    }
+
+    #[test]
+    fn read_cached_write_through() {
+        let (session, kernel) = test_session(KernelConfig::default());
+        assert!(session.inner.init_out.flags & FUSE_WRITEBACK_CACHE == 0);
+
+        let open_in = fuse_open_in {
+            flags: libc::O_RDWR as u32,
+            ..Default::default()
+        };
+        send_request(&kernel, fuse_opcode::FUSE_OPEN, 2, 2, open_in.as_bytes());
+
+        let req = session.next_request().unwrap().unwrap();
+        let mut out = crate::reply::OpenOut::default();
+        out.fh(1);
+        out.keep_cache(true);
+        req.reply(out).unwrap();
+
+        let reply = recv_reply(&kernel);
+        let mut open_out = fuse_open_out::default();
+        open_out
+            .as_bytes_mut()
+            .copy_from_slice(&reply[mem::size_of::<fuse_out_header>()..]);
+        assert_eq!(open_out.fh, 1);
+        assert_eq!(open_out.open_flags, FOPEN_KEEP_CACHE);
+    }
+
+    #[test]
+    fn op_timeout() {
+        let mut config = KernelConfig::default();
+        config.op_timeout(Duration::from_millis(50));
+        let (session, kernel) = test_session(config);
+
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 2, 1, &[]);
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 3, 1, &[]);
+        let stalled = session.next_request().unwrap().unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        req.reply_error(libc::ENOSYS).unwrap();
+
+        let mut out_header = fuse_out_header::default();
+        out_header
+            .as_bytes_mut()
+            .copy_from_slice(&recv_reply(&kernel)[..]);
+        assert_eq!(out_header.unique, 3);
+        assert_eq!(out_header.error, -libc::ENOSYS);
+
+        // The stalled request is replied by the session.
+        out_header
+            .as_bytes_mut()
+            .copy_from_slice(&recv_reply(&kernel)[..]);
+        assert_eq!(out_header.unique, 2);
+        assert_eq!(out_header.error, -libc::EIO);
+
+        // The late reply is dropped.
+        stalled.reply(()).unwrap();
+        kernel.set_nonblocking(true).unwrap();
+        let mut buf = [0u8; 64];
+        let err = kernel.recv(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn stateless_open() {
+        let open_in = fuse_open_in {
+            flags: libc::O_RDONLY as u32,
+            ..Default::default()
+        };
+
+        // The kernel does not support zero-message opens.
+        let mut config = KernelConfig::default();
+        config.stateless_open(true);
+        let (session, kernel) = test_session(config);
+        assert!(!session.no_open_support());
+        send_request(&kernel, fuse_opcode::FUSE_OPEN, 2, 2, open_in.as_bytes());
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.unique(), 2);
+
+        let mut config = KernelConfig::default();
+        config.stateless_open(true);
+        let (session, kernel) =
+            test_session_with_flags(config, INIT_FLAGS_MASK | FUSE_NO_OPEN_SUPPORT);
+        assert!(session.no_open_support());
+
+        send_request(&kernel, fuse_opcode::FUSE_OPEN, 2, 2, open_in.as_bytes());
+        send_request(&kernel, fuse_opcode::FUSE_OPENDIR, 3, 1, open_in.as_bytes());
+
+        // Only the opendir is delivered.
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.unique(), 3);
+
+        let mut out_header = fuse_out_header::default();
+        out_header
+            .as_bytes_mut()
+            .copy_from_slice(&recv_reply(&kernel)[..]);
+        assert_eq!(out_header.unique, 2);
+        assert_eq!(out_header.error, -libc::ENOSYS);
+    }
+
+    #[test]
+    fn skip_flush() {
+        let flush_in = fuse_flush_in::default();
+
+        let (session, kernel) = test_session(KernelConfig::default());
+        send_request(&kernel, fuse_opcode::FUSE_FLUSH, 2, 2, flush_in.as_bytes());
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.unique(), 2);
+
+        let mut config = KernelConfig::default();
+        config.skip_flush(true).stateless_open(true);
+        let (session, kernel) =
+            test_session_with_flags(config, INIT_FLAGS_MASK | FUSE_NO_OPEN_SUPPORT);
+        send_request(&kernel, fuse_opcode::FUSE_FLUSH, 2, 2, flush_in.as_bytes());
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 3, 1, &[]);
+
+        // The flush is not delivered to the handler.
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.unique(), 3);
+
+        let mut out_header = fuse_out_header::default();
+        out_header
+            .as_bytes_mut()
+            .copy_from_slice(&recv_reply(&kernel)[..]);
+        assert_eq!(out_header.unique, 2);
+        assert_eq!(out_header.error, -libc::ENOSYS);
+    }
+
+    #[test]
+    fn io_size_stats() {
+        let (session, _kernel) = test_session(KernelConfig::default());
+        assert!(session.io_size_stats().is_none());
+
+        let mut config = KernelConfig::default();
+        config.io_size_stats(true);
+        let (session, kernel) = test_session(config);
+
+        let mut unique = 2;
+        for &size in &[512, 4096, 4097, 65536, 65536, 4 << 20] {
+            let read_in = fuse_read_in {
+                size,
+                ..Default::default()
+            };
+            send_request(
+                &kernel,
+                fuse_opcode::FUSE_READ,
+                unique,
+                2,
+                read_in.as_bytes(),
+            );
+            unique += 1;
+        }
+        for &size in &[8192, 131_072] {
+            let mut arg = fuse_write_in {
+                size,
+                ..Default::default()
+            }
+            .as_bytes()
+            .to_vec();
+            arg.resize(arg.len() + 16, 0);
+            send_request(&kernel, fuse_opcode::FUSE_WRITE, unique, 2, &arg[..]);
+            unique += 1;
+        }
+        for _ in 2..unique {
+            session.next_request().unwrap().unwrap();
+        }
+
+        let (reads, writes) = session.io_size_stats().unwrap();
+        assert_eq!(reads.total(), 6);
+        assert_eq!(reads.count(1), 2);
+        assert_eq!(reads.count(8192), 1);
+        assert_eq!(reads.count(65536), 2);
+        assert_eq!(reads.count(u32::MAX), 1);
+        assert_eq!(
+            reads.iter().collect::<Vec<_>>(),
+            vec![
+                (4096, 2),
+                (8192, 1),
+                (16384, 0),
+                (32768, 0),
+                (65536, 2),
+                (131_072, 0),
+                (262_144, 0),
+                (524_288, 0),
+                (1 << 20, 0),
+                (u32::MAX, 1),
+            ]
+        );
+
+        assert_eq!(writes.total(), 2);
+        assert_eq!(writes.count(8192), 1);
+        assert_eq!(writes.count(131_072), 1);
+        assert_eq!(writes.count(4096), 0);
+    }
+
+    #[test]
+    fn tmpfile() {
+        let mut arg = fuse_create_in {
+            flags: (libc::O_TMPFILE | libc::O_RDWR) as u32,
+            mode: libc::S_IFREG | 0o600,
+            umask: 0o022,
+            padding: 0,
+        }
+        .as_bytes()
+        .to_vec();
+        arg.extend_from_slice(b"/\0");
+
+        // Disabled by default.
+        let (session, kernel) = test_session(KernelConfig::default());
+        send_request(&kernel, fuse_opcode::FUSE_TMPFILE, 2, 1, &arg);
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 3, 1, &[]);
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.unique(), 3);
+        let mut out_header = fuse_out_header::default();
+        out_header
+            .as_bytes_mut()
+            .copy_from_slice(&recv_reply(&kernel)[..]);
+        assert_eq!(out_header.unique, 2);
+        assert_eq!(out_header.error, -libc::ENOSYS);
+
+        let mut config = KernelConfig::default();
+        config.tmpfile(true);
+        let (session, kernel) = test_session(config);
+        send_request(&kernel, fuse_opcode::FUSE_TMPFILE, 2, 1, &arg);
+
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::Tmpfile(op) => {
+                assert_eq!(op.parent(), 1);
+                assert_eq!(op.mode().into_raw(), libc::S_IFREG | 0o600);
+                assert_eq!(op.umask(), 0o022);
+                assert!(op.flags().is_readable() && op.flags().is_writable());
+            }
+            _ => panic!("unexpected operation"),
+        }
+
+        let mut entry = crate::reply::EntryOut::default();
+        entry.ino(5);
+        let mut open = crate::reply::OpenOut::default();
+        open.fh(7);
+        req.reply((entry, open)).unwrap();
+
+        let reply = recv_reply(&kernel);
+        let mut entry_out = fuse_entry_out::default();
+        let mut open_out = fuse_open_out::default();
+        let (entry_bytes, open_bytes) =
+            reply[mem::size_of::<fuse_out_header>()..].split_at(mem::size_of::<fuse_entry_out>());
+        entry_out.as_bytes_mut().copy_from_slice(entry_bytes);
+        open_out.as_bytes_mut().copy_from_slice(open_bytes);
+        assert_eq!(entry_out.nodeid, 5);
+        assert_eq!(open_out.fh, 7);
+    }
 
-        let total_len = u32::try_from(
-            mem::size_of::<fuse_out_header>()
-                + mem::size_of::<fuse_notify_inval_entry_out>()
-                + name.as_ref().len()
-                + 1,
-        )
-        .unwrap();
+    #[test]
+    fn statx() {
+        let arg = fuse_statx_in {
+            getattr_flags: FUSE_GETATTR_FH,
+            reserved: 0,
+            fh: 9,
+            sx_flags: libc::AT_STATX_DONT_SYNC as u32,
+            sx_mask: libc::STATX_BASIC_STATS | libc::STATX_BTIME,
+        };
 
-        return write_bytes(
-            &self.session.conn,
-            InvalEntry {
-                header: fuse_out_header {
-                    len: total_len,
-                    error: fuse_notify_code::FUSE_NOTIFY_INVAL_ENTRY as i32,
-                    unique: 0,
-                },
-                arg: fuse_notify_inval_entry_out {
-                    parent,
-                    namelen,
-                    padding: 0,
-                },
-                name,
-            },
+        // Disabled by default.
+        let (session, kernel) = test_session(KernelConfig::default());
+        send_request(&kernel, fuse_opcode::FUSE_STATX, 2, 5, arg.as_bytes());
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 3, 1, &[]);
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.unique(), 3);
+        let mut out_header = fuse_out_header::default();
+        out_header
+            .as_bytes_mut()
+            .copy_from_slice(&recv_reply(&kernel)[..]);
+        assert_eq!(out_header.unique, 2);
+        assert_eq!(out_header.error, -libc::ENOSYS);
+
+        let mut config = KernelConfig::default();
+        config.statx(true);
+        let (session, kernel) = test_session(config);
+        send_request(&kernel, fuse_opcode::FUSE_STATX, 2, 5, arg.as_bytes());
+
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::Statx(op) => {
+                assert_eq!(op.ino(), 5);
+                assert_eq!(op.fh(), Some(9));
+                assert_eq!(op.flags(), libc::AT_STATX_DONT_SYNC as u32);
+                assert_ne!(op.mask() & libc::STATX_BTIME, 0);
+                assert_ne!(op.mask() & libc::STATX_SIZE, 0);
+            }
+            _ => panic!("unexpected operation"),
+        }
+
+        let mut out = StatxOut::default();
+        out.ttl(Duration::from_secs(1));
+        out.attr().ino(5);
+        out.attr().size(42);
+        out.attr().mode(libc::S_IFREG | 0o644);
+        out.attr().btime(Duration::new(1_600_000_000, 123));
+        out.attr().attributes(
+            libc::STATX_ATTR_IMMUTABLE as u64,
+            libc::STATX_ATTR_IMMUTABLE as u64,
         );
+        req.reply_statx(out).unwrap();
 
-        struct InvalEntry<T>
-        where
-            T: AsRef<OsStr>,
-        {
-            header: fuse_out_header,
-            arg: fuse_notify_inval_entry_out,
-            name: T,
+        let reply = recv_reply(&kernel);
+        assert_eq!(
+            reply.len(),
+            mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_statx_out>()
+        );
+        let mut statx_out = fuse_statx_out::default();
+        statx_out
+            .as_bytes_mut()
+            .copy_from_slice(&reply[mem::size_of::<fuse_out_header>()..]);
+        assert_eq!(statx_out.attr_valid, 1);
+        assert_eq!(
+            statx_out.stat.mask,
+            libc::STATX_INO
+                | libc::STATX_SIZE
+                | libc::STATX_TYPE
+                | libc::STATX_MODE
+                | libc::STATX_BTIME
+        );
+        assert_eq!(statx_out.stat.ino, 5);
+        assert_eq!(statx_out.stat.size, 42);
+        assert_eq!(statx_out.stat.mode as u32, libc::S_IFREG | 0o644);
+        assert_eq!(statx_out.stat.btime.tv_sec, 1_600_000_000);
+        assert_eq!(statx_out.stat.btime.tv_nsec, 123);
+        assert_eq!(
+            statx_out.stat.attributes_mask,
+            libc::STATX_ATTR_IMMUTABLE as u64
+        );
+    }
+
+    #[test]
+    fn request_nodeid() {
+        let (session, kernel) = test_session(KernelConfig::default());
+        send_request(&kernel, fuse_opcode::FUSE_LOOKUP, 2, 7, b"foo\0");
+        send_request(
+            &kernel,
+            fuse_opcode::FUSE_GETATTR,
+            3,
+            9,
+            fuse_getattr_in::default().as_bytes(),
+        );
+
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.nodeid(), 7);
+        assert_eq!(RequestInfo { req: &req }.nodeid(), 7);
+        match req.operation().unwrap() {
+            Operation::Lookup(op) => assert_eq!(op.parent(), req.nodeid()),
+            _ => panic!("unexpected operation"),
         }
-        impl<T> Bytes for InvalEntry<T>
-        where
-            T: AsRef<OsStr>,
-        {
-            fn size(&self) -> usize {
-                self.header.len as usize
+
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.nodeid(), 9);
+        match req.operation().unwrap() {
+            Operation::Getattr(op) => assert_eq!(op.ino(), req.nodeid()),
+            _ => panic!("unexpected operation"),
+        }
+    }
+
+    #[test]
+    fn reply_twice() {
+        let (session, kernel) = test_session(KernelConfig::default());
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 2, 1, &[]);
+        let req = session.next_request().unwrap().unwrap();
+        req.reply_error(libc::EIO).unwrap();
+        req.reply_error(libc::ENOENT).unwrap();
+        req.reply(crate::reply::StatfsOut::default()).unwrap();
+
+        let reply = recv_reply(&kernel);
+        let mut header = fuse_out_header::default();
+        header.as_bytes_mut().copy_from_slice(&reply[..]);
+        assert_eq!(header.unique, 2);
+        assert_eq!(header.error, -libc::EIO);
+
+        // Only the first reply is written.
+        kernel.set_nonblocking(true).unwrap();
+        let mut buf = [0u8; 128];
+        assert_eq!(
+            kernel.recv(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+
+    #[test]
+    fn cancellation_token() {
+        let (session, kernel) = test_session(KernelConfig::default());
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 2, 1, &[]);
+        let req = session.next_request().unwrap().unwrap();
+        let token = req.cancellation_token();
+        assert!(!token.is_cancelled());
+
+        let interrupt_in = fuse_interrupt_in { unique: 2 };
+        send_request(
+            &kernel,
+            fuse_opcode::FUSE_INTERRUPT,
+            3,
+            0,
+            interrupt_in.as_bytes(),
+        );
+        let interrupt = session.next_request().unwrap().unwrap();
+        assert!(token.is_cancelled());
+        assert!(req.cancellation_token().is_cancelled());
+        assert!(!interrupt.cancellation_token().is_cancelled());
+
+        req.reply_error(libc::EINTR).unwrap();
+        drop(req);
+        assert!(session.inner.in_flight.drain().is_empty());
+
+        // The request is not tracked until the token is taken.
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 4, 1, &[]);
+        let req = session.next_request().unwrap().unwrap();
+        assert!(session.inner.in_flight.drain().is_empty());
+
+        // The interrupt received meanwhile cancels the token taken later.
+        let interrupt_in = fuse_interrupt_in { unique: 4 };
+        send_request(
+            &kernel,
+            fuse_opcode::FUSE_INTERRUPT,
+            5,
+            0,
+            interrupt_in.as_bytes(),
+        );
+        let _interrupt = session.next_request().unwrap().unwrap();
+        assert!(req.cancellation_token().is_cancelled());
+        drop(req);
+
+        // The token taken after the reply is never cancelled.
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 6, 1, &[]);
+        let req = session.next_request().unwrap().unwrap();
+        req.reply_error(libc::ENOSYS).unwrap();
+        assert!(!req.cancellation_token().is_cancelled());
+        assert!(session.inner.in_flight.drain().is_empty());
+    }
+
+    #[test]
+    fn interrupt_before_request() {
+        let (session, kernel) = test_session(KernelConfig::default());
+
+        // The interrupted request is not received yet.
+        let interrupt_in = fuse_interrupt_in { unique: 4 };
+        send_request(
+            &kernel,
+            fuse_opcode::FUSE_INTERRUPT,
+            5,
+            0,
+            interrupt_in.as_bytes(),
+        );
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 4, 1, &[]);
+
+        // The kernel is asked to resend the interrupt.
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.unique(), 4);
+        let reply = recv_reply(&kernel);
+        let mut header = fuse_out_header::default();
+        header.as_bytes_mut().copy_from_slice(&reply[..]);
+        assert_eq!(header.unique, 5);
+        assert_eq!(header.error, -libc::EAGAIN);
+
+        let token = req.cancellation_token();
+        send_request(
+            &kernel,
+            fuse_opcode::FUSE_INTERRUPT,
+            5,
+            0,
+            interrupt_in.as_bytes(),
+        );
+        let interrupt = session.next_request().unwrap().unwrap();
+        assert_eq!(interrupt.unique(), 5);
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn shutdown() {
+        // All the requests are tracked on receipt.
+        let mut config = KernelConfig::default();
+        config.op_timeout(Duration::from_secs(60));
+        let (session, kernel) = test_session(config);
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 2, 1, &[]);
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 3, 1, &[]);
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 4, 1, &[]);
+        let req2 = session.next_request().unwrap().unwrap();
+        let req3 = session.next_request().unwrap().unwrap();
+        let req4 = session.next_request().unwrap().unwrap();
+        req4.reply_error(libc::ENOSYS).unwrap();
+        let mut out_header = fuse_out_header::default();
+        out_header
+            .as_bytes_mut()
+            .copy_from_slice(&recv_reply(&kernel)[..]);
+        assert_eq!(out_header.unique, 4);
+
+        assert_eq!(session.shutdown().unwrap(), 2);
+        let mut replied = vec![];
+        for _ in 0..2 {
+            out_header
+                .as_bytes_mut()
+                .copy_from_slice(&recv_reply(&kernel)[..]);
+            assert_eq!(out_header.error, -libc::EINTR);
+            replied.push(out_header.unique);
+        }
+        replied.sort_unstable();
+        assert_eq!(replied, [2, 3]);
+
+        // The late replies from the handlers are dropped.
+        req2.reply_error(libc::ENOSYS).unwrap();
+        drop((req2, req3));
+        kernel.set_nonblocking(true).unwrap();
+        assert_eq!(
+            kernel.recv(&mut [0u8; 64]).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        assert_eq!(session.shutdown().unwrap(), 0);
+
+        // Otherwise, only the requests whose cancellation token is taken.
+        let (session, kernel) = test_session(KernelConfig::default());
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 2, 1, &[]);
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 3, 1, &[]);
+        let req2 = session.next_request().unwrap().unwrap();
+        let req3 = session.next_request().unwrap().unwrap();
+        let _token = req2.cancellation_token();
+        assert_eq!(session.shutdown().unwrap(), 1);
+        out_header
+            .as_bytes_mut()
+            .copy_from_slice(&recv_reply(&kernel)[..]);
+        assert_eq!(out_header.unique, 2);
+        assert_eq!(out_header.error, -libc::EINTR);
+        req3.reply_error(libc::ENOSYS).unwrap();
+        out_header
+            .as_bytes_mut()
+            .copy_from_slice(&recv_reply(&kernel)[..]);
+        assert_eq!(out_header.unique, 3);
+        assert_eq!(out_header.error, -libc::ENOSYS);
+    }
+
+    #[test]
+    fn submounts() {
+        let (session, _kernel) = test_session(KernelConfig::default());
+        assert!(!session.submounts_support());
+
+        let mut config = KernelConfig::default();
+        config.submounts(true);
+        let (session, kernel) = test_session_with_flags(config, INIT_FLAGS_MASK);
+        assert!(session.submounts_support());
+
+        send_request(&kernel, fuse_opcode::FUSE_LOOKUP, 2, 1, b"share\0");
+        let req = session.next_request().unwrap().unwrap();
+        let mut out = crate::reply::EntryOut::default();
+        out.ino(2);
+        out.attr().ino(2);
+        out.attr().mode(libc::S_IFDIR | 0o755);
+        out.submount(true);
+        req.reply(out).unwrap();
+
+        let reply = recv_reply(&kernel);
+        let mut entry_out = fuse_entry_out::default();
+        entry_out
+            .as_bytes_mut()
+            .copy_from_slice(&reply[mem::size_of::<fuse_out_header>()..]);
+        assert_eq!(entry_out.nodeid, 2);
+        assert_eq!(
+            entry_out.attr.padding & FUSE_ATTR_SUBMOUNT,
+            FUSE_ATTR_SUBMOUNT
+        );
+    }
+
+    #[test]
+    fn reply_data_chunks() {
+        let chunks: &[&[u8]] = &[b"foo", b"", b"barbaz"];
+        let mut writer = RecordingWriter::default();
+        write_reply(&mut writer, Reply::new(2, 0, chunks)).unwrap();
+        // The chunks are passed as separate iovecs following the header,
+        // skipping the empty ones.
+        let written: Vec<&[u8]> = writer.iovecs[1..].iter().map(|v| &v[..]).collect();
+        assert_eq!(written, [&b"foo"[..], &b"barbaz"[..]]);
+
+        let (session, kernel) = test_session(KernelConfig::default());
+        let read_in = fuse_read_in {
+            size: 4096,
+            ..Default::default()
+        };
+        send_request(&kernel, fuse_opcode::FUSE_READ, 2, 2, read_in.as_bytes());
+        send_request(&kernel, fuse_opcode::FUSE_READ, 3, 2, read_in.as_bytes());
+
+        let req = session.next_request().unwrap().unwrap();
+        req.reply_data_chunks(chunks).unwrap();
+        let reply = recv_reply(&kernel);
+        let mut out_header = fuse_out_header::default();
+        out_header
+            .as_bytes_mut()
+            .copy_from_slice(&reply[..mem::size_of::<fuse_out_header>()]);
+        assert_eq!(out_header.len as usize, reply.len());
+        assert_eq!(&reply[mem::size_of::<fuse_out_header>()..], b"foobarbaz");
+
+        // Too many chunks for a single vectored write.
+        let req = session.next_request().unwrap().unwrap();
+        let chunks = vec![&b"x"[..]; libc::UIO_MAXIOV as usize];
+        req.reply_data_chunks(&chunks).unwrap();
+        let reply = recv_reply(&kernel);
+        assert_eq!(
+            reply.len(),
+            mem::size_of::<fuse_out_header>() + libc::UIO_MAXIOV as usize
+        );
+    }
+
+    #[test]
+    fn attr_flags() {
+        let reply_attr_flags = |session: &Session, kernel: &UnixDatagram| {
+            let getattr_in = fuse_getattr_in::default();
+            send_request(
+                kernel,
+                fuse_opcode::FUSE_GETATTR,
+                2,
+                5,
+                getattr_in.as_bytes(),
+            );
+            let req = session.next_request().unwrap().unwrap();
+            let mut out = AttrOut::default();
+            out.attr().ino(5);
+            out.attr().attr_flags(FUSE_ATTR_DAX);
+            req.reply_attr(out).unwrap();
+            let reply = recv_reply(kernel);
+            let mut attr_out = fuse_attr_out::default();
+            attr_out
+                .as_bytes_mut()
+                .copy_from_slice(&reply[mem::size_of::<fuse_out_header>()..]);
+
+            send_request(kernel, fuse_opcode::FUSE_LOOKUP, 3, 1, b"foo\0");
+            let req = session.next_request().unwrap().unwrap();
+            let mut out = EntryOut::default();
+            out.ino(6);
+            out.attr().ino(6);
+            out.attr().attr_flags(FUSE_ATTR_DAX);
+            req.reply_entry(out).unwrap();
+            let reply = recv_reply(kernel);
+            let mut entry_out = fuse_entry_out::default();
+            entry_out
+                .as_bytes_mut()
+                .copy_from_slice(&reply[mem::size_of::<fuse_out_header>()..]);
+
+            (attr_out.attr.padding, entry_out.attr.padding)
+        };
+
+        // ABI 7.40: the flags are written.
+        let (session, kernel, _) = init_ext(KernelConfig::default(), 0);
+        assert_eq!(
+            reply_attr_flags(&session, &kernel),
+            (FUSE_ATTR_DAX, FUSE_ATTR_DAX)
+        );
+
+        // ABI 7.31: the field is a padding.
+        let (session, kernel) = test_session(KernelConfig::default());
+        assert_eq!(reply_attr_flags(&session, &kernel), (0, 0));
+    }
+
+    #[test]
+    fn parallel_direct_writes() {
+        let reply_open_flags = |session: &Session, kernel: &UnixDatagram| {
+            let open_in = fuse_open_in {
+                flags: libc::O_RDWR as u32,
+                ..Default::default()
+            };
+            send_request(kernel, fuse_opcode::FUSE_OPEN, 2, 5, open_in.as_bytes());
+            let req = session.next_request().unwrap().unwrap();
+            let mut out = OpenOut::default();
+            out.fh(1);
+            out.direct_io(true);
+            out.parallel_direct_writes(true);
+            req.reply_open(out).unwrap();
+            let reply = recv_reply(kernel);
+            let mut open_out = fuse_open_out::default();
+            open_out
+                .as_bytes_mut()
+                .copy_from_slice(&reply[mem::size_of::<fuse_out_header>()..]);
+            open_out.open_flags
+        };
+
+        // ABI 7.40: the flag is sent.
+        let (session, kernel, _) = init_ext(KernelConfig::default(), 0);
+        assert_eq!(
+            reply_open_flags(&session, &kernel),
+            FOPEN_DIRECT_IO | FOPEN_PARALLEL_DIRECT_WRITES
+        );
+
+        // ABI 7.31: the flag is omitted.
+        let (session, kernel) = test_session(KernelConfig::default());
+        assert_eq!(reply_open_flags(&session, &kernel), FOPEN_DIRECT_IO);
+
+        // ABI 7.37: the flag is omitted, as it is defined since 7.38.
+        let (session, kernel) = init_minor(KernelConfig::default(), 37);
+        assert_eq!(reply_open_flags(&session, &kernel), FOPEN_DIRECT_IO);
+    }
+
+    #[test]
+    fn open_no_flush() {
+        let reply_open_flags = |session: &Session, kernel: &UnixDatagram| {
+            send_request(
+                kernel,
+                fuse_opcode::FUSE_OPEN,
+                2,
+                5,
+                fuse_open_in::default().as_bytes(),
+            );
+            let req = session.next_request().unwrap().unwrap();
+            let mut out = OpenOut::default();
+            out.fh(1);
+            out.keep_cache(true);
+            out.no_flush(true);
+            req.reply_open(out).unwrap();
+            let reply = recv_reply(kernel);
+            let mut open_out = fuse_open_out::default();
+            open_out
+                .as_bytes_mut()
+                .copy_from_slice(&reply[mem::size_of::<fuse_out_header>()..]);
+            open_out.open_flags
+        };
+
+        // ABI 7.35: the flag is sent.
+        let (session, kernel) = init_minor(KernelConfig::default(), 35);
+        assert_eq!(
+            reply_open_flags(&session, &kernel),
+            FOPEN_KEEP_CACHE | FOPEN_NOFLUSH
+        );
+
+        // ABI 7.34: the flag is omitted.
+        let (session, kernel) = init_minor(KernelConfig::default(), 34);
+        assert_eq!(reply_open_flags(&session, &kernel), FOPEN_KEEP_CACHE);
+    }
+
+    #[test]
+    fn partial_read() {
+        use std::os::unix::net::UnixStream;
+
+        // A stream socket does not preserve the message boundaries.
+        let (mut kernel, daemon) = UnixStream::pair().unwrap();
+        let header = |opcode: fuse_opcode, unique: u64, arg_len: usize| fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + arg_len) as u32,
+            opcode: opcode as u32,
+            unique,
+            nodeid: 1,
+            ..Default::default()
+        };
+
+        let init_in = fuse_init_in {
+            major: 7,
+            minor: FUSE_KERNEL_MINOR_VERSION,
+            max_readahead: 40,
+            flags: INIT_FLAGS_MASK,
+        };
+        let mut msg = header(fuse_opcode::FUSE_INIT, 1, mem::size_of::<fuse_init_in>())
+            .as_bytes()
+            .to_vec();
+        msg.extend_from_slice(init_in.as_bytes());
+        kernel.write_all(&msg).unwrap();
+        let session =
+            unsafe { Session::from_raw_fd(daemon.into_raw_fd(), KernelConfig::default()) }.unwrap();
+        let mut reply =
+            vec![0u8; mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_init_out>()];
+        kernel.read_exact(&mut reply).unwrap();
+
+        let lookup = |unique: u64| {
+            let mut msg = header(fuse_opcode::FUSE_LOOKUP, unique, 7)
+                .as_bytes()
+                .to_vec();
+            msg.extend_from_slice(b"foobar\0");
+            msg
+        };
+        let expect_lookup = |unique| {
+            let req = session.next_request().unwrap().unwrap();
+            assert_eq!(req.unique(), unique);
+            match req.operation().unwrap() {
+                Operation::Lookup(op) => assert_eq!(op.name(), "foobar"),
+                _ => panic!("unexpected operation"),
             }
+        };
 
-            fn count(&self) -> usize {
-                4
+        // The requests pipelined in a single write are read one by one.
+        kernel.write_all(&[lookup(2), lookup(3)].concat()).unwrap();
+        expect_lookup(2);
+        expect_lookup(3);
+
+        // The request is written in pieces, splitting both the header and
+        // the argument.
+        let msg = lookup(4);
+        for chunk in msg.chunks(10) {
+            kernel.write_all(chunk).unwrap();
+        }
+        expect_lookup(4);
+
+        // The transport is closed before the rest arrives.
+        let mut msg = header(fuse_opcode::FUSE_LOOKUP, 5, 7).as_bytes().to_vec();
+        msg.extend_from_slice(b"foo");
+        kernel.write_all(&msg).unwrap();
+        kernel.shutdown(std::net::Shutdown::Write).unwrap();
+        match session.next_request() {
+            Err(err) => assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof),
+            Ok(..) => panic!("the truncated request is accepted"),
+        }
+    }
+
+    #[test]
+    fn syncfs() {
+        let (session, kernel) = test_session(KernelConfig::default());
+
+        let syncfs_in = fuse_syncfs_in::default();
+        send_request(
+            &kernel,
+            fuse_opcode::FUSE_SYNCFS,
+            2,
+            1,
+            syncfs_in.as_bytes(),
+        );
+
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::Syncfs(op) => {
+                assert_eq!(op.ino(), 1);
+                req.reply(()).unwrap();
             }
+            _ => panic!("unexpected operation"),
+        }
 
-            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
-                dst.put(self.header.as_bytes());
-                dst.put(self.arg.as_bytes());
-                dst.put(self.name.as_ref().as_bytes());
-                dst.put(b"\0"); // null terminator
+        let expected = fuse_out_header {
+            len: mem::size_of::<fuse_out_header>() as u32,
+            error: 0,
+            unique: 2,
+        };
+        assert_eq!(recv_reply(&kernel), expected.as_bytes());
+    }
+
+    #[test]
+    fn unsupported_errors() {
+        let mut config = KernelConfig::default();
+        config
+            .unsupported_error(Category::Write, libc::EROFS)
+            .unsupported_error(Category::Xattr, libc::EOPNOTSUPP);
+        let (session, kernel) = test_session(config);
+
+        let write_in = fuse_write_in::default();
+        let setxattr_in = fuse_setxattr_in::default();
+        let getxattr_in = fuse_getxattr_in::default();
+        let getattr_in = fuse_getattr_in::default();
+        for &(opcode, arg, errno) in &[
+            (fuse_opcode::FUSE_WRITE, write_in.as_bytes(), libc::EROFS),
+            (
+                fuse_opcode::FUSE_SETXATTR,
+                setxattr_in.as_bytes(),
+                libc::EROFS,
+            ),
+            (
+                fuse_opcode::FUSE_GETXATTR,
+                getxattr_in.as_bytes(),
+                libc::EOPNOTSUPP,
+            ),
+            (
+                fuse_opcode::FUSE_GETATTR,
+                getattr_in.as_bytes(),
+                libc::ENOSYS,
+            ),
+        ] {
+            send_request(&kernel, opcode, 2, 1, arg);
+            let req = session.next_request().unwrap().unwrap();
+            req.reply_unsupported().unwrap();
+
+            let reply = recv_reply(&kernel);
+            assert_eq!(reply[4..8], (-errno).to_ne_bytes(), "{:?}", opcode as u32);
+        }
+    }
+
+    #[test]
+    fn setattr_replies_new_attributes() {
+        let (session, kernel) = test_session(KernelConfig::default());
+
+        let setattr_in = fuse_setattr_in {
+            valid: FATTR_SIZE | FATTR_MTIME,
+            size: 5,
+            mtime: 1234,
+            mtimensec: 5678,
+            ..Default::default()
+        };
+        send_request(
+            &kernel,
+            fuse_opcode::FUSE_SETATTR,
+            2,
+            42,
+            setattr_in.as_bytes(),
+        );
+
+        // The current state of the inode.
+        let mut size = 4096;
+        let mut mtime = Duration::from_secs(1);
+
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::Setattr(op) => {
+                assert!(op.mode().is_none());
+                if let Some(new_size) = op.size() {
+                    size = new_size;
+                }
+                if let Some(crate::op::SetAttrTime::Timespec(new_mtime)) = op.mtime() {
+                    mtime = new_mtime;
+                }
+
+                let mut out = crate::reply::AttrOut::default();
+                out.attr().ino(op.ino());
+                out.attr().size(size);
+                out.attr().mtime(mtime);
+                req.reply(out).unwrap();
             }
+            _ => panic!("unexpected operation"),
         }
+
+        let reply = recv_reply(&kernel);
+        let mut attr_out = fuse_attr_out::default();
+        attr_out
+            .as_bytes_mut()
+            .copy_from_slice(&reply[mem::size_of::<fuse_out_header>()..]);
+        assert_eq!(attr_out.attr.ino, 42);
+        assert_eq!(attr_out.attr.size, 5);
+        assert_eq!(attr_out.attr.mtime, 1234);
+        assert_eq!(attr_out.attr.mtimensec, 5678);
     }
 
-    /// Notify the invalidation about a directory entry to the kernel.
-    ///
-    /// The role of this notification is similar to `notify_inval_entry`.
-    /// Additionally, when the provided `child` inode matches the inode
-    /// in the dentry cache, the inotify will inform the deletion to
-    /// watchers if exists.
-    pub fn delete<T>(&self, parent: u64, child: u64, name: T) -> io::Result<()>
-    where
-        T: AsRef<OsStr>,
-    {
-        let namelen = u32::try_from(name.as_ref().len()).expect("provided name is too long");
+    #[test]
+    fn background_requests() {
+        let (session, kernel) = test_session(KernelConfig::default());
+
+        let read_in = fuse_read_in::default();
+        send_request(&kernel, fuse_opcode::FUSE_READ, 2, 2, read_in.as_bytes());
+        send_request(&kernel, fuse_opcode::FUSE_READ, 3, 2, read_in.as_bytes());
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 4, 1, &[]);
+        assert_eq!(session.background_requests(), 0);
+
+        let req1 = session.next_request().unwrap().unwrap();
+        assert_eq!(session.background_requests(), 1);
+        let req2 = session.next_request().unwrap().unwrap();
+        assert_eq!(session.background_requests(), 2);
+        let req3 = session.next_request().unwrap().unwrap();
+        assert_eq!(session.background_requests(), 2);
+
+        req1.reply(&b"foo"[..]).unwrap();
+        recv_reply(&kernel);
+        assert_eq!(session.background_requests(), 1);
+
+        // Replying twice does not decrement the counter again.
+        req1.reply_error(libc::EIO).unwrap();
+        assert_eq!(session.background_requests(), 1);
+
+        req3.reply(()).unwrap();
+        recv_reply(&kernel);
+        assert_eq!(session.background_requests(), 1);
+
+        // Dropped without replying.
+        drop(req2);
+        assert_eq!(session.background_requests(), 0);
+    }
 
-        let total_len = u32::try_from(
-            mem::size_of::<fuse_out_header>()
-                + mem::size_of::<fuse_notify_delete_out>()
-                + name.as_ref().len()
-                + 1,
-        )
-        .expect("payload is too long");
+    #[test]
+    fn mount_fsname_subtype() {
+        let mut config = KernelConfig::default();
+        config
+            .mount_option("ro")
+            .fsname("myfs")
+            .subtype("my,sub\\type");
+        assert_eq!(
+            config.mountopts.to_option_string(),
+            "ro,fsname=myfs,subtype=my\\,sub\\\\type,auto_unmount"
+        );
 
-        return write_bytes(
-            &self.session.conn,
-            Delete {
-                header: fuse_out_header {
-                    len: total_len,
-                    error: fuse_notify_code::FUSE_NOTIFY_DELETE as i32,
-                    unique: 0,
-                },
-                arg: fuse_notify_delete_out {
-                    parent,
-                    child,
-                    namelen,
-                    padding: 0,
-                },
-                name,
-            },
+        let mut config = KernelConfig::default();
+        config.auto_unmount(false).fsname("a,b");
+        assert_eq!(config.mountopts.to_option_string(), "fsname=a\\,b");
+    }
+
+    #[test]
+    fn next_request_timeout() {
+        let (session, kernel) = test_session(KernelConfig::default());
+        let timeout = Duration::from_millis(50);
+
+        let start = Instant::now();
+        assert!(session.next_request_timeout(timeout).unwrap().is_none());
+        assert!(start.elapsed() >= timeout);
+        assert!(!session.is_closed());
+
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 2, 1, &[]);
+        let start = Instant::now();
+        let req = session
+            .next_request_timeout(Duration::from_secs(10))
+            .unwrap()
+            .unwrap();
+        assert_eq!(req.unique(), 2);
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        drop(kernel);
+        assert!(session.next_request_timeout(timeout).unwrap().is_none());
+        assert!(session.is_closed());
+    }
+
+    #[test]
+    fn idle_connection() {
+        let (session, kernel) = test_session(KernelConfig::default());
+        let timeout = Duration::from_millis(50);
+        assert!(!session.is_idle(timeout));
+
+        std::thread::sleep(timeout);
+        assert!(session.is_idle(timeout));
+        assert!(session.idle_time() >= timeout);
+
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 2, 1, &[]);
+        let _req = session.next_request().unwrap().unwrap();
+        assert!(!session.is_idle(timeout));
+    }
+
+    #[test]
+    fn reply_attr() {
+        let (session, kernel) = test_session(KernelConfig::default());
+
+        let getattr_in = fuse_getattr_in::default();
+        send_request(
+            &kernel,
+            fuse_opcode::FUSE_GETATTR,
+            2,
+            5,
+            getattr_in.as_bytes(),
         );
 
-        struct Delete<T>
-        where
-            T: AsRef<OsStr>,
-        {
-            header: fuse_out_header,
-            arg: fuse_notify_delete_out,
-            name: T,
+        let req = session.next_request().unwrap().unwrap();
+        let mut out = AttrOut::default();
+        out.attr().ino(5);
+        req.reply_attr(out).unwrap();
+
+        let reply = recv_reply(&kernel);
+        let mut attr_out = fuse_attr_out::default();
+        attr_out
+            .as_bytes_mut()
+            .copy_from_slice(&reply[mem::size_of::<fuse_out_header>()..]);
+        assert_eq!(attr_out.attr.ino, 5);
+    }
+
+    #[test]
+    fn reply_attr_raw() {
+        let (session, kernel) = test_session(KernelConfig::default());
+
+        let mut attr = fuse_attr {
+            ino: 5,
+            size: 1024,
+            mode: libc::S_IFREG | 0o644,
+            nlink: 1,
+            ..Default::default()
+        };
+        attr.padding = FUSE_ATTR_SUBMOUNT;
+        let encoded = attr.as_bytes().to_vec();
+
+        let getattr_in = fuse_getattr_in::default();
+        send_request(
+            &kernel,
+            fuse_opcode::FUSE_GETATTR,
+            2,
+            5,
+            getattr_in.as_bytes(),
+        );
+        let req = session.next_request().unwrap().unwrap();
+        req.reply_attr_raw(&encoded, Duration::new(3, 500)).unwrap();
+
+        let reply = recv_reply(&kernel);
+        let mut header = fuse_out_header::default();
+        header
+            .as_bytes_mut()
+            .copy_from_slice(&reply[..mem::size_of::<fuse_out_header>()]);
+        assert_eq!(header.unique, 2);
+        assert_eq!(header.error, 0);
+        assert_eq!(
+            header.len as usize,
+            mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_attr_out>()
+        );
+
+        let mut attr_out = fuse_attr_out::default();
+        attr_out
+            .as_bytes_mut()
+            .copy_from_slice(&reply[mem::size_of::<fuse_out_header>()..]);
+        assert_eq!(attr_out.attr_valid, 3);
+        assert_eq!(attr_out.attr_valid_nsec, 500);
+
+        // The attributes are written verbatim.
+        assert_eq!(&reply[reply.len() - encoded.len()..], &encoded[..]);
+    }
+
+    #[test]
+    fn reply_attr_sparse_blocks() {
+        let (session, kernel) = test_session(KernelConfig::default());
+        let getattr_in = fuse_getattr_in::default();
+        send_request(
+            &kernel,
+            fuse_opcode::FUSE_GETATTR,
+            2,
+            5,
+            getattr_in.as_bytes(),
+        );
+
+        // A 1 MiB file with a single 4 KiB block written, the rest punched out.
+        let req = session.next_request().unwrap().unwrap();
+        let mut out = AttrOut::default();
+        out.attr().ino(5);
+        out.attr().size(1024 * 1024);
+        out.attr().blksize(4096);
+        out.attr().blocks(8);
+        req.reply_attr(out).unwrap();
+
+        let reply = recv_reply(&kernel);
+        let mut attr_out = fuse_attr_out::default();
+        attr_out
+            .as_bytes_mut()
+            .copy_from_slice(&reply[mem::size_of::<fuse_out_header>()..]);
+        assert_eq!(attr_out.attr.size, 1024 * 1024);
+        assert_eq!(attr_out.attr.blksize, 4096);
+        assert_eq!(attr_out.attr.blocks, 8);
+        assert!(attr_out.attr.blocks * 512 < attr_out.attr.size);
+    }
+
+    #[test]
+    fn reply_attr_blksize() {
+        let check = |config| {
+            let (session, kernel) = test_session(config);
+            let getattr_in = fuse_getattr_in::default();
+            send_request(
+                &kernel,
+                fuse_opcode::FUSE_GETATTR,
+                2,
+                5,
+                getattr_in.as_bytes(),
+            );
+
+            let req = session.next_request().unwrap().unwrap();
+            let mut out = AttrOut::default();
+            out.attr().ino(5);
+            out.attr().blksize(1024 * 1024);
+            req.reply_attr(out).unwrap();
+
+            let reply = recv_reply(&kernel);
+            let mut attr_out = fuse_attr_out::default();
+            attr_out
+                .as_bytes_mut()
+                .copy_from_slice(&reply[mem::size_of::<fuse_out_header>()..]);
+            assert_eq!(attr_out.attr.blksize, 1024 * 1024);
+        };
+        check(KernelConfig::default());
+
+        // Neither the TTL policy nor the disabled cache touches the block size.
+        let mut config = KernelConfig::default();
+        config.attr_ttl(|_, _| Some(Duration::from_secs(1)));
+        check(config);
+
+        let mut config = KernelConfig::default();
+        config.disable_attr_cache(true);
+        check(config);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "does not match the request")]
+    fn reply_attr_mismatched_ino() {
+        let (session, kernel) = test_session(KernelConfig::default());
+
+        let getattr_in = fuse_getattr_in::default();
+        send_request(
+            &kernel,
+            fuse_opcode::FUSE_GETATTR,
+            2,
+            5,
+            getattr_in.as_bytes(),
+        );
+
+        let req = session.next_request().unwrap().unwrap();
+        let mut out = AttrOut::default();
+        out.attr().ino(6);
+        let _ = req.reply_attr(out);
+    }
+
+    #[test]
+    fn long_symlink_target() {
+        let mut config = KernelConfig::default();
+        config.max_symlink_len(8);
+        let (session, kernel) = test_session(config);
+
+        let symlink = |unique: u64, link: &str| {
+            let mut arg = b"name\0".to_vec();
+            arg.extend_from_slice(link.as_bytes());
+            arg.push(0);
+            send_request(&kernel, fuse_opcode::FUSE_SYMLINK, unique, 1, &arg);
+        };
+        symlink(2, "/too/long/target");
+        symlink(3, "/target");
+        send_request(&kernel, fuse_opcode::FUSE_READLINK, 4, 2, &[]);
+        send_request(&kernel, fuse_opcode::FUSE_READLINK, 5, 2, &[]);
+
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.unique(), 3);
+
+        let mut out_header = fuse_out_header::default();
+        out_header
+            .as_bytes_mut()
+            .copy_from_slice(&recv_reply(&kernel)[..]);
+        assert_eq!(out_header.unique, 2);
+        assert_eq!(out_header.error, -libc::ENAMETOOLONG);
+
+        let req = session.next_request().unwrap().unwrap();
+        req.reply_readlink("/target").unwrap();
+        let reply = recv_reply(&kernel);
+        assert_eq!(reply[mem::size_of::<fuse_out_header>()..], *b"/target");
+
+        let req = session.next_request().unwrap().unwrap();
+        req.reply_readlink("/too/long/target").unwrap();
+        let reply = recv_reply(&kernel);
+        assert_eq!(reply.len(), mem::size_of::<fuse_out_header>());
+        out_header.as_bytes_mut().copy_from_slice(&reply[..]);
+        assert_eq!(out_header.unique, 5);
+        assert_eq!(out_header.error, -libc::ENAMETOOLONG);
+    }
+
+    #[test]
+    fn absent_xattr() {
+        let mut config = KernelConfig::default();
+        config
+            .absent_xattr("security.capability")
+            .absent_xattr("system.posix_acl_access");
+        let (session, kernel) = test_session(config);
+
+        let getxattr = |unique: u64, name: &str| {
+            let getxattr_in = fuse_getxattr_in {
+                size: 64,
+                ..Default::default()
+            };
+            let mut arg = getxattr_in.as_bytes().to_vec();
+            arg.extend_from_slice(name.as_bytes());
+            arg.push(0);
+            send_request(&kernel, fuse_opcode::FUSE_GETXATTR, unique, 2, &arg);
+        };
+        getxattr(2, "security.capability");
+        getxattr(3, "user.comment");
+        getxattr(4, "system.posix_acl_access");
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 5, 1, &[]);
+
+        // Only the unregistered name reaches the filesystem.
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.unique(), 3);
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.unique(), 5);
+
+        for &unique in &[2, 4] {
+            let mut out_header = fuse_out_header::default();
+            out_header
+                .as_bytes_mut()
+                .copy_from_slice(&recv_reply(&kernel)[..]);
+            assert_eq!(out_header.unique, unique);
+            assert_eq!(out_header.error, -libc::ENODATA);
         }
-        impl<T> Bytes for Delete<T>
-        where
-            T: AsRef<OsStr>,
-        {
-            fn size(&self) -> usize {
-                self.header.len as usize
-            }
+    }
+
+    #[test]
+    fn root_dotdot() {
+        let mut root_attr = AttrOut::default();
+        root_attr.attr().mode(libc::S_IFDIR | 0o755);
+        root_attr.ttl(Duration::from_secs(60));
+
+        let mut config = KernelConfig::default();
+        config.root_attr(root_attr);
+        let (session, kernel) = test_session(config);
+        send_request(&kernel, fuse_opcode::FUSE_LOOKUP, 2, FUSE_ROOT_ID, b"..\0");
+        send_request(&kernel, fuse_opcode::FUSE_LOOKUP, 3, 5, b"..\0");
+
+        // Only the parent of the root is answered by the session.
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.unique(), 3);
+
+        let reply = recv_reply(&kernel);
+        let mut out_header = fuse_out_header::default();
+        out_header
+            .as_bytes_mut()
+            .copy_from_slice(&reply[..mem::size_of::<fuse_out_header>()]);
+        assert_eq!(out_header.unique, 2);
+        assert_eq!(out_header.error, 0);
+
+        let mut entry_out = fuse_entry_out::default();
+        entry_out
+            .as_bytes_mut()
+            .copy_from_slice(&reply[mem::size_of::<fuse_out_header>()..]);
+        assert_eq!(entry_out.nodeid, FUSE_ROOT_ID);
+        assert_eq!(entry_out.entry_valid, 60);
+        assert_eq!(entry_out.attr.ino, FUSE_ROOT_ID);
+        assert_eq!(entry_out.attr.mode, libc::S_IFDIR | 0o755);
+
+        // The filesystem may handle it by itself instead.
+        let mut config = KernelConfig::default();
+        config.root_attr(AttrOut::default()).root_dotdot(false);
+        let (session, kernel) = test_session(config);
+        send_request(&kernel, fuse_opcode::FUSE_LOOKUP, 2, FUSE_ROOT_ID, b"..\0");
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.unique(), 2);
+    }
+
+    #[test]
+    fn root_attr() {
+        let mut root_attr = AttrOut::default();
+        root_attr.attr().mode(libc::S_IFDIR | 0o755);
+        root_attr.attr().nlink(2);
+        root_attr.ttl(Duration::from_secs(60));
+
+        let mut config = KernelConfig::default();
+        config.root_attr(root_attr);
+        let (session, kernel) = test_session(config);
+
+        let getattr_in = fuse_getattr_in::default();
+        send_request(
+            &kernel,
+            fuse_opcode::FUSE_GETATTR,
+            2,
+            FUSE_ROOT_ID,
+            getattr_in.as_bytes(),
+        );
+        send_request(
+            &kernel,
+            fuse_opcode::FUSE_GETATTR,
+            3,
+            2,
+            getattr_in.as_bytes(),
+        );
+
+        // The root is answered without returning the request.
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.unique(), 3);
+        assert_eq!(req.header.nodeid, 2);
+
+        let reply = recv_reply(&kernel);
+        let mut out_header = fuse_out_header::default();
+        out_header
+            .as_bytes_mut()
+            .copy_from_slice(&reply[..mem::size_of::<fuse_out_header>()]);
+        assert_eq!(out_header.unique, 2);
+        assert_eq!(out_header.error, 0);
+
+        let mut attr_out = fuse_attr_out::default();
+        attr_out
+            .as_bytes_mut()
+            .copy_from_slice(&reply[mem::size_of::<fuse_out_header>()..]);
+        assert_eq!(attr_out.attr.ino, FUSE_ROOT_ID);
+        assert_eq!(attr_out.attr.mode, libc::S_IFDIR | 0o755);
+        assert_eq!(attr_out.attr.nlink, 2);
+        assert_eq!(attr_out.attr_valid, 60);
+    }
+
+    #[test]
+    fn readdir_state() {
+        // A backend listing the entries only sequentially.
+        struct Cursor {
+            next: u64,
+        }
+
+        let (session, kernel) = test_session(KernelConfig::default());
+        let read_in = |offset| fuse_read_in {
+            fh: 7,
+            offset,
+            size: 4096,
+            ..Default::default()
+        };
+
+        let mut offset = 0;
+        for unique in 2..5 {
+            send_request(
+                &kernel,
+                fuse_opcode::FUSE_READDIR,
+                unique,
+                3,
+                read_in(offset).as_bytes(),
+            );
+            let req = session.next_request().unwrap().unwrap();
+            let mut cursor = match req.take_readdir_state::<Cursor>() {
+                Some(cursor) => cursor,
+                None => {
+                    assert_eq!(offset, 0, "the stream must be resumed");
+                    Cursor { next: 0 }
+                }
+            };
+            assert_eq!(cursor.next, offset);
+
+            // Fill a reply with 10 entries.
+            cursor.next += 10;
+            offset = cursor.next;
+            req.save_readdir_state(offset, cursor);
+        }
+
+        // The stale state is discarded by seeking back.
+        send_request(
+            &kernel,
+            fuse_opcode::FUSE_READDIR,
+            5,
+            3,
+            read_in(10).as_bytes(),
+        );
+        let req = session.next_request().unwrap().unwrap();
+        assert!(req.take_readdir_state::<Cursor>().is_none());
+        req.save_readdir_state(20, Cursor { next: 20 });
+        drop(req);
+        assert_eq!(session.inner.readdir_states.lock().unwrap().len(), 1);
+
+        // The state is dropped with the directory handle.
+        let release_in = fuse_release_in {
+            fh: 7,
+            ..Default::default()
+        };
+        send_request(
+            &kernel,
+            fuse_opcode::FUSE_RELEASEDIR,
+            6,
+            3,
+            release_in.as_bytes(),
+        );
+        let _req = session.next_request().unwrap().unwrap();
+        assert!(session.inner.readdir_states.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn readdir_state_per_directory() {
+        let (session, kernel) = test_session(KernelConfig::default());
+        let readdir = |unique, nodeid, fh, offset| {
+            let read_in = fuse_read_in {
+                fh,
+                offset,
+                size: 4096,
+                ..Default::default()
+            };
+            send_request(
+                &kernel,
+                fuse_opcode::FUSE_READDIR,
+                unique,
+                nodeid,
+                read_in.as_bytes(),
+            );
+            session.next_request().unwrap().unwrap()
+        };
+
+        // Two directories opened with the same handle.
+        readdir(2, 3, 7, 0).save_readdir_state(10, "a");
+        readdir(3, 4, 7, 0).save_readdir_state(10, "b");
+        assert_eq!(readdir(4, 4, 7, 10).take_readdir_state(), Some("b"));
+        assert_eq!(readdir(5, 3, 7, 10).take_readdir_state(), Some("a"));
 
-            fn count(&self) -> usize {
-                4
-            }
+        // The handle `0` identifies no stream.
+        readdir(6, 3, 0, 0).save_readdir_state(10, "a");
+        assert!(session.inner.readdir_states.lock().unwrap().is_empty());
+        assert_eq!(readdir(7, 3, 0, 10).take_readdir_state::<&str>(), None);
+    }
 
-            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
-                dst.put(self.header.as_bytes());
-                dst.put(self.arg.as_bytes());
-                dst.put(self.name.as_ref().as_bytes());
-                dst.put(b"\0"); // null terminator
+    #[test]
+    fn readdir_state_zero_message_opendir() {
+        let (session, kernel) = test_session_with_flags(
+            KernelConfig::default(),
+            INIT_FLAGS_MASK | FUSE_NO_OPENDIR_SUPPORT,
+        );
+        send_request(
+            &kernel,
+            fuse_opcode::FUSE_OPENDIR,
+            2,
+            3,
+            fuse_open_in::default().as_bytes(),
+        );
+        let req = session.next_request().unwrap().unwrap();
+        req.reply_error(libc::ENOSYS).unwrap();
+        recv_reply(&kernel);
+
+        let read_in = fuse_read_in {
+            fh: 7,
+            size: 4096,
+            ..Default::default()
+        };
+        send_request(&kernel, fuse_opcode::FUSE_READDIR, 3, 3, read_in.as_bytes());
+        let req = session.next_request().unwrap().unwrap();
+        req.save_readdir_state(10, ());
+        assert!(session.inner.readdir_states.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn readdirplus_fallback() {
+        let mut config = KernelConfig::default();
+        config.readdirplus(true);
+        let (session, _kernel) = test_session(config);
+        assert!(session.readdirplus_support());
+
+        // readdirplus is not negotiated by default.
+        let (session, kernel) = test_session(KernelConfig::default());
+        assert!(!session.readdirplus_support());
+
+        let read_in = fuse_read_in {
+            size: 4096,
+            ..Default::default()
+        };
+        send_request(&kernel, fuse_opcode::FUSE_READDIR, 2, 1, read_in.as_bytes());
+
+        // The readdirplus implementation is reused for the plain readdir.
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::Readdir(op) => {
+                assert_eq!(op.mode(), crate::op::ReaddirMode::Normal);
+
+                let mut entry = crate::reply::EntryOut::default();
+                entry.attr().ino(42);
+                entry.attr().mode(libc::S_IFREG | 0o644);
+
+                let mut out =
+                    crate::reply::ReaddirPlusOut::with_mode(op.size() as usize, op.mode());
+                assert!(!out.entry(OsStr::new("foo"), 1, &entry));
+                req.reply(out).unwrap();
             }
+            _ => panic!("unexpected operation"),
         }
-    }
 
-    /// Push the data in an inode for updating the kernel cache.
-    pub fn store<T>(&self, ino: u64, offset: u64, data: T) -> io::Result<()>
-    where
-        T: Bytes,
-    {
-        let size = u32::try_from(data.size()).expect("provided data is too large");
+        let reply = recv_reply(&kernel);
+        let body = &reply[mem::size_of::<fuse_out_header>()..];
+        assert_eq!(body.len(), mem::size_of::<fuse_dirent>() + 8);
+
+        let mut dirent = fuse_dirent::default();
+        dirent
+            .as_bytes_mut()
+            .copy_from_slice(&body[..mem::size_of::<fuse_dirent>()]);
+        assert_eq!(dirent.ino, 42);
+        assert_eq!(dirent.off, 1);
+        assert_eq!(dirent.namelen, 3);
+        assert_eq!(dirent.typ, u32::from(libc::DT_REG));
+        assert_eq!(body[mem::size_of::<fuse_dirent>()..], b"foo\0\0\0\0\0"[..]);
+    }
 
-        let total_len = u32::try_from(
-            mem::size_of::<fuse_out_header>()
-                + mem::size_of::<fuse_notify_store_out>()
-                + data.size(),
-        )
-        .expect("payload is too long");
+    #[test]
+    fn readdirplus_enosys() {
+        let mut config = KernelConfig::default();
+        config.readdirplus(true);
+        let (session, kernel) = test_session(config);
+        assert!(!session.readdirplus_fallback());
+
+        let read_in = fuse_read_in {
+            size: 4096,
+            ..Default::default()
+        };
 
-        return write_bytes(
-            &self.session.conn,
-            Store {
-                header: fuse_out_header {
-                    len: total_len,
-                    error: fuse_notify_code::FUSE_NOTIFY_STORE as i32,
-                    unique: 0,
-                },
-                arg: fuse_notify_store_out {
-                    nodeid: ino,
-                    offset,
-                    size,
-                    padding: 0,
-                },
-                data,
-            },
+        // ENOSYS is passed through to the kernel as is.
+        send_request(
+            &kernel,
+            fuse_opcode::FUSE_READDIRPLUS,
+            2,
+            1,
+            read_in.as_bytes(),
+        );
+        let req = session.next_request().unwrap().unwrap();
+
+        // Another request received before the fallback.
+        send_request(
+            &kernel,
+            fuse_opcode::FUSE_READDIRPLUS,
+            10,
+            1,
+            read_in.as_bytes(),
         );
+        let inflight = session.next_request().unwrap().unwrap();
 
-        struct Store<T>
-        where
-            T: Bytes,
-        {
-            header: fuse_out_header,
-            arg: fuse_notify_store_out,
-            data: T,
+        match req.operation().unwrap() {
+            Operation::Readdir(op) => assert_eq!(op.mode(), crate::op::ReaddirMode::Plus),
+            _ => panic!("unexpected operation"),
         }
-        impl<T> Bytes for Store<T>
-        where
-            T: Bytes,
-        {
-            fn size(&self) -> usize {
-                self.header.len as usize
+        req.reply_error(libc::ENOSYS).unwrap();
+
+        let reply = recv_reply(&kernel);
+        let mut header = fuse_out_header::default();
+        header
+            .as_bytes_mut()
+            .copy_from_slice(&reply[..mem::size_of::<fuse_out_header>()]);
+        assert_eq!(header.unique, 2);
+        assert_eq!(header.error, -libc::ENOSYS);
+        assert!(session.readdirplus_fallback());
+        assert!(!session.is_closed());
+
+        // The request received earlier is still decoded and replied in the
+        // readdirplus format.
+        match inflight.operation().unwrap() {
+            Operation::Readdir(op) => {
+                assert_eq!(op.mode(), crate::op::ReaddirMode::Plus);
+
+                let mut entry = crate::reply::EntryOut::default();
+                entry.attr().ino(42);
+                let mut out =
+                    crate::reply::ReaddirPlusOut::with_mode(op.size() as usize, op.mode());
+                assert!(!out.entry(OsStr::new("foo"), 1, &entry));
+                inflight.reply(out).unwrap();
+            }
+            _ => panic!("unexpected operation"),
+        }
+        let reply = recv_reply(&kernel);
+        let body = &reply[mem::size_of::<fuse_out_header>()..];
+        assert_eq!(body.len(), crate::reply::direntplus_size(3));
+        let mut direntplus = fuse_direntplus::default();
+        direntplus
+            .as_bytes_mut()
+            .copy_from_slice(&body[..mem::size_of::<fuse_direntplus>()]);
+        assert_eq!(direntplus.dirent.ino, 42);
+        assert_eq!(direntplus.dirent.namelen, 3);
+
+        // The following readdirplus requests arrive as plain readdir.
+        send_request(
+            &kernel,
+            fuse_opcode::FUSE_READDIRPLUS,
+            3,
+            1,
+            read_in.as_bytes(),
+        );
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::Readdir(op) => {
+                assert_eq!(op.mode(), crate::op::ReaddirMode::Normal);
+
+                let mut out = crate::reply::ReaddirOut::new(op.size() as usize);
+                assert!(!out.entry(OsStr::new("foo"), 42, libc::DT_REG as u32, 1));
+                req.reply(out).unwrap();
             }
+            _ => panic!("unexpected operation"),
+        }
 
-            fn count(&self) -> usize {
-                2 + self.data.count()
+        // The kernel receives the entries in the readdirplus format.
+        let reply = recv_reply(&kernel);
+        let body = &reply[mem::size_of::<fuse_out_header>()..];
+        assert_eq!(body.len(), crate::reply::direntplus_size(3));
+
+        let mut direntplus = fuse_direntplus::default();
+        direntplus
+            .as_bytes_mut()
+            .copy_from_slice(&body[..mem::size_of::<fuse_direntplus>()]);
+        assert_eq!(direntplus.entry_out.nodeid, 0);
+        assert_eq!(direntplus.dirent.ino, 42);
+        assert_eq!(direntplus.dirent.off, 1);
+        assert_eq!(direntplus.dirent.namelen, 3);
+        assert_eq!(&body[mem::size_of::<fuse_direntplus>()..][..3], b"foo");
+    }
+
+    #[test]
+    fn attr_ttl_policy() {
+        let mut config = KernelConfig::default();
+        config.attr_ttl(|ino, mode| match mode.file_type() {
+            Some(crate::op::FileType::Directory) => Some(Duration::from_secs(60)),
+            _ if ino == 3 => Some(Duration::from_millis(10)),
+            _ => None,
+        });
+        config.statx(true);
+        let (session, kernel) = test_session(config);
+
+        let getattr = |unique, ino, mode, ttl: Option<Option<Duration>>| {
+            let getattr_in = fuse_getattr_in::default();
+            send_request(
+                &kernel,
+                fuse_opcode::FUSE_GETATTR,
+                unique,
+                ino,
+                getattr_in.as_bytes(),
+            );
+
+            let req = session.next_request().unwrap().unwrap();
+            let mut out = AttrOut::default();
+            out.attr().ino(ino);
+            out.attr().mode(mode);
+            if let Some(ttl) = ttl {
+                out.ttl(ttl);
             }
+            req.reply_attr(out).unwrap();
+
+            let reply = recv_reply(&kernel);
+            let mut attr_out = fuse_attr_out::default();
+            attr_out
+                .as_bytes_mut()
+                .copy_from_slice(&reply[mem::size_of::<fuse_out_header>()..]);
+            (attr_out.attr_valid, attr_out.attr_valid_nsec)
+        };
 
-            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
-                dst.put(self.header.as_bytes());
-                dst.put(self.arg.as_bytes());
-                self.data.fill_bytes(dst);
+        // Supplied by the policy, keyed by the file type or the inode number.
+        assert_eq!(getattr(2, 2, libc::S_IFDIR | 0o755, None), (60, 0));
+        assert_eq!(getattr(3, 3, libc::S_IFREG | 0o644, None), (0, 10_000_000));
+        // Not supplied by the policy.
+        assert_eq!(getattr(4, 4, libc::S_IFREG | 0o644, None), (0, 0));
+        // Specified by the handler.
+        assert_eq!(
+            getattr(
+                5,
+                2,
+                libc::S_IFDIR | 0o755,
+                Some(Some(Duration::from_secs(1)))
+            ),
+            (1, 0)
+        );
+        // Not cached as specified by the handler, despite the policy.
+        assert_eq!(getattr(6, 2, libc::S_IFDIR | 0o755, Some(None)), (0, 0));
+        assert_eq!(
+            getattr(
+                7,
+                3,
+                libc::S_IFREG | 0o644,
+                Some(Some(Duration::from_secs(0)))
+            ),
+            (0, 0)
+        );
+
+        // The same policy applies to statx.
+        let statx = |unique, ino, mode, ttl: Option<Duration>| {
+            let statx_in = fuse_statx_in::default();
+            send_request(
+                &kernel,
+                fuse_opcode::FUSE_STATX,
+                unique,
+                ino,
+                statx_in.as_bytes(),
+            );
+
+            let req = session.next_request().unwrap().unwrap();
+            let mut out = StatxOut::default();
+            out.attr().ino(ino);
+            out.attr().mode(mode);
+            if let Some(ttl) = ttl {
+                out.ttl(ttl);
             }
-        }
+            req.reply_statx(out).unwrap();
+
+            let reply = recv_reply(&kernel);
+            let mut statx_out = fuse_statx_out::default();
+            statx_out
+                .as_bytes_mut()
+                .copy_from_slice(&reply[mem::size_of::<fuse_out_header>()..]);
+            (statx_out.attr_valid, statx_out.attr_valid_nsec)
+        };
+        assert_eq!(statx(8, 2, libc::S_IFDIR | 0o755, None), (60, 0));
+        assert_eq!(statx(9, 3, libc::S_IFREG | 0o644, None), (0, 10_000_000));
+        assert_eq!(statx(10, 4, libc::S_IFREG | 0o644, None), (0, 0));
+        assert_eq!(
+            statx(11, 2, libc::S_IFDIR | 0o755, Some(Duration::from_secs(1))),
+            (1, 0)
+        );
     }
 
-    /// Retrieve data in an inode from the kernel cache.
-    pub fn retrieve(&self, ino: u64, offset: u64, size: u32) -> io::Result<u64> {
-        let total_len = u32::try_from(
-            mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_notify_retrieve_out>(),
-        )
-        .unwrap();
+    #[test]
+    fn from_raw_fd() {
+        let (kernel, daemon) = UnixDatagram::pair().unwrap();
 
-        // FIXME: choose appropriate memory ordering.
-        let notify_unique = self.session.notify_unique.fetch_add(1, Ordering::SeqCst);
+        let init_in = fuse_init_in {
+            major: 7,
+            minor: FUSE_KERNEL_MINOR_VERSION,
+            max_readahead: 40,
+            flags: INIT_FLAGS_MASK,
+        };
+        send_request(&kernel, fuse_opcode::FUSE_INIT, 1, 0, init_in.as_bytes());
+
+        let session =
+            unsafe { Session::from_raw_fd(daemon.into_raw_fd(), KernelConfig::default()) }.unwrap();
+
+        // The handshake is done over the passed descriptor.
+        let reply = recv_reply(&kernel);
+        let mut out_header = fuse_out_header::default();
+        out_header
+            .as_bytes_mut()
+            .copy_from_slice(&reply[..mem::size_of::<fuse_out_header>()]);
+        assert_eq!(out_header.unique, 1);
+        assert_eq!(out_header.error, 0);
+        assert_eq!(
+            reply.len(),
+            mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_init_out>()
+        );
 
-        write_bytes(
-            &self.session.conn,
-            Retrieve {
-                header: fuse_out_header {
-                    len: total_len,
-                    error: fuse_notify_code::FUSE_NOTIFY_RETRIEVE as i32,
-                    unique: 0,
-                },
-                arg: fuse_notify_retrieve_out {
-                    nodeid: ino,
-                    offset,
-                    size,
-                    notify_unique,
-                    padding: 0,
-                },
-            },
-        )?;
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 2, 1, &[]);
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.unique(), 2);
+    }
 
-        return Ok(notify_unique);
+    #[test]
+    fn disable_attr_cache() {
+        let mut root_attr = AttrOut::default();
+        root_attr.attr().mode(libc::S_IFDIR | 0o755);
+        root_attr.ttl(Duration::from_secs(60));
+
+        let mut config = KernelConfig::default();
+        config
+            .root_attr(root_attr)
+            .attr_ttl(|_, _| Some(Duration::from_secs(60)))
+            .disable_attr_cache(true);
+        let (session, kernel) = test_session(config);
+
+        let attr_valid = |reply: &[u8]| {
+            let mut attr_out = fuse_attr_out::default();
+            attr_out
+                .as_bytes_mut()
+                .copy_from_slice(&reply[mem::size_of::<fuse_out_header>()..]);
+            (attr_out.attr_valid, attr_out.attr_valid_nsec)
+        };
 
-        struct Retrieve {
-            header: fuse_out_header,
-            arg: fuse_notify_retrieve_out,
-        }
-        impl Bytes for Retrieve {
-            fn size(&self) -> usize {
-                self.header.len as usize
-            }
+        let getattr_in = fuse_getattr_in::default();
+        send_request(
+            &kernel,
+            fuse_opcode::FUSE_GETATTR,
+            2,
+            FUSE_ROOT_ID,
+            getattr_in.as_bytes(),
+        );
+        send_request(
+            &kernel,
+            fuse_opcode::FUSE_GETATTR,
+            3,
+            2,
+            getattr_in.as_bytes(),
+        );
+        send_request(
+            &kernel,
+            fuse_opcode::FUSE_GETATTR,
+            4,
+            3,
+            getattr_in.as_bytes(),
+        );
 
-            fn count(&self) -> usize {
-                2
-            }
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(attr_valid(&recv_reply(&kernel)), (0, 0));
+
+        // Set by the policy.
+        let mut out = AttrOut::default();
+        out.attr().ino(2);
+        req.reply_attr(out).unwrap();
+        assert_eq!(attr_valid(&recv_reply(&kernel)), (0, 0));
+
+        // Set by the handler.
+        let req = session.next_request().unwrap().unwrap();
+        let mut out = AttrOut::default();
+        out.attr().ino(3);
+        out.ttl(Duration::from_millis(1500));
+        req.reply_attr(out).unwrap();
+        assert_eq!(attr_valid(&recv_reply(&kernel)), (0, 0));
+    }
 
-            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
-                dst.put(self.header.as_bytes());
-                dst.put(self.arg.as_bytes());
-            }
-        }
+    #[test]
+    fn explicit_inval_data() {
+        let negotiated = |config: KernelConfig| {
+            let (session, _kernel) = test_session(config);
+            session.inner.init_out.flags & (FUSE_AUTO_INVAL_DATA | FUSE_EXPLICIT_INVAL_DATA)
+        };
+        assert_eq!(negotiated(KernelConfig::default()), FUSE_AUTO_INVAL_DATA);
+
+        // The kernel honors the explicit invalidation only without the
+        // automatic one, so they are never negotiated together.
+        let mut config = KernelConfig::default();
+        config.explicit_inval_data(true);
+        assert_eq!(negotiated(config), FUSE_EXPLICIT_INVAL_DATA);
+
+        let mut config = KernelConfig::default();
+        config.explicit_inval_data(true).auto_inval_data(true);
+        assert_eq!(negotiated(config), FUSE_AUTO_INVAL_DATA);
+
+        let mut config = KernelConfig::default();
+        config.explicit_inval_data(true);
+        let (session, kernel) = test_session(config);
+
+        let expected = |off, len| {
+            let header = fuse_out_header {
+                len: (mem::size_of::<fuse_out_header>()
+                    + mem::size_of::<fuse_notify_inval_inode_out>()) as u32,
+                error: fuse_notify_code::FUSE_NOTIFY_INVAL_INODE as i32,
+                unique: 0,
+            };
+            let arg = fuse_notify_inval_inode_out { ino: 42, off, len };
+            let mut frame = header.as_bytes().to_vec();
+            frame.extend_from_slice(arg.as_bytes());
+            frame
+        };
+
+        // Only the attributes.
+        session.notifier().inval_inode(42, -1, 0).unwrap();
+        assert_eq!(recv_reply(&kernel), expected(-1, 0));
+
+        // The attributes and the whole data.
+        session.notifier().inval_inode(42, 0, 0).unwrap();
+        assert_eq!(recv_reply(&kernel), expected(0, 0));
     }
 
-    /// Send I/O readiness to the kernel.
-    pub fn poll_wakeup(&self, kh: u64) -> io::Result<()> {
-        let total_len = u32::try_from(
-            mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_notify_poll_wakeup_out>(),
-        )
-        .unwrap();
+    #[test]
+    fn nonblocking_notifications() {
+        let (session, kernel) = test_session(KernelConfig::default());
+        session.set_nonblocking(true).unwrap();
+        let notifier = session.notifier();
+        assert!(notifier
+            .poll_writable(Some(Duration::from_secs(0)))
+            .unwrap());
+
+        // Notify until the kernel side stops accepting messages.
+        let mut sent = 0;
+        let err = loop {
+            match notifier.inval_inode(42, 0, 0) {
+                Ok(()) => sent += 1,
+                Err(err) => break err,
+            }
+            assert!(sent < 1_000_000, "the notifications never blocked");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(!notifier
+            .poll_writable(Some(Duration::from_secs(0)))
+            .unwrap());
+
+        // No pending request.
+        match session.next_request() {
+            Err(err) => assert_eq!(err.kind(), io::ErrorKind::WouldBlock),
+            Ok(..) => panic!("unexpected request"),
+        }
 
-        return write_bytes(
-            &self.session.conn,
-            PollWakeup {
-                header: fuse_out_header {
-                    len: total_len,
-                    error: fuse_notify_code::FUSE_NOTIFY_POLL as i32,
-                    unique: 0,
-                },
-                arg: fuse_notify_poll_wakeup_out { kh },
-            },
-        );
+        for _ in 0..sent {
+            recv_reply(&kernel);
+        }
+        assert!(notifier
+            .poll_writable(Some(Duration::from_secs(1)))
+            .unwrap());
+        notifier.inval_inode(42, 0, 0).unwrap();
+    }
 
-        struct PollWakeup {
-            header: fuse_out_header,
-            arg: fuse_notify_poll_wakeup_out,
+    #[test]
+    fn reply_queue() {
+        let mut config = KernelConfig::default();
+        config.reply_queue(true);
+        let (session, kernel) = test_session(config);
+        session.set_nonblocking(true).unwrap();
+        assert!(session.flush_replies().unwrap());
+
+        for unique in 2..5 {
+            send_request(&kernel, fuse_opcode::FUSE_STATFS, unique, 1, &[]);
         }
-        impl Bytes for PollWakeup {
-            fn size(&self) -> usize {
-                self.header.len as usize
-            }
 
-            fn count(&self) -> usize {
-                2
+        // Fill the device, bypassing the queue.
+        let filler = fuse_out_header {
+            len: mem::size_of::<fuse_out_header>() as u32,
+            error: fuse_notify_code::FUSE_NOTIFY_RESEND as i32,
+            unique: 0,
+        };
+        let mut sent = 0;
+        loop {
+            let res = unsafe {
+                libc::write(
+                    session.as_raw_fd(),
+                    filler.as_bytes().as_ptr() as *const libc::c_void,
+                    filler.as_bytes().len(),
+                )
+            };
+            if res == -1 {
+                assert_eq!(
+                    io::Error::last_os_error().raw_os_error(),
+                    Some(libc::EAGAIN)
+                );
+                break;
             }
+            sent += 1;
+            assert!(sent < 1_000_000, "the device never blocked");
+        }
 
-            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
-                dst.put(self.header.as_bytes());
-                dst.put(self.arg.as_bytes());
-            }
+        // The replies and the notifications are queued instead of blocking,
+        // in the order they have been sent.
+        let notifier = session.notifier();
+        let req = session.next_request().unwrap().unwrap();
+        req.reply_error(libc::ENOSYS).unwrap();
+        notifier.inval_inode(42, 0, 0).unwrap();
+        for _ in 3..5 {
+            let req = session.next_request().unwrap().unwrap();
+            req.reply_error(libc::ENOSYS).unwrap();
+        }
+        assert!(session.has_queued_replies());
+        assert!(!session.flush_replies().unwrap());
+
+        for _ in 0..sent {
+            recv_reply(&kernel);
         }
+        assert!(session.flush_replies().unwrap());
+        assert!(!session.has_queued_replies());
+
+        let messages: Vec<(u64, i32)> = (0..4)
+            .map(|_| {
+                let reply = recv_reply(&kernel);
+                let mut header = fuse_out_header::default();
+                header
+                    .as_bytes_mut()
+                    .copy_from_slice(&reply[..mem::size_of::<fuse_out_header>()]);
+                (header.unique, header.error)
+            })
+            .collect();
+        let inval_inode = fuse_notify_code::FUSE_NOTIFY_INVAL_INODE as i32;
+        assert_eq!(
+            messages,
+            vec![
+                (2, -libc::ENOSYS),
+                (0, inval_inode),
+                (3, -libc::ENOSYS),
+                (4, -libc::ENOSYS)
+            ]
+        );
+
+        // The replies are written directly once the device is writable.
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 5, 1, &[]);
+        let req = session.next_request().unwrap().unwrap();
+        req.reply_error(libc::ENOSYS).unwrap();
+        assert!(!session.has_queued_replies());
+        recv_reply(&kernel);
     }
-}
 
-// ==== utils ====
+    #[test]
+    fn raw_request() {
+        let (session, kernel) = test_session(KernelConfig::default());
 
-struct Reply<T> {
-    header: fuse_out_header,
-    arg: T,
-}
-impl<T> Reply<T>
-where
-    T: Bytes,
-{
-    #[inline]
-    fn new(unique: u64, error: i32, arg: T) -> Self {
-        let len = (mem::size_of::<fuse_out_header>() + arg.size())
-            .try_into()
-            .expect("Argument size is too large");
-        Self {
-            header: fuse_out_header {
-                len,
-                error: -error,
-                unique,
-            },
-            arg,
+        let mut arg = fuse_mkdir_in {
+            mode: 0o755,
+            umask: 0o022,
         }
-    }
-}
-impl<T> Bytes for Reply<T>
-where
-    T: Bytes,
-{
-    #[inline]
-    fn size(&self) -> usize {
-        self.header.len as usize
-    }
+        .as_bytes()
+        .to_vec();
+        arg.extend_from_slice(b"foo\0");
+        let header = fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + arg.len()) as u32,
+            opcode: fuse_opcode::FUSE_MKDIR as u32,
+            unique: 2,
+            nodeid: 1,
+            uid: 100,
+            gid: 100,
+            pid: 12,
+            padding: 0,
+        };
+        let mut msg = header.as_bytes().to_vec();
+        msg.extend_from_slice(&arg);
+        kernel.send(&msg).unwrap();
 
-    #[inline]
-    fn count(&self) -> usize {
-        self.arg.count() + 1
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.raw_header(), header.as_bytes());
+        assert_eq!(req.raw_body(), &arg[..]);
+        assert_eq!(
+            req.raw_header().len() + req.raw_body().len(),
+            header.len as usize
+        );
     }
 
-    fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
-        dst.put(self.header.as_bytes());
-        self.arg.fill_bytes(dst);
+    #[test]
+    fn forward_request() {
+        let (session, kernel) = test_session(KernelConfig::default());
+
+        let mut fds = [0; 2];
+        let res = unsafe {
+            libc::socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            )
+        };
+        assert_eq!(res, 0, "socketpair: {}", io::Error::last_os_error());
+        // UnixStream provides io::Read and io::Write over the message-oriented socket.
+        let upstream = unsafe { std::os::unix::net::UnixStream::from_raw_fd(fds[0]) };
+        let downstream = unsafe { UnixDatagram::from_raw_fd(fds[1]) };
+
+        // The downstream server replies the attributes after a notification.
+        let server = std::thread::spawn(move || {
+            let mut buf = vec![0u8; pagesize() * MAX_MAX_PAGES];
+            let len = downstream.recv(&mut buf).unwrap();
+            let mut header = fuse_in_header::default();
+            header
+                .as_bytes_mut()
+                .copy_from_slice(&buf[..mem::size_of::<fuse_in_header>()]);
+            assert_eq!(len, header.len as usize);
+            assert_eq!(header.opcode, fuse_opcode::FUSE_GETATTR as u32);
+            assert_eq!(header.nodeid, 42);
+            assert_eq!(header.uid, 100);
+
+            let notify = fuse_out_header {
+                len: (mem::size_of::<fuse_out_header>()
+                    + mem::size_of::<fuse_notify_poll_wakeup_out>()) as u32,
+                error: fuse_notify_code::FUSE_NOTIFY_POLL as i32,
+                unique: 0,
+            };
+            let mut msg = notify.as_bytes().to_vec();
+            msg.extend_from_slice(fuse_notify_poll_wakeup_out { kh: 1 }.as_bytes());
+            downstream.send(&msg).unwrap();
+
+            let mut attr_out = fuse_attr_out::default();
+            attr_out.attr.ino = 42;
+            attr_out.attr.size = 1234;
+            let reply = fuse_out_header {
+                len: (mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_attr_out>()) as u32,
+                error: 0,
+                unique: header.unique,
+            };
+            let mut msg = reply.as_bytes().to_vec();
+            msg.extend_from_slice(attr_out.as_bytes());
+            downstream.send(&msg).unwrap();
+            header.unique
+        });
+
+        let getattr_in = fuse_getattr_in::default();
+        let header = fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + mem::size_of::<fuse_getattr_in>()) as u32,
+            opcode: fuse_opcode::FUSE_GETATTR as u32,
+            unique: 0xdead,
+            nodeid: 42,
+            uid: 100,
+            gid: 100,
+            pid: 12,
+            padding: 0,
+        };
+        let mut msg = header.as_bytes().to_vec();
+        msg.extend_from_slice(getattr_in.as_bytes());
+        kernel.send(&msg).unwrap();
+
+        let mut forwarder = crate::proxy::Forwarder::new(upstream);
+        let req = session.next_request().unwrap().unwrap();
+        forwarder.forward(&req).unwrap();
+
+        // The unique ID is remapped for the downstream.
+        assert_ne!(server.join().unwrap(), 0xdead);
+
+        let reply = recv_reply(&kernel);
+        let mut out_header = fuse_out_header::default();
+        out_header
+            .as_bytes_mut()
+            .copy_from_slice(&reply[..mem::size_of::<fuse_out_header>()]);
+        assert_eq!(out_header.unique, 0xdead);
+        assert_eq!(out_header.error, 0);
+        assert_eq!(out_header.len as usize, reply.len());
+
+        let mut attr_out = fuse_attr_out::default();
+        attr_out
+            .as_bytes_mut()
+            .copy_from_slice(&reply[mem::size_of::<fuse_out_header>()..]);
+        assert_eq!(attr_out.attr.ino, 42);
+        assert_eq!(attr_out.attr.size, 1234);
     }
-}
-
-#[inline]
-fn write_bytes<W, T>(mut writer: W, bytes: T) -> io::Result<()>
-where
-    W: io::Write,
-    T: Bytes,
-{
-    let size = bytes.size();
-    let count = bytes.count();
 
-    let written;
+    #[test]
+    fn resend() {
+        let (session, _kernel, _) = init_ext(KernelConfig::default(), 0);
+        assert!(!session.resend_support());
+
+        let (session, kernel, _) =
+            init_ext(KernelConfig::default(), (FUSE_HAS_RESEND >> 32) as u32);
+        assert!(session.resend_support());
+
+        session.notifier().resend().unwrap();
+        let notify = recv_reply(&kernel);
+        let header = fuse_out_header {
+            len: mem::size_of::<fuse_out_header>() as u32,
+            error: fuse_notify_code::FUSE_NOTIFY_RESEND as i32,
+            unique: 0,
+        };
+        assert_eq!(notify, header.as_bytes());
+
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 2, 1, &[]);
+        let req = session.next_request().unwrap().unwrap();
+        assert!(!req.is_resent());
+        drop(req);
+
+        // The resent request keeps the mark in the reply.
+        let unique = 2 | FUSE_UNIQUE_RESEND;
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, unique, 1, &[]);
+        let req = session.next_request().unwrap().unwrap();
+        assert!(req.is_resent());
+        req.reply_error(libc::ENOSYS).unwrap();
+
+        let reply = recv_reply(&kernel);
+        let mut header = fuse_out_header::default();
+        header
+            .as_bytes_mut()
+            .copy_from_slice(&reply[..mem::size_of::<fuse_out_header>()]);
+        assert_eq!(header.unique, unique);
+    }
 
-    macro_rules! small_write {
-        ($n:expr) => {{
-            let mut vec: [MaybeUninit<IoSlice<'_>>; $n] =
-                unsafe { MaybeUninit::uninit().assume_init() };
-            bytes.fill_bytes(&mut FillWriteBytes {
-                vec: &mut vec[..],
-                offset: 0,
-            });
-            let vec = unsafe { slice_assume_init_ref(&vec[..]) };
+    #[test]
+    fn create_supp_group() {
+        let supp_group = (FUSE_CREATE_SUPP_GROUP >> 32) as u32;
+        let (_session, _kernel, init_out) = init_ext(KernelConfig::default(), supp_group);
+        assert_eq!(init_out.unused[0], 0);
+
+        let mut config = KernelConfig::default();
+        config.create_supp_group(true);
+        let (session, kernel, init_out) = init_ext(config, supp_group);
+        assert_eq!(init_out.unused[0], supp_group);
+
+        let mut arg = fuse_create_in {
+            flags: libc::O_WRONLY as u32,
+            mode: libc::S_IFREG | 0o644,
+            umask: 0o022,
+            padding: 0,
+        }
+        .as_bytes()
+        .to_vec();
+        arg.extend_from_slice(b"foo\0");
+        // The extension follows the name without padding, so it is not
+        // aligned within the request.
+        let ext_len = mem::size_of::<fuse_ext_header>() + mem::size_of::<fuse_supp_groups>() + 4;
+        arg.extend_from_slice(
+            fuse_ext_header {
+                size: ext_len as u32,
+                typ: FUSE_EXT_GROUPS,
+            }
+            .as_bytes(),
+        );
+        arg.extend_from_slice(fuse_supp_groups { nr_groups: 1 }.as_bytes());
+        arg.extend_from_slice(&1000u32.to_ne_bytes());
+
+        // `total_extlen` is the lower half of `padding`.
+        let total_extlen = (ext_len / 8) as u16;
+        let mut padding = [0u8; 4];
+        padding[..2].copy_from_slice(&total_extlen.to_ne_bytes());
+        let header = fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + arg.len()) as u32,
+            opcode: fuse_opcode::FUSE_CREATE as u32,
+            unique: 2,
+            nodeid: 1,
+            padding: u32::from_ne_bytes(padding),
+            ..Default::default()
+        };
+        kernel
+            .send(&[header.as_bytes(), &arg[..]].concat())
+            .unwrap();
+
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::Create(op) => {
+                assert_eq!(op.name(), "foo");
+                let groups = op.header_extensions().supplementary_groups().unwrap();
+                assert_eq!(groups.len(), 1);
+                assert_eq!(groups.iter().collect::<Vec<_>>(), vec![1000]);
+            }
+            _ => panic!("unexpected operation"),
+        }
 
-            written = writer.write_vectored(vec)?;
-        }};
+        // No group is appended without the extension.
+        send_request(
+            &kernel,
+            fuse_opcode::FUSE_MKDIR,
+            3,
+            1,
+            &[
+                fuse_mkdir_in {
+                    mode: 0o755,
+                    umask: 0o022,
+                }
+                .as_bytes(),
+                b"bar\0",
+            ]
+            .concat(),
+        );
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::Mkdir(op) => {
+                assert!(op.header_extensions().supplementary_groups().is_none())
+            }
+            _ => panic!("unexpected operation"),
+        }
     }
 
-    match count {
-        // Skip writing.
-        0 => return Ok(()),
+    #[test]
+    fn header_extensions() {
+        let mut config = KernelConfig::default();
+        config
+            .security_ctx(true)
+            .create_supp_group(true)
+            .tmpfile(true);
+        let flags2 = ((FUSE_SECURITY_CTX | FUSE_CREATE_SUPP_GROUP) >> 32) as u32;
+        let (session, kernel, _) = init_ext(config, flags2);
+
+        let mut arg = fuse_mkdir_in {
+            mode: 0o755,
+            umask: 0o022,
+        }
+        .as_bytes()
+        .to_vec();
+        arg.extend_from_slice(b"foo\0");
+        arg.resize((arg.len() + 7) & !7, 0);
+        let args_len = arg.len();
+
+        // The security context, whose header doubles as the extension header.
+        let ctx_name = b"security.selinux\0";
+        let label = b"system_u:object_r:tmp_t:s0\0";
+        let ctx_len = mem::size_of::<fuse_secctx_header>()
+            + mem::size_of::<fuse_secctx>()
+            + ctx_name.len()
+            + label.len();
+        let ctx_len = (ctx_len + 7) & !7;
+        arg.extend_from_slice(
+            fuse_secctx_header {
+                size: ctx_len as u32,
+                nr_ctx: 1,
+            }
+            .as_bytes(),
+        );
+        arg.extend_from_slice(
+            fuse_secctx {
+                size: label.len() as u32,
+                padding: 0,
+            }
+            .as_bytes(),
+        );
+        arg.extend_from_slice(ctx_name);
+        arg.extend_from_slice(label);
+        arg.resize(args_len + ctx_len, 0);
+
+        // The supplementary groups.
+        arg.extend_from_slice(
+            fuse_ext_header {
+                size: 16,
+                typ: FUSE_EXT_GROUPS,
+            }
+            .as_bytes(),
+        );
+        arg.extend_from_slice(fuse_supp_groups { nr_groups: 1 }.as_bytes());
+        arg.extend_from_slice(&100u32.to_ne_bytes());
+
+        let total_extlen = ((arg.len() - args_len) / 8) as u16;
+        let mut padding = [0u8; 4];
+        padding[..2].copy_from_slice(&total_extlen.to_ne_bytes());
+        let header = fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + arg.len()) as u32,
+            opcode: fuse_opcode::FUSE_MKDIR as u32,
+            unique: 2,
+            nodeid: 1,
+            padding: u32::from_ne_bytes(padding),
+            ..Default::default()
+        };
+        kernel
+            .send(&[header.as_bytes(), &arg[..]].concat())
+            .unwrap();
+
+        let req = session.next_request().unwrap().unwrap();
+        let exts = req.header_extensions();
+        assert_eq!(exts.as_bytes(), &arg[args_len..]);
+        let exts: Vec<_> = exts.map(|ext| (ext.typ(), ext.data().len())).collect();
+        assert_eq!(exts, vec![(1, ctx_len - 8), (FUSE_EXT_GROUPS, 8)]);
+
+        match req.operation().unwrap() {
+            Operation::Mkdir(op) => {
+                assert_eq!(op.name(), "foo");
+                let ctx = op.header_extensions().security_context().unwrap();
+                assert_eq!(ctx.name(), "security.selinux");
+                assert_eq!(ctx.value(), &label[..]);
+                let groups: Vec<_> = op
+                    .header_extensions()
+                    .supplementary_groups()
+                    .unwrap()
+                    .iter()
+                    .collect();
+                assert_eq!(groups, vec![100]);
+            }
+            _ => panic!("unexpected operation"),
+        }
+
+        // The same extensions follow the name of the unnamed dentry of `tmpfile`.
+        let ext_bytes = arg[args_len..].to_vec();
+        let mut arg = fuse_create_in {
+            mode: libc::S_IFREG | 0o600,
+            ..Default::default()
+        }
+        .as_bytes()
+        .to_vec();
+        arg.extend_from_slice(b"/\0");
+        arg.resize((arg.len() + 7) & !7, 0);
+        arg.extend_from_slice(&ext_bytes[..]);
+        let header = fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + arg.len()) as u32,
+            opcode: fuse_opcode::FUSE_TMPFILE as u32,
+            unique: 3,
+            ..header
+        };
+        kernel
+            .send(&[header.as_bytes(), &arg[..]].concat())
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::Tmpfile(op) => {
+                let exts = op.header_extensions();
+                let ctx = exts.security_context().unwrap();
+                assert_eq!(ctx.name(), "security.selinux");
+                assert_eq!(ctx.value(), &label[..]);
+                let groups: Vec<_> = exts.supplementary_groups().unwrap().iter().collect();
+                assert_eq!(groups, vec![100]);
+            }
+            _ => panic!("unexpected operation"),
+        }
 
-        // Avoid heap allocation if count is small.
-        1 => small_write!(1),
-        2 => small_write!(2),
-        3 => small_write!(3),
-        4 => small_write!(4),
+        // No extension is attached without `total_extlen`.
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 4, 1, &[]);
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.header_extensions().count(), 0);
+    }
 
-        count => {
-            let mut vec: Vec<IoSlice<'_>> = Vec::with_capacity(count);
-            unsafe {
-                let dst = std::slice::from_raw_parts_mut(
-                    vec.as_mut_ptr().cast(), //
-                    count,
-                );
-                bytes.fill_bytes(&mut FillWriteBytes {
-                    vec: dst,
-                    offset: 0,
-                });
-                vec.set_len(count);
+    #[test]
+    fn security_ctx() {
+        let (_session, _kernel, init_out) =
+            init_ext(KernelConfig::default(), (FUSE_SECURITY_CTX >> 32) as u32);
+        assert!(init_out.flags & FUSE_INIT_EXT == 0);
+        assert_eq!(init_out.unused[0], 0);
+
+        let mut config = KernelConfig::default();
+        config.security_ctx(true);
+        let (session, kernel, init_out) = init_ext(config, (FUSE_SECURITY_CTX >> 32) as u32);
+        assert!(init_out.flags & FUSE_INIT_EXT != 0);
+        assert_eq!(init_out.unused[0], (FUSE_SECURITY_CTX >> 32) as u32);
+
+        let label = b"system_u:object_r:tmp_t:s0\0";
+        let mut arg = fuse_create_in {
+            flags: libc::O_WRONLY as u32,
+            mode: libc::S_IFREG | 0o644,
+            umask: 0o022,
+            padding: 0,
+        }
+        .as_bytes()
+        .to_vec();
+        arg.extend_from_slice(b"foo\0");
+        let ctx_name = b"security.selinux\0";
+        let ctx_len = mem::size_of::<fuse_secctx_header>()
+            + mem::size_of::<fuse_secctx>()
+            + ctx_name.len()
+            + label.len();
+        arg.extend_from_slice(
+            fuse_secctx_header {
+                size: ctx_len as u32,
+                nr_ctx: 1,
             }
+            .as_bytes(),
+        );
+        arg.extend_from_slice(
+            fuse_secctx {
+                size: label.len() as u32,
+                padding: 0,
+            }
+            .as_bytes(),
+        );
+        arg.extend_from_slice(ctx_name);
+        arg.extend_from_slice(label);
+        send_request(&kernel, fuse_opcode::FUSE_CREATE, 2, 1, &arg);
+
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::Create(op) => {
+                assert_eq!(op.name(), "foo");
+                let ctx = op.header_extensions().security_context().unwrap();
+                assert_eq!(ctx.name(), "security.selinux");
+                assert_eq!(ctx.value(), &label[..]);
+            }
+            _ => panic!("unexpected operation"),
+        }
+    }
 
-            written = writer.write_vectored(&*vec)?;
+    #[test]
+    fn posix_acl() {
+        let (session, _kernel) = test_session(KernelConfig::default());
+        assert!(!session.posix_acl_support());
+
+        let mut config = KernelConfig::default();
+        config.posix_acl(true);
+        let (session, kernel) = test_session(config);
+        assert!(session.posix_acl_support());
+
+        // A minimal ACL: the version followed by the entries of tag, perm and id.
+        let acl: &[u8] = &[
+            0x02, 0x00, 0x00, 0x00, //
+            0x01, 0x00, 0x06, 0x00, 0xff, 0xff, 0xff, 0xff,
+        ];
+        let mut arg = fuse_setxattr_in {
+            size: acl.len() as u32,
+            flags: 0,
         }
+        .as_bytes()
+        .to_vec();
+        arg.extend_from_slice(crate::reply::POSIX_ACL_ACCESS_XATTR.as_bytes());
+        arg.push(0);
+        arg.extend_from_slice(acl);
+        send_request(&kernel, fuse_opcode::FUSE_SETXATTR, 2, 2, &arg);
+
+        let mut arg = fuse_getxattr_in {
+            size: 64,
+            padding: 0,
+        }
+        .as_bytes()
+        .to_vec();
+        arg.extend_from_slice(crate::reply::POSIX_ACL_ACCESS_XATTR.as_bytes());
+        arg.push(0);
+        send_request(&kernel, fuse_opcode::FUSE_GETXATTR, 3, 2, &arg);
+
+        let req = session.next_request().unwrap().unwrap();
+        let stored = match req.operation().unwrap() {
+            Operation::Setxattr(op) => {
+                assert_eq!(op.name(), crate::reply::POSIX_ACL_ACCESS_XATTR);
+                op.value().to_vec()
+            }
+            _ => panic!("unexpected operation"),
+        };
+        assert_eq!(stored, acl);
+        req.reply(()).unwrap();
+        recv_reply(&kernel);
+
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::Getxattr(op) => {
+                assert_eq!(op.name(), crate::reply::POSIX_ACL_ACCESS_XATTR);
+            }
+            _ => panic!("unexpected operation"),
+        }
+        req.reply(&stored[..]).unwrap();
+        let reply = recv_reply(&kernel);
+        assert_eq!(reply[mem::size_of::<fuse_out_header>()..], *acl);
     }
 
-    if written < size {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "written data is too short",
-        ));
+    #[test]
+    fn reply_errno() {
+        let (session, kernel) = test_session(KernelConfig::default());
+        send_request(&kernel, fuse_opcode::FUSE_STATFS, 2, 1, &[]);
+
+        let req = session.next_request().unwrap().unwrap();
+        req.reply_error(crate::reply::Errno::NotFound).unwrap();
+
+        let mut out_header = fuse_out_header::default();
+        out_header
+            .as_bytes_mut()
+            .copy_from_slice(&recv_reply(&kernel)[..]);
+        assert_eq!(out_header.error, -libc::ENOENT);
     }
 
-    Ok(())
-}
+    #[test]
+    fn reply_xattr_limits() {
+        let (session, kernel) = test_session(KernelConfig::default());
 
-struct FillWriteBytes<'a, 'vec> {
-    vec: &'vec mut [MaybeUninit<IoSlice<'a>>],
-    offset: usize,
-}
+        let getxattr = |unique: u64, size: u32| {
+            let mut arg = fuse_getxattr_in { size, padding: 0 }.as_bytes().to_vec();
+            arg.extend_from_slice(b"user.big\0");
+            send_request(&kernel, fuse_opcode::FUSE_GETXATTR, unique, 2, &arg);
+        };
+        let reply_error = || {
+            let mut out_header = fuse_out_header::default();
+            out_header
+                .as_bytes_mut()
+                .copy_from_slice(&recv_reply(&kernel)[..]);
+            out_header.error
+        };
 
-impl<'a, 'vec> FillBytes<'a> for FillWriteBytes<'a, 'vec> {
-    fn put(&mut self, chunk: &'a [u8]) {
-        self.vec[self.offset] = MaybeUninit::new(IoSlice::new(chunk));
-        self.offset += 1;
+        // Query the size of an oversized value.
+        getxattr(2, 0);
+        let req = session.next_request().unwrap().unwrap();
+        let mut out = XattrOut::default();
+        XattrOut::size(&mut out, XATTR_SIZE_MAX + 1);
+        req.reply_xattr(out).unwrap();
+        assert_eq!(reply_error(), -libc::E2BIG);
+
+        let value = vec![0u8; XATTR_SIZE_MAX as usize + 1];
+        getxattr(3, XATTR_SIZE_MAX);
+        let req = session.next_request().unwrap().unwrap();
+        req.reply_xattr_value(&value[..]).unwrap();
+        assert_eq!(reply_error(), -libc::E2BIG);
+
+        // The value larger than the buffer of the caller.
+        getxattr(4, 4);
+        let req = session.next_request().unwrap().unwrap();
+        req.reply_xattr_value(b"value").unwrap();
+        assert_eq!(reply_error(), -libc::ERANGE);
+
+        getxattr(5, 8);
+        let req = session.next_request().unwrap().unwrap();
+        req.reply_xattr_value(b"value").unwrap();
+        let reply = recv_reply(&kernel);
+        assert_eq!(reply[mem::size_of::<fuse_out_header>()..], *b"value");
     }
-}
 
-// FIXME: replace with stabilized MaybeUninit::slice_assume_init_ref.
-#[inline(always)]
-unsafe fn slice_assume_init_ref<T>(slice: &[MaybeUninit<T>]) -> &[T] {
-    #[allow(unused_unsafe)]
-    unsafe {
-        &*(slice as *const [MaybeUninit<T>] as *const [T])
-    }
-}
+    #[test]
+    fn reply_iovecs_reuse() {
+        let iovecs_ptr = || {
+            REPLY_IOVECS.with(|cell| {
+                let iovecs = cell.take();
+                let ptr = iovecs.as_ptr();
+                cell.set(iovecs);
+                ptr
+            })
+        };
 
-#[inline]
-fn pagesize() -> usize {
-    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
-}
+        let write_chunks = |id: u8, count: usize| {
+            let data: Vec<Vec<u8>> = (0..count).map(|i| vec![id, i as u8]).collect();
+            let chunks: Vec<&[u8]> = data.iter().map(|chunk| &chunk[..]).collect();
+            let mut written = vec![];
+            write_reply(&mut written, &chunks[..]).unwrap();
+            assert_eq!(written, data.concat());
+        };
 
-#[inline]
-const fn default_init_out() -> fuse_init_out {
-    fuse_init_out {
-        major: FUSE_KERNEL_VERSION,
-        minor: FUSE_KERNEL_MINOR_VERSION,
-        max_readahead: u32::MAX,
-        flags: DEFAULT_INIT_FLAGS,
-        max_background: 0,
-        congestion_threshold: 0,
-        max_write: DEFAULT_MAX_WRITE,
-        time_gran: 1,
-        max_pages: 0,
-        padding: 0,
-        unused: [0; 8],
+        // The vectors allocated by the first reply are reused by the next ones.
+        write_chunks(0, 16);
+        let ptr = iovecs_ptr();
+        REPLY_IOVECS.with(|cell| {
+            let iovecs = cell.take();
+            assert!(iovecs.capacity() >= MAX_REPLY_IOVECS);
+            cell.set(iovecs);
+        });
+        write_chunks(1, 8);
+        write_chunks(2, 16);
+        assert_eq!(iovecs_ptr(), ptr);
+
+        // The replies from the concurrent workers do not interfere.
+        let workers: Vec<_> = (0..4)
+            .map(|id| {
+                std::thread::spawn(move || {
+                    for count in 5..200 {
+                        write_chunks(id, count);
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::mem;
 
     #[test]
-    fn init_default() {
-        let input_len = mem::size_of::<fuse_in_header>() + mem::size_of::<fuse_init_in>();
-        let in_header = fuse_in_header {
-            len: input_len as u32,
-            opcode: fuse_opcode::FUSE_INIT as u32,
-            unique: 2,
-            nodeid: 0,
-            uid: 100,
-            gid: 100,
-            pid: 12,
-            padding: 0,
-        };
-        let init_in = fuse_init_in {
-            major: 7,
-            minor: 23,
-            max_readahead: 40,
-            flags: INIT_FLAGS_MASK
-                | FUSE_MAX_PAGES
-                | FUSE_NO_OPEN_SUPPORT
-                | FUSE_NO_OPENDIR_SUPPORT,
+    fn reply_xattr_names() {
+        let (session, kernel) = test_session(KernelConfig::default());
+        let mut names = XattrNames::new();
+        names.push("user.foo");
+        names.push("user.barbaz");
+
+        let listxattr = |unique: u64, size: u32| {
+            let arg = fuse_getxattr_in { size, padding: 0 };
+            send_request(
+                &kernel,
+                fuse_opcode::FUSE_LISTXATTR,
+                unique,
+                2,
+                arg.as_bytes(),
+            );
+            let req = session.next_request().unwrap().unwrap();
+            req.reply_xattr_names(&names).unwrap();
+            recv_reply(&kernel)
         };
 
-        let mut input = Vec::with_capacity(input_len);
-        input.extend_from_slice(in_header.as_bytes());
-        input.extend_from_slice(init_in.as_bytes());
-        assert_eq!(input.len(), input_len);
+        // The queried size matches the list replied later.
+        let reply = listxattr(2, 0);
+        let mut xattr_out = fuse_getxattr_out::default();
+        xattr_out
+            .as_bytes_mut()
+            .copy_from_slice(&reply[mem::size_of::<fuse_out_header>()..]);
+        assert_eq!(xattr_out.size, 21);
 
-        let mut output = Vec::<u8>::new();
+        let reply = listxattr(3, xattr_out.size);
+        assert_eq!(
+            reply[mem::size_of::<fuse_out_header>()..],
+            *b"user.foo\0user.barbaz\0"
+        );
 
-        let mut init_out = default_init_out();
-        init_session(&mut init_out, &input[..], &mut output).expect("initialization failed");
+        let reply = listxattr(4, xattr_out.size - 1);
+        let mut out_header = fuse_out_header::default();
+        out_header.as_bytes_mut().copy_from_slice(&reply[..]);
+        assert_eq!(out_header.error, -libc::ERANGE);
+    }
 
-        let expected_max_pages = (DEFAULT_MAX_WRITE / (pagesize() as u32)) as u16;
+    #[test]
+    fn reply_write() {
+        let (session, kernel) = test_session(KernelConfig::default());
 
-        assert_eq!(init_out.major, 7);
-        assert_eq!(init_out.minor, 23);
-        assert_eq!(init_out.max_readahead, 40);
-        assert_eq!(init_out.max_background, 0);
-        assert_eq!(init_out.congestion_threshold, 0);
-        assert_eq!(init_out.max_write, DEFAULT_MAX_WRITE);
-        assert_eq!(init_out.max_pages, expected_max_pages);
-        assert_eq!(init_out.time_gran, 1);
-        assert!(init_out.flags & FUSE_NO_OPEN_SUPPORT != 0);
-        assert!(init_out.flags & FUSE_NO_OPENDIR_SUPPORT != 0);
+        let write_in = fuse_write_in {
+            size: 5,
+            ..Default::default()
+        };
+        let mut arg = write_in.as_bytes().to_vec();
+        arg.extend_from_slice(b"hello");
+        send_request(&kernel, fuse_opcode::FUSE_WRITE, 2, 2, &arg);
+
+        let req = session.next_request().unwrap().unwrap();
+        let mut out = WriteOut::default();
+        WriteOut::size(&mut out, 5);
+        req.reply_write(out).unwrap();
+
+        let reply = recv_reply(&kernel);
+        let mut write_out = fuse_write_out::default();
+        write_out
+            .as_bytes_mut()
+            .copy_from_slice(&reply[mem::size_of::<fuse_out_header>()..]);
+        assert_eq!(write_out.size, 5);
+    }
 
-        let output_len = mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_init_out>();
-        let out_header = fuse_out_header {
-            len: output_len as u32,
-            error: 0,
-            unique: 2,
+    #[test]
+    fn reply_short_write() {
+        let (session, kernel) = test_session(KernelConfig::default());
+        let write = |unique: u64| {
+            let write_in = fuse_write_in {
+                size: 8,
+                ..Default::default()
+            };
+            let mut arg = write_in.as_bytes().to_vec();
+            arg.extend_from_slice(b"abcdefgh");
+            send_request(&kernel, fuse_opcode::FUSE_WRITE, unique, 2, &arg);
+            session.next_request().unwrap().unwrap()
         };
-        let init_out = fuse_init_out {
-            major: 7,
-            minor: 23,
-            max_readahead: 40,
-            flags: DEFAULT_INIT_FLAGS | FUSE_MAX_PAGES | FUSE_BIG_WRITES,
-            max_background: 0,
-            congestion_threshold: 0,
-            max_write: DEFAULT_MAX_WRITE,
-            time_gran: 1,
-            max_pages: expected_max_pages,
-            padding: 0,
-            unused: [0; 8],
+        let reply_header = |reply: &[u8]| {
+            let mut out_header = fuse_out_header::default();
+            out_header
+                .as_bytes_mut()
+                .copy_from_slice(&reply[..mem::size_of::<fuse_out_header>()]);
+            out_header
         };
 
-        let mut expected = Vec::with_capacity(output_len);
-        expected.extend_from_slice(out_header.as_bytes());
-        expected.extend_from_slice(init_out.as_bytes());
-        assert_eq!(output.len(), output_len);
-
-        assert_eq!(expected[0..4], output[0..4], "out_header.len");
-        assert_eq!(expected[4..8], output[4..8], "out_header.error");
-        assert_eq!(expected[8..16], output[8..16], "out_header.unique");
-
-        let expected = &expected[mem::size_of::<fuse_out_header>()..];
-        let output = &output[mem::size_of::<fuse_out_header>()..];
-        assert_eq!(expected[0..4], output[0..4], "init_out.major");
-        assert_eq!(expected[4..8], output[4..8], "init_out.minor");
-        assert_eq!(expected[8..12], output[8..12], "init_out.max_readahead");
-        assert_eq!(expected[12..16], output[12..16], "init_out.flags");
-        assert_eq!(expected[16..18], output[16..18], "init_out.max_background");
+        // The quota is exceeded after the first 3 bytes.
+        let mut out = WriteOut::default();
+        WriteOut::size(&mut out, 3);
+        write(2).reply_write(out).unwrap();
+        let reply = recv_reply(&kernel);
+        let out_header = reply_header(&reply);
+        assert_eq!(out_header.unique, 2);
+        assert_eq!(out_header.error, 0);
         assert_eq!(
-            expected[18..20],
-            output[18..20],
-            "init_out.congestion_threshold"
-        );
-        assert_eq!(expected[20..24], output[20..24], "init_out.max_write");
-        assert_eq!(expected[24..28], output[24..28], "init_out.time_gran");
-        assert_eq!(expected[28..30], output[28..30], "init_out.max_pages");
-        assert!(
-            output[30..30 + 2 + 4 * 8].iter().all(|&b| b == 0x00),
-            "init_out.paddings"
+            out_header.len as usize,
+            mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_write_out>()
         );
+        let mut write_out = fuse_write_out::default();
+        write_out
+            .as_bytes_mut()
+            .copy_from_slice(&reply[mem::size_of::<fuse_out_header>()..]);
+        assert_eq!(write_out.size, 3);
+
+        // The next attempt writes nothing and fails.
+        write(3).reply_error(libc::EDQUOT).unwrap();
+        let reply = recv_reply(&kernel);
+        assert_eq!(reply.len(), mem::size_of::<fuse_out_header>());
+        assert_eq!(reply_header(&reply).error, -libc::EDQUOT);
     }
 
-    #[inline]
-    fn bytes(bytes: &[u8]) -> &[u8] {
-        bytes
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "exceeds the written data")]
+    fn reply_write_too_large() {
+        let (session, kernel) = test_session(KernelConfig::default());
+
+        let write_in = fuse_write_in {
+            size: 5,
+            ..Default::default()
+        };
+        let mut arg = write_in.as_bytes().to_vec();
+        arg.extend_from_slice(b"hello");
+        send_request(&kernel, fuse_opcode::FUSE_WRITE, 2, 2, &arg);
+
+        // The bytes are counted twice.
+        let req = session.next_request().unwrap().unwrap();
+        let mut out = WriteOut::default();
+        WriteOut::size(&mut out, 10);
+        let _ = req.reply_write(out);
     }
-    macro_rules! b {
-        ($($b:expr),*$(,)?) => ( *bytes(&[$($b),*]) );
+
+    #[test]
+    fn async_dio_concurrent_reads() {
+        let (session, kernel) = test_session(KernelConfig::default());
+        assert!(session.inner.init_out.flags & FUSE_ASYNC_DIO != 0);
+
+        let mut config = KernelConfig::default();
+        config.async_dio(false);
+        let (session_sync, _kernel) = test_session(config);
+        assert!(session_sync.inner.init_out.flags & FUSE_ASYNC_DIO == 0);
+
+        // Two parts of a direct read issued at once.
+        for (unique, offset) in [(2, 0), (3, 4096)].iter().copied() {
+            let read_in = fuse_read_in {
+                fh: 1,
+                offset,
+                size: 4096,
+                ..Default::default()
+            };
+            send_request(
+                &kernel,
+                fuse_opcode::FUSE_READ,
+                unique,
+                2,
+                read_in.as_bytes(),
+            );
+        }
+
+        // Both requests are received while the first one is outstanding.
+        let req1 = session.next_request().unwrap().unwrap();
+        let session = Arc::new(session);
+        let req2 = std::thread::spawn({
+            let session = session.clone();
+            move || session.next_request().unwrap().unwrap()
+        })
+        .join()
+        .unwrap();
+        assert_eq!(req1.unique(), 2);
+        assert_eq!(req2.unique(), 3);
+
+        // The replies may be sent in any order.
+        req2.reply(&b"bar"[..]).unwrap();
+        req1.reply(&b"foo"[..]).unwrap();
+
+        let header_len = mem::size_of::<fuse_out_header>();
+        let reply = recv_reply(&kernel);
+        assert_eq!(reply[8..16], 3u64.to_ne_bytes());
+        assert_eq!(reply[header_len..], b"bar"[..]);
+        let reply = recv_reply(&kernel);
+        assert_eq!(reply[8..16], 2u64.to_ne_bytes());
+        assert_eq!(reply[header_len..], b"foo"[..]);
     }
 
     #[test]
-    fn send_msg_empty() {
-        let mut buf = vec![0u8; 0];
-        write_bytes(&mut buf, Reply::new(42, -4, &[])).unwrap();
-        assert_eq!(buf[0..4], b![0x10, 0x00, 0x00, 0x00], "header.len");
-        assert_eq!(buf[4..8], b![0x04, 0x00, 0x00, 0x00], "header.error");
-        assert_eq!(
-            buf[8..16],
-            b![0x2a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
-            "header.unique"
-        );
+    fn passthrough_max_stack_depth() {
+        let passthrough = (FUSE_PASSTHROUGH >> 32) as u32;
+
+        let (session, _kernel, init_out) = init_ext(KernelConfig::default(), passthrough);
+        assert_eq!(init_out.unused[..2], [0, 0]);
+        assert_eq!(session.init_out().max_stack_depth(), None);
+
+        let mut config = KernelConfig::default();
+        config.passthrough(2);
+        let (session, _kernel, init_out) = init_ext(config, passthrough);
+        assert!(init_out.flags & FUSE_INIT_EXT != 0);
+        assert_eq!(init_out.unused[..2], [passthrough, 2]);
+        assert_eq!(session.init_out().max_stack_depth(), Some(2));
+
+        // The kernel does not support the passthrough.
+        let mut config = KernelConfig::default();
+        config.passthrough(1);
+        let (session, _kernel, init_out) = init_ext(config, 0);
+        assert!(init_out.flags & FUSE_INIT_EXT == 0);
+        assert_eq!(init_out.unused[..2], [0, 0]);
+        assert_eq!(session.init_out().max_stack_depth(), None);
     }
 
     #[test]
-    fn send_msg_single_data() {
-        let mut buf = vec![0u8; 0];
-        write_bytes(&mut buf, Reply::new(42, 0, "hello")).unwrap();
-        assert_eq!(buf[0..4], b![0x15, 0x00, 0x00, 0x00], "header.len");
-        assert_eq!(buf[4..8], b![0x00, 0x00, 0x00, 0x00], "header.error");
-        assert_eq!(
-            buf[8..16],
-            b![0x2a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
-            "header.unique"
-        );
-        assert_eq!(buf[16..], b![0x68, 0x65, 0x6c, 0x6c, 0x6f], "payload");
+    fn open_passthrough() {
+        let open = |session: &Session, kernel: &UnixDatagram| {
+            let open_in = fuse_open_in::default();
+            send_request(kernel, fuse_opcode::FUSE_OPEN, 2, 2, open_in.as_bytes());
+            let req = session.next_request().unwrap().unwrap();
+            let mut out = OpenOut::default();
+            out.fh(1);
+            out.passthrough(3);
+            req.reply_open(out).unwrap();
+
+            let reply = recv_reply(kernel);
+            let mut open_out = fuse_open_out::default();
+            open_out
+                .as_bytes_mut()
+                .copy_from_slice(&reply[mem::size_of::<fuse_out_header>()..]);
+            (open_out.open_flags, open_out.padding)
+        };
+
+        let passthrough = (FUSE_PASSTHROUGH >> 32) as u32;
+        let mut config = KernelConfig::default();
+        config.passthrough(1);
+        let (session, kernel, _) = init_ext(config, passthrough);
+        assert_eq!(open(&session, &kernel), (FOPEN_PASSTHROUGH, 3));
+
+        // Omitted unless the passthrough has been negotiated.
+        let (session, kernel, _) = init_ext(KernelConfig::default(), passthrough);
+        assert_eq!(open(&session, &kernel), (0, 0));
     }
 
     #[test]
-    fn send_msg_chunked_data() {
-        let payload: &[&[u8]] = &[
-            "hello, ".as_ref(), //
-            "this ".as_ref(),
-            "is a ".as_ref(),
-            "message.".as_ref(),
-        ];
-        let mut buf = vec![0u8; 0];
-        write_bytes(&mut buf, Reply::new(26, 0, payload)).unwrap();
-        assert_eq!(buf[0..4], b![0x29, 0x00, 0x00, 0x00], "header.len");
-        assert_eq!(buf[4..8], b![0x00, 0x00, 0x00, 0x00], "header.error");
-        assert_eq!(
-            buf[8..16],
-            b![0x1a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
-            "header.unique"
-        );
-        assert_eq!(buf[16..], *b"hello, this is a message.", "payload");
+    fn recursive_mount() {
+        let mountpoint = env::temp_dir().join(format!("polyfuse-recursive-{}", process::id()));
+        let backing = mountpoint.join("backing");
+        let link = env::temp_dir().join(format!("polyfuse-recursive-link-{}", process::id()));
+        fs::create_dir_all(&backing).unwrap();
+        let _ = fs::remove_file(&link);
+        std::os::unix::fs::symlink(&mountpoint, &link).unwrap();
+
+        let mount = |backing_path: &Path| {
+            let mut config = KernelConfig::default();
+            config.backing_path(backing_path);
+            match Session::mount(mountpoint.clone(), config) {
+                Ok(..) => panic!("the recursive mount must be refused"),
+                Err(err) => err.kind(),
+            }
+        };
+
+        // The backing store under the mountpoint, or the mountpoint itself
+        // reached through a symbolic link.
+        assert_eq!(mount(&backing), io::ErrorKind::InvalidInput);
+        assert_eq!(mount(&link), io::ErrorKind::InvalidInput);
+
+        let _ = fs::remove_file(&link);
+        let _ = fs::remove_dir_all(&mountpoint);
+    }
+
+    #[test]
+    fn rootmode() {
+        let dir = env::temp_dir().join(format!("polyfuse-rootmode-{}", process::id()));
+        let file = env::temp_dir().join(format!("polyfuse-rootmode-file-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&file, b"").unwrap();
+
+        let mount = |mountpoint: &Path, config: KernelConfig| match Session::mount(
+            mountpoint.to_owned(),
+            config,
+        ) {
+            Ok(..) => panic!("the mismatched root must be refused"),
+            Err(err) => err.kind(),
+        };
+
+        let mut config = KernelConfig::default();
+        config.rootmode(libc::S_IFREG | 0o644);
+        assert_eq!(config.mountopts.rootmode, Some(libc::S_IFREG));
+        // The mode is left for fusermount to derive from the mountpoint.
+        assert_eq!(config.mountopts.to_option_string(), "auto_unmount");
+        assert_eq!(mount(&dir, config), io::ErrorKind::InvalidInput);
+
+        // The type is taken from the root attributes unless specified.
+        let mut attr = AttrOut::default();
+        attr.attr().mode(libc::S_IFDIR | 0o755);
+        let mut config = KernelConfig::default();
+        config.root_attr(attr);
+        assert_eq!(mount(&file, config), io::ErrorKind::InvalidInput);
+
+        let _ = fs::remove_file(&file);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn clone_device_requires_fuse_device() {
+        let (session, _kernel) = test_session(KernelConfig::default());
+        assert!(session.clone_device().is_err());
     }
 }
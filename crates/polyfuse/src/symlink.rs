@@ -0,0 +1,61 @@
+//! Resolution of the symbolic links generated by the filesystem itself.
+
+use crate::reply::Errno;
+
+/// The number of symbolic links the kernel follows in a path resolution
+/// before failing with `ELOOP`.
+pub const MAX_LINK_DEPTH: usize = 40;
+
+/// Follow a chain of the symbolic links generated by the filesystem itself,
+/// such as to report the attributes of the final target.
+///
+/// The kernel limits the resolution of the links in a path, but a filesystem
+/// resolving its own synthetic links, which may point to each other, has to
+/// guard against the loops by itself. `next` returns the target of a link,
+/// or `None` if it is not a link. At most `max_depth` links are followed,
+/// and `Errno::Loop` is returned if the target is still a link after that,
+/// which can be replied to the kernel as is.
+pub fn follow_links<T, F>(start: T, max_depth: usize, mut next: F) -> Result<T, Errno>
+where
+    F: FnMut(&T) -> Result<Option<T>, Errno>,
+{
+    let mut current = start;
+    for _ in 0..max_depth {
+        match next(&current)? {
+            Some(target) => current = target,
+            None => return Ok(current),
+        }
+    }
+    match next(&current)? {
+        Some(..) => Err(Errno::Loop),
+        None => Ok(current),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loop_depth() {
+        // A chain of 3 links ending at node 3.
+        let chain = |&node: &u64| Ok(if node < 3 { Some(node + 1) } else { None });
+        assert_eq!(follow_links(0, MAX_LINK_DEPTH, chain), Ok(3));
+        assert_eq!(follow_links(0, 3, chain), Ok(3));
+        assert_eq!(follow_links(0, 2, chain), Err(Errno::Loop));
+        assert_eq!(follow_links(3, 0, chain), Ok(3));
+
+        // A cycle of the generated links: 0 -> 1 -> 2 -> 0.
+        let mut followed = 0;
+        let cycle = |&node: &u64| {
+            followed += 1;
+            Ok(Some((node + 1) % 3))
+        };
+        assert_eq!(follow_links(0, MAX_LINK_DEPTH, cycle), Err(Errno::Loop));
+        assert_eq!(followed, MAX_LINK_DEPTH + 1);
+
+        // The errors of the backend are propagated.
+        let broken = |_: &u64| Err(Errno::Io);
+        assert_eq!(follow_links(0, MAX_LINK_DEPTH, broken), Err(Errno::Io));
+    }
+}
@@ -0,0 +1,134 @@
+//! Tests against the real FUSE device.
+//!
+//! These mount a filesystem, so they need `/dev/fuse` and the privilege to
+//! call mount(2). Run them with `cargo test -p polyfuse -- --ignored`.
+
+#![cfg(target_os = "linux")]
+
+use polyfuse::{op::Operation, reply::StatfsOut, KernelConfig, Session};
+use std::{
+    env,
+    ffi::CString,
+    fs, io, mem,
+    os::unix::{ffi::OsStrExt as _, io::AsRawFd as _},
+    path::PathBuf,
+    process,
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Mount a filesystem on a temporary directory by mount(2), and establish
+/// the session on its device.
+fn mount(name: &str, config: KernelConfig) -> (Session, Mountpoint) {
+    let fd = unsafe {
+        libc::open(
+            b"/dev/fuse\0".as_ptr() as *const libc::c_char,
+            libc::O_RDWR | libc::O_CLOEXEC,
+        )
+    };
+    assert!(fd >= 0, "open /dev/fuse: {}", io::Error::last_os_error());
+
+    let mountpoint = env::temp_dir().join(format!("polyfuse-{}-{}", name, process::id()));
+    fs::create_dir_all(&mountpoint).unwrap();
+    let target = CString::new(mountpoint.as_os_str().as_bytes()).unwrap();
+    let data = CString::new(format!("fd={},rootmode=40000,user_id=0,group_id=0", fd)).unwrap();
+    let res = unsafe {
+        libc::mount(
+            b"polyfuse\0".as_ptr() as *const libc::c_char,
+            target.as_ptr(),
+            b"fuse\0".as_ptr() as *const libc::c_char,
+            0,
+            data.as_ptr() as *const libc::c_void,
+        )
+    };
+    assert_eq!(res, 0, "mount: {}", io::Error::last_os_error());
+
+    let session = unsafe { Session::from_raw_fd(fd, config) }.unwrap();
+    (
+        session,
+        Mountpoint {
+            path: mountpoint,
+            target,
+        },
+    )
+}
+
+/// A mounted directory, which is detached and removed on drop.
+struct Mountpoint {
+    path: PathBuf,
+    target: CString,
+}
+
+impl Drop for Mountpoint {
+    fn drop(&mut self) {
+        // Detaching the mount aborts the pending requests, so no thread is
+        // left blocked when the server does not answer in time.
+        unsafe { libc::umount2(self.target.as_ptr(), libc::MNT_DETACH) };
+        let _ = fs::remove_dir(&self.path);
+    }
+}
+
+#[test]
+#[ignore]
+fn clone_device() {
+    let (session, mountpoint) = mount("clone", KernelConfig::default());
+    let device = session.clone_device().unwrap();
+
+    // Only the clone is read from, so the request must arrive on it and the
+    // reply must be routed back through it.
+    let (tx, rx) = mpsc::channel();
+    let server = thread::spawn(move || {
+        let _session = session;
+        let req = device.next_request().unwrap().expect("unexpected EOF");
+        let is_statfs = match req.operation().unwrap() {
+            Operation::Statfs(..) => {
+                let mut out = StatfsOut::default();
+                out.statfs().bsize(4096);
+                req.reply(out).unwrap();
+                true
+            }
+            _ => false,
+        };
+        tx.send(is_statfs).unwrap();
+    });
+
+    let statfs = thread::spawn({
+        let target = mountpoint.target.clone();
+        move || {
+            let mut buf = mem::MaybeUninit::<libc::statvfs>::uninit();
+            let res = unsafe { libc::statvfs(target.as_ptr(), buf.as_mut_ptr()) };
+            if res == 0 {
+                Ok(unsafe { buf.assume_init() }.f_bsize)
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        }
+    });
+
+    let received = rx.recv_timeout(TIMEOUT);
+    drop(mountpoint);
+
+    assert_eq!(received, Ok(true), "statfs was not received on the clone");
+    server.join().unwrap();
+    assert_eq!(statfs.join().unwrap().unwrap(), 4096);
+}
+
+#[test]
+#[ignore]
+fn backing_file() {
+    let mut config = KernelConfig::default();
+    config.passthrough(1);
+    let (session, _mountpoint) = mount("backing", config);
+    assert_eq!(session.init_out().max_stack_depth(), Some(1));
+
+    let path = env::temp_dir().join(format!("polyfuse-backing-file-{}", process::id()));
+    let file = fs::File::create(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    let backing_id = session.open_backing(file.as_raw_fd()).unwrap();
+    session.close_backing(backing_id).unwrap();
+    assert!(session.close_backing(backing_id).is_err());
+}
@@ -2,7 +2,7 @@
 #![deny(clippy::unimplemented)]
 
 use polyfuse::{
-    op,
+    op::{self, FileType},
     reply::{AttrOut, EntryOut, FileAttr, ReaddirOut},
     KernelConfig, Operation, Request, Session,
 };
@@ -50,7 +50,7 @@ struct Hello {
 struct DirEntry {
     name: &'static str,
     ino: u64,
-    typ: u32,
+    typ: FileType,
 }
 
 impl Hello {
@@ -59,17 +59,17 @@ impl Hello {
         entries.push(DirEntry {
             name: ".",
             ino: ROOT_INO,
-            typ: libc::DT_DIR as u32,
+            typ: FileType::Directory,
         });
         entries.push(DirEntry {
             name: "..",
             ino: ROOT_INO,
-            typ: libc::DT_DIR as u32,
+            typ: FileType::Directory,
         });
         entries.push(DirEntry {
             name: HELLO_FILENAME,
             ino: HELLO_INO,
-            typ: libc::DT_REG as u32,
+            typ: FileType::RegularFile,
         });
 
         Self {
@@ -131,16 +131,7 @@ impl Hello {
             _ => return req.reply_error(libc::ENOENT),
         }
 
-        let mut data: &[u8] = &[];
-
-        let offset = op.offset() as usize;
-        if offset < HELLO_CONTENT.len() {
-            let size = op.size() as usize;
-            data = &HELLO_CONTENT[offset..];
-            data = &data[..std::cmp::min(data.len(), size)];
-        }
-
-        req.reply(data)
+        req.reply(op.slice(HELLO_CONTENT))
     }
 
     fn dir_entries(&self) -> impl Iterator<Item = (u64, &DirEntry)> + '_ {
@@ -158,7 +149,7 @@ impl Hello {
         let mut out = ReaddirOut::new(op.size() as usize);
 
         for (i, entry) in self.dir_entries().skip(op.offset() as usize) {
-            let full = out.entry(
+            let full = out.typed_entry(
                 entry.name.as_ref(), //
                 entry.ino,
                 entry.typ,
@@ -0,0 +1,115 @@
+use polyfuse::{op, reply::AttrOut, service::Service, KernelConfig, Operation, Request, Session};
+
+use anyhow::{ensure, Context as _, Result};
+use std::{
+    io,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+const CONTENT: &[u8] = b"Hello from FUSE!\n";
+
+fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let mut args = pico_args::Arguments::from_env();
+
+    let mountpoint: PathBuf = args.free_from_str()?.context("missing mountpoint")?;
+    ensure!(mountpoint.is_file(), "mountpoint must be a regular file");
+
+    let session = Session::mount(mountpoint, KernelConfig::default())?;
+
+    // The requests pass through the layers from the outermost one.
+    session.run(Logging {
+        inner: RateLimit::new(100, Filesystem),
+    })?;
+
+    Ok(())
+}
+
+/// Log every request and the time spent to handle it.
+struct Logging<S> {
+    inner: S,
+}
+
+impl<S: Service> Service for Logging<S> {
+    fn call(&self, req: &mut Request) -> io::Result<()> {
+        let start = Instant::now();
+        let res = self.inner.call(req);
+        tracing::info!(unique = req.unique(), elapsed = ?start.elapsed(), "handled");
+        res
+    }
+}
+
+/// Reject the requests exceeding the given number per second with `EAGAIN`.
+struct RateLimit<S> {
+    limit: u32,
+    window: Mutex<(Instant, u32)>,
+    inner: S,
+}
+
+impl<S> RateLimit<S> {
+    fn new(limit: u32, inner: S) -> Self {
+        Self {
+            limit,
+            window: Mutex::new((Instant::now(), 0)),
+            inner,
+        }
+    }
+}
+
+impl<S: Service> Service for RateLimit<S> {
+    fn call(&self, req: &mut Request) -> io::Result<()> {
+        let exceeded = {
+            let mut window = self.window.lock().unwrap();
+            if window.0.elapsed() >= Duration::from_secs(1) {
+                *window = (Instant::now(), 0);
+            }
+            window.1 += 1;
+            window.1 > self.limit
+        };
+        if exceeded {
+            return req.reply_error(libc::EAGAIN);
+        }
+        self.inner.call(req)
+    }
+}
+
+/// The innermost service, a read-only filesystem where the root is a regular file.
+struct Filesystem;
+
+impl Service for Filesystem {
+    fn call(&self, req: &mut Request) -> io::Result<()> {
+        match req.operation()? {
+            Operation::Getattr(op) => getattr(req, op),
+            Operation::Read(op) => read(req, op),
+            _ => req.reply_error(libc::ENOSYS),
+        }
+    }
+}
+
+fn getattr(req: &Request, op: op::Getattr<'_>) -> io::Result<()> {
+    if op.ino() != 1 {
+        return req.reply_error(libc::ENOENT);
+    }
+
+    let mut out = AttrOut::default();
+    out.attr().ino(1);
+    out.attr().mode(libc::S_IFREG | 0o444);
+    out.attr().size(CONTENT.len() as u64);
+    out.attr().nlink(1);
+    out.attr().uid(unsafe { libc::getuid() });
+    out.attr().gid(unsafe { libc::getgid() });
+    out.ttl(Duration::from_secs(1));
+
+    req.reply(out)
+}
+
+fn read(req: &Request, op: op::Read<'_>) -> io::Result<()> {
+    if op.ino() != 1 {
+        return req.reply_error(libc::ENOENT);
+    }
+
+    req.reply(op.slice(CONTENT))
+}
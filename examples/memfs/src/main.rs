@@ -362,7 +362,7 @@ impl MemFS {
         }
 
         if let Some(mode) = op.mode() {
-            inode.attr.st_mode = mode;
+            inode.attr.st_mode = mode.into_raw();
         }
         if let Some(uid) = op.uid() {
             inode.attr.st_uid = uid;
@@ -461,8 +461,8 @@ impl MemFS {
     }
 
     fn do_mknod(&self, req: &Request, op: op::Mknod<'_>) -> io::Result<()> {
-        match op.mode() & libc::S_IFMT {
-            libc::S_IFREG => (),
+        match op.mode().file_type() {
+            Some(op::FileType::RegularFile) => (),
             _ => return req.reply_error(libc::ENOTSUP),
         }
 
@@ -471,7 +471,7 @@ impl MemFS {
                 let mut attr = unsafe { mem::zeroed::<libc::stat>() };
                 attr.st_ino = entry.ino();
                 attr.st_nlink = 1;
-                attr.st_mode = op.mode();
+                attr.st_mode = op.mode().into_raw();
                 attr
             },
             xattrs: HashMap::new(),
@@ -487,7 +487,7 @@ impl MemFS {
                 let mut attr = unsafe { mem::zeroed::<libc::stat>() };
                 attr.st_ino = entry.ino();
                 attr.st_nlink = 2;
-                attr.st_mode = op.mode() | libc::S_IFDIR;
+                attr.st_mode = op.mode().into_raw() | libc::S_IFDIR;
                 attr
             },
             xattrs: HashMap::new(),
@@ -103,7 +103,7 @@ fn main() -> Result<()> {
                     try_reply!(fs.make_node(
                         op.parent(),
                         op.name(),
-                        op.mode(),
+                        op.mode().into_raw(),
                         Some(op.rdev()),
                         None
                     ))
@@ -111,7 +111,7 @@ fn main() -> Result<()> {
                 Operation::Mkdir(op) => try_reply!(fs.make_node(
                     op.parent(),
                     op.name(),
-                    libc::S_IFDIR | op.mode(),
+                    libc::S_IFDIR | op.mode().into_raw(),
                     None,
                     None
                 )),
@@ -300,7 +300,7 @@ impl Passthrough {
         };
 
         // chmod
-        if let Some(mode) = op.mode() {
+        if let Some(mode) = op.mode().map(op::Mode::into_raw) {
             if let Some(file) = file.as_mut() {
                 fs::fchmod(&**file, mode)?;
             } else {
@@ -551,7 +551,7 @@ impl Passthrough {
         let inode = inode.lock().unwrap();
 
         let mut options = OpenOptions::new();
-        match (op.flags() & 0x03) as i32 {
+        match op.flags().access_mode() {
             libc::O_RDONLY => {
                 options.read(true);
             }
@@ -563,7 +563,7 @@ impl Passthrough {
             }
             _ => (),
         }
-        options.custom_flags(op.flags() as i32 & !libc::O_NOFOLLOW);
+        options.custom_flags(op.flags().into_raw() as i32 & !libc::O_NOFOLLOW);
 
         let file = options.open(&inode.fd.procname())?;
         let fh = self.opened_files.insert(Mutex::new(file));
@@ -232,7 +232,7 @@ impl PathThrough {
 
         // chmod
         if let Some(mode) = op.mode() {
-            let perm = std::fs::Permissions::from_mode(mode);
+            let perm = std::fs::Permissions::from_mode(mode.into_raw());
             file.file.set_permissions(perm)?;
         }
 
@@ -350,7 +350,7 @@ impl PathThrough {
         let inode = self.inodes.get(op.ino()).ok_or_else(no_entry)?;
 
         let mut options = OpenOptions::new();
-        match op.flags() as i32 & libc::O_ACCMODE {
+        match op.flags().access_mode() {
             libc::O_RDONLY => {
                 options.read(true);
             }
@@ -362,7 +362,7 @@ impl PathThrough {
             }
             _ => (),
         }
-        options.custom_flags(op.flags() as i32 & !libc::O_NOFOLLOW);
+        options.custom_flags(op.flags().into_raw() as i32 & !libc::O_NOFOLLOW);
 
         let fh = self.files.insert(FileHandle {
             file: options.open(self.source.join(&inode.path))?,
@@ -84,11 +84,11 @@ impl PollFS {
             }
 
             Operation::Open(op) => {
-                if op.flags() as i32 & libc::O_ACCMODE != libc::O_RDONLY {
+                if op.flags().access_mode() != libc::O_RDONLY {
                     return req.reply_error(libc::EACCES).map_err(Into::into);
                 }
 
-                let is_nonblock = op.flags() as i32 & libc::O_NONBLOCK != 0;
+                let is_nonblock = op.flags().is_nonblock();
 
                 let fh = self.next_fh.fetch_add(1, Ordering::SeqCst);
                 let handle = Arc::new(FileHandle {